@@ -28,8 +28,8 @@ pub struct Account {
     /// Incremented when the user asks for Delayed-Unstaking. The amount of unstaked near in the pools
     pub unstaked: u128,
 
-    /// The epoch height when the unstaked will be available
-    /// The funds will be locked for -AT LEAST- NUM_EPOCHS_TO_UNLOCK epochs
+    /// The epoch height when all the unstaked will be available (latest unstake ticket, see unstake_tickets.rs)
+    /// The funds will be locked for -AT LEAST- num_epochs_to_unlock epochs
     pub unstaked_requested_unlock_epoch: EpochHeight,
 
     //-- META (now mpDAO) INCENTIVES (Disabled on 2023-05)
@@ -146,6 +146,7 @@ impl Account {
 
     /// user method
     /// completes unstake action by moving from acc.unstaked & main.retrieved_for_unstaked_claims -> acc.available & main.total_available
+    /// the unstaking delay is checked by the caller, see MetaPool.internal_finish_unstaking
     pub fn in_memory_try_finish_unstaking(
         &mut self,
         account_id: &str,
@@ -158,11 +159,6 @@ impl Account {
             self.unstaked
         );
 
-        let epoch = env::epoch_height();
-        assert!( epoch >= self.unstaked_requested_unlock_epoch,
-            "The unstaked balance is not yet available due to unstaking delay. You need to wait at least {} epochs"
-            , self.unstaked_requested_unlock_epoch - epoch);
-
         // in the account, moves from unstaked to available
         self.unstaked -= amount; //Zeroes, claimed
         self.available += amount;
@@ -199,7 +195,7 @@ impl Account {
         // if the amount is close to user's total, remove user's total
         // to: a) do not leave less than ONE_MILLI_NEAR in the account, b) Allow some yoctos of rounding, e.g. remove(100) removes 99.999993 without panicking
         // Audit Note: Do not do this for .lockup accounts because the lockup contract relies on precise amounts
-        if is_lockup_account(account_id) || !is_close(amount_requested, self.available) { 
+        if main.is_lockup_account(account_id) || !is_close(amount_requested, self.available) { 
             // exact amount
             amount_requested
         }
@@ -19,6 +19,8 @@ impl MetaPool {
         //this fn is open to be called by anyone
 
         self.assert_not_busy();
+        self.assert_staking_not_paused();
+        self.assert_operation_not_paused(PausableOperation::DistributeStaking);
 
         //do we need to stake?
         if self.total_for_staking <= self.total_actually_staked {
@@ -60,6 +62,11 @@ impl MetaPool {
             sp_inx,
             stake_required
         );
+        if stake_required == 0 {
+            // all pools requiring stake are busy with in-flight operations
+            log!("no pool available for staking");
+            return false;
+        }
         // schedule promise to stake
         let amount_to_stake = std::cmp::min(total_amount_to_stake, stake_required);
         self.launch_direct_stake(sp_inx, amount_to_stake);
@@ -71,74 +78,20 @@ impl MetaPool {
     /// **schedules promises** to stake 
     /// Note: if the sp has some sizable unstake pending, the fn will re-stake the unstaked-and-waiting-amount
     /// that amount can be lower than the amount requested to stake
-    fn launch_direct_stake(&mut self, sp_inx:usize, mut amount_to_stake:u128) {
+    fn launch_direct_stake(&mut self, sp_inx:usize, amount_to_stake:u128) {
 
         if amount_to_stake > 0 {
             //most unbalanced pool found & available
-
-            self.contract_busy = true;
-            let sp = &mut self.staking_pools[sp_inx];
-            sp.busy_lock = true;
-
-            //case 1. pool has unstaked amount (we could be at the unstaking delay waiting period)
-            //NOTE: The amount to stake can't be so low as a few yoctos because the staking-pool
-            // will panic with : "panicked at 'The calculated number of \"stake\" shares received for staking should be positive', src/internal.rs:79:9"
-            // that's because after division, if the amount is a few yoctos, the amount for shares is 0
-            if sp.unstaked >= TEN_NEAR {
-                //at least 10 NEAR
-                //pool has a sizable unstaked amount
-                if sp.unstaked < amount_to_stake {
-                    //re-stake the unstaked
-                    amount_to_stake = sp.unstaked;
-                }
-
-                //schedule async stake to re-stake in the pool
-                ext_staking_pool::stake(
-                    amount_to_stake.into(),
-                    &sp.account_id,
-                    NO_DEPOSIT,
-                    gas::staking_pool::STAKE,
-                )
-                .then(ext_self_owner::on_staking_pool_stake_maybe_deposit(
-                    sp_inx,
-                    amount_to_stake,
-                    false,
-                    &env::current_account_id(),
-                    NO_DEPOSIT,
-                    gas::owner_callbacks::ON_STAKING_POOL_DEPOSIT_AND_STAKE,
-                ));
-            } else {
-                //here the sp has no sizable unstaked balance, we must deposit_and_stake on the sp from our balance
-
-                // NOTE: This contract holds also the liquidity pool for fast unstake may be too optimistic, why not compute the storage explicitly and add
-                //    a safety margin on top of that. That's because the account state may
-                //    potentially exceed the 35N (or 3.5M right now). But I guess it can happen
-                //    only at the beginning of metapool before the liquidity is provided.
-                assert!(
-                    env::account_balance() - MIN_BALANCE_FOR_STORAGE >= amount_to_stake,
-                    "env::account_balance()-MIN_BALANCE_FOR_STORAGE < amount_to_stake"
-                );
-
-                //schedule async stake or deposit_and_stake on that pool
-                ext_staking_pool::deposit_and_stake(
-                    &sp.account_id,
-                    amount_to_stake.into(), //attached amount
-                    gas::staking_pool::DEPOSIT_AND_STAKE,
-                )
-                .then(ext_self_owner::on_staking_pool_stake_maybe_deposit(
-                    sp_inx,
-                    amount_to_stake,
-                    true,
-                    &env::current_account_id(),
-                    NO_DEPOSIT,
-                    gas::owner_callbacks::ON_STAKING_POOL_DEPOSIT_AND_STAKE,
-                ));
-            }
+            let (promise, amount_to_stake, included_deposit) = self.internal_launch_stake(sp_inx, amount_to_stake);
+            promise.then(ext_self_owner::on_staking_pool_stake_maybe_deposit(
+                self.staking_pools[sp_inx].id,
+                amount_to_stake,
+                included_deposit,
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                gas::owner_callbacks::ON_STAKING_POOL_DEPOSIT_AND_STAKE,
+            ));
         }
-
-        //Here we did some staking (the promises are scheduled for exec after this fn completes)
-        self.total_actually_staked += amount_to_stake; //preventively consider the amount staked (undoes if async fails)
-        self.epoch_stake_orders -= amount_to_stake; //preventively reduce stake orders
     }
 
     //prev fn continues here
@@ -147,57 +100,14 @@ impl MetaPool {
     #[private]
     pub fn on_staking_pool_stake_maybe_deposit(
         &mut self,
-        sp_inx: usize,
+        sp_id: u16,
         amount: u128,
         included_deposit: bool,
     ) -> bool {
-        let sp = &mut self.staking_pools[sp_inx];
-        let sp_account_id = sp.account_id.clone();
-
-        //WARN: This is a callback after-cross-contract-call method
-        //busy locks must be saved false in the state, this method SHOULD NOT PANIC
-        sp.busy_lock = false;
-        self.contract_busy = false;
-
         let stake_succeeded = is_promise_success();
-
-        let result: &str;
-        if stake_succeeded {
-            // STAKED OK
-            result = "succeeded";
-            // move into staked
-            sp.staked += amount;
-            // update accums based on the source of the funds
-            let event: &str;
-            if included_deposit {
-                // we sent NEAR from the contract into the staking-pool
-                event = "dist.stak"; //stake in the pools (including transfer)
-                self.contract_account_balance -= amount; // we took from contract balance (transfer)
-            } else {
-                // stake the unstaked in the pool, no-transfer
-                event = "dist.stak.nt"; //not deposited first, so staked funds came from unstaked funds already in the staking-pool
-                sp.unstaked -= amount; //we've now less unstaked in this sp
-                self.total_unstaked_and_waiting -= amount; // contract total of all unstaked & waiting, now there's less there.
-                                                           // We kept the NEAR in the contract and took from unstaked_and_waiting
-                                                           // ... unstaked_and_waiting was in their way to be converted in retrieved_for_unstake_claims
-                self.consider_retrieved_for_unstake_claims(amount); // so this is a special case: the NEAR to stake was taken from total_unstaked_and_waiting,
-                                                             // so we compensate and take the NEAR in the contract and consider it reserved for_unstake_claims
-            }
-            //log event
-            event!(
-                r#"{{"event":"{}","sp":"{}","amount":"{}"}}"#,
-                event,
-                sp_account_id,
-                amount
-            );
-        } else {
-            //STAKE FAILED
-            result = "has failed";
-            self.total_actually_staked -= amount; //undo preventive action considering the amount staked
-            self.epoch_stake_orders += amount; //undo preventively reduce stake orders
+        if let Some(sp_inx) = self.settle_sp_inx(sp_id) {
+            self.internal_settle_stake(sp_inx, amount, included_deposit, stake_succeeded);
         }
-        log!("Staking of {} at @{} {}", amount, sp_account_id, result);
-
         return stake_succeeded;
     }
 
@@ -205,9 +115,11 @@ impl MetaPool {
     // used by operator if a validator requires stake to keep a seat
     // Note: this fn stakes from current epochs_stake_orders,
     // consider that the scheduled promise-to-stake/restake can fail
-    pub fn manual_stake(&mut self, inx: u16, amount: U128String) {
-        self.assert_operator_or_owner();
+    pub fn manual_stake(&mut self, inx: StakingPoolRef, amount: U128String) {
+        self.assert_role(Role::Keeper);
         self.assert_not_busy();
+        self.assert_staking_not_paused();
+        self.assert_operation_not_paused(PausableOperation::DistributeStaking);
 
         assert!(self.epoch_stake_orders > MIN_STAKE_AMOUNT,
             "self.epoch_stake_orders too low {}", 
@@ -218,10 +130,10 @@ impl MetaPool {
             self.epoch_stake_orders, amount.0
         );
 
-        let sp_inx = inx as usize;
-        assert!(sp_inx < self.staking_pools.len(), "invalid index");
+        let sp_inx = self.sp_inx(&inx);
         let sp = &self.staking_pools[sp_inx];
-        assert!(!sp.busy_lock, "sp busy");
+        assert!(!sp.is_busy(), "sp busy");
+        assert!(!sp.stake_paused, "staking into sp is paused");
         // schedule promise to direct stake
         self.launch_direct_stake(sp_inx, amount.0);
         // Note: if the pool has some sizable unstake pending, the fn will re-stake the unstaked-and-waiting-amount
@@ -231,14 +143,17 @@ impl MetaPool {
     /// Start a forced rebalance unstake of ALL extra for a pool
     /// used by operator when a validator goes offline, to not wait and unstake immediately even over the max-rebalance-cap
     /// the stake of the sp is adjusted to weight, if weight==0, the sp is fully unstaked
-    pub fn force_rebalance_unstake(&mut self, inx: u16) {
+    /// Note: allowed while staking is paused, it's the tool to get the funds out of a failing validator
+    pub fn force_rebalance_unstake(&mut self, inx: StakingPoolRef) {
         self.perform_rebalance(inx, self.total_for_staking);
     }
 
     /// Start a rebalance unstake of PARTIAL extra for a pool (capped by max_unstake_for_rebalance)
     /// used by operator when a validator to rebalance low performers
     /// the stake of the sp is adjusted limited by extra and max-rebalance-unstake
-    pub fn rebalance_unstake_sp(&mut self, inx: u16) {
+    pub fn rebalance_unstake_sp(&mut self, inx: StakingPoolRef) {
+        self.assert_staking_not_paused();
+        self.assert_operation_not_paused(PausableOperation::DistributeUnstaking);
         let max_unstake_for_rebalance = self.max_unstake_for_rebalance();
         assert!(self.unstaked_for_rebalance + MIN_STAKE_UNSTAKE_AMOUNT_MOVEMENT < max_unstake_for_rebalance, 
             "max unstake for rebalance already reached");
@@ -247,13 +162,12 @@ impl MetaPool {
     }
 
     // internal common process for the prev 2 pub fns
-    fn perform_rebalance(&mut self, inx: u16, cap: u128) {
-        self.assert_operator_or_owner();
+    fn perform_rebalance(&mut self, inx: StakingPoolRef, cap: u128) {
+        self.assert_role(Role::Keeper);
         self.assert_not_busy();
-        let sp_inx = inx as usize;
-        assert!(sp_inx < self.staking_pools.len(), "invalid index");
+        let sp_inx = self.sp_inx(&inx);
         let sp = &self.staking_pools[sp_inx];
-        assert!(!sp.busy_lock, "sp busy");
+        assert!(!sp.is_busy(), "sp busy");
         // can not unstake while unstake pending (if it was done on previous epochs) 
         // because it will extend the waiting period
         assert!(
@@ -290,7 +204,15 @@ impl MetaPool {
     /// Note: It could happen that some users perform delayed-unstake during those epochs, that amount will be preserved in the contract,...
     /// ... because total_unstake_claims has priority over rebalance.
     pub fn do_rebalance_unstake(&mut self) -> bool {
-        self.assert_operator_or_owner();
+        self.assert_role(Role::Keeper);
+        self.internal_do_rebalance_unstake()
+    }
+
+    /// do_rebalance_unstake without the role check, the heartbeat runs it for any keeper
+    /// (the amounts come from get_staking_pool_requiring_unstake, so the caller can't choose them)
+    pub(crate) fn internal_do_rebalance_unstake(&mut self) -> bool {
+        self.assert_staking_not_paused();
+        self.assert_operation_not_paused(PausableOperation::DistributeUnstaking);
 
         // check for max x% unstaked
         let max_unstake_for_rebalance = self.max_unstake_for_rebalance() ;
@@ -336,6 +258,8 @@ impl MetaPool {
         //this fn is open to be called by anyone
 
         self.assert_not_busy();
+        self.assert_staking_not_paused();
+        self.assert_operation_not_paused(PausableOperation::DistributeUnstaking);
         // clearing first
         self.internal_end_of_epoch_clearing();
         // after clearing, epoch_unstake_orders is the amount to unstake
@@ -347,39 +271,17 @@ impl MetaPool {
             return false;
         }
 
-        let mut unstake_from_orders = self.epoch_unstake_orders;
-        let mut unstake_from_rebalance = 0;
-
-        // resulting "amount_to_unstake" can be lower than total_to_unstake, according to conditions in get_staking_pool_requiring_unstake 
-        let gspru = self.internal_get_staking_pool_requiring_unstake();
-
-        log!(
-            r#"{{"event":"sp_has_extra","sp":"{}","amount":"{}"}}"#,
-            gspru.sp_inx,
-            gspru.extra
-        );
-
-        if gspru.extra > 0 {
-            if gspru.extra <= self.epoch_unstake_orders {
-                unstake_from_orders = gspru.extra // no more than what the pool has extra
-            }
-            else { // more extra in the sp that the amount ordered
-                // check if we can seize this opportunity to also unstake for rebalance
-                let max_unstake_for_rebalance = self.max_unstake_for_rebalance();
-                if self.unstaked_for_rebalance < max_unstake_for_rebalance {
-                    let cap_to_extra_unstake_for_rebalance = max_unstake_for_rebalance - self.unstaked_for_rebalance;
-                    if cap_to_extra_unstake_for_rebalance > 1*NEAR {
-                        unstake_from_rebalance = std::cmp::min(cap_to_extra_unstake_for_rebalance, gspru.extra - unstake_from_orders);
-                    }
-                }
-            }
-        }
+        // the largest part of the split, the next call continues with the next pool
+        let plan = match self.internal_plan_unstake(MAX_BATCH_POOLS as usize).into_iter().next() {
+            Some(plan) => plan,
+            None => return false,
+        };
+        let (unstake_from_orders, unstake_from_rebalance) = (plan.from_orders, plan.from_rebalance);
 
         if unstake_from_orders + unstake_from_rebalance > 10 * TGAS as u128 {
             // only if the amount justifies tx-fee
-            // most unbalanced pool found & available
             // continue with generating the promise for async cross-contract call to unstake
-            self.perform_unstake(gspru.sp_inx as usize, unstake_from_orders, unstake_from_rebalance);
+            self.perform_unstake(plan.sp_inx, unstake_from_orders, unstake_from_rebalance);
             return self.epoch_unstake_orders > 0; // if needs to be called again
         } else {
             return false;
@@ -400,105 +302,116 @@ impl MetaPool {
             return;
         }
         self.assert_not_busy();
-
-        assert!(self.total_actually_staked >= total_amount, "IUN");
-        assert!(sp_inx < self.staking_pools.len(), "invalid index");
-        let sp = &mut self.staking_pools[sp_inx];
-        assert!(!sp.busy_lock,"sp is busy");
-        assert!(
-            sp.staked >= total_amount,
-            "only {} staked can not unstake {}",
-            sp.staked,
-            total_amount,
-        );
-        
-        self.contract_busy = true;
-        sp.busy_lock = true;
-
-        // preventively consider the amount un-staked (undoes if promise fails)
-        self.total_actually_staked -= total_amount;
-        self.epoch_unstake_orders -= amount_from_unstake_orders; // preventively consider the unstake_order fulfilled
-
-        //launch async to un-stake from the pool
-        ext_staking_pool::unstake(
-            total_amount.into(),
-            &sp.account_id,
-            NO_DEPOSIT,
-            gas::staking_pool::UNSTAKE,
-        )
-        .then(ext_self_owner::on_staking_pool_unstake(
-            sp_inx,
-            amount_from_unstake_orders.into(),
-            amount_from_rebalance.into(),
-            //extra async call args
-            &env::current_account_id(),
-            NO_DEPOSIT,
-            gas::owner_callbacks::ON_STAKING_POOL_UNSTAKE,
-        ));
+        self.internal_launch_unstake(sp_inx, amount_from_unstake_orders, amount_from_rebalance)
+            .then(ext_self_owner::on_staking_pool_unstake(
+                self.staking_pools[sp_inx].id,
+                amount_from_unstake_orders.into(),
+                amount_from_rebalance.into(),
+                //extra async call args
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                gas::owner_callbacks::ON_STAKING_POOL_UNSTAKE,
+            ));
     }
     /// The prev fn continues here
     /// Called after the given amount was unstaked at the staking pool contract.
     /// This method needs to update staking pool status.
     #[private]
     pub fn on_staking_pool_unstake(&mut self, 
-        sp_inx: usize, 
+        sp_id: u16, 
         amount_from_unstake_orders: U128String, 
         amount_from_rebalance: U128String, 
     ) 
     {
-        let sp = &mut self.staking_pools[sp_inx];
-        let total_amount = amount_from_unstake_orders.0 + amount_from_rebalance.0;
-
         let unstake_succeeded = is_promise_success();
-
-        let result: &str;
-        if unstake_succeeded {
-            result = "succeeded";
-            sp.staked -= total_amount;
-            sp.unstaked += total_amount;
-            sp.unstk_req_epoch_height = env::epoch_height();
-            self.total_unstaked_and_waiting += total_amount; // contract total unstaked_and_waiting
-            self.unstaked_for_rebalance += amount_from_rebalance.0; // total unstaked_and_waiting for rebalance
-            event!(
-                r#"{{"event":"unstk","sp":"{}","amount_fuo":"{}","amount_fr":"{}" }}"#,
-                sp.account_id,
-                amount_from_unstake_orders.0,
-                amount_from_rebalance.0
-            );
-        } else {
-            result = "has failed";
-            self.total_actually_staked += total_amount; //undo preventive action considering the amount unstaked
-            self.epoch_unstake_orders += amount_from_unstake_orders.0; // undo preventive action considering the order fulfilled
+        if let Some(sp_inx) = self.settle_sp_inx(sp_id) {
+            self.internal_settle_unstake(sp_inx, amount_from_unstake_orders.0, amount_from_rebalance.0, unstake_succeeded);
         }
-
-        log!("Unstaking of {} at @{} {}", total_amount, sp.account_id, result);
-
-        //WARN: This is a callback after-cross-contract-call method
-        //busy locks must be saved false in the state, this method SHOULD NOT PANIC
-        sp.busy_lock = false;
-        self.contract_busy = false;
     }
 
     //utility to set contract busy flag manually by operator.
+    //Note: the heartbeat only locks the pools it talks to. contract_busy is a manual global lock
+    //that stops user operations and the heartbeat until cleared
     #[payable]
     pub fn set_busy(&mut self, value: bool) {
         assert_one_yocto();
-        self.assert_operator_or_owner();
+        self.assert_role(Role::Pauser);
         assert!(self.contract_busy != value,"contract_busy is already {}",value);
-        self.contract_busy = value;
+        if value {
+            self.lock_contract();
+        } else {
+            self.contract_busy = false;
+        }
     }
     //operator manual set sp.busy_lock
     #[payable]
-    pub fn sp_busy(&mut self, sp_inx: u16, value: bool) {
+    pub fn sp_busy(&mut self, sp_inx: StakingPoolRef, value: bool) {
         assert_one_yocto();
-        self.assert_operator_or_owner();
+        self.assert_role(Role::Pauser);
+
+        let inx = self.sp_inx(&sp_inx);
+
+        let sp = &mut self.staking_pools[inx];
+        assert!(sp.busy_lock != value,"sp {}.busy_lock is already {}",sp.account_id,value);
+        if value {
+            sp.lock();
+        } else {
+            sp.unlock();
+        }
+    }
+
+    /// Clears sp busy locks older than busy_lock_expiry_blocks
+    /// open to anyone. A lock can get stuck if a callback is never executed,
+    /// e.g. it ran out of gas
+    /// Locks of pools with stake/unstake/retrieve calls in flight are not cleared, see reset_sp_in_flight
+    /// Note: contract_busy is only set manually (set_busy), so it is not cleared here
+    /// returns how many locks were cleared
+    pub fn clear_stale_busy_locks(&mut self) -> u16 {
+        let expiry_blocks = self.busy_lock_expiry_blocks;
+        let mut cleared: u16 = 0;
+        for sp in self.staking_pools.iter_mut() {
+            if sp.busy_lock && sp.in_flight == 0 && sp.busy_lock_age_blocks() >= expiry_blocks {
+                event!(
+                    r#"{{"event":"unlock.stale","sp":"{}","since_block":"{}","since_epoch":"{}"}}"#,
+                    sp.account_id,
+                    sp.busy_lock_since_block,
+                    sp.busy_lock_since_epoch
+                );
+                sp.unlock();
+                cleared += 1;
+            }
+        }
+        cleared
+    }
 
-        let inx = sp_inx as usize;
-        assert!(inx < self.staking_pools.len());
+    /// Owner's method. Sets how many blocks a busy lock can be held before anyone can clear it
+    pub fn set_busy_lock_expiry_blocks(&mut self, blocks: U64String) {
+        self.assert_owner_calling();
+        assert!(
+            blocks.0 >= MIN_BUSY_LOCK_EXPIRY_BLOCKS,
+            "min expiry is {} blocks",
+            MIN_BUSY_LOCK_EXPIRY_BLOCKS
+        );
+        self.busy_lock_expiry_blocks = blocks.0;
+    }
 
+    /// Owner's method. Recovery for a pool whose stake/unstake/retrieve callback never ran:
+    /// sets sp.in_flight to 0 and clears the busy lock.
+    /// The amounts reserved by the lost calls are not restored, the owner must check the pool's real balances
+    #[payable]
+    pub fn reset_sp_in_flight(&mut self, sp_inx: StakingPoolRef) {
+        assert_one_yocto();
+        self.assert_owner_calling();
+        let inx = self.sp_inx(&sp_inx);
         let sp = &mut self.staking_pools[inx];
-        assert!(sp.busy_lock != value,"sp[{}].busy_lock is already {}",inx,value);
-        sp.busy_lock = value;
+        assert!(sp.in_flight > 0, "sp {} has no calls in flight", sp.account_id);
+        event!(
+            r#"{{"event":"unlock.in_flight","sp":"{}","in_flight":{}}}"#,
+            sp.account_id,
+            sp.in_flight
+        );
+        sp.in_flight = 0;
+        sp.unlock();
     }
 
     //-- check If extra balance has accumulated (30% of tx fees by near-protocol)
@@ -525,18 +438,18 @@ impl MetaPool {
     /// when you unstake, core-contracts/staking-pool does some share calculation *rounding*, so the real unstaked amount is not exactly
     /// the same amount requested (a minor, few yoctoNEARS difference)
     /// this fn syncs sp.unstaked with the real, current unstaked amount informed by the sp
-    pub fn sync_unstaked_balance(&mut self, sp_inx: u16) -> Promise {
-        // Note: We avoid locking the contract here (busy_flag), to close the possibility of someone spamming this method
+    pub fn sync_unstaked_balance(&mut self, sp_inx: StakingPoolRef) -> Promise {
+        // Note: We avoid locking the pool here (busy_lock), to close the possibility of someone spamming this method
         //  to prevent operator from issuing a command. Assuming there will be a way to front-run a transaction, it can
-        //    block the contract. We do not lock the pool and the contract at all, but if the callback
-        //    is called at the moment when the pool or the contract is locked, the result is ignored.
+        //    block the pool. We do not lock the pool at all, but if the callback
+        //    is called at the moment when the pool is locked, the result is ignored.
 
-        let inx = sp_inx as usize;
-        assert!(inx < self.staking_pools.len());
+        let inx = self.sp_inx(&sp_inx);
 
         self.assert_not_busy();
+        self.assert_operation_not_paused(PausableOperation::RetrieveFunds);
         let sp = &mut self.staking_pools[inx];
-        assert!(!sp.busy_lock, "sp is busy");
+        assert!(!sp.is_busy(), "sp is busy");
 
         // SUGGESTION: Maybe better to call `get_account` to get information about `staked` and
         //    `unstaked` balance at the same time. Sometimes the staking pool may throw yoctoNEAR
@@ -552,7 +465,7 @@ impl MetaPool {
             gas::staking_pool::GET_ACCOUNT_TOTAL_BALANCE,
         )
         .then(ext_self_owner::on_get_sp_unstaked_balance(
-            inx,
+            sp.id,
             //promise params
             &env::current_account_id(),
             NO_DEPOSIT,
@@ -565,7 +478,7 @@ impl MetaPool {
     #[private]
     pub fn on_get_sp_unstaked_balance(
         &mut self,
-        sp_inx: usize,
+        sp_id: u16,
         #[callback] unstaked_balance: U128String,
     ) {
         // NOTE: be careful on `#[callback]` here. If the pool view call fails for some
@@ -576,39 +489,16 @@ impl MetaPool {
 
         //we enter here after asking the staking-pool how much do we have *unstaked*
         //unstaked_balance: U128String contains the answer from the staking-pool
-
-        let sp = &mut self.staking_pools[sp_inx];
-
-        // real unstaked amount for this pool
-        let real_unstaked_balance: u128 = unstaked_balance.0;
-
-        log!(
-            "inx:{} sp:{} old_unstaked_balance:{} new_unstaked_balance:{}",
-            sp_inx,
-            sp.account_id,
-            sp.unstaked,
-            real_unstaked_balance
-        );
-        // we're not locking at the start, so we check there's no in-flight transaction if we need to
-        // adjust the unstaked in a few yoctos
-        if real_unstaked_balance != sp.unstaked && (self.contract_busy || sp.busy_lock) {
+        let sp_inx = match self.settle_sp_inx(sp_id) {
+            Some(sp_inx) => sp_inx,
+            None => return,
+        };
+        if !self.internal_settle_unstaked_balance(sp_inx, unstaked_balance.0) {
             // do not proceed to update if another operation is in mid-flight
-            panic!("cant not update unstaked, contract or sp is busy, another operation is in mid-flight");
-        }
-
-        if real_unstaked_balance > sp.unstaked {
-            //positive difference
-            let difference = real_unstaked_balance - sp.unstaked;
-            log!("positive difference {}", difference);
-            sp.unstaked = real_unstaked_balance;
-            sp.staked = sp.staked.saturating_sub(difference); //the difference was in "our" record of "staked"
-        } else if real_unstaked_balance < sp.unstaked {
-            //negative difference
-            let difference = sp.unstaked - real_unstaked_balance;
-            log!("negative difference {}", difference);
-            sp.unstaked = real_unstaked_balance;
-            sp.staked += difference; //the difference was in "our" record of "staked"
+            panic!("cant not update unstaked, sp is busy, another operation is in mid-flight");
         }
+        // the heartbeat can now retrieve from this pool, see heartbeat.rs
+        self.heartbeat_synced_sp = Some(sp_id);
     }
 
     //------------------------------------------------------------------------
@@ -618,18 +508,19 @@ impl MetaPool {
     /// Ask total balance from the staking pool and remembers it internally.
     /// Also computes and distributes rewards for operator and stakers
     /// this fn queries the staking pool (makes a cross-contract call)
-    pub fn distribute_rewards(&mut self, sp_inx: u16) {
+    pub fn distribute_rewards(&mut self, sp_inx: StakingPoolRef) {
         //Note: In order to make this contract independent from the operator
         //this fn is open to be called by anyone
         //self.assert_operator_or_owner();
 
         self.assert_not_busy();
+        self.assert_operation_not_paused(PausableOperation::DistributeRewards);
 
-        let inx = sp_inx as usize;
-        assert!(inx < self.staking_pools.len());
+        let inx = self.sp_inx(&sp_inx);
 
-        let sp = &mut self.staking_pools[inx];
-        assert!(!sp.busy_lock, "sp is busy");
+        let sp = &self.staking_pools[inx];
+        assert!(!sp.is_busy(), "sp is busy");
+        let sp_id = sp.id;
 
         let epoch_height = env::epoch_height();
 
@@ -646,19 +537,10 @@ impl MetaPool {
             sp.account_id
         );
 
-        self.contract_busy = true;
-        sp.busy_lock = true;
-
         //query our current balance (includes staked+unstaked+staking rewards)
-        ext_staking_pool::get_account_total_balance(
-            env::current_account_id(),
-            //promise params
-            &sp.account_id,
-            NO_DEPOSIT,
-            gas::staking_pool::GET_ACCOUNT_TOTAL_BALANCE,
-        )
+        self.internal_launch_get_total_balance(inx)
         .then(ext_self_owner::on_get_sp_total_balance(
-            inx,
+            sp_id,
             //promise params
             &env::current_account_id(),
             NO_DEPOSIT,
@@ -667,106 +549,22 @@ impl MetaPool {
     }
 
     /// prev fn continues here
-    /*
-    Note: what does the tag #[callback] applied to a fn in parameter do?
-    #[callback] parses the previous promise's result into the param
-        Check out https://nomicon.io/RuntimeSpec/Components/BindingsSpec/PromisesAPI.html
-        1. check promise_results_count() == 1
-        2  check the execution status of the first promise and write the result into the register using promise_result(0, register_id) == 1
-            Let's say that you used register_id == 0
-        3. read register using register_len and read_register into Wasm memory
-        4. parse the data using: let total_balance: WrappedBalance = serde_json::from_slice(&buf).unwrap();
-
-    it has be last argument? can you add another argument for the on_xxx callback ?
-    before that
-    for example:
-        /// Called after the request to get the current total balance from the staking pool.
-        pub fn on_get_account_total_balance(&mut self, staking_pool_account: AccountId, #[callback] total_balance: WrappedBalance) {
-            assert_self();
-            self.set_staking_pool_status(TransactionStatus::Idle);
-            ...
-        and in the call
-            ext_staking_pool::get_account_total_balance(
-                env::current_account_id(),
-                staking_pool_account_id,
-                NO_DEPOSIT,
-                gas::staking_pool::GET_ACCOUNT_TOTAL_BALANCE,
-            )
-            .then(ext_self_owner::on_get_account_total_balance(
-                staking_pool_account_id,
-                &env::current_account_id(),
-                NO_DEPOSIT,
-                gas::owner_callbacks::ON_GET_ACCOUNT_TOTAL_BALANCE,
-            ))
-
-    #[callback] marked-arguments are parsed in order. The position within arguments are not important, but the order is.
-    If you have 2 arguments marked as #[callback] then you need to expect 2 promise results joined with promise_and
-    */
+    /// Note: the result is read with env::promise_result instead of #[callback]
+    /// a #[callback] fn is not entered when the pool's view call fails, and the pool would stay locked
     #[private]
-    pub fn on_get_sp_total_balance(
-        &mut self,
-        sp_inx: usize,
-        #[callback] total_balance: U128String,
-    ) {
+    pub fn on_get_sp_total_balance(&mut self, sp_id: u16) {
         //we enter here after asking the staking-pool how much do we have staked (plus rewards)
-        //total_balance: U128String contains the answer from the staking-pool
-
-        //new_total_balance has the new staked amount for this pool
-        let new_total_balance: u128;
-        let sp = &mut self.staking_pools[sp_inx];
-
-        //WARN: This is a callback after-cross-contract-call method
-        //busy locks must be saved false in the state, this method SHOULD NOT PANIC
-        sp.busy_lock = false;
-        self.contract_busy = false;
-
-        sp.last_asked_rewards_epoch_height = env::epoch_height();
-
-        //total_balance informed is staking-pool.staked + staking-pool.unstaked
-        new_total_balance = total_balance.0;
-
-        let rewards: u128;
-        if new_total_balance < sp.total_balance() {
-            log!(
-                "INCONSISTENCY @{} says new_total_balance < our info sp.total_balance()",
-                sp.account_id
-            );
-            rewards = 0;
-        } else {
-            //compute rewards, as new balance minus old balance
-            rewards = new_total_balance - sp.total_balance();
-        }
-
-        log!(
-            "sp:{} old_balance:{} new_balance:{} rewards:{} unstaked:{}",
-            sp.account_id,
-            sp.total_balance(),
-            new_total_balance,
-            rewards,
-            sp.unstaked
-        );
-
-        //updated accumulated_staked_rewards value for the contract
-        self.accumulated_staked_rewards += rewards;
-        //updated new "staked" value for this pool
-        sp.staked = new_total_balance - sp.unstaked;
-
-        if rewards > 0 {
-            //add to total_for_staking & total_actually_staked, increasing share value for all stNEAR holders
-            self.total_actually_staked += rewards;
-            self.total_for_staking += rewards;
-
-            // mint extra stNEAR representing fees for operator & developers
-            // The fee the operator takes from rewards (0.5%)
-            let operator_fee = apply_pct(self.operator_rewards_fee_basis_points, rewards);
-            let operator_fee_shares = self.stake_shares_from_amount(operator_fee);
-            // The fee the contract authors take from rewards (0.2%)
-            let developers_fee = apply_pct(DEVELOPERS_REWARDS_FEE_BASIS_POINTS, rewards);
-            let developers_fee_shares = self.stake_shares_from_amount(developers_fee);
-            // Now add the newly minted shares. The fee is taken by making share price increase slightly smaller
-            self.add_extra_minted_shares(self.operator_account_id.clone(), operator_fee_shares);
-            self.add_extra_minted_shares(DEVELOPERS_ACCOUNT_ID.into(), developers_fee_shares);
-
+        let sp_inx = match self.settle_sp_inx(sp_id) {
+            Some(sp_inx) => sp_inx,
+            None => return,
+        };
+        match parse_u128_result(&env::promise_result(0)) {
+            Some(total_balance) => self.internal_settle_total_balance(sp_inx, total_balance),
+            None => {
+                let sp = &mut self.staking_pools[sp_inx];
+                sp.unlock();
+                log!("get_account_total_balance from @{} has failed", sp.account_id);
+            }
         }
     }
 
@@ -774,25 +572,25 @@ impl MetaPool {
     // Operator method, but open to anyone
     //----------------------------------------------------------------------
     /// finds a pool with the unstake delay completed and some unstake ready for retrieve
-    /// Returns `sp_index` or:
+    /// Returns the pool id or:
     /// -1 if there are funds ready to retrieve but the pool is busy
     /// -2 if there are funds unstaked, but not ready in this epoch
     /// -3 if there are no unstaked funds
     pub fn get_staking_pool_requiring_retrieve(&self) -> i32 {
         let mut not_found_result_code: i32 = -3;
 
-        for (sp_inx, sp) in self.staking_pools.iter().enumerate() {
+        for sp in self.staking_pools.iter() {
             if sp.unstaked > 0 {
                 if not_found_result_code == -3 {
                     not_found_result_code = -2
                 };
-                if sp.wait_period_ended() {
+                if sp.wait_period_ended(self.num_epochs_to_unlock()) {
                     if not_found_result_code == -2 {
                         not_found_result_code = -1
                     };
-                    if !sp.busy_lock {
+                    if !sp.is_busy() {
                         // if this pool has unstaked and the waiting period has ended
-                        return sp_inx as i32;
+                        return sp.id as i32;
                     }
                 }
             }
@@ -806,41 +604,35 @@ impl MetaPool {
     //----------------------------------------------------------------------
     /// launches a withdrawal call
     /// returns the amount withdrawn
-    /// you MUST call get_staking_pool_requiring_retrieve() first, to obtain a valid inx
-    /// and you MUST call sync_unstaked_balance(inx) before this, to get the exact amount to the yocto stored in sp.unstaked
-    pub fn retrieve_funds_from_a_pool(&mut self, inx: u16) -> Promise {
+    /// you MUST call get_staking_pool_requiring_retrieve() first, to obtain a valid pool id
+    /// and you MUST call sync_unstaked_balance(id) before this, to get the exact amount to the yocto stored in sp.unstaked
+    pub fn retrieve_funds_from_a_pool(&mut self, inx: StakingPoolRef) -> Promise {
         //Note: In order to make fund-recovering independent from the operator
         //this fn is open to be called by anyone
 
-        assert!(inx < self.staking_pools.len() as u16, "invalid index");
+        let sp_inx = self.sp_inx(&inx);
 
         self.assert_not_busy();
+        self.assert_operation_not_paused(PausableOperation::RetrieveFunds);
 
-        let sp = &mut self.staking_pools[inx as usize];
-        assert!(!sp.busy_lock, "sp is busy");
+        let sp = &self.staking_pools[sp_inx];
+        assert!(!sp.is_busy(), "sp is busy");
         assert!(sp.unstaked > 0, "sp unstaked == 0");
-        if !sp.wait_period_ended() {
+        let sp_id = sp.id;
+        if !sp.wait_period_ended(self.num_epochs_to_unlock()) {
             panic!(
                 "unstaking-delay ends at {}, now is {}",
-                sp.unstk_req_epoch_height + NUM_EPOCHS_TO_UNLOCK,
+                sp.unstk_req_epoch_height + self.num_epochs_to_unlock(),
                 env::epoch_height()
             );
         }
 
         // if we're here, the pool is not busy, and we unstaked and the waiting period has elapsed
 
-        self.contract_busy = true;
-        sp.busy_lock = true;
-
         //return promise
-        return ext_staking_pool::withdraw_all(
-            //promise params:
-            &sp.account_id,
-            NO_DEPOSIT,
-            gas::staking_pool::WITHDRAW,
-        )
+        return self.internal_launch_retrieve(sp_inx)
         .then(ext_self_owner::on_retrieve_from_staking_pool(
-            inx,
+            sp_id,
             //promise params:
             &env::current_account_id(),
             NO_DEPOSIT,
@@ -850,22 +642,401 @@ impl MetaPool {
     //prev fn continues here
     /// This method needs to update staking pool busyLock
     #[private]
-    pub fn on_retrieve_from_staking_pool(&mut self, inx: u16) -> U128String {
-
-        let sp = &mut self.staking_pools[inx as usize];
-        let sp_account_id = sp.account_id.clone();
-        let amount = sp.unstaked; // we retrieved all
-
-        //WARN: This is a callback after-cross-contract-call method
-        //busy locks must be saved false in the state, this method SHOULD NOT PANIC
-        sp.busy_lock = false;
-        self.contract_busy = false;
-
+    pub fn on_retrieve_from_staking_pool(&mut self, sp_id: u16) -> U128String {
         let retrieve_succeeded = is_promise_success();
+        return match self.settle_sp_inx(sp_id) {
+            Some(sp_inx) => self.internal_settle_retrieve(sp_inx, retrieve_succeeded),
+            None => 0,
+        }
+        .into();
+    }
 
-        let result: &str;
-        let retrieved_amount: u128;
-        if retrieve_succeeded {
+    // Operator method, but open to anyone. No need to be called, is auto called before distribute stake/unstake
+    //----------------------------------------------------------------------
+    // End of Epoch clearing of STAKE_ORDERS vs UNSTAKE_ORDERS
+    //----------------------------------------------------------------------
+    // At the end of the epoch, only the delta between stake & unstake orders needs to be actually staked
+    // if there are more in the stake orders than the unstake orders, some NEAR will not be sent to the pools
+    // e.g. stake-orders: 1200, unstake-orders:1000 => net: stake 200 and keep 1000 to fulfill unstake claims after 4 epochs.
+    // if there was more in the unstake orders than in the stake orders, a real unstake was initiated with one or more pools,
+    // the rest should also be kept to fulfill unstake claims after 4 epochs.
+    // e.g. stake-orders: 700, unstake-orders:1000 => net: start-unstake 300 and keep 700 to fulfill unstake claims after 4 epochs
+    // if the delta is 0, there's no real stake-unstake, but the amount should be kept to fulfill unstake claims after 4 epochs
+    // e.g. stake-orders: 500, unstake-orders:500 => net: 0 so keep 500 to fulfill unstake claims after 4 epochs.
+    //
+    pub fn end_of_epoch_clearing(&mut self) {
+        self.internal_end_of_epoch_clearing() 
+    }
+
+    /// compute max cap for the unstakes-for-rebalance
+    /// default unstake_for_rebalance_cap_bp = 100, so max_unstake_for_rebalance = 1%
+    pub(crate) fn max_unstake_for_rebalance(&self) -> u128 {
+        apply_pct(self.unstake_for_rebalance_cap_bp, self.total_for_staking)
+    }
+
+}
+
+//-----------------------------
+// Launch & settle helpers
+// shared by the single-pool heartbeat fns and the batch versions (see distribute_batch.rs)
+//-----------------------------
+impl MetaPool {
+    /// index of the pool a callback refers to
+    /// a pool can not be removed with stake/unstake/retrieve calls in flight (sp.in_flight),
+    /// so None means the pool was removed after a view call, logged and ignored
+    pub(crate) fn settle_sp_inx(&self, sp_id: u16) -> Option<usize> {
+        let sp_inx = self.sp_inx_by_id(sp_id);
+        if sp_inx.is_none() {
+            log!("sp id {} not found, result ignored", sp_id);
+        }
+        sp_inx
+    }
+
+    /// locks the pool, reserves the amounts and returns the stake promise (without callback)
+    /// Note: if the sp has some sizable unstake pending, the fn will re-stake the unstaked-and-waiting-amount
+    /// returns (promise, amount actually staked, included_deposit)
+    pub(crate) fn internal_launch_stake(&mut self, sp_inx: usize, mut amount_to_stake: u128) -> (Promise, u128, bool) {
+        //only this pool is locked, the amount is reserved below so other pools & users can proceed
+        let sp = &mut self.staking_pools[sp_inx];
+        sp.lock();
+        sp.in_flight += 1;
+
+        let promise: Promise;
+        let included_deposit: bool;
+        //case 1. pool has unstaked amount (we could be at the unstaking delay waiting period)
+        //NOTE: The amount to stake can't be so low as a few yoctos because the staking-pool
+        // will panic with : "panicked at 'The calculated number of \"stake\" shares received for staking should be positive', src/internal.rs:79:9"
+        // that's because after division, if the amount is a few yoctos, the amount for shares is 0
+        if sp.unstaked >= TEN_NEAR {
+            //at least 10 NEAR
+            //pool has a sizable unstaked amount
+            if sp.unstaked < amount_to_stake {
+                //re-stake the unstaked
+                amount_to_stake = sp.unstaked;
+            }
+
+            //schedule async stake to re-stake in the pool
+            included_deposit = false;
+            promise = ext_staking_pool::stake(
+                amount_to_stake.into(),
+                &sp.account_id,
+                NO_DEPOSIT,
+                gas::staking_pool::STAKE,
+            );
+        } else {
+            //here the sp has no sizable unstaked balance, we must deposit_and_stake on the sp from our balance
+
+            // NOTE: This contract holds also the liquidity pool for fast unstake may be too optimistic, why not compute the storage explicitly and add
+            //    a safety margin on top of that. That's because the account state may
+            //    potentially exceed the 35N (or 3.5M right now). But I guess it can happen
+            //    only at the beginning of metapool before the liquidity is provided.
+            assert!(
+                env::account_balance() - MIN_BALANCE_FOR_STORAGE >= amount_to_stake,
+                "env::account_balance()-MIN_BALANCE_FOR_STORAGE < amount_to_stake"
+            );
+            // preventively take from contract balance (the NEAR is attached to the promise, undoes if async fails)
+            self.contract_account_balance -= amount_to_stake;
+
+            //schedule async stake or deposit_and_stake on that pool
+            included_deposit = true;
+            promise = ext_staking_pool::deposit_and_stake(
+                &sp.account_id,
+                amount_to_stake.into(), //attached amount
+                gas::staking_pool::DEPOSIT_AND_STAKE,
+            );
+        }
+
+        //Here we did some staking (the promises are scheduled for exec after this fn completes)
+        self.total_actually_staked += amount_to_stake; //preventively consider the amount staked (undoes if async fails)
+        self.epoch_stake_orders -= amount_to_stake; //preventively reduce stake orders
+
+        (promise, amount_to_stake, included_deposit)
+    }
+
+    /// after stake or deposit_and_stake
+    /// this fn is called from callbacks, it SHOULD NOT PANIC
+    pub(crate) fn internal_settle_stake(
+        &mut self,
+        sp_inx: usize,
+        amount: u128,
+        included_deposit: bool,
+        stake_succeeded: bool,
+    ) {
+        let sp = &mut self.staking_pools[sp_inx];
+        let sp_account_id = sp.account_id.clone();
+
+        //WARN: This is a callback after-cross-contract-call method
+        //busy locks must be saved false in the state, this method SHOULD NOT PANIC
+        sp.unlock();
+        sp.in_flight = sp.in_flight.saturating_sub(1);
+
+        let result: &str;
+        if stake_succeeded {
+            // STAKED OK
+            result = "succeeded";
+            // move into staked
+            sp.staked += amount;
+            // update accums based on the source of the funds
+            let event: &str;
+            if included_deposit {
+                // we sent NEAR from the contract into the staking-pool
+                // (contract_account_balance was already decremented when launching)
+                event = "dist.stak"; //stake in the pools (including transfer)
+            } else {
+                // stake the unstaked in the pool, no-transfer
+                event = "dist.stak.nt"; //not deposited first, so staked funds came from unstaked funds already in the staking-pool
+                sp.unstaked -= amount; //we've now less unstaked in this sp
+                self.total_unstaked_and_waiting -= amount; // contract total of all unstaked & waiting, now there's less there.
+                                                           // We kept the NEAR in the contract and took from unstaked_and_waiting
+                                                           // ... unstaked_and_waiting was in their way to be converted in retrieved_for_unstake_claims
+                self.consider_retrieved_for_unstake_claims(amount); // so this is a special case: the NEAR to stake was taken from total_unstaked_and_waiting,
+                                                             // so we compensate and take the NEAR in the contract and consider it reserved for_unstake_claims
+            }
+            //log event
+            event!(
+                r#"{{"event":"{}","sp":"{}","amount":"{}"}}"#,
+                event,
+                sp_account_id,
+                amount
+            );
+        } else {
+            //STAKE FAILED
+            result = "has failed";
+            self.total_actually_staked -= amount; //undo preventive action considering the amount staked
+            self.epoch_stake_orders += amount; //undo preventively reduce stake orders
+            if included_deposit {
+                self.contract_account_balance += amount; //undo preventive action, the attached NEAR is refunded
+            }
+        }
+        log!("Staking of {} at @{} {}", amount, sp_account_id, result);
+    }
+
+    /// locks the pool, reserves the amounts and returns the unstake promise (without callback)
+    pub(crate) fn internal_launch_unstake(
+        &mut self,
+        sp_inx: usize,
+        amount_from_unstake_orders: u128,
+        amount_from_rebalance: u128,
+    ) -> Promise {
+        let total_amount = amount_from_unstake_orders + amount_from_rebalance;
+        assert!(self.total_actually_staked >= total_amount, "IUN");
+        assert!(sp_inx < self.staking_pools.len(), "invalid index");
+        let sp = &self.staking_pools[sp_inx];
+        assert!(!sp.is_busy(),"sp is busy");
+        assert!(
+            sp.staked >= total_amount,
+            "only {} staked can not unstake {}",
+            sp.staked,
+            total_amount,
+        );
+        
+        // only this pool is locked
+        let sp = &mut self.staking_pools[sp_inx];
+        sp.lock();
+        sp.in_flight += 1;
+
+        // preventively consider the amount un-staked (undoes if promise fails)
+        self.total_actually_staked -= total_amount;
+        self.epoch_unstake_orders -= amount_from_unstake_orders; // preventively consider the unstake_order fulfilled
+        self.unstaked_for_rebalance += amount_from_rebalance; // reserve now, so a rebalance in another pool respects the cap
+
+        //launch async to un-stake from the pool
+        ext_staking_pool::unstake(
+            total_amount.into(),
+            &sp.account_id,
+            NO_DEPOSIT,
+            gas::staking_pool::UNSTAKE,
+        )
+    }
+
+    /// after unstake
+    /// this fn is called from callbacks, it SHOULD NOT PANIC
+    pub(crate) fn internal_settle_unstake(
+        &mut self,
+        sp_inx: usize,
+        amount_from_unstake_orders: u128,
+        amount_from_rebalance: u128,
+        unstake_succeeded: bool,
+    ) {
+        let sp = &mut self.staking_pools[sp_inx];
+        let total_amount = amount_from_unstake_orders + amount_from_rebalance;
+
+        let result: &str;
+        if unstake_succeeded {
+            result = "succeeded";
+            sp.staked -= total_amount;
+            sp.unstaked += total_amount;
+            sp.unstk_req_epoch_height = env::epoch_height();
+            self.total_unstaked_and_waiting += total_amount; // contract total unstaked_and_waiting
+                                                             // (unstaked_for_rebalance was incremented when launching)
+            event!(
+                r#"{{"event":"unstk","sp":"{}","amount_fuo":"{}","amount_fr":"{}" }}"#,
+                sp.account_id,
+                amount_from_unstake_orders,
+                amount_from_rebalance
+            );
+        } else {
+            result = "has failed";
+            self.total_actually_staked += total_amount; //undo preventive action considering the amount unstaked
+            self.epoch_unstake_orders += amount_from_unstake_orders; // undo preventive action considering the order fulfilled
+            self.unstaked_for_rebalance = self.unstaked_for_rebalance.saturating_sub(amount_from_rebalance); // undo reservation
+        }
+
+        log!("Unstaking of {} at @{} {}", total_amount, sp.account_id, result);
+
+        //WARN: This is a callback after-cross-contract-call method
+        //busy locks must be saved false in the state, this method SHOULD NOT PANIC
+        sp.unlock();
+        sp.in_flight = sp.in_flight.saturating_sub(1);
+    }
+
+    /// syncs sp.unstaked with the real unstaked amount informed by the sp
+    /// returns false (and changes nothing) if the amounts differ but the sp is busy
+    pub(crate) fn internal_settle_unstaked_balance(&mut self, sp_inx: usize, real_unstaked_balance: u128) -> bool {
+        let sp = &mut self.staking_pools[sp_inx];
+
+        log!(
+            "inx:{} sp:{} old_unstaked_balance:{} new_unstaked_balance:{}",
+            sp_inx,
+            sp.account_id,
+            sp.unstaked,
+            real_unstaked_balance
+        );
+        // we're not locking at the start, so we check there's no in-flight transaction on this pool if we need to
+        // adjust the unstaked in a few yoctos. (In-flight operations on other pools don't touch this pool's numbers)
+        if real_unstaked_balance != sp.unstaked && sp.is_busy() {
+            return false;
+        }
+
+        if real_unstaked_balance > sp.unstaked {
+            //positive difference
+            let difference = real_unstaked_balance - sp.unstaked;
+            log!("positive difference {}", difference);
+            sp.unstaked = real_unstaked_balance;
+            sp.staked = sp.staked.saturating_sub(difference); //the difference was in "our" record of "staked"
+        } else if real_unstaked_balance < sp.unstaked {
+            //negative difference
+            let difference = sp.unstaked - real_unstaked_balance;
+            log!("negative difference {}", difference);
+            sp.unstaked = real_unstaked_balance;
+            sp.staked += difference; //the difference was in "our" record of "staked"
+        }
+        true
+    }
+
+    /// locks the pool and returns the get_account_total_balance promise (without callback)
+    pub(crate) fn internal_launch_get_total_balance(&mut self, sp_inx: usize) -> Promise {
+        let sp = &mut self.staking_pools[sp_inx];
+        sp.lock();
+        ext_staking_pool::get_account_total_balance(
+            env::current_account_id(),
+            //promise params
+            &sp.account_id,
+            NO_DEPOSIT,
+            gas::staking_pool::GET_ACCOUNT_TOTAL_BALANCE,
+        )
+    }
+
+    /// computes and distributes rewards from the total balance informed by the sp
+    /// this fn is called from callbacks, it SHOULD NOT PANIC
+    pub(crate) fn internal_settle_total_balance(&mut self, sp_inx: usize, total_balance: u128) {
+        //new_total_balance has the new staked amount for this pool
+        let new_total_balance: u128;
+        let sp = &mut self.staking_pools[sp_inx];
+
+        //WARN: This is a callback after-cross-contract-call method
+        //busy locks must be saved false in the state, this method SHOULD NOT PANIC
+        sp.unlock();
+
+        let previous_rewards_epoch = sp.last_asked_rewards_epoch_height;
+        sp.last_asked_rewards_epoch_height = env::epoch_height();
+
+        //total_balance informed is staking-pool.staked + staking-pool.unstaked
+        new_total_balance = total_balance;
+
+        if new_total_balance < sp.total_balance() {
+            // the pool lost funds (slashing)
+            self.internal_register_loss(sp_inx, new_total_balance);
+        }
+        let sp = &mut self.staking_pools[sp_inx];
+
+        let rewards: u128;
+        if new_total_balance < sp.total_balance() {
+            // unreachable, internal_register_loss adjusted sp to new_total_balance
+            rewards = 0;
+        } else {
+            //compute rewards, as new balance minus old balance
+            rewards = new_total_balance - sp.total_balance();
+        }
+
+        log!(
+            "sp:{} old_balance:{} new_balance:{} rewards:{} unstaked:{}",
+            sp.account_id,
+            sp.total_balance(),
+            new_total_balance,
+            rewards,
+            sp.unstaked
+        );
+
+        let staked_before = sp.staked;
+        //updated accumulated_staked_rewards value for the contract
+        self.accumulated_staked_rewards += rewards;
+        //updated new "staked" value for this pool
+        sp.staked = new_total_balance - sp.unstaked;
+
+        let mut fee: u128 = 0;
+        if rewards > 0 {
+            //add to total_for_staking & total_actually_staked, increasing share value for all stNEAR holders
+            self.total_actually_staked += rewards;
+            self.total_for_staking += rewards;
+
+            // mint extra stNEAR representing fees for operator & developers
+            // The fee the operator takes from rewards (0.5%)
+            let operator_fee = apply_pct(self.operator_rewards_fee_basis_points, rewards);
+            let operator_fee_shares = self.stake_shares_from_amount(operator_fee);
+            // The fee the contract authors take from rewards (0.2%)
+            let developers_fee = apply_pct(DEVELOPERS_REWARDS_FEE_BASIS_POINTS, rewards);
+            let developers_fee_shares = self.stake_shares_from_amount(developers_fee);
+            fee = operator_fee + developers_fee;
+            // part of the operator fee funds the keeper bounty
+            let keeper_fund_shares = self.keeper_fund_part(operator_fee_shares);
+            // Now add the newly minted shares. The fee is taken by making share price increase slightly smaller
+            self.add_extra_minted_shares(self.operator_account_id.clone(), operator_fee_shares - keeper_fund_shares);
+            self.add_extra_minted_shares(KEEPER_FUND_INTERNAL_ACCOUNT.into(), keeper_fund_shares);
+            self.add_extra_minted_shares(DEVELOPERS_ACCOUNT_ID.into(), developers_fee_shares);
+
+        }
+        self.internal_record_rewards(sp_inx, previous_rewards_epoch, staked_before, rewards, fee);
+    }
+
+    /// locks the pool and returns the withdraw_all promise (without callback)
+    pub(crate) fn internal_launch_retrieve(&mut self, sp_inx: usize) -> Promise {
+        let sp = &mut self.staking_pools[sp_inx];
+        sp.lock();
+        sp.in_flight += 1;
+        ext_staking_pool::withdraw_all(
+            //promise params:
+            &sp.account_id,
+            NO_DEPOSIT,
+            gas::staking_pool::WITHDRAW,
+        )
+    }
+
+    /// after withdraw_all, returns the amount retrieved
+    /// this fn is called from callbacks, it SHOULD NOT PANIC
+    pub(crate) fn internal_settle_retrieve(&mut self, sp_inx: usize, retrieve_succeeded: bool) -> u128 {
+        let sp = &mut self.staking_pools[sp_inx];
+        let sp_account_id = sp.account_id.clone();
+        let amount = sp.unstaked; // we retrieved all
+
+        //WARN: This is a callback after-cross-contract-call method
+        //busy locks must be saved false in the state, this method SHOULD NOT PANIC
+        sp.unlock();
+        sp.in_flight = sp.in_flight.saturating_sub(1);
+
+        let result: &str;
+        let retrieved_amount: u128;
+        if retrieve_succeeded {
             result = "succeeded";
             retrieved_amount = amount;
             sp.unstaked -= amount; // is no longer in the pool as "unstaked"
@@ -891,32 +1062,280 @@ impl MetaPool {
             sp_account_id,
             result
         );
+        retrieved_amount
+    }
+}
+
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::{testing_env, MockedBlockchain, PromiseResult};
 
+    const EPOCH: EpochHeight = 10;
+    const ALICE: &str = "alice.testnet";
 
-        return retrieved_amount.into();
+    /// pools with `staked` NEAR each, that last asked for rewards in the previous epoch
+    fn new_contract_with_pools_due(staked: &[u128]) -> MetaPool {
+        let mut contract = new_contract_with_pools(staked);
+        for sp in contract.staking_pools.iter_mut() {
+            sp.last_asked_rewards_epoch_height = EPOCH - 1;
+        }
+        contract
     }
 
-    // Operator method, but open to anyone. No need to be called, is auto called before distribute stake/unstake
-    //----------------------------------------------------------------------
-    // End of Epoch clearing of STAKE_ORDERS vs UNSTAKE_ORDERS
-    //----------------------------------------------------------------------
-    // At the end of the epoch, only the delta between stake & unstake orders needs to be actually staked
-    // if there are more in the stake orders than the unstake orders, some NEAR will not be sent to the pools
-    // e.g. stake-orders: 1200, unstake-orders:1000 => net: stake 200 and keep 1000 to fulfill unstake claims after 4 epochs.
-    // if there was more in the unstake orders than in the stake orders, a real unstake was initiated with one or more pools,
-    // the rest should also be kept to fulfill unstake claims after 4 epochs.
-    // e.g. stake-orders: 700, unstake-orders:1000 => net: start-unstake 300 and keep 700 to fulfill unstake claims after 4 epochs
-    // if the delta is 0, there's no real stake-unstake, but the amount should be kept to fulfill unstake claims after 4 epochs
-    // e.g. stake-orders: 500, unstake-orders:500 => net: 0 so keep 500 to fulfill unstake claims after 4 epochs.
-    //
-    pub fn end_of_epoch_clearing(&mut self) {
-        self.internal_end_of_epoch_clearing() 
+    /// (contract_account_balance, epoch_stake_orders, epoch_unstake_orders, unstaked_for_rebalance, total_actually_staked)
+    fn totals(contract: &MetaPool) -> (u128, u128, u128, u128, u128) {
+        (
+            contract.contract_account_balance,
+            contract.epoch_stake_orders,
+            contract.epoch_unstake_orders,
+            contract.unstaked_for_rebalance,
+            contract.total_actually_staked,
+        )
     }
 
-    /// compute max cap for the unstakes-for-rebalance
-    /// default unstake_for_rebalance_cap_bp = 100, so max_unstake_for_rebalance = 1%
-    fn max_unstake_for_rebalance(&self) -> u128 {
-        apply_pct(self.unstake_for_rebalance_cap_bp, self.total_for_staking)
+    fn set_keeper_context() {
+        testing_env!(context("keeper.testnet", EPOCH, 0).account_balance(10_000 * NEAR).build());
+    }
+
+    fn set_callback_result(succeeded: bool) {
+        let result = if succeeded { PromiseResult::Successful(vec![]) } else { PromiseResult::Failed };
+        set_callback_context(EPOCH, result);
+    }
+
+    /// 50 NEAR deposited by users, waiting to be staked
+    fn new_contract_with_stake_orders() -> MetaPool {
+        let mut contract = new_contract_with_pools(&[1000]);
+        contract.contract_account_balance += 50 * NEAR;
+        contract.epoch_stake_orders += 50 * NEAR;
+        contract.total_for_staking += 50 * NEAR;
+        contract
+    }
+
+    /// launches the stake like distribute_staking, without chaining the callback
+    fn launch_stake(contract: &mut MetaPool) {
+        set_keeper_context();
+        let (_, amount, included_deposit) = contract.internal_launch_stake(0, 50 * NEAR);
+        assert_eq!((amount, included_deposit), (50 * NEAR, true));
+    }
+
+    #[test]
+    fn test_failed_stake_restores_the_reserved_amounts() {
+        let mut contract = new_contract_with_stake_orders();
+        let before = totals(&contract);
+        launch_stake(&mut contract);
+        assert_eq!(contract.staking_pools[0].in_flight, 1);
+        assert_eq!(contract.contract_account_balance, before.0 - 50 * NEAR);
+        assert_eq!(contract.epoch_stake_orders, before.1 - 50 * NEAR);
+        assert_eq!(contract.total_actually_staked, before.4 + 50 * NEAR);
+
+        set_callback_result(false);
+        assert!(!contract.on_staking_pool_stake_maybe_deposit(0, 50 * NEAR, true));
+        let sp = &contract.staking_pools[0];
+        assert!(!sp.is_busy());
+        assert_eq!(sp.staked, 1000 * NEAR);
+        assert_eq!(totals(&contract), before);
+    }
+
+    #[test]
+    fn test_stake_settles_the_reserved_amounts() {
+        let mut contract = new_contract_with_stake_orders();
+        launch_stake(&mut contract);
+        let launched = totals(&contract);
+
+        set_callback_result(true);
+        assert!(contract.on_staking_pool_stake_maybe_deposit(0, 50 * NEAR, true));
+        let sp = &contract.staking_pools[0];
+        assert!(!sp.is_busy());
+        assert_eq!(sp.staked, 1050 * NEAR);
+        assert_eq!(totals(&contract), launched);
+    }
+
+    #[test]
+    fn test_failed_unstake_restores_the_reserved_amounts() {
+        let mut contract = new_contract_with_pools(&[1000]);
+        contract.epoch_unstake_orders = 30 * NEAR;
+        let before = totals(&contract);
+        set_keeper_context();
+        contract.perform_unstake(0, 30 * NEAR, 20 * NEAR);
+        assert_eq!(contract.staking_pools[0].in_flight, 1);
+        assert_eq!(contract.epoch_unstake_orders, 0);
+        assert_eq!(contract.unstaked_for_rebalance, 20 * NEAR);
+        assert_eq!(contract.total_actually_staked, before.4 - 50 * NEAR);
+
+        set_callback_result(false);
+        contract.on_staking_pool_unstake(0, (30 * NEAR).into(), (20 * NEAR).into());
+        let sp = &contract.staking_pools[0];
+        assert!(!sp.is_busy());
+        assert_eq!((sp.staked, sp.unstaked), (1000 * NEAR, 0));
+        assert_eq!(contract.total_unstaked_and_waiting, 0);
+        assert_eq!(totals(&contract), before);
     }
 
+    #[test]
+    fn test_unstake_settles_the_reserved_amounts() {
+        let mut contract = new_contract_with_pools(&[1000]);
+        contract.epoch_unstake_orders = 30 * NEAR;
+        set_keeper_context();
+        contract.perform_unstake(0, 30 * NEAR, 20 * NEAR);
+        let launched = totals(&contract);
+
+        set_callback_result(true);
+        contract.on_staking_pool_unstake(0, (30 * NEAR).into(), (20 * NEAR).into());
+        let sp = &contract.staking_pools[0];
+        assert!(!sp.is_busy());
+        assert_eq!((sp.staked, sp.unstaked), (950 * NEAR, 50 * NEAR));
+        assert_eq!(sp.unstk_req_epoch_height, EPOCH);
+        assert_eq!(contract.total_unstaked_and_waiting, 50 * NEAR);
+        assert_eq!(totals(&contract), launched);
+    }
+
+    /// pool 0 has 50 NEAR unstaked, ready to retrieve
+    fn new_contract_with_unstaked_pool() -> MetaPool {
+        let mut contract = new_contract_with_pools(&[1000]);
+        let num_epochs_to_unlock = contract.num_epochs_to_unlock();
+        let sp = &mut contract.staking_pools[0];
+        sp.unstaked = 50 * NEAR;
+        sp.unstk_req_epoch_height = EPOCH - num_epochs_to_unlock;
+        contract.total_unstaked_and_waiting = 50 * NEAR;
+        contract.total_unstake_claims = 50 * NEAR;
+        contract
+    }
+
+    #[test]
+    fn test_failed_retrieve_changes_nothing() {
+        let mut contract = new_contract_with_unstaked_pool();
+        let before = totals(&contract);
+        set_keeper_context();
+        contract.retrieve_funds_from_a_pool(StakingPoolRef::Id(0));
+        assert_eq!(contract.staking_pools[0].in_flight, 1);
+
+        set_callback_result(false);
+        assert_eq!(contract.on_retrieve_from_staking_pool(0).0, 0);
+        let sp = &contract.staking_pools[0];
+        assert!(!sp.is_busy());
+        assert_eq!(sp.unstaked, 50 * NEAR);
+        assert_eq!(contract.total_unstaked_and_waiting, 50 * NEAR);
+        assert_eq!(contract.retrieved_for_unstake_claims, 0);
+        assert_eq!(totals(&contract), before);
+    }
+
+    #[test]
+    fn test_retrieve_moves_the_unstaked_into_the_contract() {
+        let mut contract = new_contract_with_unstaked_pool();
+        let before = totals(&contract);
+        set_keeper_context();
+        contract.retrieve_funds_from_a_pool(StakingPoolRef::Id(0));
+
+        set_callback_result(true);
+        assert_eq!(contract.on_retrieve_from_staking_pool(0).0, 50 * NEAR);
+        let sp = &contract.staking_pools[0];
+        assert!(!sp.is_busy());
+        assert_eq!(sp.unstaked, 0);
+        assert_eq!(contract.total_unstaked_and_waiting, 0);
+        assert_eq!(contract.retrieved_for_unstake_claims, 50 * NEAR);
+        assert_eq!(contract.contract_account_balance, before.0 + 50 * NEAR);
+    }
+
+    #[test]
+    fn test_users_and_other_pools_proceed_while_a_pool_is_in_flight() {
+        let mut contract = new_contract_with_pools_due(&[1000, 1000]);
+        contract.epoch_unstake_orders = 50 * NEAR;
+        set_keeper_context();
+        contract.perform_unstake(0, 50 * NEAR, 0);
+        assert!(contract.staking_pools[0].is_busy());
+
+        // a user deposits and unstakes
+        testing_env!(context(ALICE, EPOCH, 20 * NEAR).account_balance(10_000 * NEAR).build());
+        contract.deposit_and_stake();
+        set_context(ALICE, EPOCH, 0);
+        contract.unstake((5 * NEAR).into());
+        let unstaked = contract.internal_get_account(&ALICE.into()).unstaked;
+        assert!(unstaked <= 5 * NEAR && unstaked > 5 * NEAR - 1000);
+
+        // the other pool can be queried
+        set_keeper_context();
+        contract.distribute_rewards(StakingPoolRef::Id(1));
+        assert!(contract.staking_pools[1].busy_lock);
+        assert_eq!(contract.staking_pools[1].in_flight, 0);
+
+        set_callback_result(true);
+        contract.on_staking_pool_unstake(0, (50 * NEAR).into(), 0.into());
+        assert!(!contract.staking_pools[0].is_busy());
+    }
+
+    #[test]
+    fn test_in_flight_pool_stays_busy_after_its_lock_is_cleared() {
+        let mut contract = new_contract_with_pools(&[1000]);
+        contract.epoch_unstake_orders = 50 * NEAR;
+        set_keeper_context();
+        contract.perform_unstake(0, 50 * NEAR, 0);
+
+        // stale locks with calls in flight are not cleared
+        let expiry_blocks = contract.busy_lock_expiry_blocks;
+        testing_env!(context("anyone.testnet", EPOCH, 0).block_index(expiry_blocks + 1).build());
+        assert_eq!(contract.clear_stale_busy_locks(), 0);
+        assert!(contract.staking_pools[0].busy_lock);
+
+        // a lock cleared by hand does not free the pool
+        contract.staking_pools[0].unlock();
+        assert!(contract.staking_pools[0].is_busy());
+        assert_eq!(contract.get_staking_pool_requiring_stake().1, 0);
+    }
+
+    #[test]
+    fn test_owner_resets_a_stuck_in_flight_counter() {
+        let mut contract = new_contract_with_pools(&[1000]);
+        contract.epoch_unstake_orders = 50 * NEAR;
+        set_keeper_context();
+        contract.perform_unstake(0, 50 * NEAR, 0);
+
+        set_context(OWNER_ID, EPOCH, 1);
+        contract.reset_sp_in_flight(StakingPoolRef::Id(0));
+        let sp = &contract.staking_pools[0];
+        assert!(!sp.is_busy());
+        assert_eq!(sp.in_flight, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Can only be called by the owner")]
+    fn test_reset_sp_in_flight_is_owner_only() {
+        let mut contract = new_contract_with_pools(&[1000]);
+        contract.staking_pools[0].in_flight = 1;
+        set_context(OPERATOR_ID, EPOCH, 1);
+        contract.reset_sp_in_flight(StakingPoolRef::Id(0));
+    }
+
+    #[test]
+    fn test_failed_total_balance_view_unlocks_the_pool() {
+        let mut contract = new_contract_with_pools_due(&[100]);
+        set_context("keeper.testnet", EPOCH, 0);
+        contract.distribute_rewards(StakingPoolRef::Id(0));
+        assert!(contract.staking_pools[0].busy_lock);
+
+        set_callback_context(EPOCH, PromiseResult::Failed);
+        contract.on_get_sp_total_balance(0);
+        let sp = &contract.staking_pools[0];
+        assert!(!sp.busy_lock);
+        assert_eq!(sp.staked, 100 * NEAR);
+        assert_eq!(sp.last_asked_rewards_epoch_height, EPOCH - 1);
+    }
+
+    #[test]
+    fn test_total_balance_view_settles_rewards() {
+        let mut contract = new_contract_with_pools_due(&[100]);
+        set_context("keeper.testnet", EPOCH, 0);
+        contract.distribute_rewards(StakingPoolRef::Id(0));
+
+        let total_balance = near_sdk::serde_json::to_vec(&U128String::from(101 * NEAR)).unwrap();
+        set_callback_context(EPOCH, PromiseResult::Successful(total_balance));
+        contract.on_get_sp_total_balance(0);
+        let sp = &contract.staking_pools[0];
+        assert!(!sp.busy_lock);
+        assert_eq!(sp.staked, 101 * NEAR);
+        assert_eq!(sp.last_asked_rewards_epoch_height, EPOCH);
+        assert_eq!(contract.total_actually_staked, 101 * NEAR);
+    }
 }
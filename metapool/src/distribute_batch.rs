@@ -0,0 +1,471 @@
+use crate::*;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{log, near_bindgen, Gas, Promise, PromiseResult};
+
+//------------------------------------
+// Batch heartbeat
+//------------------------------------
+// Same operations as distribute.rs, but sending promises to up to `max_pools` pools at once.
+// The promises are joined and settled in one callback, on_batch_settle.
+// Each pool is locked as in the single-pool fns, so batch & single-pool calls can be mixed.
+// on_batch_settle reads each promise result explicitly (no #[callback] params),
+// so a failed view call still unlocks its pool.
+
+/// hard limit of pools per batch call (the gas budget usually limits it before)
+pub const MAX_BATCH_POOLS: u16 = 16;
+
+/// an operation launched by a batch fn, settled by on_batch_settle.
+/// items are in the same order as the joined promises
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BatchItem {
+    Stake {
+        sp_id: u16,
+        amount: U128String,
+        included_deposit: bool,
+    },
+    Unstake {
+        sp_id: u16,
+        amount_from_unstake_orders: U128String,
+        amount_from_rebalance: U128String,
+    },
+    TotalBalance {
+        sp_id: u16,
+    },
+    UnstakedBalance {
+        sp_id: u16,
+    },
+    Retrieve {
+        sp_id: u16,
+    },
+}
+
+impl BatchItem {
+    pub fn sp_id(&self) -> u16 {
+        match self {
+            BatchItem::Stake { sp_id, .. }
+            | BatchItem::Unstake { sp_id, .. }
+            | BatchItem::TotalBalance { sp_id }
+            | BatchItem::UnstakedBalance { sp_id }
+            | BatchItem::Retrieve { sp_id } => *sp_id,
+        }
+    }
+}
+
+/// accumulates the promises of a batch call
+struct Batch {
+    max_pools: u16,
+    promise: Option<Promise>,
+    items: Vec<BatchItem>,
+    settle_gas: Gas,
+}
+
+impl Batch {
+    fn new(max_pools: u16) -> Self {
+        assert!(
+            max_pools > 0 && max_pools <= MAX_BATCH_POOLS,
+            "max_pools must be 1..{}",
+            MAX_BATCH_POOLS
+        );
+        Self {
+            max_pools,
+            promise: None,
+            items: Vec::new(),
+            settle_gas: gas::batch::ON_BATCH_SETTLE,
+        }
+    }
+
+    /// true if another pool fits, by count and by remaining prepaid gas
+    fn can_add(&self, call_gas: Gas, settle_gas: Gas) -> bool {
+        if self.items.len() >= self.max_pools as usize {
+            return false;
+        }
+        let remaining_gas = env::prepaid_gas().saturating_sub(env::used_gas());
+        remaining_gas >= call_gas + self.settle_gas + settle_gas + gas::batch::LOCAL_RESERVE
+    }
+
+    fn add(&mut self, promise: Promise, item: BatchItem, settle_gas: Gas) {
+        self.promise = Some(match self.promise.take() {
+            Some(joined) => joined.and(promise),
+            None => promise,
+        });
+        self.items.push(item);
+        self.settle_gas += settle_gas;
+    }
+
+    /// schedules the joined callback, returns how many pools were included
+    fn launch(self) -> u16 {
+        let count = self.items.len() as u16;
+        if let Some(joined) = self.promise {
+            joined.then(ext_self_owner::on_batch_settle(
+                self.items,
+                //promise params
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                self.settle_gas,
+            ));
+        }
+        count
+    }
+}
+
+/// parses a U128String returned by a staking-pool view fn
+pub(crate) fn parse_u128_result(result: &PromiseResult) -> Option<u128> {
+    match result {
+        PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128String>(value)
+            .ok()
+            .map(|v| v.0),
+        _ => None,
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// Operator method, but open to anyone
+    /// distribute_staking() to up to max_pools pools in one call
+    /// returns "true" if the operator needs to call this fn again
+    pub fn distribute_staking_batch(&mut self, max_pools: u16) -> bool {
+        self.assert_not_busy();
+        self.assert_staking_not_paused();
+        self.assert_operation_not_paused(PausableOperation::DistributeStaking);
+
+        //do we need to stake?
+        if self.total_for_staking <= self.total_actually_staked {
+            log!("no staking needed");
+            return false;
+        }
+        self.internal_end_of_epoch_clearing();
+
+        let mut batch = Batch::new(max_pools);
+        while batch.can_add(
+            gas::staking_pool::DEPOSIT_AND_STAKE,
+            gas::batch::ON_BATCH_SETTLE_PER_POOL,
+        ) {
+            let total_amount_to_stake = std::cmp::min(
+                self.epoch_stake_orders,
+                self.total_for_staking.saturating_sub(self.total_actually_staked),
+            );
+            if total_amount_to_stake < MIN_STAKE_AMOUNT {
+                break;
+            }
+            // the pool just launched is locked, so the next iteration selects another one
+            let (sp_inx, stake_required) = self.get_staking_pool_requiring_stake();
+            let amount_to_stake = std::cmp::min(total_amount_to_stake, stake_required);
+            if amount_to_stake < MIN_STAKE_AMOUNT {
+                // no unbalanced pool available
+                break;
+            }
+            let (promise, amount_to_stake, included_deposit) =
+                self.internal_launch_stake(sp_inx, amount_to_stake);
+            batch.add(
+                promise,
+                BatchItem::Stake {
+                    sp_id: self.staking_pools[sp_inx].id,
+                    amount: amount_to_stake.into(),
+                    included_deposit,
+                },
+                gas::batch::ON_BATCH_SETTLE_PER_POOL,
+            );
+        }
+        let launched = batch.launch();
+        log!("staking launched on {} pools", launched);
+
+        let amount_left = std::cmp::min(
+            self.epoch_stake_orders,
+            self.total_for_staking.saturating_sub(self.total_actually_staked),
+        );
+        return launched > 0 && amount_left >= MIN_STAKE_AMOUNT;
+    }
+
+    /// Operator method, but open to anyone
+    /// distribute_unstaking() from up to max_pools pools in one call
+    /// epoch_unstake_orders are split across the unblocked pools, see unstake_planner.rs
+    /// returns "true" if needs to be called again
+    pub fn distribute_unstaking_batch(&mut self, max_pools: u16) -> bool {
+        self.assert_not_busy();
+        self.assert_staking_not_paused();
+        self.assert_operation_not_paused(PausableOperation::DistributeUnstaking);
+        // clearing first
+        self.internal_end_of_epoch_clearing();
+
+        let mut batch = Batch::new(max_pools);
+        // check if the amount justifies tx-fee (see distribute_unstaking)
+        if self.epoch_unstake_orders > 10 * TGAS as u128 {
+            for plan in self.internal_plan_unstake(max_pools as usize) {
+                if !batch.can_add(gas::staking_pool::UNSTAKE, gas::batch::ON_BATCH_SETTLE_PER_POOL) {
+                    break;
+                }
+                if plan.from_orders + plan.from_rebalance <= 10 * TGAS as u128 {
+                    continue;
+                }
+                let promise =
+                    self.internal_launch_unstake(plan.sp_inx, plan.from_orders, plan.from_rebalance);
+                batch.add(
+                    promise,
+                    BatchItem::Unstake {
+                        sp_id: self.staking_pools[plan.sp_inx].id,
+                        amount_from_unstake_orders: plan.from_orders.into(),
+                        amount_from_rebalance: plan.from_rebalance.into(),
+                    },
+                    gas::batch::ON_BATCH_SETTLE_PER_POOL,
+                );
+            }
+        }
+        let launched = batch.launch();
+        log!("unstaking launched on {} pools", launched);
+
+        return launched > 0 && self.epoch_unstake_orders > 0;
+    }
+
+    /// Operator method, but open to anyone
+    /// distribute_rewards() for up to max_pools pools not yet asked in this epoch
+    /// returns how many pools were queried, call again until it returns 0
+    pub fn distribute_rewards_batch(&mut self, max_pools: u16) -> u16 {
+        self.assert_not_busy();
+        self.assert_operation_not_paused(PausableOperation::DistributeRewards);
+
+        let epoch_height = env::epoch_height();
+        let mut batch = Batch::new(max_pools);
+        for inx in 0..self.staking_pools.len() {
+            if !batch.can_add(
+                gas::staking_pool::GET_ACCOUNT_TOTAL_BALANCE,
+                gas::batch::ON_BATCH_SETTLE_REWARDS_PER_POOL,
+            ) {
+                break;
+            }
+            let sp = &self.staking_pools[inx];
+            if sp.is_busy()
+                || (sp.staked == 0 && sp.unstaked == 0)
+                || sp.last_asked_rewards_epoch_height == epoch_height
+            {
+                continue;
+            }
+            let promise = self.internal_launch_get_total_balance(inx);
+            batch.add(
+                promise,
+                BatchItem::TotalBalance { sp_id: self.staking_pools[inx].id },
+                gas::batch::ON_BATCH_SETTLE_REWARDS_PER_POOL,
+            );
+        }
+        batch.launch()
+    }
+
+    /// Operator method, but open to anyone
+    /// sync_unstaked_balance() for up to max_pools pools with unstaked funds ready to retrieve
+    /// should be called before `retrieve_funds_batch`
+    /// returns how many pools were queried
+    pub fn sync_unstaked_balance_batch(&mut self, max_pools: u16) -> u16 {
+        self.assert_not_busy();
+        self.assert_operation_not_paused(PausableOperation::RetrieveFunds);
+
+        let mut batch = Batch::new(max_pools);
+        for inx in 0..self.staking_pools.len() {
+            if !batch.can_add(
+                gas::staking_pool::GET_ACCOUNT_UNSTAKED_BALANCE,
+                gas::batch::ON_BATCH_SETTLE_PER_POOL,
+            ) {
+                break;
+            }
+            let sp = &self.staking_pools[inx];
+            if sp.is_busy()
+                || sp.unstaked == 0
+                || !sp.wait_period_ended(self.num_epochs_to_unlock())
+            {
+                continue;
+            }
+            // not locked, see sync_unstaked_balance
+            let promise = ext_staking_pool::get_account_unstaked_balance(
+                env::current_account_id(),
+                //promise params
+                &sp.account_id,
+                NO_DEPOSIT,
+                gas::staking_pool::GET_ACCOUNT_UNSTAKED_BALANCE,
+            );
+            batch.add(
+                promise,
+                BatchItem::UnstakedBalance { sp_id: self.staking_pools[inx].id },
+                gas::batch::ON_BATCH_SETTLE_PER_POOL,
+            );
+        }
+        batch.launch()
+    }
+
+    /// Operator method, but open to anyone
+    /// retrieve_funds_from_a_pool() for up to max_pools pools with the unstake delay completed
+    /// you SHOULD call sync_unstaked_balance_batch() before this
+    /// returns how many pools were included
+    pub fn retrieve_funds_batch(&mut self, max_pools: u16) -> u16 {
+        self.assert_not_busy();
+        self.assert_operation_not_paused(PausableOperation::RetrieveFunds);
+
+        let mut batch = Batch::new(max_pools);
+        for inx in 0..self.staking_pools.len() {
+            if !batch.can_add(gas::staking_pool::WITHDRAW, gas::batch::ON_BATCH_SETTLE_PER_POOL) {
+                break;
+            }
+            let sp = &self.staking_pools[inx];
+            if sp.is_busy()
+                || sp.unstaked == 0
+                || !sp.wait_period_ended(self.num_epochs_to_unlock())
+            {
+                continue;
+            }
+            let promise = self.internal_launch_retrieve(inx);
+            batch.add(
+                promise,
+                BatchItem::Retrieve { sp_id: self.staking_pools[inx].id },
+                gas::batch::ON_BATCH_SETTLE_PER_POOL,
+            );
+        }
+        batch.launch()
+    }
+
+    /// prev fns continue here
+    /// settles every item of a batch, reading the joined promise results in order
+    /// WARN: This is a callback after-cross-contract-call method, this method SHOULD NOT PANIC
+    #[private]
+    pub fn on_batch_settle(&mut self, items: Vec<BatchItem>) {
+        let results_count = env::promise_results_count();
+        for (i, item) in items.into_iter().enumerate() {
+            let result = if (i as u64) < results_count {
+                env::promise_result(i as u64)
+            } else {
+                PromiseResult::Failed
+            };
+            let succeeded = matches!(result, PromiseResult::Successful(_));
+            let sp_inx = match self.settle_sp_inx(item.sp_id()) {
+                Some(sp_inx) => sp_inx,
+                None => continue,
+            };
+            match item {
+                BatchItem::Stake {
+                    amount,
+                    included_deposit,
+                    ..
+                } => {
+                    self.internal_settle_stake(sp_inx, amount.0, included_deposit, succeeded);
+                }
+                BatchItem::Unstake {
+                    amount_from_unstake_orders,
+                    amount_from_rebalance,
+                    ..
+                } => {
+                    self.internal_settle_unstake(
+                        sp_inx,
+                        amount_from_unstake_orders.0,
+                        amount_from_rebalance.0,
+                        succeeded,
+                    );
+                }
+                BatchItem::TotalBalance { .. } => match parse_u128_result(&result) {
+                    Some(total_balance) => self.internal_settle_total_balance(sp_inx, total_balance),
+                    None => {
+                        let sp = &mut self.staking_pools[sp_inx];
+                        sp.unlock();
+                        log!("get_account_total_balance from @{} has failed", sp.account_id);
+                    }
+                },
+                BatchItem::UnstakedBalance { .. } => {
+                    if let Some(unstaked_balance) = parse_u128_result(&result) {
+                        if !self.internal_settle_unstaked_balance(sp_inx, unstaked_balance) {
+                            log!("sp {} is busy, unstaked not updated", self.staking_pools[sp_inx].account_id);
+                        }
+                    }
+                }
+                BatchItem::Retrieve { .. } => {
+                    self.internal_settle_retrieve(sp_inx, succeeded);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::{testing_env, MockedBlockchain};
+
+    const EPOCH: EpochHeight = 10;
+
+    fn total_balance_result(amount: u128) -> PromiseResult {
+        PromiseResult::Successful(near_sdk::serde_json::to_vec(&U128String::from(amount)).unwrap())
+    }
+
+    #[test]
+    fn test_mixed_results_settle_each_pool() {
+        let mut contract = new_contract_with_pools(&[1000, 1000, 1000, 1000]);
+        contract.contract_account_balance += 100 * NEAR;
+        contract.epoch_stake_orders = 100 * NEAR;
+        contract.total_for_staking += 100 * NEAR;
+        contract.epoch_unstake_orders = 80 * NEAR;
+        contract.staking_pools[3].last_asked_rewards_epoch_height = EPOCH - 1;
+        testing_env!(context("keeper.testnet", EPOCH, 0).account_balance(10_000 * NEAR).build());
+
+        // launched as the batch fns do: stakes on pools 0 & 1, an unstake on 2, a rewards view on 3
+        contract.internal_launch_stake(0, 60 * NEAR);
+        contract.internal_launch_stake(1, 40 * NEAR);
+        contract.internal_launch_unstake(2, 50 * NEAR, 30 * NEAR);
+        contract.internal_launch_get_total_balance(3);
+        assert_eq!(contract.staking_pools.iter().filter(|sp| sp.is_busy()).count(), 4);
+        assert_eq!(contract.contract_account_balance, 0);
+        assert_eq!(contract.unstaked_for_rebalance, 30 * NEAR);
+
+        let items = vec![
+            BatchItem::Stake { sp_id: 0, amount: (60 * NEAR).into(), included_deposit: true },
+            BatchItem::Stake { sp_id: 1, amount: (40 * NEAR).into(), included_deposit: true },
+            BatchItem::Unstake {
+                sp_id: 2,
+                amount_from_unstake_orders: (50 * NEAR).into(),
+                amount_from_rebalance: (30 * NEAR).into(),
+            },
+            BatchItem::TotalBalance { sp_id: 3 },
+        ];
+        set_callback_context_with_results(
+            EPOCH,
+            vec![
+                PromiseResult::Successful(vec![]),
+                PromiseResult::Failed,
+                PromiseResult::Failed,
+                PromiseResult::Failed,
+            ],
+        );
+        contract.on_batch_settle(items);
+
+        assert!(contract.staking_pools.iter().all(|sp| !sp.is_busy()));
+        let staked: Vec<u128> = contract.staking_pools.iter().map(|sp| sp.staked / NEAR).collect();
+        assert_eq!(staked, vec![1060, 1000, 1000, 1000]);
+        // only the successful stake keeps its reservation
+        assert_eq!(contract.contract_account_balance, 40 * NEAR);
+        assert_eq!(contract.epoch_stake_orders, 40 * NEAR);
+        assert_eq!(contract.epoch_unstake_orders, 80 * NEAR);
+        assert_eq!(contract.unstaked_for_rebalance, 0);
+        assert_eq!(contract.total_actually_staked, 4060 * NEAR);
+        assert_eq!(contract.total_unstaked_and_waiting, 0);
+        // the failed view did not ask for rewards
+        assert_eq!(contract.staking_pools[3].last_asked_rewards_epoch_height, EPOCH - 1);
+    }
+
+    #[test]
+    fn test_missing_results_count_as_failed() {
+        let mut contract = new_contract_with_pools(&[1000, 1000]);
+        for sp in contract.staking_pools.iter_mut() {
+            sp.last_asked_rewards_epoch_height = EPOCH - 1;
+        }
+        set_context("keeper.testnet", EPOCH, 0);
+        contract.internal_launch_get_total_balance(0);
+        contract.internal_launch_get_total_balance(1);
+
+        let items = vec![BatchItem::TotalBalance { sp_id: 0 }, BatchItem::TotalBalance { sp_id: 1 }];
+        set_callback_context_with_results(EPOCH, vec![total_balance_result(1001 * NEAR)]);
+        contract.on_batch_settle(items);
+
+        assert!(contract.staking_pools.iter().all(|sp| !sp.is_busy()));
+        assert_eq!(contract.staking_pools[0].staked, 1001 * NEAR);
+        assert_eq!(contract.staking_pools[0].last_asked_rewards_epoch_height, EPOCH);
+        assert_eq!(contract.staking_pools[1].staked, 1000 * NEAR);
+        assert_eq!(contract.staking_pools[1].last_asked_rewards_epoch_height, EPOCH - 1);
+        assert_eq!(contract.total_actually_staked, 2001 * NEAR);
+    }
+}
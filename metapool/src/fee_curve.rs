@@ -0,0 +1,350 @@
+use crate::*;
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+
+//------------------------------------
+// NSLP fee curve
+//------------------------------------
+// The liquid-unstake fee depends on the NEAR left in the NSLP: the less liquidity, the higher the fee.
+// Every shape is reduced to knots (liquidity, fee_bp) joined by straight lines, flat before the first and after the last knot:
+// - linear: nslp_max_discount_basis_points with an empty pool, down to nslp_min_discount_basis_points at nslp_liquidity_target
+// - piecewise_linear: knots set by governance, liquidity in basis points of the target
+// - exponential: the fee over min halves every half_life_bp of the target, reaching min at the target
+// - flat_band: linear from max down to fee_bp at from_bp, flat until to_bp, then linear down to min at the target
+// With price_whole_trade the fee is the curve's average over the liquidity the trade consumes,
+// otherwise it's the fee at the post-trade balance. Knots are in bp of the target, so the curve follows set_contract_params
+
+/// limits of a piecewise_linear curve
+const MAX_FEE_CURVE_KNOTS: usize = 16;
+const MAX_FEE_CURVE_LIQUIDITY_BP: u32 = 100_000;
+/// limits of an exponential curve, a shorter half life means more knots
+const MIN_FEE_CURVE_HALF_LIFE_BP: u32 = 500;
+/// max samples returned by get_fee_curve_samples
+const MAX_FEE_CURVE_SAMPLES: u16 = 200;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeCurveKnot {
+    /// NEAR in the NSLP, in basis points of nslp_liquidity_target
+    pub liquidity_bp: u32,
+    pub fee_bp: u16,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum FeeCurveShape {
+    Linear,
+    PiecewiseLinear { knots: Vec<FeeCurveKnot> },
+    Exponential { half_life_bp: u32 },
+    FlatBand { from_bp: u32, to_bp: u32, fee_bp: u16 },
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NslpFeeCurve {
+    pub shape: FeeCurveShape,
+    /// average the fee over the whole trade instead of using the post-trade balance
+    pub price_whole_trade: bool,
+}
+
+impl Default for NslpFeeCurve {
+    fn default() -> Self {
+        Self {
+            shape: FeeCurveShape::Linear,
+            price_whole_trade: false,
+        }
+    }
+}
+
+/// Struct returned from get_fee_curve_samples
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeCurveSample {
+    /// NEAR in the NSLP
+    pub liquidity: U128String,
+    /// fee of a small trade leaving that liquidity
+    pub fee_bp: u16,
+}
+
+/// fee at liquidity x, knots sorted by liquidity, fees non-increasing
+fn fee_at(knots: &Vec<(u128, u16)>, x: u128) -> u16 {
+    let first = knots[0];
+    if x <= first.0 {
+        return first.1;
+    }
+    for pair in knots.windows(2) {
+        let (x0, f0) = pair[0];
+        let (x1, f1) = pair[1];
+        if x < x1 {
+            return f0 - proportional((f0 - f1) as u128, x - x0, x1 - x0) as u16;
+        }
+    }
+    knots[knots.len() - 1].1
+}
+
+/// average fee over liquidity from..to, exact for straight segments
+fn average_fee(knots: &Vec<(u128, u16)>, from: u128, to: u128) -> u16 {
+    if to <= from {
+        return fee_at(knots, from);
+    }
+    let mut points: Vec<u128> = vec![from];
+    points.extend(knots.iter().map(|k| k.0).filter(|x| *x > from && *x < to));
+    points.push(to);
+    let mut integral: u128 = 0;
+    for pair in points.windows(2) {
+        let f0 = fee_at(knots, pair[0]) as u128;
+        let f1 = fee_at(knots, pair[1]) as u128;
+        integral += (f0 + f1) * (pair[1] - pair[0]) / 2;
+    }
+    (integral / (to - from)) as u16
+}
+
+impl MetaPool {
+    pub(crate) fn assert_nslp_fee_curve_is_valid(&self, curve: &NslpFeeCurve) {
+        match &curve.shape {
+            FeeCurveShape::Linear => {}
+            FeeCurveShape::PiecewiseLinear { knots } => {
+                assert!(
+                    knots.len() >= 2 && knots.len() <= MAX_FEE_CURVE_KNOTS,
+                    "2..{} knots required",
+                    MAX_FEE_CURVE_KNOTS
+                );
+                for pair in knots.windows(2) {
+                    assert!(
+                        pair[0].liquidity_bp < pair[1].liquidity_bp,
+                        "knots must be sorted by liquidity_bp"
+                    );
+                    assert!(
+                        pair[0].fee_bp >= pair[1].fee_bp,
+                        "the fee can not grow with liquidity"
+                    );
+                }
+                assert!(knots[0].fee_bp < 10000, "fee_bp must be < 10000");
+                assert!(
+                    knots[knots.len() - 1].liquidity_bp <= MAX_FEE_CURVE_LIQUIDITY_BP,
+                    "liquidity_bp must be <= {}",
+                    MAX_FEE_CURVE_LIQUIDITY_BP
+                );
+            }
+            FeeCurveShape::Exponential { half_life_bp } => {
+                assert!(
+                    *half_life_bp >= MIN_FEE_CURVE_HALF_LIFE_BP && *half_life_bp <= 10000,
+                    "half_life_bp must be {}..10000",
+                    MIN_FEE_CURVE_HALF_LIFE_BP
+                );
+            }
+            FeeCurveShape::FlatBand {
+                from_bp,
+                to_bp,
+                fee_bp,
+            } => {
+                assert!(from_bp <= to_bp && *to_bp <= 10000, "must be from_bp <= to_bp <= 10000");
+                assert!(
+                    *fee_bp >= self.nslp_min_discount_basis_points
+                        && *fee_bp <= self.nslp_max_discount_basis_points,
+                    "fee_bp must be between the min and max discount"
+                );
+            }
+        }
+    }
+
+    /// the curve as (liquidity, fee_bp) knots
+    fn nslp_fee_curve_knots(&self) -> Vec<(u128, u16)> {
+        let target = self.nslp_liquidity_target;
+        let max = self.nslp_max_discount_basis_points;
+        let min = self.nslp_min_discount_basis_points;
+        if target == 0 {
+            // no liquidity is enough, every trade pays the max
+            return vec![(0, max)];
+        }
+        let at_bp = |bp: u32| proportional(target, bp as u128, 10000);
+        match &self.nslp_fee_curve.shape {
+            FeeCurveShape::Linear => vec![(0, max), (target, min)],
+            FeeCurveShape::PiecewiseLinear { knots } => knots
+                .iter()
+                .map(|k| (at_bp(k.liquidity_bp), k.fee_bp))
+                .collect(),
+            FeeCurveShape::Exponential { half_life_bp } => {
+                // 2^(-liquidity/half_life), in bp, linear between halvings
+                let halving_bp = |bp: u32| -> u128 {
+                    let halvings = bp / half_life_bp;
+                    if halvings >= 14 {
+                        return 0;
+                    }
+                    let base: u128 = 10000 >> halvings;
+                    base - proportional(base - base / 2, (bp % half_life_bp) as u128, *half_life_bp as u128)
+                };
+                // scaled so the fee is max with an empty pool and min at the target
+                let at_target = halving_bp(10000);
+                let range = (max - min) as u128;
+                let mut knots: Vec<(u128, u16)> = (0..10000 / half_life_bp + 1)
+                    .map(|k| k * half_life_bp)
+                    .filter(|bp| *bp < 10000)
+                    .map(|bp| {
+                        let excess = proportional(range, halving_bp(bp) - at_target, 10000 - at_target);
+                        (at_bp(bp), min + excess as u16)
+                    })
+                    .collect();
+                knots.push((target, min));
+                knots
+            }
+            FeeCurveShape::FlatBand {
+                from_bp,
+                to_bp,
+                fee_bp,
+            } => {
+                // min & max could have changed after the curve was set
+                let band_fee = std::cmp::min(std::cmp::max(*fee_bp, min), max);
+                vec![(0, max), (at_bp(*from_bp), band_fee), (at_bp(*to_bp), band_fee), (target, min)]
+            }
+        }
+    }
+
+    /// fee for taking nears_requested from available_near, following the curve
+    pub(crate) fn internal_fee_curve_basis_points(&self, available_near: u128, nears_requested: u128) -> u16 {
+        let knots = self.nslp_fee_curve_knots();
+        if available_near <= nears_requested {
+            return fee_at(&knots, 0);
+        }
+        //amount after the swap
+        let near_after = available_near - nears_requested;
+        if self.nslp_fee_curve.price_whole_trade {
+            average_fee(&knots, near_after, available_near)
+        } else {
+            fee_at(&knots, near_after)
+        }
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// Fee manager's method. Queues a change of the NSLP fee curve
+    pub fn set_nslp_fee_curve(&mut self, curve: NslpFeeCurve) -> u64 {
+        self.assert_role(Role::FeeManager);
+        self.internal_propose_change(GovernanceAction::SetNslpFeeCurve { curve })
+    }
+
+    pub fn get_nslp_fee_curve(&self) -> NslpFeeCurve {
+        self.nslp_fee_curve.clone()
+    }
+
+    /// fee at `points` liquidity levels from 0 to max_liquidity (default 1.3 x nslp_liquidity_target), to chart the curve
+    pub fn get_fee_curve_samples(
+        &self,
+        max_liquidity: Option<U128String>,
+        points: Option<u16>,
+    ) -> Vec<FeeCurveSample> {
+        let max_liquidity = max_liquidity.map_or(self.nslp_liquidity_target / 10 * 13, |amount| amount.0);
+        let points = std::cmp::min(points.unwrap_or(27), MAX_FEE_CURVE_SAMPLES);
+        assert!(points >= 2, "at least 2 points");
+        let knots = self.nslp_fee_curve_knots();
+        (0..points as u128)
+            .map(|inx| {
+                let liquidity = proportional(max_liquidity, inx, (points - 1) as u128);
+                FeeCurveSample {
+                    liquidity: liquidity.into(),
+                    fee_bp: fee_at(&knots, liquidity),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    /// max 180bp, min 25bp, target 10_000 NEAR
+    fn new_contract_with_curve(shape: FeeCurveShape, price_whole_trade: bool) -> MetaPool {
+        let mut contract = new_contract();
+        contract.nslp_fee_curve = NslpFeeCurve {
+            shape,
+            price_whole_trade,
+        };
+        contract
+    }
+
+    /// fee of a trade leaving `liquidity` NEAR in the NSLP
+    fn fee_leaving(contract: &MetaPool, liquidity: u128) -> u16 {
+        contract.internal_fee_curve_basis_points(liquidity * NEAR + NEAR, NEAR)
+    }
+
+    #[test]
+    fn test_linear() {
+        let contract = new_contract_with_curve(FeeCurveShape::Linear, false);
+        assert_eq!(contract.internal_fee_curve_basis_points(10_000 * NEAR, 10_000 * NEAR), 180);
+        assert_eq!(fee_leaving(&contract, 5_000), 103);
+        assert_eq!(fee_leaving(&contract, 10_000), 25);
+        assert_eq!(fee_leaving(&contract, 50_000), 25);
+    }
+
+    #[test]
+    fn test_max_fee_without_a_target() {
+        let mut contract = new_contract_with_curve(FeeCurveShape::Linear, false);
+        contract.nslp_liquidity_target = 0;
+        assert_eq!(fee_leaving(&contract, 1_000), 180);
+    }
+
+    #[test]
+    fn test_price_whole_trade_averages_the_curve() {
+        let contract = new_contract_with_curve(FeeCurveShape::Linear, true);
+        // from 10_000 down to 5_000 NEAR: (25 + 103) / 2
+        assert_eq!(contract.internal_fee_curve_basis_points(10_000 * NEAR, 5_000 * NEAR), 64);
+        // across the target, the part above it is at min
+        assert_eq!(contract.internal_fee_curve_basis_points(15_000 * NEAR, 10_000 * NEAR), 44);
+    }
+
+    #[test]
+    fn test_piecewise_linear() {
+        let knots = vec![
+            FeeCurveKnot { liquidity_bp: 0, fee_bp: 200 },
+            FeeCurveKnot { liquidity_bp: 5000, fee_bp: 100 },
+            FeeCurveKnot { liquidity_bp: 20000, fee_bp: 10 },
+        ];
+        let contract = new_contract_with_curve(FeeCurveShape::PiecewiseLinear { knots }, false);
+        assert_eq!(fee_leaving(&contract, 2_500), 150);
+        assert_eq!(fee_leaving(&contract, 15_000), 40);
+        assert_eq!(fee_leaving(&contract, 30_000), 10);
+    }
+
+    #[test]
+    fn test_exponential() {
+        let contract = new_contract_with_curve(FeeCurveShape::Exponential { half_life_bp: 5000 }, false);
+        assert_eq!(contract.internal_fee_curve_basis_points(NEAR, NEAR), 180);
+        // 2^-1 scaled between 2^0 and 2^-2
+        assert_eq!(fee_leaving(&contract, 5_000), 76);
+        assert_eq!(fee_leaving(&contract, 10_000), 25);
+    }
+
+    #[test]
+    fn test_flat_band() {
+        let shape = FeeCurveShape::FlatBand {
+            from_bp: 2000,
+            to_bp: 6000,
+            fee_bp: 50,
+        };
+        let mut contract = new_contract_with_curve(shape, false);
+        assert_eq!(fee_leaving(&contract, 1_000), 115);
+        assert_eq!(fee_leaving(&contract, 4_000), 50);
+        assert_eq!(fee_leaving(&contract, 8_000), 38);
+        // the band follows a new min
+        contract.nslp_min_discount_basis_points = 60;
+        assert_eq!(fee_leaving(&contract, 4_000), 60);
+    }
+
+    #[test]
+    #[should_panic(expected = "knots must be sorted by liquidity_bp")]
+    fn test_unsorted_knots_are_refused() {
+        let knots = vec![
+            FeeCurveKnot { liquidity_bp: 5000, fee_bp: 100 },
+            FeeCurveKnot { liquidity_bp: 0, fee_bp: 200 },
+        ];
+        let contract = new_contract();
+        contract.assert_nslp_fee_curve_is_valid(&NslpFeeCurve {
+            shape: FeeCurveShape::PiecewiseLinear { knots },
+            price_whole_trade: false,
+        });
+    }
+}
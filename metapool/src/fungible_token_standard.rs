@@ -71,6 +71,7 @@ impl FungibleTokenCore for MetaPool {
         #[allow(unused)] memo: Option<String>,
     ) {
         assert_one_yocto();
+        self.assert_operation_not_paused(PausableOperation::FtTransfer);
         //log!("env::storage_byte_cost {}",env::storage_byte_cost());
         //log!("env::storage_usage {}",env::storage_usage());
         self.internal_st_near_transfer(
@@ -90,6 +91,7 @@ impl FungibleTokenCore for MetaPool {
         msg: String,
     ) -> PromiseOrValue<U128> {
         assert_one_yocto();
+        self.assert_operation_not_paused(PausableOperation::FtTransfer);
         assert!(
             env::prepaid_gas() > GAS_FOR_FT_TRANSFER_CALL + GAS_FOR_RESOLVE_TRANSFER + FIVE_TGAS,
             "gas required {}",
@@ -47,6 +47,14 @@ pub mod staking_pool {
     /// staking pool.
     /// Requires BASE for local processing.
     pub const GET_ACCOUNT_TOTAL_BALANCE: u64 = super::BASE_GAS;
+
+    /// The amount of gas required to get the reward fee fraction of the staking pool.
+    /// Requires BASE for local processing.
+    pub const GET_REWARD_FEE_FRACTION: u64 = super::BASE_GAS;
+
+    /// The amount of gas required to get the owner of the staking pool.
+    /// Requires BASE for local processing.
+    pub const GET_OWNER_ID: u64 = super::BASE_GAS;
 }
 
 pub mod transfer_poll {
@@ -61,6 +69,11 @@ pub mod owner_callbacks {
     /// Requires BASE for local execution.
     pub const ON_WHITELIST_IS_WHITELISTED: u64 = super::BASE_GAS;
 
+    /// Gas attached to the callback of add_staking_pool, processing the whitelist check,
+    /// the reward fee fraction and the owner of the new pool.
+    /// Requires BASE for local updates.
+    pub const ON_ADD_STAKING_POOL_CHECKS: u64 = super::BASE_GAS;
+
     /// Gas attached to the inner callback for processing result of the deposit call to the
     /// staking pool.
     /// Requires BASE for local updates.
@@ -108,3 +121,18 @@ pub mod owner_callbacks {
     pub const ON_GET_ACCOUNT_UNSTAKED_BALANCE_TO_WITHDRAW_BY_OWNER: u64 =
         super::BASE_GAS + super::staking_pool::WITHDRAW + ON_STAKING_POOL_WITHDRAW;
 }
+
+pub mod batch {
+    /// Gas attached to the joined callback of a batch heartbeat call (on_batch_settle),
+    /// the callback also gets ON_BATCH_SETTLE_PER_POOL (or ON_BATCH_SETTLE_REWARDS_PER_POOL) for each pool.
+    pub const ON_BATCH_SETTLE: u64 = super::BASE_GAS;
+
+    /// Gas to settle the result of one pool in on_batch_settle.
+    pub const ON_BATCH_SETTLE_PER_POOL: u64 = 10 * super::TGAS;
+
+    /// Gas to settle rewards of one pool in on_batch_settle (mints fee shares for operator & developers).
+    pub const ON_BATCH_SETTLE_REWARDS_PER_POOL: u64 = 20 * super::TGAS;
+
+    /// Gas kept for the local execution of the batch fn itself.
+    pub const LOCAL_RESERVE: u64 = 20 * super::TGAS;
+}
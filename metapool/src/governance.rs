@@ -0,0 +1,439 @@
+use crate::*;
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+
+//------------------------------------
+// Timelocked governance changes
+//------------------------------------
+// Parameter changes are not applied immediately. They're queued as a pending change
+// with an earliest execution epoch, so integrators pricing stNEAR get a warning
+// before fees or weights change. Anyone can execute a pending change once the delay has passed.
+// The owner can cancel it before that.
+// Emergency actions (pausing, busy locks, force_rebalance_unstake) remain immediate.
+
+/// default delay ~ 1 day
+pub const DEFAULT_GOVERNANCE_DELAY_EPOCHS: EpochHeight = 2;
+/// hard coded max delay, ~ 15 days
+pub const MAX_GOVERNANCE_DELAY_EPOCHS: EpochHeight = 30;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum GovernanceAction {
+    SetContractParams { params: ContractParamsJSON },
+    SetStakingPools { list: Vec<StakingPoolArgItem> },
+    SetRewardFee { basis_points: u16 },
+    SetOperatorAccountId { account_id: AccountId },
+    SetTreasuryAccountId { account_id: AccountId },
+    SetOwnerId { owner_id: AccountId },
+    SetGovernanceDelay { epochs: EpochHeight },
+    SetKeeperBountyConfig { config: KeeperBountyConfig },
+    SetSlashThreshold { basis_points: u16 },
+    SetStakingPoolRequirements { requirements: StakingPoolRequirements },
+    SetWeightStrategyConfig { config: WeightStrategyConfig },
+    SetNetworkProfile { profile: NetworkProfile },
+    AddLockupProxy { account_id: AccountId },
+    SetLockupAccountSuffixes { suffixes: Vec<String> },
+    SetNslpFeeCurve { curve: NslpFeeCurve },
+    SetNslpBuyPremium { basis_points: u16 },
+}
+
+impl GovernanceAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GovernanceAction::SetContractParams { .. } => "set_contract_params",
+            GovernanceAction::SetStakingPools { .. } => "set_staking_pools",
+            GovernanceAction::SetRewardFee { .. } => "set_reward_fee",
+            GovernanceAction::SetOperatorAccountId { .. } => "set_operator_account_id",
+            GovernanceAction::SetTreasuryAccountId { .. } => "set_treasury_account_id",
+            GovernanceAction::SetOwnerId { .. } => "set_owner_id",
+            GovernanceAction::SetGovernanceDelay { .. } => "set_governance_delay",
+            GovernanceAction::SetKeeperBountyConfig { .. } => "set_keeper_bounty_config",
+            GovernanceAction::SetSlashThreshold { .. } => "set_slash_threshold",
+            GovernanceAction::SetStakingPoolRequirements { .. } => "set_staking_pool_requirements",
+            GovernanceAction::SetWeightStrategyConfig { .. } => "set_weight_strategy_config",
+            GovernanceAction::SetNetworkProfile { .. } => "set_network_profile",
+            GovernanceAction::AddLockupProxy { .. } => "add_lockup_proxy",
+            GovernanceAction::SetLockupAccountSuffixes { .. } => "set_lockup_account_suffixes",
+            GovernanceAction::SetNslpFeeCurve { .. } => "set_nslp_fee_curve",
+            GovernanceAction::SetNslpBuyPremium { .. } => "set_nslp_buy_premium",
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct PendingChange {
+    pub action: GovernanceAction,
+    pub proposed_by: AccountId,
+    pub proposed_epoch: EpochHeight,
+    pub executable_from_epoch: EpochHeight,
+}
+
+/// Struct returned from get_pending_changes
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingChangeJSON {
+    pub id: u64,
+    pub action: GovernanceAction,
+    pub proposed_by: AccountId,
+    pub proposed_epoch: U64String,
+    pub executable_from_epoch: U64String,
+}
+
+/// all key accounts must be different
+pub(crate) fn assert_key_accounts_different(owner: &AccountId, operator: &AccountId, treasury: &AccountId) {
+    assert!(owner != operator);
+    assert!(owner != DEVELOPERS_ACCOUNT_ID);
+    assert!(owner != treasury);
+    assert!(operator != DEVELOPERS_ACCOUNT_ID);
+    assert!(operator != treasury);
+    assert!(treasury != DEVELOPERS_ACCOUNT_ID);
+}
+
+impl MetaPool {
+    /// checks that can be done at proposal time, so obviously wrong changes are rejected early
+    /// (the action is validated again when executed)
+    fn assert_governance_action_is_valid(&self, action: &GovernanceAction) {
+        match action {
+            GovernanceAction::SetContractParams { params } => {
+                assert!(params.nslp_max_discount_basis_points > params.nslp_min_discount_basis_points);
+                assert!(params.unstake_for_rebalance_cap_bp < 2000); // hard coded limit, no more than 20%
+            }
+            GovernanceAction::SetStakingPools { list } => {
+                // make sure no additions or removals
+                assert_eq!(list.len(), self.staking_pools.len());
+                let mut total_weight = 0;
+                for sp_inx in 0..list.len() {
+                    // assert same order
+                    assert_eq!(self.staking_pools[sp_inx].account_id, list[sp_inx].account_id);
+                    // no staking pool can have 50% or more
+                    assert!(list[sp_inx].weight_basis_points < 5000);
+                    // a retiring pool keeps weight 0
+                    assert!(
+                        !self.staking_pools[sp_inx].draining || list[sp_inx].weight_basis_points == 0,
+                        "sp {} is draining",
+                        list[sp_inx].account_id
+                    );
+                    total_weight += list[sp_inx].weight_basis_points;
+                }
+                assert_eq!(total_weight, 10000);
+            }
+            GovernanceAction::SetRewardFee { basis_points } => {
+                assert!(*basis_points < 1000); // less than 10%
+            }
+            GovernanceAction::SetOperatorAccountId { account_id } => {
+                assert!(env::is_valid_account_id(account_id.as_bytes()));
+                assert_key_accounts_different(&self.owner_account_id, account_id, &self.treasury_account_id);
+            }
+            GovernanceAction::SetTreasuryAccountId { account_id } => {
+                assert!(env::is_valid_account_id(account_id.as_bytes()));
+                assert_key_accounts_different(&self.owner_account_id, &self.operator_account_id, account_id);
+            }
+            GovernanceAction::SetOwnerId { owner_id } => {
+                assert!(env::is_valid_account_id(owner_id.as_bytes()));
+                assert_key_accounts_different(owner_id, &self.operator_account_id, &self.treasury_account_id);
+            }
+            GovernanceAction::SetGovernanceDelay { epochs } => {
+                assert!(
+                    *epochs <= MAX_GOVERNANCE_DELAY_EPOCHS,
+                    "max delay is {} epochs",
+                    MAX_GOVERNANCE_DELAY_EPOCHS
+                );
+            }
+            GovernanceAction::SetKeeperBountyConfig { config } => {
+                Self::assert_keeper_bounty_config_is_valid(config);
+            }
+            GovernanceAction::SetSlashThreshold { basis_points } => {
+                assert!(*basis_points <= 10000, "basis_points must be <= 10000");
+            }
+            GovernanceAction::SetStakingPoolRequirements { requirements } => {
+                assert!(env::is_valid_account_id(requirements.whitelist_account_id.as_bytes()));
+                assert!(requirements.max_reward_fee_bp <= 10000, "max_reward_fee_bp must be <= 10000");
+            }
+            GovernanceAction::SetWeightStrategyConfig { config } => {
+                Self::assert_weight_strategy_config_is_valid(config);
+            }
+            GovernanceAction::SetNetworkProfile { profile } => {
+                profile.assert_valid(&env::current_account_id());
+            }
+            GovernanceAction::AddLockupProxy { account_id } => {
+                assert!(env::is_valid_account_id(account_id.as_bytes()));
+                assert!(
+                    !self.lockup_proxies.get(account_id).map_or(false, |info| info.trusted),
+                    "already a trusted lockup proxy"
+                );
+            }
+            GovernanceAction::SetLockupAccountSuffixes { suffixes } => {
+                assert_lockup_account_suffixes_are_valid(suffixes);
+            }
+            GovernanceAction::SetNslpFeeCurve { curve } => {
+                self.assert_nslp_fee_curve_is_valid(curve);
+            }
+            GovernanceAction::SetNslpBuyPremium { basis_points } => {
+                assert!(
+                    *basis_points <= MAX_NSLP_BUY_PREMIUM_BP,
+                    "basis_points must be <= {}",
+                    MAX_NSLP_BUY_PREMIUM_BP
+                );
+            }
+        }
+    }
+
+    /// queues a change, returns the pending change id
+    pub(crate) fn internal_propose_change(&mut self, action: GovernanceAction) -> u64 {
+        self.assert_governance_action_is_valid(&action);
+        let id = self.next_pending_change_id;
+        self.next_pending_change_id += 1;
+        let change = PendingChange {
+            proposed_by: env::predecessor_account_id(),
+            proposed_epoch: env::epoch_height(),
+            executable_from_epoch: env::epoch_height() + self.governance_delay_epochs,
+            action,
+        };
+        event!(
+            r#"{{"event":"GOV.P","id":{},"action":"{}","by":"{}","executable_from_epoch":"{}"}}"#,
+            id,
+            change.action.name(),
+            change.proposed_by,
+            change.executable_from_epoch
+        );
+        self.pending_changes.insert(&id, &change);
+        id
+    }
+
+    fn internal_apply_governance_action(&mut self, action: GovernanceAction) {
+        self.assert_governance_action_is_valid(&action);
+        match action {
+            GovernanceAction::SetContractParams { params } => {
+                self.nslp_liquidity_target = params.nslp_liquidity_target.0;
+                self.nslp_max_discount_basis_points = params.nslp_max_discount_basis_points;
+                self.nslp_min_discount_basis_points = params.nslp_min_discount_basis_points;
+
+                self.staker_meta_mult_pct = params.staker_meta_mult_pct;
+                self.stnear_sell_meta_mult_pct = params.stnear_sell_meta_mult_pct;
+                self.lp_provider_meta_mult_pct = params.lp_provider_meta_mult_pct;
+                self.operator_rewards_fee_basis_points = params.operator_rewards_fee_basis_points;
+                self.operator_swap_cut_basis_points = params.operator_swap_cut_basis_points;
+                self.treasury_swap_cut_basis_points = params.treasury_swap_cut_basis_points;
+
+                self.min_deposit_amount = params.min_deposit_amount.0;
+                self.unstake_for_rebalance_cap_bp = params.unstake_for_rebalance_cap_bp;
+            }
+            GovernanceAction::SetStakingPools { list } => {
+                for sp_inx in 0..list.len() {
+                    let bp = list[sp_inx].weight_basis_points;
+                    // if there's a change
+                    if self.staking_pools[sp_inx].weight_basis_points != bp {
+                        // check pool is not busy
+                        assert!(!self.staking_pools[sp_inx].is_busy(), "sp {} is busy", sp_inx);
+                        // set new value
+                        self.staking_pools[sp_inx].weight_basis_points = bp;
+                    }
+                }
+            }
+            GovernanceAction::SetRewardFee { basis_points } => {
+                self.operator_rewards_fee_basis_points =
+                    basis_points.saturating_sub(DEVELOPERS_REWARDS_FEE_BASIS_POINTS);
+            }
+            GovernanceAction::SetOperatorAccountId { account_id } => {
+                self.internal_set_operator_account_id(account_id);
+            }
+            GovernanceAction::SetTreasuryAccountId { account_id } => {
+                self.treasury_account_id = account_id;
+            }
+            GovernanceAction::SetOwnerId { owner_id } => {
+                self.owner_account_id = owner_id;
+            }
+            GovernanceAction::SetGovernanceDelay { epochs } => {
+                self.governance_delay_epochs = epochs;
+            }
+            GovernanceAction::SetKeeperBountyConfig { config } => {
+                self.keeper_bounty_config = config;
+            }
+            GovernanceAction::SetSlashThreshold { basis_points } => {
+                self.slash_threshold_bp = basis_points;
+            }
+            GovernanceAction::SetStakingPoolRequirements { requirements } => {
+                self.staking_pool_whitelist_account_id = requirements.whitelist_account_id;
+                self.max_sp_reward_fee_bp = requirements.max_reward_fee_bp;
+            }
+            GovernanceAction::SetWeightStrategyConfig { config } => {
+                self.weight_strategy_config = config;
+            }
+            GovernanceAction::SetNetworkProfile { profile } => {
+                self.staking_pool_whitelist_account_id = profile.staking_pool_whitelist_account_id.clone();
+                self.network_profile = profile;
+            }
+            GovernanceAction::AddLockupProxy { account_id } => {
+                self.internal_add_lockup_proxy(&account_id);
+                event!(r#"{{"event":"ADD.LOCKUP.PROXY","account_id":"{}"}}"#, account_id);
+            }
+            GovernanceAction::SetLockupAccountSuffixes { suffixes } => {
+                self.lockup_account_suffixes = suffixes;
+            }
+            GovernanceAction::SetNslpFeeCurve { curve } => {
+                self.nslp_fee_curve = curve;
+            }
+            GovernanceAction::SetNslpBuyPremium { basis_points } => {
+                self.nslp_buy_premium_bp = basis_points;
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// executes a pending change once its delay has passed
+    /// open to anyone
+    pub fn execute_pending_change(&mut self, id: u64) {
+        let change = self
+            .pending_changes
+            .get(&id)
+            .unwrap_or_else(|| panic!("pending change {} not found", id));
+        assert!(
+            env::epoch_height() >= change.executable_from_epoch,
+            "pending change {} can be executed from epoch {}, now is {}",
+            id,
+            change.executable_from_epoch,
+            env::epoch_height()
+        );
+        self.pending_changes.remove(&id);
+        let name = change.action.name();
+        self.internal_apply_governance_action(change.action);
+        event!(
+            r#"{{"event":"GOV.X","id":{},"action":"{}","by":"{}"}}"#,
+            id,
+            name,
+            env::predecessor_account_id()
+        );
+    }
+
+    /// Owner's method. Cancels a pending change
+    pub fn cancel_pending_change(&mut self, id: u64) {
+        self.assert_owner_calling();
+        let change = self
+            .pending_changes
+            .remove(&id)
+            .unwrap_or_else(|| panic!("pending change {} not found", id));
+        event!(
+            r#"{{"event":"GOV.C","id":{},"action":"{}"}}"#,
+            id,
+            change.action.name()
+        );
+    }
+
+    /// Owner's method. Queues a change of the governance delay itself
+    pub fn set_governance_delay(&mut self, epochs: EpochHeight) -> u64 {
+        self.assert_owner_calling();
+        self.internal_propose_change(GovernanceAction::SetGovernanceDelay { epochs })
+    }
+
+    pub fn get_governance_delay(&self) -> U64String {
+        self.governance_delay_epochs.into()
+    }
+
+    /// list of changes waiting to be executed
+    pub fn get_pending_changes(&self) -> Vec<PendingChangeJSON> {
+        self.pending_changes
+            .iter()
+            .map(|(id, change)| PendingChangeJSON {
+                id,
+                action: change.action,
+                proposed_by: change.proposed_by,
+                proposed_epoch: change.proposed_epoch.into(),
+                executable_from_epoch: change.executable_from_epoch.into(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn test_change_waits_for_the_delay() {
+        let mut contract = new_contract();
+        set_context(OWNER_ID, 10, 1);
+        let id = contract.set_reward_fee(400);
+        let pending = contract.get_pending_changes();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, id);
+        assert_eq!(pending[0].executable_from_epoch.0, 10 + contract.governance_delay_epochs);
+        let operator_fee = 400 - DEVELOPERS_REWARDS_FEE_BASIS_POINTS;
+        assert_ne!(contract.operator_rewards_fee_basis_points, operator_fee);
+
+        // anyone can execute it once the delay has passed
+        set_context("anyone.testnet", 10 + contract.governance_delay_epochs, 0);
+        contract.execute_pending_change(id);
+        assert_eq!(contract.operator_rewards_fee_basis_points, operator_fee);
+        assert!(contract.get_pending_changes().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "can be executed from epoch")]
+    fn test_change_can_not_be_executed_early() {
+        let mut contract = new_contract();
+        set_context(OWNER_ID, 10, 1);
+        let id = contract.set_reward_fee(400);
+        set_context("anyone.testnet", 10 + contract.governance_delay_epochs - 1, 0);
+        contract.execute_pending_change(id);
+    }
+
+    #[test]
+    #[should_panic(expected = "not found")]
+    fn test_cancelled_change_can_not_be_executed() {
+        let mut contract = new_contract();
+        set_context(OWNER_ID, 10, 1);
+        let id = contract.set_reward_fee(400);
+        set_context(OWNER_ID, 10, 0);
+        contract.cancel_pending_change(id);
+        assert!(contract.get_pending_changes().is_empty());
+        set_context("anyone.testnet", 20, 0);
+        contract.execute_pending_change(id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Can only be called by the owner")]
+    fn test_only_owner_cancels() {
+        let mut contract = new_contract();
+        set_context(OWNER_ID, 10, 1);
+        let id = contract.set_reward_fee(400);
+        set_context("anyone.testnet", 10, 0);
+        contract.cancel_pending_change(id);
+    }
+
+    #[test]
+    fn test_changes_get_distinct_ids() {
+        let mut contract = new_contract();
+        set_context(OWNER_ID, 10, 1);
+        let first = contract.set_reward_fee(400);
+        let second = contract.set_reward_fee(500);
+        assert_ne!(first, second);
+        assert_eq!(contract.get_pending_changes().len(), 2);
+        // the last executed change wins
+        set_context("anyone.testnet", 20, 0);
+        contract.execute_pending_change(second);
+        contract.execute_pending_change(first);
+        assert_eq!(
+            contract.operator_rewards_fee_basis_points,
+            400 - DEVELOPERS_REWARDS_FEE_BASIS_POINTS
+        );
+    }
+
+    #[test]
+    fn test_new_operator_takes_over_the_operator_roles() {
+        let mut contract = new_contract();
+        assert!(contract.has_role(OPERATOR_ID.into(), Role::Keeper));
+        set_context(OWNER_ID, 10, 0);
+        let id = contract.set_operator_account_id("new-operator.testnet".into());
+        set_context("anyone.testnet", 20, 0);
+        contract.execute_pending_change(id);
+        assert_eq!(contract.operator_account_id, "new-operator.testnet");
+        for role in OPERATOR_ROLES.iter() {
+            assert!(!contract.has_role(OPERATOR_ID.into(), *role));
+            assert!(contract.has_role("new-operator.testnet".into(), *role));
+        }
+    }
+}
@@ -0,0 +1,468 @@
+use crate::*;
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+
+//------------------------------------
+// Heartbeat state machine
+//------------------------------------
+// heartbeat() runs the next needed step of the per-epoch cycle documented in docs/technical-notes.md:
+// clearing, sync & retrieve, rewards, unstaking, retire, staking and finally rebalance.
+// The stage only moves forward during an epoch, and resets to Clearing when a new epoch starts.
+// get_next_heartbeat_action() computes the same step without mutating state, so any keeper can drive the pool.
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum HeartbeatStage {
+    Clearing,
+    SyncAndRetrieve,
+    Rewards,
+    Unstaking,
+    Retire,
+    Staking,
+    Rebalance,
+    Done,
+}
+
+impl HeartbeatStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HeartbeatStage::Clearing => "clearing",
+            HeartbeatStage::SyncAndRetrieve => "sync_and_retrieve",
+            HeartbeatStage::Rewards => "rewards",
+            HeartbeatStage::Unstaking => "unstaking",
+            HeartbeatStage::Retire => "retire",
+            HeartbeatStage::Staking => "staking",
+            HeartbeatStage::Rebalance => "rebalance",
+            HeartbeatStage::Done => "done",
+        }
+    }
+
+    pub fn next(&self) -> HeartbeatStage {
+        match self {
+            HeartbeatStage::Clearing => HeartbeatStage::SyncAndRetrieve,
+            HeartbeatStage::SyncAndRetrieve => HeartbeatStage::Rewards,
+            HeartbeatStage::Rewards => HeartbeatStage::Unstaking,
+            HeartbeatStage::Unstaking => HeartbeatStage::Retire,
+            HeartbeatStage::Retire => HeartbeatStage::Staking,
+            HeartbeatStage::Staking => HeartbeatStage::Rebalance,
+            HeartbeatStage::Rebalance | HeartbeatStage::Done => HeartbeatStage::Done,
+        }
+    }
+}
+
+/// the step heartbeat() will run (or has run)
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum HeartbeatAction {
+    /// end_of_epoch_clearing()
+    EndOfEpochClearing,
+    /// sync_unstaked_balance(sp_id), the next step is retrieve from the same pool once the sync callback ran
+    SyncUnstakedBalance { sp_id: u16 },
+    /// retrieve_funds_from_a_pool(sp_id)
+    RetrieveFunds { sp_id: u16 },
+    /// distribute_rewards(sp_id)
+    DistributeRewards { sp_id: u16 },
+    /// distribute_unstaking()
+    DistributeUnstaking,
+    /// remove_retired_pool(sp_id)
+    RemoveRetiredPool { sp_id: u16 },
+    /// unstake_retiring_pool(sp_id)
+    UnstakeRetiringPool { sp_id: u16 },
+    /// distribute_staking()
+    DistributeStaking,
+    /// do_rebalance_unstake()
+    RebalanceUnstake,
+    /// nothing can be done until in-flight operations finish
+    Wait { reason: String },
+    /// the cycle is complete for this epoch
+    Done,
+}
+
+impl HeartbeatAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeartbeatAction::EndOfEpochClearing => "end_of_epoch_clearing",
+            HeartbeatAction::SyncUnstakedBalance { .. } => "sync_unstaked_balance",
+            HeartbeatAction::RetrieveFunds { .. } => "retrieve_funds",
+            HeartbeatAction::DistributeRewards { .. } => "distribute_rewards",
+            HeartbeatAction::DistributeUnstaking => "distribute_unstaking",
+            HeartbeatAction::RemoveRetiredPool { .. } => "remove_retired_pool",
+            HeartbeatAction::UnstakeRetiringPool { .. } => "unstake_retiring_pool",
+            HeartbeatAction::DistributeStaking => "distribute_staking",
+            HeartbeatAction::RebalanceUnstake => "rebalance_unstake",
+            HeartbeatAction::Wait { .. } => "wait",
+            HeartbeatAction::Done => "done",
+        }
+    }
+}
+
+/// Struct returned from get_next_heartbeat_action
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HeartbeatActionJSON {
+    pub epoch: U64String,
+    pub stage: HeartbeatStage,
+    #[serde(flatten)]
+    pub action: HeartbeatAction,
+}
+
+fn wait(reason: &str) -> Option<HeartbeatAction> {
+    Some(HeartbeatAction::Wait {
+        reason: reason.into(),
+    })
+}
+
+impl MetaPool {
+    /// stage to start from, considering a new epoch resets the cycle
+    fn heartbeat_current_stage(&self) -> HeartbeatStage {
+        if self.heartbeat_epoch != env::epoch_height() {
+            HeartbeatStage::Clearing
+        } else {
+            self.heartbeat_stage
+        }
+    }
+
+    fn any_pool_busy(&self) -> bool {
+        self.staking_pools.iter().any(|sp| sp.is_busy())
+    }
+
+    fn staking_or_op_paused(&self, operation: PausableOperation) -> bool {
+        self.staking_paused || self.pause_state.is_paused(operation)
+    }
+
+    /// the step for `stage`, or None if the stage has nothing to do
+    fn internal_heartbeat_stage_action(&self, stage: HeartbeatStage) -> Option<HeartbeatAction> {
+        match stage {
+            HeartbeatStage::Clearing => {
+                if self.epoch_stake_orders > 0 && self.epoch_unstake_orders > 0 {
+                    return Some(HeartbeatAction::EndOfEpochClearing);
+                }
+            }
+            HeartbeatStage::SyncAndRetrieve => {
+                if self.pause_state.is_paused(PausableOperation::RetrieveFunds) {
+                    return None;
+                }
+                match self.get_staking_pool_requiring_retrieve() {
+                    id if id >= 0 => {
+                        let sp_id = id as u16;
+                        return Some(if self.heartbeat_synced_sp == Some(sp_id) {
+                            HeartbeatAction::RetrieveFunds { sp_id }
+                        } else {
+                            HeartbeatAction::SyncUnstakedBalance { sp_id }
+                        });
+                    }
+                    -1 => return wait("a pool with funds ready to retrieve is busy"),
+                    _ => {}
+                }
+            }
+            HeartbeatStage::Rewards => {
+                if self.pause_state.is_paused(PausableOperation::DistributeRewards) {
+                    return None;
+                }
+                let epoch_height = env::epoch_height();
+                let mut busy = false;
+                for sp in self.staking_pools.iter() {
+                    if (sp.staked > 0 || sp.unstaked > 0)
+                        && sp.last_asked_rewards_epoch_height != epoch_height
+                    {
+                        if !sp.is_busy() {
+                            return Some(HeartbeatAction::DistributeRewards { sp_id: sp.id });
+                        }
+                        busy = true;
+                    }
+                }
+                if busy {
+                    return wait("a pool requiring distribute_rewards is busy");
+                }
+            }
+            HeartbeatStage::Unstaking => {
+                if self.staking_or_op_paused(PausableOperation::DistributeUnstaking) {
+                    return None;
+                }
+                // same threshold as distribute_unstaking
+                if self.epoch_unstake_orders > 10 * TGAS as u128 {
+                    if !self.internal_plan_unstake(1).is_empty() {
+                        return Some(HeartbeatAction::DistributeUnstaking);
+                    }
+                    if self.any_pool_busy() {
+                        return wait("pools able to unstake are busy");
+                    }
+                }
+            }
+            HeartbeatStage::Retire => {
+                if let Some(sp_id) = self.get_retired_pool_to_remove() {
+                    return Some(HeartbeatAction::RemoveRetiredPool { sp_id });
+                }
+                if self.staking_or_op_paused(PausableOperation::DistributeUnstaking) {
+                    return None;
+                }
+                if let Some(sp_id) = self.get_retiring_pool_to_unstake() {
+                    return Some(HeartbeatAction::UnstakeRetiringPool { sp_id });
+                }
+            }
+            HeartbeatStage::Staking => {
+                if self.staking_or_op_paused(PausableOperation::DistributeStaking) {
+                    return None;
+                }
+                let total_amount_to_stake = std::cmp::min(
+                    self.epoch_stake_orders,
+                    self.total_for_staking.saturating_sub(self.total_actually_staked),
+                );
+                if total_amount_to_stake >= MIN_STAKE_AMOUNT {
+                    if self.get_staking_pool_requiring_stake().1 > 0 {
+                        return Some(HeartbeatAction::DistributeStaking);
+                    }
+                    if self.any_pool_busy() {
+                        return wait("pools requiring stake are busy");
+                    }
+                }
+            }
+            HeartbeatStage::Rebalance => {
+                if self.staking_or_op_paused(PausableOperation::DistributeUnstaking) {
+                    return None;
+                }
+                if self.unstaked_for_rebalance + MIN_STAKE_UNSTAKE_AMOUNT_MOVEMENT
+                    < self.max_unstake_for_rebalance()
+                    && self.internal_get_staking_pool_requiring_unstake().extra > 0
+                {
+                    return Some(HeartbeatAction::RebalanceUnstake);
+                }
+            }
+            HeartbeatStage::Done => return Some(HeartbeatAction::Done),
+        }
+        None
+    }
+
+    /// first stage from the current one with something to do
+    pub(crate) fn internal_next_heartbeat_action(&self) -> (HeartbeatStage, HeartbeatAction) {
+        if self.contract_busy {
+            return (
+                self.heartbeat_current_stage(),
+                HeartbeatAction::Wait {
+                    reason: "contract is busy".into(),
+                },
+            );
+        }
+        let mut stage = self.heartbeat_current_stage();
+        loop {
+            if let Some(action) = self.internal_heartbeat_stage_action(stage) {
+                return (stage, action);
+            }
+            stage = stage.next();
+        }
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// Operator method, but open to anyone
+    /// runs the next needed step of the per-epoch heartbeat cycle, see get_next_heartbeat_action
+    /// returns the step performed. Wait & Done do not change state
+    /// each step that changed state pays the keeper bounty to the caller, see keeper.rs
+    pub fn heartbeat(&mut self) -> HeartbeatAction {
+        let (stage, action) = self.internal_next_heartbeat_action();
+        if let HeartbeatAction::Wait { .. } | HeartbeatAction::Done = action {
+            return action;
+        }
+        self.heartbeat_epoch = env::epoch_height();
+        self.heartbeat_stage = stage;
+        let in_flight_before = self.in_flight_snapshot();
+
+        // fns returning "call again?", when false the stage is complete
+        let call_again = match &action {
+            HeartbeatAction::EndOfEpochClearing => {
+                self.internal_end_of_epoch_clearing();
+                false
+            }
+            HeartbeatAction::SyncUnstakedBalance { sp_id } => {
+                // heartbeat_synced_sp is set by the callback, if the sync fails the next step syncs again
+                self.sync_unstaked_balance(StakingPoolRef::Id(*sp_id));
+                true
+            }
+            HeartbeatAction::RetrieveFunds { sp_id } => {
+                self.retrieve_funds_from_a_pool(StakingPoolRef::Id(*sp_id));
+                self.heartbeat_synced_sp = None;
+                true
+            }
+            HeartbeatAction::DistributeRewards { sp_id } => {
+                self.distribute_rewards(StakingPoolRef::Id(*sp_id));
+                true
+            }
+            HeartbeatAction::DistributeUnstaking => self.distribute_unstaking(),
+            HeartbeatAction::RemoveRetiredPool { sp_id } => {
+                self.remove_retired_pool(StakingPoolRef::Id(*sp_id));
+                true
+            }
+            HeartbeatAction::UnstakeRetiringPool { sp_id } => {
+                self.unstake_retiring_pool(StakingPoolRef::Id(*sp_id));
+                // this pool is now busy. If the unstake fails it's retried in the next epoch, the stage moves on
+                self.get_retiring_pool_to_unstake().is_some()
+            }
+            HeartbeatAction::DistributeStaking => self.distribute_staking(),
+            HeartbeatAction::RebalanceUnstake => self.internal_do_rebalance_unstake(),
+            HeartbeatAction::Wait { .. } | HeartbeatAction::Done => unreachable!(),
+        };
+        if !call_again {
+            self.heartbeat_stage = stage.next();
+        }
+
+        // only steps that changed state are paid, see keeper.rs
+        let (changed_state, paid_sp_id) = match &action {
+            HeartbeatAction::EndOfEpochClearing => (true, None),
+            HeartbeatAction::RemoveRetiredPool { sp_id }
+            | HeartbeatAction::SyncUnstakedBalance { sp_id }
+            | HeartbeatAction::DistributeRewards { sp_id } => (true, Some(*sp_id)),
+            // stake, unstake & retrieve: paid if a call was launched
+            _ => {
+                let launched_sp_id = self.pool_launched_since(&in_flight_before);
+                (launched_sp_id.is_some(), launched_sp_id)
+            }
+        };
+        if changed_state {
+            self.internal_pay_keeper_bounty(&env::predecessor_account_id(), &action, paid_sp_id);
+        }
+
+        event!(
+            r#"{{"event":"HB","epoch":"{}","stage":"{}","action":"{}"}}"#,
+            self.heartbeat_epoch,
+            stage.as_str(),
+            action.name()
+        );
+        action
+    }
+
+    /// the step heartbeat() would run now
+    pub fn get_next_heartbeat_action(&self) -> HeartbeatActionJSON {
+        let (stage, action) = self.internal_next_heartbeat_action();
+        HeartbeatActionJSON {
+            epoch: env::epoch_height().into(),
+            stage,
+            action,
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::PromiseResult;
+
+    const EPOCH: EpochHeight = 10;
+
+    fn next_action(contract: &MetaPool) -> HeartbeatAction {
+        contract.get_next_heartbeat_action().action
+    }
+
+    /// a pool with stake, rewards already distributed in this epoch
+    #[test]
+    fn test_nothing_to_do() {
+        let mut contract = new_contract();
+        set_context("keeper.testnet", EPOCH, 0);
+        assert_eq!(next_action(&contract), HeartbeatAction::Done);
+        assert_eq!(contract.heartbeat(), HeartbeatAction::Done);
+        // done does not change state
+        assert_eq!(contract.heartbeat_epoch, 0);
+    }
+
+    #[test]
+    fn test_clearing_then_next_stage() {
+        let mut contract = new_contract();
+        contract.epoch_stake_orders = 5 * NEAR;
+        contract.epoch_unstake_orders = 3 * NEAR;
+        set_context("keeper.testnet", EPOCH, 0);
+        let json = contract.get_next_heartbeat_action();
+        assert_eq!(json.stage, HeartbeatStage::Clearing);
+        assert_eq!(json.action, HeartbeatAction::EndOfEpochClearing);
+
+        assert_eq!(contract.heartbeat(), HeartbeatAction::EndOfEpochClearing);
+        assert_eq!(contract.epoch_unstake_orders, 0);
+        assert_eq!(contract.heartbeat_epoch, EPOCH);
+        assert_eq!(contract.heartbeat_stage, HeartbeatStage::SyncAndRetrieve);
+    }
+
+    #[test]
+    fn test_new_epoch_restarts_at_clearing() {
+        let mut contract = new_contract();
+        contract.heartbeat_epoch = EPOCH;
+        contract.heartbeat_stage = HeartbeatStage::Done;
+        contract.epoch_stake_orders = 5 * NEAR;
+        contract.epoch_unstake_orders = 3 * NEAR;
+        // same epoch, the cycle is complete
+        set_context("keeper.testnet", EPOCH, 0);
+        assert_eq!(next_action(&contract), HeartbeatAction::Done);
+        // next epoch
+        set_context("keeper.testnet", EPOCH + 1, 0);
+        assert_eq!(next_action(&contract), HeartbeatAction::EndOfEpochClearing);
+    }
+
+    #[test]
+    fn test_wait_while_contract_busy() {
+        let mut contract = new_contract();
+        contract.contract_busy = true;
+        set_context("keeper.testnet", EPOCH, 0);
+        match contract.heartbeat() {
+            HeartbeatAction::Wait { reason } => assert_eq!(reason, "contract is busy"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_retrieve_only_after_the_sync_callback() {
+        let mut contract = new_contract();
+        let sp_id = add_pool(&mut contract, "pool.testnet", 10000);
+        contract.staking_pools[0].unstaked = 50 * NEAR;
+        contract.staking_pools[0].unstk_req_epoch_height = 1;
+        contract.total_unstaked_and_waiting = 50 * NEAR;
+
+        set_context("keeper.testnet", EPOCH, 0);
+        assert_eq!(next_action(&contract), HeartbeatAction::SyncUnstakedBalance { sp_id });
+        assert_eq!(contract.heartbeat(), HeartbeatAction::SyncUnstakedBalance { sp_id });
+        // the callback has not run yet, the next step syncs again
+        assert_eq!(next_action(&contract), HeartbeatAction::SyncUnstakedBalance { sp_id });
+
+        set_context(CONTRACT_ID, EPOCH, 0);
+        contract.on_get_sp_unstaked_balance(sp_id, (50 * NEAR).into());
+        set_context("keeper.testnet", EPOCH, 0);
+        assert_eq!(next_action(&contract), HeartbeatAction::RetrieveFunds { sp_id });
+        assert_eq!(contract.heartbeat(), HeartbeatAction::RetrieveFunds { sp_id });
+        assert_eq!(contract.heartbeat_synced_sp, None);
+        match next_action(&contract) {
+            HeartbeatAction::Wait { .. } => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        set_callback_context(EPOCH, PromiseResult::Successful(vec![]));
+        contract.on_retrieve_from_staking_pool(sp_id);
+        assert_eq!(contract.staking_pools[0].unstaked, 0);
+        set_context("keeper.testnet", EPOCH, 0);
+        assert_eq!(next_action(&contract), HeartbeatAction::Done);
+    }
+
+    #[test]
+    fn test_any_keeper_can_run_the_rebalance_step() {
+        let mut contract = new_contract();
+        add_staked_pool(&mut contract, "pool-a.testnet", 5000, 8_000 * NEAR);
+        add_staked_pool(&mut contract, "pool-b.testnet", 5000, 2_000 * NEAR);
+        contract.heartbeat_epoch = EPOCH;
+        contract.heartbeat_stage = HeartbeatStage::Rebalance;
+
+        // no keeper role
+        set_context("anyone.testnet", EPOCH, 0);
+        assert_eq!(next_action(&contract), HeartbeatAction::RebalanceUnstake);
+        assert_eq!(contract.heartbeat(), HeartbeatAction::RebalanceUnstake);
+        assert!(contract.staking_pools[0].busy_lock);
+        assert_eq!(contract.unstaked_for_rebalance, contract.max_unstake_for_rebalance());
+        assert_eq!(contract.heartbeat_stage, HeartbeatStage::Done);
+    }
+
+    #[test]
+    #[should_panic(expected = "keeper role")]
+    fn test_do_rebalance_unstake_requires_the_keeper_role() {
+        let mut contract = new_contract();
+        set_context("anyone.testnet", EPOCH, 0);
+        contract.do_rebalance_unstake();
+    }
+}
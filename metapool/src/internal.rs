@@ -6,7 +6,7 @@ use near_sdk::{
     log, AccountId, Balance, Promise, PromiseResult,
 };
 
-const UNSTAKED_YOCTOS_TO_IGNORE: u128 = 100;
+pub(crate) const UNSTAKED_YOCTOS_TO_IGNORE: u128 = 100;
 
 pub struct GSPRUResult {
     pub sp_inx: u16,
@@ -28,18 +28,28 @@ impl MetaPool {
             "Can only be called by the owner"
         )
     }
-    pub fn assert_operator_or_owner(&self) {
-        assert!(
-            &env::predecessor_account_id() == &self.owner_account_id
-                || &env::predecessor_account_id() == &self.operator_account_id,
-            "Can only be called by the operator or the owner"
-        );
-    }
 
+    /// contract_busy is a manual global lock (see set_busy),
+    /// in-flight heartbeat operations only lock their staking pool
     pub fn assert_not_busy(&self) {
         assert!(!self.contract_busy, "Contract is busy. Try again later");
     }
 
+    /// set contract_busy, remembering when
+    pub(crate) fn lock_contract(&mut self) {
+        self.contract_busy = true;
+        self.contract_busy_since_block = env::block_index();
+        self.contract_busy_since_epoch = env::epoch_height();
+    }
+    /// how many blocks ago contract_busy was set, 0 if not busy
+    pub(crate) fn contract_busy_age_blocks(&self) -> u64 {
+        if self.contract_busy {
+            env::block_index().saturating_sub(self.contract_busy_since_block)
+        } else {
+            0
+        }
+    }
+
     pub fn assert_min_deposit_amount(&self, amount: u128) {
         assert!(
             amount >= self.min_deposit_amount,
@@ -95,6 +105,140 @@ impl MetaPool {
         amount
     }
 
+    //------------------------------
+    /// amounts of selling st_near_to_sell stNEAR in the Liquidity Pool, used by internal_liquid_unstake & the quote views.
+    /// Does not check the liquidity, see LiquidUnstakeQuote.enough_liquidity
+    pub(crate) fn internal_quote_liquid_unstake(
+        &self,
+        available_near: u128,
+        st_near_to_sell: u128,
+    ) -> LiquidUnstakeQuote {
+        //compute how many nears are the st_near valued at
+        let nears_out = self.amount_from_stake_shares(st_near_to_sell);
+        let fee_bp = self.internal_get_discount_basis_points(available_near, nears_out);
+        assert!(fee_bp < 10000, "inconsistency d>1");
+        let fee = apply_pct(fee_bp, nears_out);
+        // compute how many shares the swap fee represent
+        let fee_in_st_near = self.stake_shares_from_amount(fee);
+        let treasury_st_near_cut = apply_pct(self.treasury_swap_cut_basis_points, fee_in_st_near);
+        let operator_st_near_cut = apply_pct(self.operator_swap_cut_basis_points, fee_in_st_near);
+        let developers_st_near_cut = apply_pct(DEVELOPERS_SWAP_CUT_BASIS_POINTS, fee_in_st_near);
+        LiquidUnstakeQuote {
+            nears_out,
+            fee_bp,
+            fee,
+            near_to_receive: nears_out - fee,
+            fee_in_st_near,
+            treasury_st_near_cut,
+            operator_st_near_cut,
+            developers_st_near_cut,
+            st_near_to_liq_pool: st_near_to_sell
+                - (treasury_st_near_cut + operator_st_near_cut + developers_st_near_cut),
+        }
+    }
+
+    //------------------------------
+    /// swaps st_near_to_sell stNEAR->NEAR in the Liquidity Pool, exact amounts.
+    /// The NEAR is credited to user_account.available, the swap fee is split between the LP, treasury, operator & developers.
+    /// The caller saves user_account. Returns (NEAR credited, fee in stNEAR)
+    pub(crate) fn internal_liquid_unstake(
+        &mut self,
+        account_id: &AccountId,
+        user_account: &mut Account,
+        st_near_to_sell: u128,
+        min_expected_near: u128,
+    ) -> (u128, u128) {
+        assert!(
+            user_account.stake_shares >= st_near_to_sell,
+            "Not enough stNEAR. You own {}",
+            user_account.stake_shares
+        );
+
+        let mut nslp_account = self.internal_get_nslp_account();
+
+        let quote = self.internal_quote_liquid_unstake(nslp_account.available, st_near_to_sell);
+        let near_to_receive = quote.near_to_receive;
+        let fee_in_st_near = quote.fee_in_st_near;
+        assert!(
+            near_to_receive >= min_expected_near,
+            "Price changed, your min amount {} is not satisfied {}. Try again",
+            min_expected_near,
+            near_to_receive
+        );
+        assert!(
+            nslp_account.available >= near_to_receive,
+            "Not enough liquidity in the liquidity pool"
+        );
+
+        //the NEAR for the user comes from the LP
+        nslp_account.available -= near_to_receive;
+        user_account.available += near_to_receive;
+
+        // involved accounts
+        assert!(
+            account_id != &self.treasury_account_id,
+            "can't use treasury account"
+        );
+        let mut treasury_account = self
+            .accounts
+            .get(&self.treasury_account_id)
+            .unwrap_or_default();
+        assert!(
+            account_id != &self.operator_account_id,
+            "can't use operator account"
+        );
+        let mut operator_account = self
+            .accounts
+            .get(&self.operator_account_id)
+            .unwrap_or_default();
+        assert!(
+            account_id != DEVELOPERS_ACCOUNT_ID,
+            "can't use developers account"
+        );
+        let mut developers_account = self
+            .accounts
+            .get(&DEVELOPERS_ACCOUNT_ID.into())
+            .unwrap_or_default();
+
+        // The treasury cut in stnear-shares (25% by default)
+        let treasury_st_near_cut = quote.treasury_st_near_cut;
+        treasury_account.add_st_near(treasury_st_near_cut, &self);
+
+        // The cut that the contract owner (operator) takes. (3% of 1% normally)
+        let operator_st_near_cut = quote.operator_st_near_cut;
+        operator_account.add_st_near(operator_st_near_cut, &self);
+
+        // The cut that the developers take. (2% of 1% normally)
+        let developers_st_near_cut = quote.developers_st_near_cut;
+        developers_account.add_st_near(developers_st_near_cut, &self);
+
+        log!("treasury_st_near_cut:{} operator_st_near_cut:{} developers_st_near_cut:{} fee_in_st_near:{}",
+            treasury_st_near_cut,operator_st_near_cut,developers_st_near_cut,fee_in_st_near);
+
+        assert!(
+            fee_in_st_near > treasury_st_near_cut + developers_st_near_cut + operator_st_near_cut
+        );
+
+        // The rest of the st_near sold goes into the liq-pool. Because it is a larger amount than NEARs removed, it will increase share value for all LP providers.
+        // Adding value to the pool via adding more stNEAR value than the NEAR removed
+        let st_near_to_liq_pool = quote.st_near_to_liq_pool;
+        log!("nslp_account.add_st_near {}", st_near_to_liq_pool);
+        // major part of stNEAR sold goes to the NSLP
+        nslp_account.add_st_near(st_near_to_liq_pool, &self);
+
+        //complete the transfer, remove stnear from the user (stnear was transferred to the LP & others)
+        user_account.sub_st_near(st_near_to_sell, &self);
+
+        //Save involved accounts
+        self.internal_update_account(&self.treasury_account_id.clone(), &treasury_account);
+        self.internal_update_account(&self.operator_account_id.clone(), &operator_account);
+        self.internal_update_account(&DEVELOPERS_ACCOUNT_ID.into(), &developers_account);
+        //Save nslp accounts
+        self.internal_save_nslp_account(&nslp_account);
+
+        (near_to_receive, fee_in_st_near)
+    }
+
     //------------------------------
     // MIMIC staking-pool, if there are unstaked, it must be free to withdraw
     pub(crate) fn internal_withdraw_use_unstaked(
@@ -105,7 +249,7 @@ impl MetaPool {
         let mut account = self.internal_get_account(&account_id);
 
         //MIMIC staking-pool, move 1st form unstaked->available, it must be free to withdraw
-        account.in_memory_try_finish_unstaking(&account_id, requested_amount, self);
+        self.internal_finish_unstaking(&account_id, &mut account, requested_amount);
 
         // NOTE: While ability to withdraw close to all available helps, it prevents lockup contracts from using this in a replacement to a staking pool,
         // because the lockup contracts relies on exact precise amount being withdrawn.
@@ -121,6 +265,22 @@ impl MetaPool {
         //transfer to user native near account
         self.native_transfer(account_id, amount)
     }
+    /// lockup withdraw: takes first from available (e.g. liquid_unstake_for_lockup), the rest from unstaked.
+    /// Always the exact amount requested
+    pub(crate) fn internal_withdraw_for_lockup(
+        &mut self,
+        lockup_account_id: &String,
+        requested_amount: u128,
+    ) -> Promise {
+        let mut account = self.internal_get_account(&lockup_account_id);
+        let from_unstaked = requested_amount.saturating_sub(account.available);
+        if from_unstaked > 0 {
+            self.internal_finish_unstaking(&lockup_account_id, &mut account, from_unstaked);
+        }
+        let amount = account.take_from_available(lockup_account_id, requested_amount, self);
+        self.internal_update_account(&lockup_account_id, &account);
+        self.native_transfer(lockup_account_id, amount)
+    }
     pub(crate) fn native_transfer(&mut self, account_id: &String, amount: u128) -> Promise {
         //transfer to user native near account
         self.contract_account_balance -= amount;
@@ -203,15 +363,17 @@ impl MetaPool {
         stake_shares_to_burn: u128,
     ) -> (u128, u64) {
         self.assert_not_busy();
+        self.assert_operation_not_paused(PausableOperation::DelayedUnstake);
         assert!(stake_shares_to_burn > 0 && stake_shares_to_burn <= acc.stake_shares);
         //remove acc stake shares
         let amount_to_unstake = self.amount_from_stake_shares(stake_shares_to_burn);
         acc.sub_stake_shares(stake_shares_to_burn, amount_to_unstake);
         //the amount is now "unstaked", i.e. the user has a claim to this amount, 4-8 epochs form now
-        acc.unstaked += amount_to_unstake;
-        acc.unstaked_requested_unlock_epoch =
-            env::epoch_height() + self.internal_compute_current_unstaking_delay(amount_to_unstake); //when the unstake will be available
-                                                                                                    //--contract totals
+        //in a new ticket, with the epoch when it will be available
+        let unlock_epoch = self.internal_unstake_unlock_epoch(amount_to_unstake);
+        let ticket_unlock_epoch =
+            self.internal_add_unstake_ticket(account_id, acc, amount_to_unstake, unlock_epoch);
+        //--contract totals
         self.epoch_unstake_orders += amount_to_unstake;
         self.total_unstake_claims += amount_to_unstake;
         self.total_stake_shares -= stake_shares_to_burn; //burn
@@ -235,8 +397,8 @@ impl MetaPool {
             acc.stake_shares,
             env::epoch_height()
         );
-        // return unstaked_requested_unlock_epoch
-        (amount_to_unstake, acc.unstaked_requested_unlock_epoch)
+        // return the epoch when this amount will be available
+        (amount_to_unstake, ticket_unlock_epoch)
     }
 
     //--------------------------------------------------
@@ -246,7 +408,8 @@ impl MetaPool {
         &mut self,
         account_id: &String,
         amount_requested: u128,
-    ) -> u16 {
+        min_shares_expected: u128,
+    ) -> AddLiquidityResult {
         self.assert_not_busy();
 
         let mut acc = self.internal_get_account(&account_id);
@@ -256,10 +419,19 @@ impl MetaPool {
 
         //get NSLP account
         let mut nslp_account = self.internal_get_nslp_account();
+        let pool_value_before = self.nslp_pool_value(&nslp_account);
+        let share_price_before = self.amount_from_nslp_shares(ONE_E24, &nslp_account);
 
         // Calculate the number of "nslp" shares the account will receive for adding the given amount of near liquidity
         let num_shares = self.nslp_shares_from_amount(amount, &nslp_account);
         assert!(num_shares > 0);
+        // slippage protection, the pool value could have changed in the same block (e.g. a large liquid_unstake)
+        assert!(
+            num_shares >= min_shares_expected,
+            "Price changed, your shares would be {}, min expected {}",
+            num_shares,
+            min_shares_expected
+        );
 
         //register added liquidity to compute rewards correctly
         acc.lp_meter.stake(amount);
@@ -279,35 +451,128 @@ impl MetaPool {
         self.internal_save_nslp_account(&nslp_account);
 
         event!(
-            r#"{{"event":"ADD.L","account_id":"{}","amount":"{}"}}"#,
+            r#"{{"event":"ADD.L","account_id":"{}","amount":"{}","shares":"{}"}}"#,
             account_id,
-            amount
+            amount,
+            num_shares
         );
 
-        return result_bp;
+        return AddLiquidityResult {
+            shares_minted: num_shares.into(),
+            ownership_bp: result_bp,
+            pool_value_before: pool_value_before.into(),
+            pool_value_after: self.nslp_pool_value(&nslp_account).into(),
+            share_price_before: share_price_before.into(),
+            share_price_after: self.amount_from_nslp_shares(ONE_E24, &nslp_account).into(),
+        };
     }
 
     //--------------------------------------------------
-    /// computes unstaking delay on current situation
-    pub fn internal_compute_current_unstaking_delay(&self, amount: u128) -> u64 {
-        let mut total_staked: u128 = 0;
-        let mut normal_wait_staked_available: u128 = 0;
-        for (_, sp) in self.staking_pools.iter().enumerate() {
-            //if the pool has no unstaking in process
-            total_staked += sp.staked;
-            if !sp.busy_lock && sp.staked > 0 && sp.wait_period_ended() {
-                normal_wait_staked_available += sp.staked;
-                if normal_wait_staked_available > amount {
-                    return NUM_EPOCHS_TO_UNLOCK;
-                }
-            }
+    /// removes amount of liquidity, sending the NEAR part to the account and leaving the stNEAR part in it.
+    /// Fails if the account would receive less than min_near_expected NEAR or min_st_near_expected stNEAR
+    pub(crate) fn internal_nslp_remove_liquidity(
+        &mut self,
+        account_id: &String,
+        amount: u128,
+        min_near_expected: u128,
+        min_st_near_expected: u128,
+    ) -> RemoveLiquidityResult {
+        self.assert_not_busy();
+
+        let mut acc = self.internal_get_account(account_id);
+        let mut nslp_account = self.internal_get_nslp_account();
+
+        //how much does this user owns
+        let valued_actual_shares = acc.valued_nslp_shares(self, &nslp_account);
+
+        let mut to_remove = amount;
+        let nslp_shares_to_burn: u128;
+        // if the amount is close to user's total, remove user's total
+        // to: a) do not leave less than ONE_MILLI_NEAR in the account, b) Allow 10 yoctos of rounding, e.g. remove(100) removes 99.999993 without panicking
+        if is_close(to_remove, valued_actual_shares) {
+            // allow for rounding simplification
+            to_remove = valued_actual_shares;
+            nslp_shares_to_burn = acc.nslp_shares; // close enough to all shares, burn-it all (avoid leaving "dust")
+        } else {
+            assert!(
+                valued_actual_shares >= to_remove,
+                "Not enough share value {} to remove the requested amount from the pool",
+                valued_actual_shares
+            );
+            // Calculate the number of "nslp" shares that the account will burn based on the amount requested
+            nslp_shares_to_burn = self.nslp_shares_from_amount(to_remove, &nslp_account);
         }
-        if total_staked == 0 {
-            //initial stake, nothing staked, someone delay-unstaking in contract epoch 0
-            return NUM_EPOCHS_TO_UNLOCK;
+
+        assert!(nslp_shares_to_burn > 0);
+
+        //register removed liquidity to compute rewards correctly
+        acc.lp_meter.unstake(to_remove);
+
+        //compute proportionals stNEAR/NEAR
+        //1st: stNEAR how much stNEAR from the Liq-Pool represents the ratio: nslp_shares_to_burn relative to total nslp_shares
+        let st_near_to_remove_from_pool = proportional(
+            nslp_account.stake_shares,
+            nslp_shares_to_burn,
+            nslp_account.nslp_shares,
+        );
+        //2nd: NEAR, by difference
+        let near_value_of_st_near = self.amount_from_stake_shares(st_near_to_remove_from_pool);
+        assert!(
+            to_remove >= near_value_of_st_near,
+            "inconsistency NTR<STR+UTR"
+        );
+        let near_to_remove = to_remove - near_value_of_st_near;
+        // slippage protection, the pool composition could have changed in the same block (e.g. a large liquid_unstake)
+        assert!(
+            near_to_remove >= min_near_expected,
+            "Pool changed, you would receive {} NEAR, min expected {}",
+            near_to_remove,
+            min_near_expected
+        );
+        assert!(
+            st_near_to_remove_from_pool >= min_st_near_expected,
+            "Pool changed, you would receive {} stNEAR, min expected {}",
+            st_near_to_remove_from_pool,
+            min_st_near_expected
+        );
+
+        //update user account
+        //remove first from stNEAR in the pool, proportional to shares being burned
+        //NOTE: To simplify user-operations, the LIQ.POOL DO NOT carry "unstaked". The NSLP self-balances only by internal-clearing on `deposit_and_stake`
+        acc.available += near_to_remove;
+        acc.add_st_near(st_near_to_remove_from_pool, &self); //add stnear to user acc
+        acc.nslp_shares -= nslp_shares_to_burn; //shares this user burns
+                                                //update NSLP account
+        nslp_account.available -= near_to_remove;
+        nslp_account.sub_st_near(st_near_to_remove_from_pool, &self); //remove stnear from the pool
+        nslp_account.nslp_shares -= nslp_shares_to_burn; //burn from total nslp shares
+
+        //simplify user-flow
+        //direct transfer to user (instead of leaving it in-contract as "available")
+        let transfer_amount = acc.take_from_available(account_id, near_to_remove, self);
+        self.native_transfer(account_id, transfer_amount);
+
+        //--SAVE ACCOUNTS
+        self.internal_update_account(account_id, &acc);
+        self.internal_save_nslp_account(&nslp_account);
+
+        event!(
+            r#"{{"event":"REM.L","account_id":"{}","near":"{}","stnear":"{}"}}"#,
+            account_id,
+            transfer_amount,
+            st_near_to_remove_from_pool
+        );
+
+        return RemoveLiquidityResult {
+            near: transfer_amount.into(),
+            st_near: st_near_to_remove_from_pool.into(),
         };
-        //all pools are in unstaking-delay, it will take double the time
-        return 2 * NUM_EPOCHS_TO_UNLOCK;
+    }
+
+    //--------------------------------------------------
+    /// computes unstaking delay on current situation, see internal_unstake_unlock_epoch
+    pub fn internal_compute_current_unstaking_delay(&self, amount: u128) -> u64 {
+        self.internal_unstake_unlock_epoch(amount) - env::epoch_height()
     }
 
     //--------------------------------
@@ -341,17 +606,20 @@ impl MetaPool {
     // NSLP: NEAR/stNEAR Liquidity Pool
     //-----------------------------
 
+    /// NEAR + value of the stNEAR in the NSLP
+    pub(crate) fn nslp_pool_value(&self, nslp_account: &Account) -> u128 {
+        nslp_account.available + self.amount_from_stake_shares(nslp_account.stake_shares)
+    }
+
     // NSLP shares are trickier to compute since the NSLP itself can have stNEAR
     pub(crate) fn nslp_shares_from_amount(&self, amount: u128, nslp_account: &Account) -> u128 {
-        let total_pool_value: u128 =
-            nslp_account.available + self.amount_from_stake_shares(nslp_account.stake_shares);
+        let total_pool_value: u128 = self.nslp_pool_value(nslp_account);
         return shares_from_amount(amount, total_pool_value, nslp_account.nslp_shares);
     }
 
     // NSLP shares are trickier to compute since the NSLP itself can have stNEAR
     pub(crate) fn amount_from_nslp_shares(&self, num_shares: u128, nslp_account: &Account) -> u128 {
-        let total_pool_value: u128 =
-            nslp_account.available + self.amount_from_stake_shares(nslp_account.stake_shares);
+        let total_pool_value: u128 = self.nslp_pool_value(nslp_account);
         return amount_from_shares(num_shares, total_pool_value, nslp_account.nslp_shares);
     }
 
@@ -411,7 +679,7 @@ impl MetaPool {
         return false;
     }
 
-    /// computes swap_fee_basis_points for NEAR/stNEAR Swap based on NSLP Balance
+    /// computes swap_fee_basis_points for NEAR/stNEAR Swap based on NSLP Balance, see fee_curve.rs
     pub(crate) fn internal_get_discount_basis_points(
         &self,
         available_near: u128,
@@ -423,22 +691,46 @@ impl MetaPool {
             nears_requested
         );
 
-        if available_near <= nears_requested {
-            return self.nslp_max_discount_basis_points;
+        self.internal_fee_curve_basis_points(available_near, nears_requested)
+    }
+
+    /// largest stNEAR amount, up to st_near_max, that can be liquid-unstaked now with a fee <= max_fee_bp
+    pub(crate) fn internal_max_liquid_unstake_under_fee(
+        &self,
+        available_near: u128,
+        st_near_max: u128,
+        max_fee_bp: u16,
+    ) -> u128 {
+        let fits = |st_near: u128| -> bool {
+            let nears_out = self.amount_from_stake_shares(st_near);
+            let fee_bp = self.internal_fee_curve_basis_points(available_near, nears_out);
+            fee_bp <= max_fee_bp && nears_out - apply_pct(fee_bp, nears_out) <= available_near
+        };
+        if fits(st_near_max) {
+            return st_near_max;
         }
-        //amount after the swap
-        let near_after = available_near - nears_requested;
-        if near_after >= self.nslp_liquidity_target {
-            //still >= target
-            return self.nslp_min_discount_basis_points;
+        // the fee grows with the amount: binary search, fits(low) && !fits(high)
+        let mut low: u128 = 0;
+        let mut high: u128 = st_near_max;
+        if !fits(low) {
+            return 0;
         }
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if fits(mid) {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
 
-        //linear curve from max to min on target
-        let range = self.nslp_max_discount_basis_points - self.nslp_min_discount_basis_points;
-        //here 0<near_after<self.nslp_liquidity_target, so 0<proportional_bp<range
-        let proportional_bp = proportional(range as u128, near_after, self.nslp_liquidity_target);
-
-        return self.nslp_max_discount_basis_points - proportional_bp as u16;
+    /// NEAR/stNEAR SWAP functions
+    /// returns (stNEAR, premium) you get by paying near_amount NEAR, from the NSLP's stNEAR
+    pub(crate) fn internal_get_stnear_amount_buy_stnear(&self, near_amount: u128) -> (u128, u128) {
+        let premium = apply_pct(self.nslp_buy_premium_bp, near_amount);
+        (self.stake_shares_from_amount(near_amount - premium), premium)
     }
 
     /// NEAR/stNEAR SWAP functions
@@ -448,13 +740,10 @@ impl MetaPool {
         available_near: u128,
         st_near_to_sell: u128,
     ) -> u128 {
-        //compute how many nears are the st_near valued at
-        let nears_out = self.amount_from_stake_shares(st_near_to_sell);
-        let swap_fee_basis_points =
-            self.internal_get_discount_basis_points(available_near, nears_out);
-        assert!(swap_fee_basis_points < 10000, "inconsistency d>1");
-        let fee = apply_pct(swap_fee_basis_points, nears_out);
-        return (nears_out - fee).into(); //when stNEAR is sold user pays a swap fee (the user skips the waiting period)
+        //when stNEAR is sold user pays a swap fee (the user skips the waiting period)
+        return self
+            .internal_quote_liquid_unstake(available_near, st_near_to_sell)
+            .near_to_receive;
 
         // env::log(
         //     format!(
@@ -493,6 +782,45 @@ impl MetaPool {
         self.internal_update_account(&NSLP_INTERNAL_ACCOUNT.into(), &nslp_account);
     }
 
+    /// sets the weight of sp_inx to 0, spreading it across the other pools with weight (and staking not paused),
+    /// proportionally to their weights. The rounding remainder goes to the pool with the highest weight
+    /// this fn is called from callbacks, it SHOULD NOT PANIC
+    pub(crate) fn internal_redistribute_weight(&mut self, sp_inx: usize) {
+        let weight = self.staking_pools[sp_inx].weight_basis_points;
+        if weight == 0 {
+            return;
+        }
+        self.staking_pools[sp_inx].weight_basis_points = 0;
+
+        let receives = |inx: usize, sp: &StakingPoolInfo| inx != sp_inx && !sp.stake_paused && sp.weight_basis_points > 0;
+        let total_other: u32 = self
+            .staking_pools
+            .iter()
+            .enumerate()
+            .filter(|(inx, sp)| receives(*inx, sp))
+            .map(|(_, sp)| sp.weight_basis_points as u32)
+            .sum();
+        if total_other == 0 {
+            // no pool can receive it
+            return;
+        }
+        let mut given: u16 = 0;
+        let mut max_weight_inx = sp_inx;
+        let mut max_weight: u16 = 0;
+        for (inx, sp) in self.staking_pools.iter_mut().enumerate() {
+            if receives(inx, sp) {
+                let part = (weight as u32 * sp.weight_basis_points as u32 / total_other) as u16;
+                sp.weight_basis_points += part;
+                given += part;
+                if sp.weight_basis_points > max_weight {
+                    max_weight = sp.weight_basis_points;
+                    max_weight_inx = inx;
+                }
+            }
+        }
+        self.staking_pools[max_weight_inx].weight_basis_points += weight - given;
+    }
+
     /// finds a staking pool requiring some stake to get balanced
     /// WARN: (returns 0,0) if no pool requires staking/all are busy
     pub(crate) fn get_staking_pool_requiring_stake(&self) -> (usize, u128) {
@@ -501,7 +829,7 @@ impl MetaPool {
 
         for (sp_inx, sp) in self.staking_pools.iter().enumerate() {
             // if the pool is not busy, and this pool can stake
-            if !sp.busy_lock && sp.weight_basis_points > 0 {
+            if !sp.is_busy() && !sp.stake_paused && sp.weight_basis_points > 0 {
                 // if this pool has an unbalance requiring staking
                 let should_have = apply_pct(sp.weight_basis_points, self.total_for_staking);
                 // this pool requires staking?
@@ -531,7 +859,7 @@ impl MetaPool {
 
         for (sp_inx, sp) in self.staking_pools.iter().enumerate() {
             // if the pool is not busy, has stake
-            if !sp.busy_lock && sp.staked > 0 {
+            if !sp.is_busy() && sp.staked > 0 {
                 // count how how many sps are unblocked, i.e. can receive an unstake request
                 count_with_stake += 1;
                 if sp.unstaked <= UNSTAKED_YOCTOS_TO_IGNORE {
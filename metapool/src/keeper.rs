@@ -0,0 +1,389 @@
+use crate::*;
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+
+//------------------------------------
+// Keeper bounty
+//------------------------------------
+// Anyone can drive the pool by calling heartbeat(). Each heartbeat step that changes state pays a small bounty
+// in stNEAR to the caller. Steps that end up doing nothing (e.g. a rebalance stopped by the 40%-unblocked rule) are not paid.
+// The bounty is funded with a part of the operator rewards fee, minted into an internal keeper-fund account
+// when rewards are distributed. The bounty is capped per epoch, and per keeper per epoch.
+// A step on a pool (sync, retrieve, rewards, stake, unstake) is paid once per pool per epoch, so a call that keeps
+// failing and being retried (e.g. withdraw_all looping sync->retrieve) can not be farmed.
+
+/// hard coded max bounty per step, in NEAR
+pub const MAX_KEEPER_BOUNTY_PER_STEP: u128 = ONE_NEAR;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct KeeperBountyConfig {
+    /// NEAR value paid (in stNEAR) for each useful heartbeat step. 0 disables the bounty
+    pub bounty_per_step: U128String,
+    /// max NEAR value paid to all keepers in an epoch
+    pub max_bounty_per_epoch: U128String,
+    /// max paid steps for a single keeper in an epoch
+    pub max_paid_steps_per_keeper: u16,
+    /// part of the operator rewards fee that goes to the keeper fund, in basis points of the fee
+    pub fund_bp: u16,
+}
+
+impl Default for KeeperBountyConfig {
+    /// disabled
+    fn default() -> Self {
+        Self {
+            bounty_per_step: 0.into(),
+            max_bounty_per_epoch: 0.into(),
+            max_paid_steps_per_keeper: 0,
+            fund_bp: 0,
+        }
+    }
+}
+
+/// heartbeat steps on a pool, each one is paid once per pool per epoch
+#[derive(Clone, Copy)]
+pub enum KeeperPoolStep {
+    SyncUnstakedBalance,
+    RetrieveFunds,
+    DistributeRewards,
+    DistributeUnstaking,
+    RemoveRetiredPool,
+    UnstakeRetiringPool,
+    DistributeStaking,
+    RebalanceUnstake,
+}
+
+impl KeeperPoolStep {
+    pub fn of(action: &HeartbeatAction) -> Option<Self> {
+        match action {
+            HeartbeatAction::SyncUnstakedBalance { .. } => Some(Self::SyncUnstakedBalance),
+            HeartbeatAction::RetrieveFunds { .. } => Some(Self::RetrieveFunds),
+            HeartbeatAction::DistributeRewards { .. } => Some(Self::DistributeRewards),
+            HeartbeatAction::DistributeUnstaking => Some(Self::DistributeUnstaking),
+            HeartbeatAction::RemoveRetiredPool { .. } => Some(Self::RemoveRetiredPool),
+            HeartbeatAction::UnstakeRetiringPool { .. } => Some(Self::UnstakeRetiringPool),
+            HeartbeatAction::DistributeStaking => Some(Self::DistributeStaking),
+            HeartbeatAction::RebalanceUnstake => Some(Self::RebalanceUnstake),
+            HeartbeatAction::EndOfEpochClearing | HeartbeatAction::Wait { .. } | HeartbeatAction::Done => None,
+        }
+    }
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+/// steps paid on a pool in `epoch`, one bit per KeeperPoolStep
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct KeeperPaidSteps {
+    pub epoch: EpochHeight,
+    pub steps: u16,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct KeeperStats {
+    pub paid_steps: u64,
+    /// total stNEAR received
+    pub bounty_shares: u128,
+    pub last_paid_epoch: EpochHeight,
+    pub paid_steps_in_last_epoch: u16,
+}
+
+/// Struct returned from get_keeper_stats
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct KeeperStatsJSON {
+    pub paid_steps: U64String,
+    pub bounty_shares: U128String,
+    pub last_paid_epoch: U64String,
+    pub paid_steps_in_last_epoch: u16,
+}
+
+/// Struct returned from get_keeper_bounty_info
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct KeeperBountyInfoJSON {
+    pub config: KeeperBountyConfig,
+    /// stNEAR available in the keeper fund
+    pub fund_shares: U128String,
+    pub fund_value: U128String,
+    /// NEAR value paid in the current epoch
+    pub paid_in_epoch: U128String,
+}
+
+impl MetaPool {
+    pub(crate) fn assert_keeper_bounty_config_is_valid(config: &KeeperBountyConfig) {
+        assert!(
+            config.bounty_per_step.0 <= MAX_KEEPER_BOUNTY_PER_STEP,
+            "max bounty per step is {}",
+            MAX_KEEPER_BOUNTY_PER_STEP
+        );
+        assert!(config.fund_bp <= 10000, "fund_bp must be <= 10000");
+        assert!(
+            config.bounty_per_step.0 == 0 || config.max_paid_steps_per_keeper > 0,
+            "max_paid_steps_per_keeper must be > 0"
+        );
+    }
+
+    /// part of the operator fee shares that goes to the keeper fund
+    pub(crate) fn keeper_fund_part(&self, operator_fee_shares: u128) -> u128 {
+        apply_pct(self.keeper_bounty_config.fund_bp, operator_fee_shares)
+    }
+
+    /// in-flight calls of each pool (id, in_flight), taken before a heartbeat step
+    pub(crate) fn in_flight_snapshot(&self) -> Vec<(u16, u16)> {
+        self.staking_pools.iter().map(|sp| (sp.id, sp.in_flight)).collect()
+    }
+
+    /// first pool where a stake/unstake/retrieve was launched since the snapshot
+    pub(crate) fn pool_launched_since(&self, snapshot: &Vec<(u16, u16)>) -> Option<u16> {
+        self.staking_pools
+            .iter()
+            .find(|sp| {
+                snapshot
+                    .iter()
+                    .find(|(id, _)| *id == sp.id)
+                    .map_or(sp.in_flight > 0, |(_, in_flight)| sp.in_flight > *in_flight)
+            })
+            .map(|sp| sp.id)
+    }
+
+    /// pays the bounty for a heartbeat step that changed state, returns the stNEAR paid
+    /// a step on a pool (sp_id) is paid once per pool per epoch
+    /// never panics, if a limit is reached or the fund is empty, nothing is paid
+    pub(crate) fn internal_pay_keeper_bounty(
+        &mut self,
+        keeper_id: &AccountId,
+        action: &HeartbeatAction,
+        sp_id: Option<u16>,
+    ) -> u128 {
+        let bounty_per_step = self.keeper_bounty_config.bounty_per_step.0;
+        if bounty_per_step == 0 {
+            return 0;
+        }
+        let epoch_height = env::epoch_height();
+        if self.keeper_bounty_epoch != epoch_height {
+            self.keeper_bounty_epoch = epoch_height;
+            self.keeper_bounty_paid_in_epoch = 0;
+        }
+        // once per pool per epoch
+        let pool_step = sp_id.zip(KeeperPoolStep::of(action));
+        let mut paid_steps = KeeperPaidSteps {
+            epoch: epoch_height,
+            steps: 0,
+        };
+        if let Some((sp_id, step)) = pool_step {
+            if let Some(paid) = self.keeper_bounty_paid_pool_steps.get(&sp_id) {
+                if paid.epoch == epoch_height {
+                    if paid.steps & step.bit() != 0 {
+                        return 0;
+                    }
+                    paid_steps = paid;
+                }
+            }
+        }
+        // epoch cap
+        let amount = std::cmp::min(
+            bounty_per_step,
+            self.keeper_bounty_config
+                .max_bounty_per_epoch
+                .0
+                .saturating_sub(self.keeper_bounty_paid_in_epoch),
+        );
+        if amount == 0 {
+            return 0;
+        }
+        // per keeper cap
+        let mut stats = self.keeper_stats.get(keeper_id).unwrap_or_default();
+        if stats.last_paid_epoch != epoch_height {
+            stats.last_paid_epoch = epoch_height;
+            stats.paid_steps_in_last_epoch = 0;
+        }
+        if stats.paid_steps_in_last_epoch >= self.keeper_bounty_config.max_paid_steps_per_keeper {
+            return 0;
+        }
+        // from the fund
+        let mut fund = self
+            .accounts
+            .get(&KEEPER_FUND_INTERNAL_ACCOUNT.into())
+            .unwrap_or_default();
+        let shares = std::cmp::min(self.stake_shares_from_amount(amount), fund.stake_shares);
+        if shares == 0 {
+            return 0;
+        }
+        let near_amount = self.amount_from_stake_shares(shares);
+        fund.sub_stake_shares(shares, near_amount);
+        self.internal_update_account(&KEEPER_FUND_INTERNAL_ACCOUNT.into(), &fund);
+        let mut keeper = self.accounts.get(keeper_id).unwrap_or_default();
+        keeper.add_stake_shares(shares, near_amount);
+        self.internal_update_account(keeper_id, &keeper);
+
+        self.keeper_bounty_paid_in_epoch += near_amount;
+        stats.paid_steps += 1;
+        stats.paid_steps_in_last_epoch += 1;
+        stats.bounty_shares += shares;
+        self.keeper_stats.insert(keeper_id, &stats);
+        if let Some((sp_id, step)) = pool_step {
+            paid_steps.steps |= step.bit();
+            self.keeper_bounty_paid_pool_steps.insert(&sp_id, &paid_steps);
+        }
+
+        event!(
+            r#"{{"event":"KEEP.B","keeper":"{}","step":"{}","shares":"{}","amount":"{}"}}"#,
+            keeper_id,
+            action.name(),
+            shares,
+            near_amount
+        );
+        shares
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// Fee manager's method. Queues a change of the keeper bounty config
+    /// returns the pending change id
+    pub fn set_keeper_bounty_config(&mut self, config: KeeperBountyConfig) -> u64 {
+        self.assert_role(Role::FeeManager);
+        self.internal_propose_change(GovernanceAction::SetKeeperBountyConfig { config })
+    }
+
+    pub fn get_keeper_bounty_info(&self) -> KeeperBountyInfoJSON {
+        let fund_shares = self
+            .accounts
+            .get(&KEEPER_FUND_INTERNAL_ACCOUNT.into())
+            .map_or(0, |acc| acc.stake_shares);
+        KeeperBountyInfoJSON {
+            config: self.keeper_bounty_config.clone(),
+            fund_shares: fund_shares.into(),
+            fund_value: self.amount_from_stake_shares(fund_shares).into(),
+            paid_in_epoch: if self.keeper_bounty_epoch == env::epoch_height() {
+                self.keeper_bounty_paid_in_epoch
+            } else {
+                0
+            }
+            .into(),
+        }
+    }
+
+    pub fn get_keeper_stats(&self, account_id: AccountId) -> Option<KeeperStatsJSON> {
+        self.keeper_stats.get(&account_id).map(|stats| KeeperStatsJSON {
+            paid_steps: stats.paid_steps.into(),
+            bounty_shares: stats.bounty_shares.into(),
+            last_paid_epoch: stats.last_paid_epoch.into(),
+            paid_steps_in_last_epoch: stats.paid_steps_in_last_epoch,
+        })
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::PromiseResult;
+
+    const EPOCH: EpochHeight = 10;
+    const KEEPER: &str = "keeper.testnet";
+
+    /// bounty of 1 NEAR per step, funded with 50 stNEAR
+    fn new_contract_with_bounty() -> MetaPool {
+        let mut contract = new_contract();
+        contract.keeper_bounty_config = KeeperBountyConfig {
+            bounty_per_step: ONE_NEAR.into(),
+            max_bounty_per_epoch: (100 * ONE_NEAR).into(),
+            max_paid_steps_per_keeper: 100,
+            fund_bp: 0,
+        };
+        add_account_with_stake(&mut contract, KEEPER_FUND_INTERNAL_ACCOUNT, 50 * ONE_NEAR);
+        contract
+    }
+
+    fn pay_rewards_step(contract: &mut MetaPool, sp_id: u16) -> u128 {
+        let action = HeartbeatAction::DistributeRewards { sp_id };
+        contract.internal_pay_keeper_bounty(&KEEPER.into(), &action, Some(sp_id))
+    }
+
+    fn paid_steps(contract: &MetaPool) -> u64 {
+        contract.get_keeper_stats(KEEPER.into()).map_or(0, |stats| stats.paid_steps.0)
+    }
+
+    #[test]
+    fn test_failing_retrieve_is_paid_once_per_epoch() {
+        let mut contract = new_contract_with_bounty();
+        let sp_id = add_pool(&mut contract, "pool.testnet", 10000);
+        contract.staking_pools[0].unstaked = 50 * NEAR;
+        contract.staking_pools[0].unstk_req_epoch_height = 1;
+        contract.total_unstaked_and_waiting = 50 * NEAR;
+
+        for round in 0..3 {
+            set_context(KEEPER, EPOCH, 0);
+            assert_eq!(contract.heartbeat(), HeartbeatAction::SyncUnstakedBalance { sp_id });
+            set_context(CONTRACT_ID, EPOCH, 0);
+            contract.on_get_sp_unstaked_balance(sp_id, (50 * NEAR).into());
+            set_context(KEEPER, EPOCH, 0);
+            assert_eq!(contract.heartbeat(), HeartbeatAction::RetrieveFunds { sp_id });
+            // withdraw_all fails, the heartbeat goes back to sync
+            set_callback_context(EPOCH, PromiseResult::Failed);
+            contract.on_retrieve_from_staking_pool(sp_id);
+            assert_eq!(contract.staking_pools[0].unstaked, 50 * NEAR);
+            assert_eq!(paid_steps(&contract), 2, "round {}", round);
+        }
+
+        // paid again in the next epoch
+        set_context(KEEPER, EPOCH + 1, 0);
+        assert_eq!(contract.heartbeat(), HeartbeatAction::SyncUnstakedBalance { sp_id });
+        assert_eq!(paid_steps(&contract), 3);
+    }
+
+    #[test]
+    fn test_step_without_effect_is_not_paid() {
+        let mut contract = new_contract_with_bounty();
+        // pool-a has extra stake, but only 1 of 5 pools with stake can receive an unstake (40% required)
+        for n in 0..5 {
+            add_pool(&mut contract, &format!("pool-{}.testnet", n), 2000);
+            let sp = contract.staking_pools.last_mut().unwrap();
+            sp.staked = if n == 0 { 1000 * NEAR } else { 375 * NEAR };
+            if n > 0 {
+                sp.unstaked = NEAR;
+                sp.unstk_req_epoch_height = EPOCH - 1;
+            }
+        }
+        contract.total_for_staking = 2500 * NEAR;
+        contract.total_actually_staked = 2500 * NEAR;
+        contract.total_stake_shares = 2500 * NEAR;
+        contract.heartbeat_epoch = EPOCH;
+        contract.heartbeat_stage = HeartbeatStage::Rebalance;
+
+        set_context(KEEPER, EPOCH, 0);
+        assert_eq!(contract.heartbeat(), HeartbeatAction::RebalanceUnstake);
+        assert!(!contract.staking_pools[0].busy_lock);
+        assert_eq!(contract.heartbeat_stage, HeartbeatStage::Done);
+        assert_eq!(paid_steps(&contract), 0);
+    }
+
+    #[test]
+    fn test_bounty_respects_the_keeper_cap() {
+        let mut contract = new_contract_with_bounty();
+        contract.keeper_bounty_config.max_paid_steps_per_keeper = 1;
+        set_context(KEEPER, EPOCH, 0);
+        assert!(pay_rewards_step(&mut contract, 0) > 0);
+        assert_eq!(pay_rewards_step(&mut contract, 1), 0);
+        assert_eq!(paid_steps(&contract), 1);
+    }
+
+    #[test]
+    fn test_each_pool_step_is_paid_once_per_epoch() {
+        let mut contract = new_contract_with_bounty();
+        set_context(KEEPER, EPOCH, 0);
+        assert!(pay_rewards_step(&mut contract, 0) > 0);
+        assert_eq!(pay_rewards_step(&mut contract, 0), 0);
+        // another pool, or another step on the same pool
+        assert!(pay_rewards_step(&mut contract, 1) > 0);
+        let sync = HeartbeatAction::SyncUnstakedBalance { sp_id: 0 };
+        assert!(contract.internal_pay_keeper_bounty(&KEEPER.into(), &sync, Some(0)) > 0);
+        assert_eq!(paid_steps(&contract), 3);
+
+        set_context(KEEPER, EPOCH + 1, 0);
+        assert!(pay_rewards_step(&mut contract, 0) > 0);
+        assert_eq!(paid_steps(&contract), 4);
+    }
+}
@@ -16,6 +16,7 @@ use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::collections::{LookupMap, UnorderedMap};
 use near_sdk::json_types::Base58PublicKey;
 use near_sdk::{env, ext_contract, log, near_bindgen, AccountId, PanicOnDefault, Promise};
+use crate::empty_nep_145::STORAGE_COST_YOCTOS;
 
 //-- Sputnik DAO remote upgrade requires BLOCKCHAIN_INTERFACE low-level access
 #[cfg(target_arch = "wasm32")]
@@ -36,8 +37,41 @@ pub use crate::internal::*;
 pub use crate::staking_pools::*;
 
 pub mod distribute;
+pub mod distribute_batch;
+pub use crate::distribute_batch::*;
 mod migrations;
 pub mod owner;
+pub mod pause;
+pub use crate::pause::*;
+pub mod roles;
+pub use crate::roles::*;
+pub mod governance;
+pub use crate::governance::*;
+pub mod heartbeat;
+pub use crate::heartbeat::*;
+pub mod keeper;
+pub use crate::keeper::*;
+pub mod slashing;
+pub use crate::slashing::*;
+pub mod sp_whitelist;
+pub use crate::sp_whitelist::*;
+pub mod retire;
+pub mod reward_history;
+pub use crate::reward_history::*;
+pub mod weight_strategy;
+pub use crate::weight_strategy::*;
+pub mod unstake_planner;
+pub use crate::unstake_planner::*;
+pub mod network;
+pub use crate::network::*;
+pub mod lockup_registry;
+pub use crate::lockup_registry::*;
+pub mod unstake_tickets;
+pub use crate::unstake_tickets::*;
+pub mod unstake_nft;
+pub use crate::unstake_nft::*;
+pub mod fee_curve;
+pub use crate::fee_curve::*;
 
 pub mod reward_meter;
 pub use reward_meter::*;
@@ -45,6 +79,9 @@ pub use reward_meter::*;
 pub mod empty_nep_145;
 pub mod fungible_token_standard;
 
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test_utils;
+
 // setup_alloc adds a #[cfg(target_arch = "wasm32")] to the global allocator, which prevents the allocator
 // from being used when the contract's main file is used in simulation testing.
 near_sdk::setup_alloc!();
@@ -54,33 +91,37 @@ near_sdk::setup_alloc!();
 pub trait ExtMetaStakingPoolOwnerCallbacks {
     fn on_staking_pool_deposit(&mut self, amount: U128String) -> bool;
 
-    fn on_retrieve_from_staking_pool(&mut self, inx: u16) -> bool;
+    fn on_retrieve_from_staking_pool(&mut self, sp_id: u16) -> bool;
 
     fn on_staking_pool_stake_maybe_deposit(
         &mut self,
-        sp_inx: usize,
+        sp_id: u16,
         amount: u128,
         included_deposit: bool,
     ) -> bool;
 
     fn on_staking_pool_unstake(
         &mut self,
-        sp_inx: usize,
+        sp_id: u16,
         amount_from_unstake_orders: U128String,
         amount_from_rebalance: U128String,
     ) -> bool;
 
     fn on_get_result_from_transfer_poll(&mut self, #[callback] poll_result: PollResult) -> bool;
 
-    fn on_get_sp_total_balance(&mut self, sp_inx: usize, #[callback] total_balance: U128String);
+    fn on_get_sp_total_balance(&mut self, sp_id: u16);
 
     fn on_get_sp_unstaked_balance(
         &mut self,
-        sp_inx: usize,
+        sp_id: u16,
         #[callback] unstaked_balance: U128String,
     );
 
     fn after_minting_meta(self, account_id: AccountId, to_mint: U128String);
+
+    fn on_batch_settle(&mut self, items: Vec<BatchItem>);
+
+    fn on_add_staking_pool_checks(&mut self, account_id: AccountId, whitelist_account_id: AccountId) -> bool;
 }
 
 // #[ext_contract(meta_token_mint)]
@@ -99,7 +140,9 @@ pub struct MetaPool {
     /// Owner's account ID (DAO)
     pub owner_account_id: AccountId,
 
-    /// Avoid re-entry when async-calls are in-flight
+    /// Manual global lock, stops user operations & the heartbeat (see set_busy)
+    /// Note: in-flight async-calls only lock their staking pool (sp.busy_lock)
+    /// see also contract_busy_since_block/epoch
     pub contract_busy: bool,
 
     /// no auto-staking. true while changing staking pools
@@ -251,6 +294,80 @@ pub struct MetaPool {
     /// represents the amount that's not staked because is in transit for rebalance.
     /// it could be in unstaked_and_waiting or in the contract & epoch_stake_orders
     pub unstaked_for_rebalance: u128,
+
+    /// per-operation pause switches (emergency brakes), see pause.rs
+    pub pause_state: PauseState,
+
+    /// role-based access control, see roles.rs. The owner implicitly holds all roles
+    pub roles: UnorderedMap<AccountId, Vec<Role>>,
+
+    /// timelocked parameter changes, see governance.rs
+    pub pending_changes: UnorderedMap<u64, PendingChange>,
+    pub next_pending_change_id: u64,
+    /// how many epochs a pending change must wait before it can be executed
+    pub governance_delay_epochs: EpochHeight,
+
+    /// when contract_busy was set (only meaningful while contract_busy==true)
+    pub contract_busy_since_block: u64,
+    pub contract_busy_since_epoch: EpochHeight,
+    /// busy locks (contract & sp) older than this can be cleared by anyone, see clear_stale_busy_locks
+    pub busy_lock_expiry_blocks: u64,
+
+    /// heartbeat state machine, see heartbeat.rs
+    pub heartbeat_epoch: EpochHeight,
+    pub heartbeat_stage: HeartbeatStage,
+    /// pool (id) whose unstaked balance was synced (set by the sync callback), the next heartbeat step retrieves from it
+    pub heartbeat_synced_sp: Option<u16>,
+
+    /// keeper bounty, see keeper.rs
+    pub keeper_bounty_config: KeeperBountyConfig,
+    pub keeper_stats: LookupMap<AccountId, KeeperStats>,
+    pub keeper_bounty_epoch: EpochHeight,
+    /// NEAR value paid in keeper_bounty_epoch
+    pub keeper_bounty_paid_in_epoch: u128,
+    /// steps paid on each pool (sp_id) in the last epoch it was paid, see keeper.rs
+    pub keeper_bounty_paid_pool_steps: LookupMap<u16, KeeperPaidSteps>,
+
+    /// losses reported by each pool, see slashing.rs
+    pub sp_loss_history: LookupMap<AccountId, Vec<LossRecord>>,
+    /// a single loss of this fraction of a pool's balance zeroes its weight and pauses staking into it, 0=>disabled
+    pub slash_threshold_bp: u16,
+
+    /// contract answering is_whitelisted(staking_pool_account_id) for new pools, see sp_whitelist.rs
+    pub staking_pool_whitelist_account_id: AccountId,
+    /// new pools with a higher reward fee are rejected
+    pub max_sp_reward_fee_bp: u16,
+
+    /// id for the next staking pool added, see StakingPoolInfo.id
+    pub next_sp_id: u16,
+
+    /// reward records of each pool, see reward_history.rs
+    pub sp_reward_history: LookupMap<AccountId, RewardHistory>,
+
+    /// see weight_strategy.rs
+    pub weight_strategy_config: WeightStrategyConfig,
+    pub weight_strategy_applied_epoch: EpochHeight,
+
+    /// chain-specific values, see network.rs
+    pub network_profile: NetworkProfile,
+
+    /// trusted lockup proxies & their stats, see lockup_registry.rs
+    pub lockup_proxies: UnorderedMap<AccountId, LockupProxyInfo>,
+    pub lockup_account_suffixes: Vec<String>,
+
+    /// delayed-unstake tickets of each account, see unstake_tickets.rs
+    pub unstake_tickets: LookupMap<AccountId, Vec<UnstakeTicket>>,
+
+    /// minted unstake claims (NEP-171 tokens) & token ids per owner, see unstake_nft.rs
+    pub unstake_nfts: UnorderedMap<TokenId, UnstakeClaim>,
+    pub unstake_nfts_per_owner: LookupMap<AccountId, Vec<TokenId>>,
+    pub next_unstake_nft_id: u64,
+
+    /// shape of the liquid-unstake fee, see fee_curve.rs
+    pub nslp_fee_curve: NslpFeeCurve,
+
+    /// premium paid by buy_stnear, kept by the NSLP
+    pub nslp_buy_premium_bp: u16,
 }
 
 #[near_bindgen]
@@ -272,14 +389,22 @@ impl MetaPool {
 
     /// Initializes MetaPool contract.
     /// - `owner_account_id` - the account ID of the owner.  Only this account can call owner's methods on this contract.
+    /// - `network_profile` - "mainnet", "testnet" or "sandbox" (only on *.test.near). If omitted, chosen from this contract account id, mainnet if unknown
     #[init]
     pub fn new(
         owner_account_id: AccountId,
         treasury_account_id: AccountId,
         operator_account_id: AccountId,
         meta_token_account_id: AccountId,
+        network_profile: Option<String>,
     ) -> Self {
-        let result = Self {
+        let network_profile = match network_profile {
+            Some(name) => NetworkProfile::by_name(&name)
+                .unwrap_or_else(|| panic!("unknown network profile {}", name)),
+            None => NetworkProfile::for_account(&env::current_account_id()),
+        };
+        network_profile.assert_valid(&env::current_account_id());
+        let mut result = Self {
             owner_account_id,
             contract_busy: false,
             operator_account_id,
@@ -325,20 +450,73 @@ impl MetaPool {
             max_meta_rewards_lp: 100_000 * ONE_NEAR, // (deprecated)
             unstaked_for_rebalance: 0,
             unstake_for_rebalance_cap_bp: 100,
+            pause_state: PauseState::default(),
+            roles: UnorderedMap::new(b"R".to_vec()),
+            pending_changes: UnorderedMap::new(b"G".to_vec()),
+            next_pending_change_id: 0,
+            governance_delay_epochs: DEFAULT_GOVERNANCE_DELAY_EPOCHS,
+            contract_busy_since_block: 0,
+            contract_busy_since_epoch: 0,
+            busy_lock_expiry_blocks: DEFAULT_BUSY_LOCK_EXPIRY_BLOCKS,
+            heartbeat_epoch: 0,
+            heartbeat_stage: HeartbeatStage::Clearing,
+            heartbeat_synced_sp: None,
+            keeper_bounty_config: KeeperBountyConfig::default(),
+            keeper_stats: LookupMap::new(b"K".to_vec()),
+            keeper_bounty_epoch: 0,
+            keeper_bounty_paid_in_epoch: 0,
+            keeper_bounty_paid_pool_steps: LookupMap::new(b"B".to_vec()),
+            sp_loss_history: LookupMap::new(b"S".to_vec()),
+            slash_threshold_bp: 0,
+            staking_pool_whitelist_account_id: network_profile.staking_pool_whitelist_account_id.clone(),
+            max_sp_reward_fee_bp: DEFAULT_MAX_SP_REWARD_FEE_BP,
+            next_sp_id: 0,
+            sp_reward_history: LookupMap::new(b"W".to_vec()),
+            weight_strategy_config: WeightStrategyConfig::default(),
+            weight_strategy_applied_epoch: 0,
+            network_profile,
+            lockup_proxies: UnorderedMap::new(b"P".to_vec()),
+            lockup_account_suffixes: Vec::new(),
+            unstake_tickets: LookupMap::new(b"T".to_vec()),
+            unstake_nfts: UnorderedMap::new(b"U".to_vec()),
+            unstake_nfts_per_owner: LookupMap::new(b"O".to_vec()),
+            next_unstake_nft_id: 0,
+            nslp_fee_curve: NslpFeeCurve::default(),
+            nslp_buy_premium_bp: DEFAULT_NSLP_BUY_PREMIUM_BP,
         };
+        result.internal_seed_lockup_registry();
         //all key accounts must be different
         result.assert_key_accounts_are_different();
+        // the operator runs the heartbeat and can pull the emergency brakes
+        result.grant_operator_roles();
         return result;
     }
 
+    fn grant_operator_roles(&mut self) {
+        let operator_account_id = self.operator_account_id.clone();
+        for role in OPERATOR_ROLES.iter() {
+            self.internal_grant_role(&operator_account_id, *role);
+        }
+    }
+
+    /// the operator roles go with the operator account: revoked from the old one, granted to the new one
+    pub(crate) fn internal_set_operator_account_id(&mut self, account_id: AccountId) {
+        let old_operator = std::mem::replace(&mut self.operator_account_id, account_id);
+        for role in OPERATOR_ROLES.iter() {
+            if self.roles.get(&old_operator).map_or(false, |roles| roles.contains(role)) {
+                self.internal_revoke_role(&old_operator, *role);
+            }
+        }
+        self.grant_operator_roles();
+    }
+
     fn assert_key_accounts_are_different(&self) {
         //all accounts must be different
-        assert!(self.owner_account_id != self.operator_account_id);
-        assert!(self.owner_account_id != DEVELOPERS_ACCOUNT_ID);
-        assert!(self.owner_account_id != self.treasury_account_id);
-        assert!(self.operator_account_id != DEVELOPERS_ACCOUNT_ID);
-        assert!(self.operator_account_id != self.treasury_account_id);
-        assert!(self.treasury_account_id != DEVELOPERS_ACCOUNT_ID);
+        assert_key_accounts_different(
+            &self.owner_account_id,
+            &self.operator_account_id,
+            &self.treasury_account_id,
+        );
     }
 
     //------------------------------------
@@ -358,31 +536,34 @@ impl MetaPool {
 
     /// Withdraws from "UNSTAKED" balance *TO MIMIC core-contracts/staking-pool* .- core-contracts/staking-pool only has "unstaked" to withdraw from
     pub fn withdraw(&mut self, amount: U128String) -> Promise {
-        assert_not_lockup_account_calling();
+        self.assert_not_lockup_account_calling();
         self.internal_withdraw_use_unstaked(&env::predecessor_account_id(), amount.0)
     }
     /// Withdraws ALL from from "UNSTAKED" balance *TO MIMIC core-contracts/staking-pool .- core-contracts/staking-pool only has "unstaked" to withdraw from
+    /// (all the matured unstake tickets)
     pub fn withdraw_all(&mut self) -> Promise {
-        assert_not_lockup_account_calling();
-        let account_id = env::predecessor_account_id();
-        let account = self.internal_get_account(&account_id);
-        self.internal_withdraw_use_unstaked(&account_id, account.unstaked)
+        self.withdraw_unstaked()
     }
 
     /// user method - simplified flow
     /// completes delayed-unstake action by transferring from retrieved_from_the_pools to user's NEAR account
     /// equivalent to core-contracts/staking-pool.withdraw_all, used by metastaking webapp
+    /// pays out all the matured unstake tickets
     pub fn withdraw_unstaked(&mut self) -> Promise {
-        assert_not_lockup_account_calling();
+        self.assert_not_lockup_account_calling();
         let account_id = env::predecessor_account_id();
         let account = self.internal_get_account(&account_id);
-        self.internal_withdraw_use_unstaked(&account_id, account.unstaked)
+        let matured = self.internal_matured_unstaked(&account_id, &account);
+        // nothing matured: ask for all, so it fails telling when the next ticket unlocks
+        let amount = if matured == 0 { account.unstaked } else { matured };
+        self.internal_withdraw_use_unstaked(&account_id, amount)
     }
 
     /// Deposits the attached amount into the inner account of the predecessor and stakes it.
     #[payable]
     pub fn deposit_and_stake(&mut self) -> U128String {
-        assert_not_lockup_account_calling();
+        self.assert_not_lockup_account_calling();
+        self.assert_operation_not_paused(PausableOperation::DepositAndStake);
         let account_id = env::predecessor_account_id();
         let amount = self.internal_deposit(&account_id);
         let shares = self.internal_stake_from_account(&account_id, amount);
@@ -409,7 +590,7 @@ impl MetaPool {
     /// Unstakes all staked balance from the inner account of the predecessor.
     /// The new total unstaked balance will be available for withdrawal in four epochs.
     pub fn unstake_all(&mut self) {
-        assert_not_lockup_account_calling();
+        self.assert_not_lockup_account_calling();
         let account_id = env::predecessor_account_id();
         let mut account = self.internal_get_account(&account_id);
         let all_shares = account.stake_shares;
@@ -421,7 +602,7 @@ impl MetaPool {
     /// The new total unstaked balance will be available for withdrawal in four epochs.
     /// delayed_unstake, amount_requested is in yoctoNEARs
     pub fn unstake(&mut self, amount: U128String) {
-        assert_not_lockup_account_calling();
+        self.assert_not_lockup_account_calling();
         self.internal_unstake(&env::predecessor_account_id(), amount.0);
     }
 
@@ -434,9 +615,14 @@ impl MetaPool {
     /// return value is the amount of shares
     #[payable]
     pub fn stake_for_lockup(&mut self, lockup_account_id: String) -> U128String {
-        assert_lockup_contract_calling();
+        let proxy_id = self.assert_lockup_contract_calling();
+        self.assert_operation_not_paused(PausableOperation::DepositAndStake);
         let amount = self.internal_deposit(&lockup_account_id);
         let shares = self.internal_stake_from_account(&lockup_account_id, amount);
+        self.internal_update_lockup_proxy_stats(&proxy_id, |info| {
+            info.stake_shares.0 += shares;
+            info.total_staked.0 += amount;
+        });
         //----------
         // check if the liquidity pool needs liquidity, and then use this opportunity to liquidate stnear in the LP by internal-clearing
         // the amount just deposited, might be swapped in the liquid-unstake pool
@@ -452,15 +638,59 @@ impl MetaPool {
         lockup_account_id: String,
         shares: U128String,
     ) -> (U128String, U64String) {
-        assert_lockup_contract_calling();
+        let proxy_id = self.assert_lockup_contract_calling();
         let mut acc = self.internal_get_account(&lockup_account_id);
         let (nears, epoch) = self.internal_unstake_shares(&lockup_account_id, &mut acc, shares.0);
+        self.internal_update_lockup_proxy_stats(&proxy_id, |info| {
+            info.stake_shares.0 = info.stake_shares.0.saturating_sub(shares.0);
+            info.total_unstaked.0 += nears;
+        });
         (nears.into(), epoch.into())
     }
 
+    /// Withdraws the exact amount to the lockup account, from its available balance first, the rest from unstaked
     pub fn withdraw_to_lockup(&mut self, lockup_account_id: String, amount: U128String) -> Promise {
-        assert_lockup_contract_calling();
-        self.internal_withdraw_use_unstaked(&lockup_account_id, amount.0)
+        let proxy_id = self.assert_lockup_contract_calling();
+        self.internal_update_lockup_proxy_stats(&proxy_id, |info| info.total_withdrawn.0 += amount.0);
+        self.internal_withdraw_for_lockup(&lockup_account_id, amount.0)
+    }
+
+    /// Liquid-unstakes the exact amount of shares from a lockup account, same fees as liquid_unstake
+    /// The NEAR is not transferred, it is left in the lockup account's available balance, to be pulled with withdraw_to_lockup
+    /// return value is the NEAR credited and the fee in stNEAR
+    pub fn liquid_unstake_for_lockup(
+        &mut self,
+        lockup_account_id: String,
+        shares: U128String,
+        min_expected_near: U128String,
+    ) -> LiquidUnstakeResult {
+        let proxy_id = self.assert_lockup_contract_calling();
+        self.assert_not_busy();
+        self.assert_operation_not_paused(PausableOperation::LiquidUnstake);
+        let mut acc = self.internal_get_account(&lockup_account_id);
+        let (near_to_receive, fee_in_st_near) = self.internal_liquid_unstake(
+            &lockup_account_id,
+            &mut acc,
+            shares.0,
+            min_expected_near.0,
+        );
+        self.internal_update_account(&lockup_account_id, &acc);
+        self.internal_update_lockup_proxy_stats(&proxy_id, |info| {
+            info.stake_shares.0 = info.stake_shares.0.saturating_sub(shares.0);
+            info.total_unstaked.0 += near_to_receive;
+        });
+        event!(
+            r#"{{"event":"LIQ.U.LOCKUP","account_id":"{}","proxy":"{}","stnear":"{}","near":"{}"}}"#,
+            lockup_account_id,
+            proxy_id,
+            shares.0,
+            near_to_receive
+        );
+        LiquidUnstakeResult {
+            near: near_to_receive.into(),
+            fee: fee_in_st_near.into(),
+            meta: 0.into(),
+        }
     }
 
     /*****************************/
@@ -519,14 +749,13 @@ impl MetaPool {
         self.operator_rewards_fee_basis_points + DEVELOPERS_REWARDS_FEE_BASIS_POINTS
     }
 
+    /// queues a reward fee change, see governance.rs
+    /// returns the pending change id
     #[payable]
-    pub fn set_reward_fee(&mut self, basis_points: u16) {
-        self.assert_owner_calling();
+    pub fn set_reward_fee(&mut self, basis_points: u16) -> u64 {
+        self.assert_role(Role::FeeManager);
         assert_one_yocto();
-        assert!(env::attached_deposit() > 0);
-        assert!(basis_points < 1000); // less than 10%
-        self.operator_rewards_fee_basis_points =
-            basis_points.saturating_sub(DEVELOPERS_REWARDS_FEE_BASIS_POINTS);
+        self.internal_propose_change(GovernanceAction::SetRewardFee { basis_points })
     }
 
     /// Returns the staking public key
@@ -594,12 +823,59 @@ impl MetaPool {
             .into();
     }
 
+    /// how much stNEAR you get by paying near_amount NEAR with buy_stnear
+    pub fn get_stnear_amount_buy_stnear(&self, near_amount: U128String) -> U128String {
+        let (st_near, _) = self.internal_get_stnear_amount_buy_stnear(near_amount.0);
+        return st_near.into();
+    }
+
     /// NEAR/stNEAR Liquidity Pool
     /// computes the discount_basis_points for NEAR/stNEAR Swap based on NSLP Balance
     /// If you want to sell x stNEAR
     pub fn nslp_get_discount_basis_points(&self, stnear_to_sell: U128String) -> u16 {
         let lp_account = self.internal_get_nslp_account();
-        return self.internal_get_discount_basis_points(lp_account.available, stnear_to_sell.0);
+        return self
+            .internal_quote_liquid_unstake(lp_account.available, stnear_to_sell.0)
+            .fee_bp;
+    }
+
+    /// all the amounts of a liquid_unstake of st_near_to_sell now, computed as liquid_unstake does.
+    /// max_st_near_under_fee: the largest amount sellable with a fee <= max_fee_bp (default: no limit)
+    pub fn quote_liquid_unstake(
+        &self,
+        st_near_to_sell: U128String,
+        max_fee_bp: Option<u16>,
+    ) -> LiquidUnstakeQuoteJSON {
+        let lp_account = self.internal_get_nslp_account();
+        let quote = self.internal_quote_liquid_unstake(lp_account.available, st_near_to_sell.0);
+        let max_fee_bp = max_fee_bp.unwrap_or(9999);
+        let max_st_near_under_fee = self.internal_max_liquid_unstake_under_fee(
+            lp_account.available,
+            self.total_stake_shares,
+            max_fee_bp,
+        );
+        LiquidUnstakeQuoteJSON {
+            st_near_to_sell,
+            near_out: quote.near_to_receive.into(),
+            fee_bp: quote.fee_bp,
+            fee_near: quote.fee.into(),
+            fee_st_near: quote.fee_in_st_near.into(),
+            treasury_st_near_cut: quote.treasury_st_near_cut.into(),
+            operator_st_near_cut: quote.operator_st_near_cut.into(),
+            developers_st_near_cut: quote.developers_st_near_cut.into(),
+            nslp_st_near_cut: (quote.fee_in_st_near
+                - (quote.treasury_st_near_cut
+                    + quote.operator_st_near_cut
+                    + quote.developers_st_near_cut))
+                .into(),
+            enough_liquidity: lp_account.available >= quote.near_to_receive,
+            nslp_liquidity_after: lp_account
+                .available
+                .saturating_sub(quote.near_to_receive)
+                .into(),
+            max_fee_bp,
+            max_st_near_under_fee: max_st_near_under_fee.into(),
+        }
     }
 
     /// user method
@@ -612,6 +888,7 @@ impl MetaPool {
         min_expected_near: U128String,
     ) -> LiquidUnstakeResult {
         self.assert_not_busy();
+        self.assert_operation_not_paused(PausableOperation::LiquidUnstake);
         // Q: Why not? - R: liquid_unstake It's not as problematic as transfer, because it moves tokens between accounts of the same user
         // so let's remove the one_yocto_requirement, waiting for a better solution for the function-call keys NEP-141 problem
         //assert_one_yocto();
@@ -636,102 +913,12 @@ impl MetaPool {
             user_account.stake_shares, st_near_to_sell
         );
 
-        assert!(
-            stnear_owned >= st_near_to_sell,
-            "Not enough stNEAR. You own {}",
-            stnear_owned
-        );
-
-        let mut nslp_account = self.internal_get_nslp_account();
-
-        //compute how many nears are the st_near valued at
-        let nears_out = self.amount_from_stake_shares(st_near_to_sell);
-        let swap_fee_basis_points =
-            self.internal_get_discount_basis_points(nslp_account.available, nears_out);
-        assert!(swap_fee_basis_points < 10000, "inconsistency d>1");
-        let fee = apply_pct(swap_fee_basis_points, nears_out);
-
-        let near_to_receive = nears_out - fee;
-        assert!(
-            near_to_receive >= min_expected_near.0,
-            "Price changed, your min amount {} is not satisfied {}. Try again",
+        let (near_to_receive, fee_in_st_near) = self.internal_liquid_unstake(
+            &account_id,
+            &mut user_account,
+            st_near_to_sell,
             min_expected_near.0,
-            near_to_receive
-        );
-        assert!(
-            nslp_account.available >= near_to_receive,
-            "Not enough liquidity in the liquidity pool"
-        );
-
-        //the NEAR for the user comes from the LP
-        nslp_account.available -= near_to_receive;
-        user_account.available += near_to_receive;
-
-        // compute how many shares the swap fee represent
-        let fee_in_st_near = self.stake_shares_from_amount(fee);
-
-        // involved accounts
-        assert!(
-            &account_id != &self.treasury_account_id,
-            "can't use treasury account"
-        );
-        let mut treasury_account = self
-            .accounts
-            .get(&self.treasury_account_id)
-            .unwrap_or_default();
-        assert!(
-            &account_id != &self.operator_account_id,
-            "can't use operator account"
-        );
-        let mut operator_account = self
-            .accounts
-            .get(&self.operator_account_id)
-            .unwrap_or_default();
-        assert!(
-            &account_id != &DEVELOPERS_ACCOUNT_ID,
-            "can't use developers account"
         );
-        let mut developers_account = self
-            .accounts
-            .get(&DEVELOPERS_ACCOUNT_ID.into())
-            .unwrap_or_default();
-
-        // The treasury cut in stnear-shares (25% by default)
-        let treasury_st_near_cut = apply_pct(self.treasury_swap_cut_basis_points, fee_in_st_near);
-        treasury_account.add_st_near(treasury_st_near_cut, &self);
-
-        // The cut that the contract owner (operator) takes. (3% of 1% normally)
-        let operator_st_near_cut = apply_pct(self.operator_swap_cut_basis_points, fee_in_st_near);
-        operator_account.add_st_near(operator_st_near_cut, &self);
-
-        // The cut that the developers take. (2% of 1% normally)
-        let developers_st_near_cut = apply_pct(DEVELOPERS_SWAP_CUT_BASIS_POINTS, fee_in_st_near);
-        developers_account.add_st_near(developers_st_near_cut, &self);
-
-        log!("treasury_st_near_cut:{} operator_st_near_cut:{} developers_st_near_cut:{} fee_in_st_near:{}",
-            treasury_st_near_cut,operator_st_near_cut,developers_st_near_cut,fee_in_st_near);
-
-        assert!(
-            fee_in_st_near > treasury_st_near_cut + developers_st_near_cut + operator_st_near_cut
-        );
-
-        // The rest of the st_near sold goes into the liq-pool. Because it is a larger amount than NEARs removed, it will increase share value for all LP providers.
-        // Adding value to the pool via adding more stNEAR value than the NEAR removed
-        let st_near_to_liq_pool = st_near_to_sell
-            - (treasury_st_near_cut + operator_st_near_cut + developers_st_near_cut);
-        log!("nslp_account.add_st_near {}", st_near_to_liq_pool);
-        // major part of stNEAR sold goes to the NSLP
-        nslp_account.add_st_near(st_near_to_liq_pool, &self);
-
-        //complete the transfer, remove stnear from the user (stnear was transferred to the LP & others)
-        user_account.sub_st_near(st_near_to_sell, &self);
-
-        //Save involved accounts
-        self.internal_update_account(&self.treasury_account_id.clone(), &treasury_account);
-        self.internal_update_account(&self.operator_account_id.clone(), &operator_account);
-        self.internal_update_account(&DEVELOPERS_ACCOUNT_ID.into(), &developers_account);
-        //Save nslp accounts
-        self.internal_save_nslp_account(&nslp_account);
 
         //simplified user-flow
         //direct transfer to user (instead of leaving it in-contract as "available")
@@ -761,100 +948,204 @@ impl MetaPool {
         };
     }
 
-    /// add liquidity - payable
-    #[payable]
-    pub fn nslp_add_liquidity(&mut self) -> u16 {
-        // TODO: Since this method doesn't guard the resulting liquidity, is it possible to put it
-        //    into a front-run/end-run sandwich to capitalize on the transaction?
-        let account_id = env::predecessor_account_id();
-        let amount = self.internal_deposit(&account_id);
-        return self.internal_nslp_add_liquidity(&account_id, amount);
-    }
-
-    /// remove liquidity from liquidity pool
-    //#[payable]
-    pub fn nslp_remove_liquidity(&mut self, amount: U128String) -> RemoveLiquidityResult {
+    /// user method
+    /// liquid-unstakes as much of st_near_to_burn as the NSLP can take with a fee <= max_fee_bp,
+    /// and delayed-unstakes the rest
+    pub fn smart_unstake(&mut self, st_near_to_burn: U128String, max_fee_bp: u16) -> SmartUnstakeResult {
         self.assert_not_busy();
-        //assert_one_yocto();
+        self.assert_not_lockup_account_calling();
 
         let account_id = env::predecessor_account_id();
-        let mut acc = self.internal_get_account(&account_id);
-        let mut nslp_account = self.internal_get_nslp_account();
+        let mut user_account = self.internal_get_account(&account_id);
 
-        //how much does this user owns
-        let valued_actual_shares = acc.valued_nslp_shares(self, &nslp_account);
+        let stnear_owned = user_account.stake_shares;
+        // if the amount is close to user's total, remove user's total, see liquid_unstake
+        let st_near_to_sell: u128 = if is_close(st_near_to_burn.0, stnear_owned) {
+            stnear_owned
+        } else {
+            st_near_to_burn.0
+        };
+        assert!(st_near_to_sell > 0, "nothing to unstake");
+        assert!(
+            stnear_owned >= st_near_to_sell,
+            "Not enough stNEAR. You own {}",
+            stnear_owned
+        );
 
-        let mut to_remove = amount.0;
-        let nslp_shares_to_burn: u128;
-        // if the amount is close to user's total, remove user's total
-        // to: a) do not leave less than ONE_MILLI_NEAR in the account, b) Allow 10 yoctos of rounding, e.g. remove(100) removes 99.999993 without panicking
-        if is_close(to_remove, valued_actual_shares) {
-            // allow for rounding simplification
-            to_remove = valued_actual_shares;
-            nslp_shares_to_burn = acc.nslp_shares; // close enough to all shares, burn-it all (avoid leaving "dust")
+        let liquid_st_near = if self.pause_state.is_paused(PausableOperation::LiquidUnstake) {
+            0
         } else {
-            assert!(
-                valued_actual_shares >= to_remove,
-                "Not enough share value {} to remove the requested amount from the pool",
-                valued_actual_shares
-            );
-            // Calculate the number of "nslp" shares that the account will burn based on the amount requested
-            nslp_shares_to_burn = self.nslp_shares_from_amount(to_remove, &nslp_account);
+            let nslp_account = self.internal_get_nslp_account();
+            let liquid =
+                self.internal_max_liquid_unstake_under_fee(nslp_account.available, st_near_to_sell, max_fee_bp);
+            // a tiny liquid part is delayed-unstaked too: its fee could round to 0, and internal_liquid_unstake needs fee > cuts
+            if self.amount_from_stake_shares(liquid) < MIN_STAKE_UNSTAKE_AMOUNT_MOVEMENT
+                || self
+                    .internal_quote_liquid_unstake(nslp_account.available, liquid)
+                    .fee_in_st_near
+                    == 0
+            {
+                0
+            } else {
+                liquid
+            }
+        };
+
+        let mut transfer_amount = 0;
+        let mut fee_in_st_near = 0;
+        if liquid_st_near > 0 {
+            let (near_to_receive, fee) =
+                self.internal_liquid_unstake(&account_id, &mut user_account, liquid_st_near, 0);
+            fee_in_st_near = fee;
+            //direct transfer to user, see liquid_unstake
+            transfer_amount = user_account.take_from_available(&account_id, near_to_receive, self);
+            self.native_transfer(&account_id, transfer_amount);
         }
 
-        assert!(nslp_shares_to_burn > 0);
+        let delayed_st_near = st_near_to_sell - liquid_st_near;
+        let (delayed_near, unlock_epoch) = if delayed_st_near > 0 {
+            let (amount, epoch) = self.internal_unstake_shares(&account_id, &mut user_account, delayed_st_near);
+            (amount, Some(epoch.into()))
+        } else {
+            self.internal_update_account(&account_id, &user_account);
+            (0, None)
+        };
+
+        event!(
+            r#"{{"event":"SMART.U","account_id":"{}","liquid_stnear":"{}","near":"{}","delayed_stnear":"{}","delayed_near":"{}"}}"#,
+            &account_id,
+            liquid_st_near,
+            transfer_amount,
+            delayed_st_near,
+            delayed_near
+        );
+
+        return SmartUnstakeResult {
+            liquid_st_near: liquid_st_near.into(),
+            near: transfer_amount.into(),
+            fee: fee_in_st_near.into(),
+            delayed_st_near: delayed_st_near.into(),
+            delayed_near: delayed_near.into(),
+            unlock_epoch,
+        };
+    }
+
+    /// user method - payable
+    /// swaps NEAR->stNEAR against the NSLP's stNEAR, paying nslp_buy_premium_bp. The premium stays in the NSLP
+    #[payable]
+    pub fn buy_stnear(&mut self, min_expected_st_near: U128String) -> BuyStNearResult {
+        self.assert_not_busy();
+        self.assert_operation_not_paused(PausableOperation::BuyStNear);
+        self.assert_not_lockup_account_calling();
 
-        //register removed liquidity to compute rewards correctly
-        acc.lp_meter.unstake(to_remove);
+        let account_id = env::predecessor_account_id();
+        let attached = env::attached_deposit();
+        self.assert_min_deposit_amount(attached);
+        // the attached NEAR goes straight to the LP, the account's available balance is not used
+        let opt_account = self.accounts.get(&account_id);
+        let amount = if opt_account.is_none() {
+            log!(
+                "new account, {} yoctos used for storage_deposit",
+                STORAGE_COST_YOCTOS
+            );
+            assert!(attached > STORAGE_COST_YOCTOS, "deposit too low");
+            attached - STORAGE_COST_YOCTOS
+        } else {
+            attached
+        };
+        let mut user_account = opt_account.unwrap_or_default();
+        let mut nslp_account = self.internal_get_nslp_account();
 
-        //compute proportionals stNEAR/NEAR
-        //1st: stNEAR how much stNEAR from the Liq-Pool represents the ratio: nslp_shares_to_burn relative to total nslp_shares
-        let st_near_to_remove_from_pool = proportional(
-            nslp_account.stake_shares,
-            nslp_shares_to_burn,
-            nslp_account.nslp_shares,
+        let (st_near_to_receive, premium) = self.internal_get_stnear_amount_buy_stnear(amount);
+        assert!(st_near_to_receive > 0, "amount too low");
+        assert!(
+            st_near_to_receive >= min_expected_st_near.0,
+            "Price changed, your min amount {} is not satisfied {}. Try again",
+            min_expected_st_near.0,
+            st_near_to_receive
         );
-        //2nd: NEAR, by difference
-        let near_value_of_st_near = self.amount_from_stake_shares(st_near_to_remove_from_pool);
         assert!(
-            to_remove >= near_value_of_st_near,
-            "inconsistency NTR<STR+UTR"
+            nslp_account.stake_shares >= st_near_to_receive,
+            "Not enough stNEAR in the liquidity pool"
         );
-        let near_to_remove = to_remove - near_value_of_st_near;
-
-        //update user account
-        //remove first from stNEAR in the pool, proportional to shares being burned
-        //NOTE: To simplify user-operations, the LIQ.POOL DO NOT carry "unstaked". The NSLP self-balances only by internal-clearing on `deposit_and_stake`
-        acc.available += near_to_remove;
-        acc.add_st_near(st_near_to_remove_from_pool, &self); //add stnear to user acc
-        acc.nslp_shares -= nslp_shares_to_burn; //shares this user burns
-                                                //update NSLP account
-        nslp_account.available -= near_to_remove;
-        nslp_account.sub_st_near(st_near_to_remove_from_pool, &self); //remove stnear from the pool
-        nslp_account.nslp_shares -= nslp_shares_to_burn; //burn from total nslp shares
-
-        //simplify user-flow
-        //direct transfer to user (instead of leaving it in-contract as "available")
-        let transfer_amount = acc.take_from_available(&account_id, near_to_remove, self);
-        self.native_transfer(&account_id, transfer_amount);
 
-        //--SAVE ACCOUNTS
-        self.internal_update_account(&account_id, &acc);
+        //the NEAR goes to the LP
+        self.contract_account_balance += amount;
+        nslp_account.available += amount;
+        self.total_available += amount;
+        //the stNEAR comes from the LP
+        nslp_account.sub_st_near(st_near_to_receive, &self);
+        user_account.add_st_near(st_near_to_receive, &self);
+
+        //Save accounts
+        self.internal_update_account(&account_id, &user_account);
         self.internal_save_nslp_account(&nslp_account);
 
         event!(
-            r#"{{"event":"REM.L","account_id":"{}","near":"{}","stnear":"{}"}}"#,
-            account_id,
-            transfer_amount,
-            st_near_to_remove_from_pool
+            r#"{{"event":"BUY.ST","account_id":"{}","near":"{}","stnear":"{}","premium":"{}"}}"#,
+            &account_id,
+            amount,
+            st_near_to_receive,
+            premium
         );
 
-        return RemoveLiquidityResult {
-            near: transfer_amount.into(),
-            st_near: st_near_to_remove_from_pool.into(),
+        return BuyStNearResult {
+            st_near: st_near_to_receive.into(),
+            premium: premium.into(),
         };
     }
 
+    /// add liquidity - payable
+    /// returns the % of the pool the account owns, in basis points.
+    /// Takes no minimum, use nslp_add_liquidity_with_min to guard against price changes in the same block
+    #[payable]
+    pub fn nslp_add_liquidity(&mut self) -> u16 {
+        self.assert_operation_not_paused(PausableOperation::NslpAddLiquidity);
+        let account_id = env::predecessor_account_id();
+        let amount = self.internal_deposit(&account_id);
+        return self
+            .internal_nslp_add_liquidity(&account_id, amount, 0)
+            .ownership_bp;
+    }
+
+    /// add liquidity - payable
+    /// fails if the account would receive less than min_shares_expected NSLP shares
+    #[payable]
+    pub fn nslp_add_liquidity_with_min(&mut self, min_shares_expected: U128String) -> AddLiquidityResult {
+        self.assert_operation_not_paused(PausableOperation::NslpAddLiquidity);
+        let account_id = env::predecessor_account_id();
+        let amount = self.internal_deposit(&account_id);
+        return self.internal_nslp_add_liquidity(&account_id, amount, min_shares_expected.0);
+    }
+
+    /// remove liquidity from liquidity pool
+    /// Takes no minimum, use nslp_remove_liquidity_with_min to guard against pool changes in the same block
+    //#[payable]
+    pub fn nslp_remove_liquidity(&mut self, amount: U128String) -> RemoveLiquidityResult {
+        self.assert_operation_not_paused(PausableOperation::NslpRemoveLiquidity);
+        //assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        return self.internal_nslp_remove_liquidity(&account_id, amount.0, 0, 0);
+    }
+
+    /// remove liquidity from liquidity pool
+    /// fails if the account would receive less than min_near_expected NEAR or min_st_near_expected stNEAR
+    pub fn nslp_remove_liquidity_with_min(
+        &mut self,
+        amount: U128String,
+        min_near_expected: U128String,
+        min_st_near_expected: U128String,
+    ) -> RemoveLiquidityResult {
+        self.assert_operation_not_paused(PausableOperation::NslpRemoveLiquidity);
+        let account_id = env::predecessor_account_id();
+        return self.internal_nslp_remove_liquidity(
+            &account_id,
+            amount.0,
+            min_near_expected.0,
+            min_st_near_expected.0,
+        );
+    }
+
     //----------------------------------
     // Use part of the NSLP to stake. This is the inverse operation of nslp_try_internal_clearing
     // can be used by the operator to increase epoch_stake_orders
@@ -863,7 +1154,7 @@ impl MetaPool {
     #[payable]
     pub fn stake_from_nslp(&mut self, near_amount: U128String) {
         assert_one_yocto();
-        self.assert_operator_or_owner();
+        self.assert_role(Role::Keeper);
         // check the amount
         let nslp_account = self.internal_get_nslp_account();
         let amount = near_amount.0;
@@ -897,7 +1188,7 @@ impl MetaPool {
     ///
     #[cfg(target_arch = "wasm32")]
     pub fn upgrade(self) {
-        assert!(env::predecessor_account_id() == self.owner_account_id);
+        self.assert_role(Role::Upgrader);
         //input is code:<Vec<u8> on REGISTER 0
         //log!("bytes.length {}", code.unwrap().len());
         assert!(
@@ -955,3 +1246,264 @@ impl MetaPool {
         }
     }
 }
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const BUYER_ID: &str = "buyer.testnet";
+
+    /// the NSLP holds 100 stNEAR and 1_000 NEAR
+    fn new_contract_with_nslp_st_near() -> MetaPool {
+        let mut contract = new_contract();
+        add_account_with_stake(&mut contract, NSLP_INTERNAL_ACCOUNT, 100 * NEAR);
+        add_nslp_liquidity(&mut contract, 1_000 * NEAR);
+        contract
+    }
+
+    #[test]
+    fn test_buy_stnear_takes_the_stnear_from_the_nslp() {
+        let mut contract = new_contract_with_nslp_st_near();
+        let nslp_value_before = contract.nslp_pool_value(&contract.internal_get_nslp_account());
+        let balance_before = contract.contract_account_balance;
+
+        // a new account pays the storage
+        set_context(BUYER_ID, 10, 10 * NEAR);
+        let amount = 10 * NEAR - STORAGE_COST_YOCTOS;
+        let premium = amount * DEFAULT_NSLP_BUY_PREMIUM_BP as u128 / 10_000;
+        assert_eq!(contract.get_stnear_amount_buy_stnear(amount.into()).0, amount - premium);
+        let result = contract.buy_stnear((amount - premium).into());
+        assert_eq!(result.st_near.0, amount - premium);
+        assert_eq!(result.premium.0, premium);
+
+        assert_eq!(contract.internal_get_account(&BUYER_ID.into()).stake_shares, amount - premium);
+        let nslp_account = contract.internal_get_nslp_account();
+        assert_eq!(nslp_account.stake_shares, 100 * NEAR - (amount - premium));
+        assert_eq!(nslp_account.available, 1_000 * NEAR + amount);
+        assert_eq!(contract.contract_account_balance, balance_before + amount);
+        // the LPs keep the premium
+        assert_eq!(contract.nslp_pool_value(&nslp_account), nslp_value_before + premium);
+        // no stNEAR is minted
+        assert_eq!(contract.total_stake_shares, 100 * NEAR);
+    }
+
+    #[test]
+    fn test_buy_stnear_existing_account_pays_no_storage() {
+        let mut contract = new_contract_with_nslp_st_near();
+        add_account_with_stake(&mut contract, BUYER_ID, 5 * NEAR);
+        set_context(BUYER_ID, 10, 10 * NEAR);
+        let result = contract.buy_stnear(0.into());
+        let premium = 10 * NEAR * DEFAULT_NSLP_BUY_PREMIUM_BP as u128 / 10_000;
+        assert_eq!(result.st_near.0, 10 * NEAR - premium);
+        assert_eq!(
+            contract.internal_get_account(&BUYER_ID.into()).stake_shares,
+            5 * NEAR + 10 * NEAR - premium
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Price changed, your min amount")]
+    fn test_buy_stnear_checks_the_min_expected() {
+        let mut contract = new_contract_with_nslp_st_near();
+        set_context(BUYER_ID, 10, 10 * NEAR);
+        contract.buy_stnear((10 * NEAR).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough stNEAR in the liquidity pool")]
+    fn test_buy_stnear_is_limited_by_the_nslp_stnear() {
+        let mut contract = new_contract_with_nslp_st_near();
+        set_context(BUYER_ID, 10, 200 * NEAR);
+        contract.buy_stnear(0.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "buy_stnear is paused")]
+    fn test_buy_stnear_can_be_paused() {
+        let mut contract = new_contract_with_nslp_st_near();
+        set_context(OWNER_ID, 10, 0);
+        contract.set_operation_paused(PausableOperation::BuyStNear, true);
+        set_context(BUYER_ID, 10, 10 * NEAR);
+        contract.buy_stnear(0.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "a lockup account can not be used here")]
+    fn test_lockup_account_can_not_buy_stnear() {
+        let mut contract = new_contract_with_nslp_st_near();
+        set_context("alice.lockupy.testnet", 10, 10 * NEAR);
+        contract.buy_stnear(0.into());
+    }
+
+    const SELLER_ID: &str = "seller.testnet";
+
+    /// the seller holds 5_000 stNEAR, the NSLP 1_000 NEAR: under its 10_000 NEAR target, the fee is over 160bp
+    fn new_contract_for_smart_unstake() -> MetaPool {
+        let mut contract = new_contract();
+        add_account_with_stake(&mut contract, SELLER_ID, 5_000 * NEAR);
+        add_nslp_liquidity(&mut contract, 1_000 * NEAR);
+        contract
+    }
+
+    #[test]
+    fn test_smart_unstake_all_liquid_when_the_nslp_can_take_it() {
+        let mut contract = new_contract_for_smart_unstake();
+        set_context(SELLER_ID, 10, 0);
+        let quote = contract.quote_liquid_unstake((100 * NEAR).into(), None);
+        let result = contract.smart_unstake((100 * NEAR).into(), 180);
+        assert_eq!(result.liquid_st_near.0, 100 * NEAR);
+        assert_eq!(result.near.0, quote.near_out.0);
+        assert_eq!(result.fee.0, quote.fee_st_near.0);
+        assert_eq!(result.delayed_st_near.0, 0);
+        assert_eq!(result.delayed_near.0, 0);
+        assert!(result.unlock_epoch.is_none());
+        let account = contract.internal_get_account(&SELLER_ID.into());
+        assert_eq!(account.stake_shares, 4_900 * NEAR);
+        assert_eq!(account.unstaked, 0);
+    }
+
+    #[test]
+    fn test_smart_unstake_delays_what_the_nslp_can_not_take() {
+        let mut contract = new_contract_for_smart_unstake();
+        let available = contract.internal_get_nslp_account().available;
+        let balance_before = contract.contract_account_balance;
+        set_context(SELLER_ID, 10, 0);
+        let result = contract.smart_unstake((5_000 * NEAR).into(), 180);
+
+        // the liquid part is the largest the NSLP can pay
+        let liquid = result.liquid_st_near.0;
+        assert!(liquid > 0);
+        assert!(contract.internal_quote_liquid_unstake(available, liquid).near_to_receive <= available);
+        assert!(contract.internal_quote_liquid_unstake(available, liquid + 1).near_to_receive > available);
+        assert_eq!(result.near.0, contract.internal_quote_liquid_unstake(available, liquid).near_to_receive);
+        assert_eq!(contract.internal_get_nslp_account().available, available - result.near.0);
+        assert_eq!(contract.contract_account_balance, balance_before - result.near.0);
+
+        // the rest is delayed
+        assert_eq!(result.delayed_st_near.0, 5_000 * NEAR - liquid);
+        assert_eq!(result.delayed_near.0, result.delayed_st_near.0);
+        assert!(result.unlock_epoch.unwrap().0 > 10);
+        let account = contract.internal_get_account(&SELLER_ID.into());
+        assert_eq!(account.stake_shares, 0);
+        assert_eq!(account.unstaked, result.delayed_near.0);
+        assert_eq!(contract.epoch_unstake_orders, result.delayed_near.0);
+    }
+
+    #[test]
+    fn test_smart_unstake_respects_the_max_fee() {
+        let mut contract = new_contract_for_smart_unstake();
+        let available = contract.internal_get_nslp_account().available;
+        set_context(SELLER_ID, 10, 0);
+        let result = contract.smart_unstake((500 * NEAR).into(), 170);
+        let liquid = result.liquid_st_near.0;
+        assert!(liquid > 0 && liquid < 500 * NEAR);
+        assert!(contract.internal_quote_liquid_unstake(available, liquid).fee_bp <= 170);
+        assert!(contract.internal_quote_liquid_unstake(available, liquid + NEAR).fee_bp > 170);
+        assert_eq!(result.delayed_st_near.0, 500 * NEAR - liquid);
+
+        // a max fee under the current fee delays it all
+        let result = contract.smart_unstake((100 * NEAR).into(), 100);
+        assert_eq!(result.liquid_st_near.0, 0);
+        assert_eq!(result.near.0, 0);
+        assert_eq!(result.delayed_st_near.0, 100 * NEAR);
+    }
+
+    #[test]
+    fn test_smart_unstake_delays_it_all_when_liquid_unstake_is_paused() {
+        let mut contract = new_contract_for_smart_unstake();
+        set_context(OWNER_ID, 10, 0);
+        contract.set_operation_paused(PausableOperation::LiquidUnstake, true);
+        set_context(SELLER_ID, 10, 0);
+        let result = contract.smart_unstake((100 * NEAR).into(), 180);
+        assert_eq!(result.liquid_st_near.0, 0);
+        assert_eq!(result.delayed_st_near.0, 100 * NEAR);
+        assert_eq!(contract.internal_get_nslp_account().available, 1_000 * NEAR);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough stNEAR")]
+    fn test_smart_unstake_more_than_owned() {
+        let mut contract = new_contract_for_smart_unstake();
+        set_context(SELLER_ID, 10, 0);
+        contract.smart_unstake((6_000 * NEAR).into(), 180);
+    }
+
+    #[test]
+    #[should_panic(expected = "a lockup account can not be used here")]
+    fn test_lockup_account_can_not_smart_unstake() {
+        let mut contract = new_contract_for_smart_unstake();
+        add_account_with_stake(&mut contract, "alice.lockupy.testnet", 100 * NEAR);
+        set_context("alice.lockupy.testnet", 10, 0);
+        contract.smart_unstake((100 * NEAR).into(), 180);
+    }
+
+    fn st_near_of(contract: &MetaPool, account_id: &str) -> u128 {
+        contract.accounts.get(&account_id.into()).unwrap_or_default().stake_shares
+    }
+
+    /// quotes selling `st_near`, sells it and compares
+    fn assert_quote_is_what_liquid_unstake_executes(st_near: u128) {
+        let mut contract = new_contract_for_smart_unstake();
+        // rewards: the stNEAR is worth more than 1 NEAR
+        contract.total_for_staking += 500 * NEAR;
+        let treasury_before = st_near_of(&contract, TREASURY_ID);
+        let nslp_before = st_near_of(&contract, NSLP_INTERNAL_ACCOUNT);
+
+        let quote = contract.quote_liquid_unstake(st_near.into(), None);
+        assert!(quote.enough_liquidity);
+        assert_eq!(contract.get_near_amount_sell_stnear(st_near.into()), quote.near_out);
+        assert_eq!(contract.nslp_get_discount_basis_points(st_near.into()), quote.fee_bp);
+
+        set_context(SELLER_ID, 10, 0);
+        let result = contract.liquid_unstake(st_near.into(), quote.near_out);
+        assert_eq!(result.near.0, quote.near_out.0);
+        assert_eq!(result.fee.0, quote.fee_st_near.0);
+        assert_eq!(quote.near_out.0 + quote.fee_near.0, contract.amount_from_stake_shares(st_near));
+        assert_eq!(st_near_of(&contract, TREASURY_ID) - treasury_before, quote.treasury_st_near_cut.0);
+        assert_eq!(st_near_of(&contract, OPERATOR_ID), quote.operator_st_near_cut.0);
+        assert_eq!(st_near_of(&contract, DEVELOPERS_ACCOUNT_ID), quote.developers_st_near_cut.0);
+        assert_eq!(
+            st_near_of(&contract, NSLP_INTERNAL_ACCOUNT) - nslp_before,
+            st_near - (quote.fee_st_near.0 - quote.nslp_st_near_cut.0)
+        );
+        assert_eq!(contract.internal_get_nslp_account().available, quote.nslp_liquidity_after.0);
+    }
+
+    #[test]
+    fn test_quote_small_liquid_unstake() {
+        assert_quote_is_what_liquid_unstake_executes(10 * NEAR);
+    }
+
+    #[test]
+    fn test_quote_large_liquid_unstake() {
+        assert_quote_is_what_liquid_unstake_executes(800 * NEAR);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough liquidity in the liquidity pool")]
+    fn test_quote_flags_missing_liquidity() {
+        let mut contract = new_contract_for_smart_unstake();
+        let quote = contract.quote_liquid_unstake((2_000 * NEAR).into(), None);
+        assert!(!quote.enough_liquidity);
+        assert_eq!(quote.nslp_liquidity_after.0, 0);
+        set_context(SELLER_ID, 10, 0);
+        contract.liquid_unstake((2_000 * NEAR).into(), 0.into());
+    }
+
+    #[test]
+    fn test_quote_max_st_near_under_fee_can_be_sold() {
+        let mut contract = new_contract_for_smart_unstake();
+        let quote = contract.quote_liquid_unstake(NEAR.into(), Some(170));
+        assert_eq!(quote.max_fee_bp, 170);
+        let max = quote.max_st_near_under_fee.0;
+        assert!(max > 0);
+        assert!(contract.quote_liquid_unstake((max + NEAR).into(), None).fee_bp > 170);
+
+        let at_max = contract.quote_liquid_unstake(max.into(), None);
+        assert!(at_max.fee_bp <= 170);
+        set_context(SELLER_ID, 10, 0);
+        let result = contract.liquid_unstake(max.into(), at_max.near_out);
+        assert_eq!(result.near, at_max.near_out);
+    }
+}
@@ -0,0 +1,358 @@
+use crate::*;
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+
+//------------------------------------
+// Lockup registry
+//------------------------------------
+// Lockup accounts use this contract through a lockup-proxy contract, that calls
+// stake_for_lockup, unstake_from_lockup_shares & withdraw_to_lockup on behalf of the lockup account.
+// - trusted proxies: the only callers of those fns. Added by the owner (timelocked), removed by the owner at once.
+//   Each proxy keeps stats of what it moved. A removed proxy keeps its stats.
+// - lockup account suffixes: lockup accounts can not call the liquid fns and always withdraw exact amounts.
+// The registry is seeded at init (and migration) from the network profile name, see network.rs
+
+/// Struct stored per lockup proxy, returned from get_lockup_proxy
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LockupProxyInfo {
+    /// can call the lockup fns
+    pub trusted: bool,
+    /// epoch when it was (last) added
+    pub added_epoch: U64String,
+    /// stNEAR held by lockup accounts, minted through this proxy minus burned through it
+    pub stake_shares: U128String,
+    pub total_staked: U128String,
+    pub total_unstaked: U128String,
+    pub total_withdrawn: U128String,
+}
+
+/// Struct returned from get_lockup_proxies
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LockupProxyJSON {
+    pub account_id: AccountId,
+    #[serde(flatten)]
+    pub info: LockupProxyInfo,
+}
+
+/// lockup proxies and lockup account suffixes of the built-in network profiles
+pub fn default_lockup_registry(network_profile_name: &str) -> (Vec<AccountId>, Vec<String>) {
+    match network_profile_name {
+        "mainnet" => (vec!["lockup-meta-pool.near".into()], vec![".lockup.near".into()]),
+        "testnet" => (vec!["lockup.meta-v2.pool.testnet".into()], vec![".lockupy.testnet".into()]),
+        _ => (vec![], vec![".lockup.test.near".into()]),
+    }
+}
+
+pub(crate) fn assert_lockup_account_suffixes_are_valid(suffixes: &Vec<String>) {
+    for suffix in suffixes.iter() {
+        assert!(
+            suffix.starts_with('.') && suffix.len() > 1,
+            "invalid lockup account suffix {}",
+            suffix
+        );
+    }
+}
+
+impl MetaPool {
+    /// registers the default proxies & suffixes of the network profile
+    pub(crate) fn internal_seed_lockup_registry(&mut self) {
+        let (proxies, suffixes) = default_lockup_registry(&self.network_profile.name);
+        for account_id in proxies.iter() {
+            self.internal_add_lockup_proxy(account_id);
+        }
+        self.lockup_account_suffixes = suffixes;
+    }
+
+    pub(crate) fn internal_add_lockup_proxy(&mut self, account_id: &AccountId) {
+        let mut info = self.lockup_proxies.get(account_id).unwrap_or_default();
+        info.trusted = true;
+        info.added_epoch = env::epoch_height().into();
+        self.lockup_proxies.insert(account_id, &info);
+    }
+
+    pub(crate) fn is_lockup_account(&self, account_id: &AccountId) -> bool {
+        self.lockup_account_suffixes
+            .iter()
+            .any(|suffix| account_id.ends_with(suffix.as_str()))
+    }
+
+    /// returns the calling proxy
+    pub(crate) fn assert_lockup_contract_calling(&self) -> AccountId {
+        let proxy_id = env::predecessor_account_id();
+        assert!(
+            self.lockup_proxies.get(&proxy_id).map_or(false, |info| info.trusted),
+            "the function can only be operated by a trusted lockup proxy"
+        );
+        proxy_id
+    }
+
+    /// assert it is not a lockup account
+    pub(crate) fn assert_not_lockup_account_calling(&self) {
+        assert!(
+            !self.is_lockup_account(&env::predecessor_account_id()),
+            "a lockup account can not be used here"
+        );
+    }
+
+    pub(crate) fn internal_update_lockup_proxy_stats(
+        &mut self,
+        proxy_id: &AccountId,
+        update: impl FnOnce(&mut LockupProxyInfo),
+    ) {
+        let mut info = self.lockup_proxies.get(proxy_id).unwrap_or_default();
+        update(&mut info);
+        self.lockup_proxies.insert(proxy_id, &info);
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// Owner's method. Queues the registration of a trusted lockup proxy
+    pub fn add_lockup_proxy(&mut self, account_id: AccountId) -> u64 {
+        self.assert_owner_calling();
+        self.internal_propose_change(GovernanceAction::AddLockupProxy { account_id })
+    }
+
+    /// Owner's method. The proxy stops being trusted at once, its stats are kept
+    pub fn remove_lockup_proxy(&mut self, account_id: AccountId) {
+        self.assert_owner_calling();
+        let mut info = self.lockup_proxies.get(&account_id).expect("not a lockup proxy");
+        assert!(info.trusted, "lockup proxy already removed");
+        info.trusted = false;
+        self.lockup_proxies.insert(&account_id, &info);
+        event!(r#"{{"event":"REM.LOCKUP.PROXY","account_id":"{}"}}"#, account_id);
+    }
+
+    /// Owner's method. Queues the replacement of the lockup account suffixes
+    pub fn set_lockup_account_suffixes(&mut self, suffixes: Vec<String>) -> u64 {
+        self.assert_owner_calling();
+        self.internal_propose_change(GovernanceAction::SetLockupAccountSuffixes { suffixes })
+    }
+
+    /// registered proxies, including removed ones
+    pub fn get_lockup_proxies(&self, from_index: u64, limit: u64) -> Vec<LockupProxyJSON> {
+        let keys = self.lockup_proxies.keys_as_vector();
+        let values = self.lockup_proxies.values_as_vector();
+        (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| LockupProxyJSON {
+                account_id: keys.get(index).unwrap(),
+                info: values.get(index).unwrap(),
+            })
+            .collect()
+    }
+
+    pub fn get_lockup_proxy(&self, account_id: AccountId) -> Option<LockupProxyInfo> {
+        self.lockup_proxies.get(&account_id)
+    }
+
+    pub fn get_lockup_account_suffixes(&self) -> Vec<String> {
+        self.lockup_account_suffixes.clone()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const PROXY_ID: &str = "proxy.testnet";
+    const LOCKUP_ID: &str = "alice.lockupy.testnet";
+
+    fn new_contract_with_proxy() -> MetaPool {
+        let mut contract = new_contract();
+        set_context(OWNER_ID, 10, 0);
+        let id = contract.add_lockup_proxy(PROXY_ID.into());
+        set_context("anyone.testnet", 10 + contract.governance_delay_epochs, 0);
+        contract.execute_pending_change(id);
+        contract
+    }
+
+    #[test]
+    fn test_profile_seeds_the_registry() {
+        let contract = new_contract();
+        let proxies = contract.get_lockup_proxies(0, 10);
+        assert_eq!(proxies.len(), 1);
+        assert_eq!(proxies[0].account_id, "lockup.meta-v2.pool.testnet");
+        assert!(proxies[0].info.trusted);
+        assert_eq!(contract.get_lockup_account_suffixes(), vec![".lockupy.testnet".to_string()]);
+    }
+
+    #[test]
+    fn test_added_proxy_is_trusted_after_the_delay() {
+        let mut contract = new_contract();
+        set_context(OWNER_ID, 10, 0);
+        let id = contract.add_lockup_proxy(PROXY_ID.into());
+        assert!(contract.get_lockup_proxy(PROXY_ID.into()).is_none());
+
+        set_context("anyone.testnet", 10 + contract.governance_delay_epochs, 0);
+        contract.execute_pending_change(id);
+        let info = contract.get_lockup_proxy(PROXY_ID.into()).unwrap();
+        assert!(info.trusted);
+        assert_eq!(info.added_epoch.0, 10 + contract.governance_delay_epochs);
+
+        set_context(PROXY_ID, 20, 0);
+        assert_eq!(contract.assert_lockup_contract_calling(), PROXY_ID);
+    }
+
+    #[test]
+    #[should_panic(expected = "already a trusted lockup proxy")]
+    fn test_trusted_proxy_can_not_be_added_twice() {
+        let mut contract = new_contract_with_proxy();
+        set_context(OWNER_ID, 20, 0);
+        contract.add_lockup_proxy(PROXY_ID.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "the function can only be operated by a trusted lockup proxy")]
+    fn test_removed_proxy_can_not_call() {
+        let mut contract = new_contract_with_proxy();
+        set_context(OWNER_ID, 20, 0);
+        contract.remove_lockup_proxy(PROXY_ID.into());
+        set_context(PROXY_ID, 20, 10 * NEAR);
+        contract.stake_for_lockup(LOCKUP_ID.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "the function can only be operated by a trusted lockup proxy")]
+    fn test_unknown_caller_can_not_call() {
+        let mut contract = new_contract_with_proxy();
+        set_context("someone.testnet", 20, 10 * NEAR);
+        contract.stake_for_lockup(LOCKUP_ID.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "lockup proxy already removed")]
+    fn test_proxy_can_not_be_removed_twice() {
+        let mut contract = new_contract_with_proxy();
+        set_context(OWNER_ID, 20, 0);
+        contract.remove_lockup_proxy(PROXY_ID.into());
+        contract.remove_lockup_proxy(PROXY_ID.into());
+    }
+
+    #[test]
+    fn test_proxy_stats_follow_its_calls_and_survive_removal() {
+        let mut contract = new_contract_with_proxy();
+        set_context(PROXY_ID, 20, 10 * NEAR);
+        let shares = contract.stake_for_lockup(LOCKUP_ID.into()).0;
+        let staked = 10 * NEAR - STORAGE_COST_YOCTOS;
+        assert_eq!(shares, staked);
+
+        set_context(PROXY_ID, 20, 0);
+        let (nears, _) = contract.unstake_from_lockup_shares(LOCKUP_ID.into(), (shares / 2).into());
+        assert_eq!(nears.0, staked / 2);
+
+        let info = contract.get_lockup_proxy(PROXY_ID.into()).unwrap();
+        assert_eq!(info.stake_shares.0, shares - shares / 2);
+        assert_eq!(info.total_staked.0, staked);
+        assert_eq!(info.total_unstaked.0, nears.0);
+        assert_eq!(info.total_withdrawn.0, 0);
+
+        // the other proxy saw nothing
+        let seeded = contract.get_lockup_proxy("lockup.meta-v2.pool.testnet".into()).unwrap();
+        assert_eq!(seeded.total_staked.0, 0);
+
+        // removing and re-adding it keeps the stats
+        set_context(OWNER_ID, 20, 0);
+        contract.remove_lockup_proxy(PROXY_ID.into());
+        let info = contract.get_lockup_proxy(PROXY_ID.into()).unwrap();
+        assert!(!info.trusted);
+        assert_eq!(info.total_staked.0, staked);
+        let id = contract.add_lockup_proxy(PROXY_ID.into());
+        set_context("anyone.testnet", 20 + contract.governance_delay_epochs, 0);
+        contract.execute_pending_change(id);
+        let info = contract.get_lockup_proxy(PROXY_ID.into()).unwrap();
+        assert!(info.trusted);
+        assert_eq!(info.total_staked.0, staked);
+        assert_eq!(info.total_unstaked.0, nears.0);
+    }
+
+    /// a lockup account with 100 stNEAR and 20_000 NEAR in the NSLP, over its target: the fee is the min, 25bp
+    fn new_contract_for_liquid_unstake() -> MetaPool {
+        let mut contract = new_contract_with_proxy();
+        add_account_with_stake(&mut contract, LOCKUP_ID, 100 * NEAR);
+        add_nslp_liquidity(&mut contract, 20_000 * NEAR);
+        contract
+    }
+
+    #[test]
+    fn test_liquid_unstake_for_lockup_credits_exact_amounts() {
+        let mut contract = new_contract_for_liquid_unstake();
+        let balance_before = contract.contract_account_balance;
+        set_context(PROXY_ID, 20, 0);
+        let near = 10 * NEAR - 10 * NEAR * 25 / 10_000;
+        let result = contract.liquid_unstake_for_lockup(LOCKUP_ID.into(), (10 * NEAR).into(), near.into());
+        assert_eq!(result.near.0, near);
+        assert_eq!(result.fee.0, 10 * NEAR * 25 / 10_000);
+
+        // the NEAR stays in the lockup account, nothing is transferred yet
+        let account = contract.internal_get_account(&LOCKUP_ID.into());
+        assert_eq!(account.available, near);
+        assert_eq!(account.stake_shares, 90 * NEAR);
+        assert_eq!(contract.internal_get_nslp_account().available, 20_000 * NEAR - near);
+        assert_eq!(contract.contract_account_balance, balance_before);
+        let treasury = contract.internal_get_account(&TREASURY_ID.into());
+        assert_eq!(treasury.stake_shares, apply_pct(contract.treasury_swap_cut_basis_points, result.fee.0));
+
+        // withdraw_to_lockup takes the exact amount, even when it is close to the available balance
+        contract.withdraw_to_lockup(LOCKUP_ID.into(), (near - 1).into());
+        assert_eq!(contract.internal_get_account(&LOCKUP_ID.into()).available, 1);
+        assert_eq!(contract.contract_account_balance, balance_before - (near - 1));
+
+        let info = contract.get_lockup_proxy(PROXY_ID.into()).unwrap();
+        assert_eq!(info.total_unstaked.0, near);
+        assert_eq!(info.total_withdrawn.0, near - 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Price changed, your min amount")]
+    fn test_liquid_unstake_for_lockup_checks_the_min_expected() {
+        let mut contract = new_contract_for_liquid_unstake();
+        set_context(PROXY_ID, 20, 0);
+        let near = 10 * NEAR - 10 * NEAR * 25 / 10_000;
+        contract.liquid_unstake_for_lockup(LOCKUP_ID.into(), (10 * NEAR).into(), (near + 1).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "the function can only be operated by a trusted lockup proxy")]
+    fn test_lockup_account_can_not_liquid_unstake_for_itself() {
+        let mut contract = new_contract_for_liquid_unstake();
+        set_context(LOCKUP_ID, 20, 0);
+        contract.liquid_unstake_for_lockup(LOCKUP_ID.into(), (10 * NEAR).into(), 0.into());
+    }
+
+    #[test]
+    fn test_lockup_accounts_are_matched_by_suffix() {
+        let mut contract = new_contract();
+        assert!(contract.is_lockup_account(&LOCKUP_ID.into()));
+        assert!(!contract.is_lockup_account(&"alice.testnet".into()));
+        // the suffix has to end the account id
+        assert!(!contract.is_lockup_account(&"alice.lockupy.testnet.near".into()));
+        assert!(!contract.is_lockup_account(&"lockupy.testnet".into()));
+
+        set_context(OWNER_ID, 10, 0);
+        let id = contract.set_lockup_account_suffixes(vec![".lockup.near".into(), ".lockup.testnet".into()]);
+        set_context("anyone.testnet", 10 + contract.governance_delay_epochs, 0);
+        contract.execute_pending_change(id);
+        assert!(!contract.is_lockup_account(&LOCKUP_ID.into()));
+        assert!(contract.is_lockup_account(&"bob.lockup.near".into()));
+        assert!(contract.is_lockup_account(&"bob.lockup.testnet".into()));
+    }
+
+    #[test]
+    #[should_panic(expected = "a lockup account can not be used here")]
+    fn test_lockup_account_can_not_use_the_liquid_fns() {
+        let mut contract = new_contract();
+        set_context(LOCKUP_ID, 10, 10 * NEAR);
+        contract.deposit_and_stake();
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid lockup account suffix")]
+    fn test_suffix_has_to_start_with_a_dot() {
+        let mut contract = new_contract();
+        set_context(OWNER_ID, 10, 0);
+        contract.set_lockup_account_suffixes(vec!["lockup.near".into()]);
+    }
+}
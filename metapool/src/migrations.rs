@@ -9,6 +9,18 @@ use near_sdk::{env, near_bindgen, AccountId, EpochHeight};
 
 use crate::*;
 
+/// PREVIOUS staking pool info, before busy lock timestamps
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OldStakingPoolInfo {
+    pub account_id: AccountId,
+    pub weight_basis_points: u16,
+    pub busy_lock: bool,
+    pub staked: u128,
+    pub unstaked: u128,
+    pub unstk_req_epoch_height: EpochHeight,
+    pub last_asked_rewards_epoch_height: EpochHeight,
+}
+
 //---------------------------------------------------
 //  PREVIOUS Main Contract State for state migrations
 //---------------------------------------------------
@@ -109,7 +121,7 @@ pub struct OldMetaPool {
     pub accounts: UnorderedMap<AccountId, Account>,
 
     //list of pools to diversify in
-    pub staking_pools: Vec<StakingPoolInfo>,
+    pub staking_pools: Vec<OldStakingPoolInfo>,
 
     // validator loan request
     // action on audit suggestions, this field is not used. No need for this to be on the main contract
@@ -192,8 +204,10 @@ impl MetaPool {
         // uncomment when state migration is required on upgrade
         let old: OldMetaPool = env::state_read().expect("Old state doesn't exist");
 
+        let network_profile = NetworkProfile::for_account(&env::current_account_id());
+
         // can only be called by this same contract (it's called from fn upgrade())
-        if !env::current_account_id().ends_with(".testnet") {
+        if !network_profile.open_migrate {
             assert_eq!(
                 &env::predecessor_account_id(),
                 &env::current_account_id(),
@@ -201,9 +215,12 @@ impl MetaPool {
             );
         }
 
+        // existing pools get id = current index, so callers using indexes keep working
+        let sp_count = old.staking_pools.len() as u16;
+
         // Create the new contract state using the data from the old contract state.
         // returns this struct that gets stored as contract state
-        return Self {
+        let mut new_state = Self {
             owner_account_id: old.owner_account_id,
             contract_busy: false,
             staking_paused: old.staking_paused,
@@ -228,7 +245,30 @@ impl MetaPool {
 
             accounts: old.accounts,
 
-            staking_pools: old.staking_pools,
+            staking_pools: old
+                .staking_pools
+                .into_iter()
+                .enumerate()
+                .map(|(inx, sp)| StakingPoolInfo {
+                    account_id: sp.account_id,
+                    weight_basis_points: sp.weight_basis_points,
+                    busy_lock: sp.busy_lock,
+                    staked: sp.staked,
+                    unstaked: sp.unstaked,
+                    unstk_req_epoch_height: sp.unstk_req_epoch_height,
+                    last_asked_rewards_epoch_height: sp.last_asked_rewards_epoch_height,
+                    // a lock in place during the upgrade becomes clearable after busy_lock_expiry_blocks
+                    busy_lock_since_block: env::block_index(),
+                    busy_lock_since_epoch: env::epoch_height(),
+                    in_flight: 0,
+                    total_loss: 0,
+                    stake_paused: false,
+                    validation: None,
+                    id: inx as u16,
+                    draining: false,
+                    governance_score_bp: DEFAULT_GOVERNANCE_SCORE_BP,
+                })
+                .collect(),
 
             loan_requests: old.loan_requests,
 
@@ -263,6 +303,45 @@ impl MetaPool {
 
             unstaked_for_rebalance: old.unstaked_for_rebalance, 
             unstake_for_rebalance_cap_bp: old.unstake_for_rebalance_cap_bp,
+
+            pause_state: PauseState::default(),
+            roles: UnorderedMap::new(b"R".to_vec()),
+            pending_changes: UnorderedMap::new(b"G".to_vec()),
+            next_pending_change_id: 0,
+            governance_delay_epochs: DEFAULT_GOVERNANCE_DELAY_EPOCHS,
+            contract_busy_since_block: env::block_index(),
+            contract_busy_since_epoch: env::epoch_height(),
+            busy_lock_expiry_blocks: DEFAULT_BUSY_LOCK_EXPIRY_BLOCKS,
+            heartbeat_epoch: 0,
+            heartbeat_stage: HeartbeatStage::Clearing,
+            heartbeat_synced_sp: None,
+            keeper_bounty_config: KeeperBountyConfig::default(),
+            keeper_stats: LookupMap::new(b"K".to_vec()),
+            keeper_bounty_epoch: 0,
+            keeper_bounty_paid_in_epoch: 0,
+            keeper_bounty_paid_pool_steps: LookupMap::new(b"B".to_vec()),
+            sp_loss_history: LookupMap::new(b"S".to_vec()),
+            slash_threshold_bp: 0,
+            staking_pool_whitelist_account_id: network_profile.staking_pool_whitelist_account_id.clone(),
+            max_sp_reward_fee_bp: DEFAULT_MAX_SP_REWARD_FEE_BP,
+            next_sp_id: sp_count,
+            sp_reward_history: LookupMap::new(b"W".to_vec()),
+            weight_strategy_config: WeightStrategyConfig::default(),
+            weight_strategy_applied_epoch: 0,
+            network_profile,
+            lockup_proxies: UnorderedMap::new(b"P".to_vec()),
+            lockup_account_suffixes: Vec::new(),
+            unstake_tickets: LookupMap::new(b"T".to_vec()),
+            unstake_nfts: UnorderedMap::new(b"U".to_vec()),
+            unstake_nfts_per_owner: LookupMap::new(b"O".to_vec()),
+            next_unstake_nft_id: 0,
+            nslp_fee_curve: NslpFeeCurve::default(),
+            nslp_buy_premium_bp: DEFAULT_NSLP_BUY_PREMIUM_BP,
         };
+        new_state.internal_seed_lockup_registry();
+        // keep the operator able to run the heartbeat & pause
+        // fees & weights now require the owner or an explicit role grant
+        new_state.grant_operator_roles();
+        return new_state;
     }
 }
@@ -0,0 +1,161 @@
+use crate::*;
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+
+//------------------------------------
+// Network profiles
+//------------------------------------
+// Values that depend on the chain the contract is deployed to, so the same wasm can be deployed
+// to mainnet, testnet or a local sandbox. The profile is chosen at init (by name, or from the contract account suffix,
+// defaulting to mainnet) and can be replaced by the owner (timelocked, see governance.rs).
+// The sandbox profile and open_migrate are only accepted on sandbox accounts (*.test.near).
+// The developers account is not part of the profile: it's the DEVELOPERS_ACCOUNT_ID constant on every network,
+// so a profile change (or a custom profile) can't redirect the developers fee
+// The profile name also selects the lockup proxies registered at init, see lockup_registry.rs
+
+pub const MAINNET_STAKING_POOL_WHITELIST: &str = "lockup-whitelist.near";
+pub const TESTNET_STAKING_POOL_WHITELIST: &str = "whitelist.f863973.m0";
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NetworkProfile {
+    /// "mainnet", "testnet", "sandbox" or a custom name
+    pub name: String,
+    /// whitelist used by the lockup contracts, set as the staking pool whitelist when the profile is applied
+    pub staking_pool_whitelist_account_id: AccountId,
+    /// epochs a staking pool keeps unstaked funds locked
+    pub num_epochs_to_unlock: EpochHeight,
+    /// migrate() can be called by any account, not only by this contract (local sandbox only)
+    pub open_migrate: bool,
+}
+
+impl NetworkProfile {
+    pub fn mainnet() -> Self {
+        Self {
+            name: "mainnet".into(),
+            staking_pool_whitelist_account_id: MAINNET_STAKING_POOL_WHITELIST.into(),
+            num_epochs_to_unlock: NUM_EPOCHS_TO_UNLOCK,
+            open_migrate: false,
+        }
+    }
+
+    pub fn testnet() -> Self {
+        Self {
+            name: "testnet".into(),
+            staking_pool_whitelist_account_id: TESTNET_STAKING_POOL_WHITELIST.into(),
+            num_epochs_to_unlock: NUM_EPOCHS_TO_UNLOCK,
+            open_migrate: false,
+        }
+    }
+
+    /// local sandbox, accounts are sub-accounts of "test.near"
+    pub fn sandbox() -> Self {
+        Self {
+            name: "sandbox".into(),
+            staking_pool_whitelist_account_id: "whitelist.test.near".into(),
+            num_epochs_to_unlock: NUM_EPOCHS_TO_UNLOCK,
+            open_migrate: true,
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "mainnet" => Some(Self::mainnet()),
+            "testnet" => Some(Self::testnet()),
+            "sandbox" => Some(Self::sandbox()),
+            _ => None,
+        }
+    }
+
+    /// accounts of a local sandbox, the only ones where the sandbox profile is accepted
+    pub fn is_sandbox_account(account_id: &AccountId) -> bool {
+        account_id.ends_with(".test.near")
+    }
+
+    /// profile of the network a contract account belongs to, mainnet if unknown (e.g. implicit accounts)
+    pub fn for_account(account_id: &AccountId) -> Self {
+        if account_id.ends_with(".testnet") {
+            Self::testnet()
+        } else if Self::is_sandbox_account(account_id) {
+            Self::sandbox()
+        } else {
+            Self::mainnet()
+        }
+    }
+
+    /// panics if the profile can't be used by the contract at account_id
+    pub(crate) fn assert_valid(&self, account_id: &AccountId) {
+        assert!(self.name.len() > 0, "name is required");
+        assert!(env::is_valid_account_id(self.staking_pool_whitelist_account_id.as_bytes()));
+        if self.name == "sandbox" || self.open_migrate {
+            assert!(
+                Self::is_sandbox_account(account_id),
+                "the sandbox profile and open_migrate are only allowed on a local sandbox"
+            );
+        }
+        assert!(
+            self.num_epochs_to_unlock <= 2 * NUM_EPOCHS_TO_UNLOCK,
+            "num_epochs_to_unlock must be <= {}",
+            2 * NUM_EPOCHS_TO_UNLOCK
+        );
+    }
+}
+
+impl MetaPool {
+    pub(crate) fn num_epochs_to_unlock(&self) -> EpochHeight {
+        self.network_profile.num_epochs_to_unlock
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    pub fn get_network_profile(&self) -> NetworkProfile {
+        self.network_profile.clone()
+    }
+
+    /// Owner's method. Queues the replacement of the network profile
+    pub fn set_network_profile(&mut self, profile: NetworkProfile) -> u64 {
+        self.assert_owner_calling();
+        self.internal_propose_change(GovernanceAction::SetNetworkProfile { profile })
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn test_unknown_accounts_get_mainnet() {
+        let implicit: AccountId = "a".repeat(64);
+        assert_eq!(NetworkProfile::for_account(&implicit).name, "mainnet");
+        assert_eq!(NetworkProfile::for_account(&"meta-pool.near".into()).name, "mainnet");
+        assert_eq!(NetworkProfile::for_account(&"meta-pool.testnet".into()).name, "testnet");
+        assert_eq!(NetworkProfile::for_account(&"meta-pool.test.near".into()).name, "sandbox");
+        assert!(!NetworkProfile::mainnet().open_migrate && !NetworkProfile::testnet().open_migrate);
+    }
+
+    #[test]
+    #[should_panic(expected = "only allowed on a local sandbox")]
+    fn test_sandbox_profile_refused_outside_a_sandbox() {
+        set_context(OWNER_ID, 10, 0);
+        MetaPool::new(
+            OWNER_ID.into(),
+            TREASURY_ID.into(),
+            OPERATOR_ID.into(),
+            META_TOKEN_ID.into(),
+            Some("sandbox".into()),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "only allowed on a local sandbox")]
+    fn test_open_migrate_refused_outside_a_sandbox() {
+        let mut contract = new_contract();
+        let mut profile = contract.get_network_profile();
+        profile.open_migrate = true;
+        set_context(OWNER_ID, 10, 1);
+        contract.set_network_profile(profile);
+    }
+
+}
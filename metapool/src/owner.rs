@@ -5,6 +5,7 @@ use near_sdk::{near_bindgen, serde::Serialize};
 #[serde(crate = "near_sdk::serde")]
 pub struct GSPRUResultJson {
     sp_inx:u16, 
+    sp_id:u16, 
     extra:U128String, 
     count_unblocked:u16,
     count_with_stake:u16,
@@ -15,18 +16,23 @@ pub struct GSPRUResultJson {
 impl MetaPool {
     // OWNER'S METHODS and other general view-methods
 
-    /// Owner's method.
+    /// Pauser's method.
     /// Pauses pool staking.
+    /// While paused, the heartbeat does not stake, unstake or rebalance (distribute_staking, manual_stake,
+    /// distribute_unstaking, do_rebalance_unstake & rebalance_unstake_sp panic)
+    /// force_rebalance_unstake remains available to pull stake from an offline validator during an incident
     pub fn pause_staking(&mut self) {
-        self.assert_operator_or_owner();
+        self.assert_role(Role::Pauser);
         assert!(!self.staking_paused, "The staking is already paused");
         self.staking_paused = true;
+        self.emit_pause_event("staking", true);
     }
     /// unPauses pool staking.
     pub fn un_pause_staking(&mut self) {
-        self.assert_operator_or_owner();
+        self.assert_role(Role::Pauser);
         assert!(self.staking_paused, "The staking is not paused");
         self.staking_paused = false;
+        self.emit_pause_event("staking", false);
     }
 
     //---------------------------------
@@ -39,81 +45,37 @@ impl MetaPool {
     pub fn get_staking_pool_list(&self) -> Vec<StakingPoolJSONInfo> {
         let mut result = Vec::with_capacity(self.staking_pools.len());
         for inx in 0..self.staking_pools.len() {
-            let elem = &self.staking_pools[inx];
-            result.push(StakingPoolJSONInfo {
-                inx: inx as u16,
-                account_id: elem.account_id.clone(),
-                weight_basis_points: elem.weight_basis_points,
-                staked: elem.staked.into(),
-                unstaked: elem.unstaked.into(),
-                last_asked_rewards_epoch_height: elem.last_asked_rewards_epoch_height.into(),
-                unstaked_requested_epoch_height: elem.unstk_req_epoch_height.into(),
-                busy_lock: elem.busy_lock,
-            })
+            result.push(self.staking_pools[inx].json_info(inx as u16))
         }
         return result;
     }
 
     ///remove staking pool from list *if it's empty*
-    pub fn remove_staking_pool(&mut self, inx: u16) {
-        self.assert_operator_or_owner();
-
-        let sp = &self.staking_pools[inx as usize];
+    ///the indexes of the following pools change, but their ids don't, so it's safe while other pools are busy
+    pub fn remove_staking_pool(&mut self, inx: StakingPoolRef) {
+        self.assert_role(Role::WeightManager);
+
+        let sp_inx = self.sp_inx(&inx);
+        let sp = &self.staking_pools[sp_inx];
+        // the busy lock could have been cleared by hand, the callbacks still have to settle their amounts
+        assert!(sp.in_flight == 0, "sp {} has {} calls in flight", sp.account_id, sp.in_flight);
         if !sp.is_empty() {
             panic!("sp is not empty")
         }
-        self.staking_pools.remove(inx as usize);
+        self.internal_remove_staking_pool(sp_inx);
     }
 
-    /// add a new staking pool, checking that it is not already in the list
-    /// added with weight_basis_points = 0, to preserve sum(weights)=100%
-    pub fn add_staking_pool(&mut self, account_id: AccountId) {
-        self.assert_operator_or_owner();
-        assert!(
-            account_id.ends_with(".poolv1.near") 
-                || account_id.ends_with(".pool.near") 
-                || account_id.ends_with(".pool.f863973.m0") 
-                || account_id.ends_with(".testnet"),
-            "invalid staking-pool contract account {}", account_id);
-        // assert that is not already in the list
-        assert!(
-            self.staking_pools.iter().find(|x| x.account_id==account_id).is_none(),
-            "already in the list"
-        );
-        // not in list, add
-        self.staking_pools
-            .push(StakingPoolInfo::new(account_id, 0));
-    }
+    // add_staking_pool: see sp_whitelist.rs
 
     /// update existing staking pools list, field weight_basis_points
     /// sum(weight_basis_points) must be eq 100%
     /// can not add, remove or change order of staking pools
+    /// the change is queued, see governance.rs. Returns the pending change id
     #[payable]
-    pub fn set_staking_pools(&mut self, list: Vec<StakingPoolArgItem>) {
+    pub fn set_staking_pools(&mut self, list: Vec<StakingPoolArgItem>) -> u64 {
         assert_one_yocto();
-        self.assert_operator_or_owner();
-        // make sure no additions or removals
-        assert_eq!(list.len(),self.staking_pools.len());
-        // process the list
-        let mut total_weight = 0;
-        for sp_inx in 0..list.len() {
-            // assert same order
-            assert_eq!(self.staking_pools[sp_inx].account_id, list[sp_inx].account_id);
-            // get weight_basis_points to set
-            let bp = list[sp_inx].weight_basis_points;
-            // no staking pool can have 50% or more
-            assert!(bp<5000);
-            // if there's a change
-            if self.staking_pools[sp_inx].weight_basis_points != bp {
-                // check pool is not busy
-                assert!(!self.staking_pools[sp_inx].busy_lock,"sp {} is busy",sp_inx);
-                // set new value
-                self.staking_pools[sp_inx].weight_basis_points = bp;
-            }
-            // keep totals
-            total_weight += bp;
-        }
-        assert_eq!(total_weight,10000);
+        self.assert_role(Role::WeightManager);
+        self.internal_propose_change(GovernanceAction::SetStakingPools { list })
     }
 
     //--------------------------------------------------
@@ -129,27 +91,23 @@ impl MetaPool {
     pub fn get_operator_account_id(&self) -> AccountId {
         return self.operator_account_id.clone();
     }
-    pub fn set_operator_account_id(&mut self, account_id: AccountId) {
-        assert!(env::is_valid_account_id(account_id.as_bytes()));
+    /// queued, see governance.rs. Returns the pending change id
+    pub fn set_operator_account_id(&mut self, account_id: AccountId) -> u64 {
         self.assert_owner_calling();
-        self.operator_account_id = account_id;
-        //all key accounts must be different
-        self.assert_key_accounts_are_different();
+        self.internal_propose_change(GovernanceAction::SetOperatorAccountId { account_id })
     }
     pub fn get_treasury_account_id(&self) -> AccountId {
         return self.treasury_account_id.clone();
     }
-    pub fn set_treasury_account_id(&mut self, account_id: AccountId) {
-        assert!(env::is_valid_account_id(account_id.as_bytes()));
+    /// queued, see governance.rs. Returns the pending change id
+    pub fn set_treasury_account_id(&mut self, account_id: AccountId) -> u64 {
         self.assert_owner_calling();
-        self.treasury_account_id = account_id;
-        self.assert_key_accounts_are_different();
+        self.internal_propose_change(GovernanceAction::SetTreasuryAccountId { account_id })
     }
-    pub fn set_owner_id(&mut self, owner_id: AccountId) {
-        assert!(env::is_valid_account_id(owner_id.as_bytes()));
+    /// queued, see governance.rs. Returns the pending change id
+    pub fn set_owner_id(&mut self, owner_id: AccountId) -> u64 {
         self.assert_owner_calling();
-        self.owner_account_id = owner_id.into();
-        self.assert_key_accounts_are_different();
+        self.internal_propose_change(GovernanceAction::SetOwnerId { owner_id })
     }
 
     /// The amount of tokens that were deposited to the staking pool.
@@ -294,6 +252,12 @@ impl MetaPool {
             max_meta_rewards_lu: self.max_meta_rewards_lu.into(), //liquid-unstakers
             max_meta_rewards_lp: self.max_meta_rewards_lp.into(), //liquidity-providers
             unstaked_for_rebalance: self.unstaked_for_rebalance.into(), //floating for rebalance
+            contract_busy: self.contract_busy,
+            contract_busy_since_block: self.contract_busy_since_block.into(),
+            contract_busy_since_epoch: self.contract_busy_since_epoch.into(),
+            contract_busy_age_blocks: self.contract_busy_age_blocks().into(),
+            busy_lock_expiry_blocks: self.busy_lock_expiry_blocks.into(),
+            busy_pools: self.staking_pools.iter().filter(|sp| sp.is_busy()).count() as u16,
         };
     }
 
@@ -318,24 +282,20 @@ impl MetaPool {
     }
 
     /// Sets contract parameters
-    pub fn set_contract_params(&mut self, params: ContractParamsJSON) {
-        self.assert_operator_or_owner();
-        assert!(params.nslp_max_discount_basis_points > params.nslp_min_discount_basis_points);
-
-        self.nslp_liquidity_target = params.nslp_liquidity_target.0;
-        self.nslp_max_discount_basis_points = params.nslp_max_discount_basis_points;
-        self.nslp_min_discount_basis_points = params.nslp_min_discount_basis_points;
+    /// the change is queued, see governance.rs. Returns the pending change id
+    pub fn set_contract_params(&mut self, params: ContractParamsJSON) -> u64 {
+        self.assert_role(Role::FeeManager);
+        self.internal_propose_change(GovernanceAction::SetContractParams { params })
+    }
 
-        self.staker_meta_mult_pct = params.staker_meta_mult_pct;
-        self.stnear_sell_meta_mult_pct = params.stnear_sell_meta_mult_pct;
-        self.lp_provider_meta_mult_pct = params.lp_provider_meta_mult_pct;
-        self.operator_rewards_fee_basis_points = params.operator_rewards_fee_basis_points;
-        self.operator_swap_cut_basis_points = params.operator_swap_cut_basis_points;
-        self.treasury_swap_cut_basis_points = params.treasury_swap_cut_basis_points;
+    /// Fee manager's method. Queues a change of the premium paid by buy_stnear
+    pub fn set_nslp_buy_premium(&mut self, basis_points: u16) -> u64 {
+        self.assert_role(Role::FeeManager);
+        self.internal_propose_change(GovernanceAction::SetNslpBuyPremium { basis_points })
+    }
 
-        self.min_deposit_amount = params.min_deposit_amount.0;
-        assert!(params.unstake_for_rebalance_cap_bp<2000); // hard coded limit, no more than 20%
-        self.unstake_for_rebalance_cap_bp = params.unstake_for_rebalance_cap_bp;
+    pub fn get_nslp_buy_premium(&self) -> u16 {
+        self.nslp_buy_premium_bp
     }
 
     /// Sets contract parameters
@@ -345,7 +305,7 @@ impl MetaPool {
         lp_pct: u16,
         liquid_unstake_pct: u16,
     ) {
-        self.assert_operator_or_owner();
+        self.assert_role(Role::FeeManager);
         self.staker_meta_mult_pct = stakers_pct;
         self.stnear_sell_meta_mult_pct = liquid_unstake_pct;
         self.lp_provider_meta_mult_pct = lp_pct;
@@ -353,7 +313,7 @@ impl MetaPool {
 
     /// Sets contract parameters
     pub fn set_max_meta_rewards(&mut self, stakers: u32, lu: u32, lp: u32) {
-        self.assert_operator_or_owner();
+        self.assert_role(Role::FeeManager);
         self.max_meta_rewards_stakers = stakers as u128 * ONE_NEAR; //stakers
         self.max_meta_rewards_lu = lu as u128 * ONE_NEAR; //liquid-unstakers
         self.max_meta_rewards_lp = lp as u128 * ONE_NEAR; //liquidity-providers
@@ -361,20 +321,9 @@ impl MetaPool {
 
     /// get sp (staking-pool) info
     /// Returns JSON representation of sp recorded state
-    pub fn get_sp_info(&self, inx: u16) -> StakingPoolJSONInfo {
-        assert!((inx as usize) < self.staking_pools.len());
-        let sp = &self.staking_pools[inx as usize];
-
-        return StakingPoolJSONInfo {
-            inx,
-            account_id: sp.account_id.clone(),
-            weight_basis_points: sp.weight_basis_points.clone(),
-            staked: sp.staked.into(),
-            unstaked: sp.unstaked.into(),
-            unstaked_requested_epoch_height: sp.unstk_req_epoch_height.into(),
-            last_asked_rewards_epoch_height: sp.last_asked_rewards_epoch_height.into(),
-            busy_lock: sp.busy_lock,
-        };
+    pub fn get_sp_info(&self, inx: StakingPoolRef) -> StakingPoolJSONInfo {
+        let sp_inx = self.sp_inx(&inx);
+        return self.staking_pools[sp_inx].json_info(sp_inx as u16);
     }
 
     pub fn get_staking_pool_requiring_unstake(self) -> GSPRUResultJson 
@@ -382,6 +331,7 @@ impl MetaPool {
         let gspru = self.internal_get_staking_pool_requiring_unstake();
         GSPRUResultJson {
             sp_inx: gspru.sp_inx, 
+            sp_id: self.staking_pools[gspru.sp_inx as usize].id, 
             extra: gspru.extra.into(),
             count_unblocked: gspru.count_unblocked,
             count_with_stake: gspru.count_with_stake,
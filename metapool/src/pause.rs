@@ -0,0 +1,168 @@
+use crate::*;
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+
+//------------------------------------
+// Emergency brakes. Per-operation pause switches
+//------------------------------------
+
+/// operations that can be paused independently by an account with the Pauser role
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum PausableOperation {
+    /// deposit_and_stake & stake_for_lockup
+    DepositAndStake,
+    /// liquid_unstake
+    LiquidUnstake,
+    /// buy_stnear
+    BuyStNear,
+    /// unstake, unstake_all & unstake_from_lockup_shares
+    DelayedUnstake,
+    NslpAddLiquidity,
+    NslpRemoveLiquidity,
+    /// ft_transfer & ft_transfer_call
+    FtTransfer,
+    /// distribute_staking & manual_stake
+    DistributeStaking,
+    /// distribute_unstaking & the rebalance-unstake fns
+    DistributeUnstaking,
+    /// distribute_rewards
+    DistributeRewards,
+    /// sync_unstaked_balance & retrieve_funds_from_a_pool
+    RetrieveFunds,
+}
+
+impl PausableOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PausableOperation::DepositAndStake => "deposit_and_stake",
+            PausableOperation::LiquidUnstake => "liquid_unstake",
+            PausableOperation::BuyStNear => "buy_stnear",
+            PausableOperation::DelayedUnstake => "delayed_unstake",
+            PausableOperation::NslpAddLiquidity => "nslp_add_liquidity",
+            PausableOperation::NslpRemoveLiquidity => "nslp_remove_liquidity",
+            PausableOperation::FtTransfer => "ft_transfer",
+            PausableOperation::DistributeStaking => "distribute_staking",
+            PausableOperation::DistributeUnstaking => "distribute_unstaking",
+            PausableOperation::DistributeRewards => "distribute_rewards",
+            PausableOperation::RetrieveFunds => "retrieve_funds",
+        }
+    }
+}
+
+/// one switch per pausable operation, all false (running) by default
+/// Note: user withdrawals of already unstaked funds can not be paused
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Default, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PauseState {
+    pub deposit_and_stake: bool,
+    pub liquid_unstake: bool,
+    pub buy_stnear: bool,
+    pub delayed_unstake: bool,
+    pub nslp_add_liquidity: bool,
+    pub nslp_remove_liquidity: bool,
+    pub ft_transfer: bool,
+    pub distribute_staking: bool,
+    pub distribute_unstaking: bool,
+    pub distribute_rewards: bool,
+    pub retrieve_funds: bool,
+}
+
+impl PauseState {
+    pub fn is_paused(&self, operation: PausableOperation) -> bool {
+        *self.switch(operation)
+    }
+
+    fn switch(&self, operation: PausableOperation) -> &bool {
+        match operation {
+            PausableOperation::DepositAndStake => &self.deposit_and_stake,
+            PausableOperation::LiquidUnstake => &self.liquid_unstake,
+            PausableOperation::BuyStNear => &self.buy_stnear,
+            PausableOperation::DelayedUnstake => &self.delayed_unstake,
+            PausableOperation::NslpAddLiquidity => &self.nslp_add_liquidity,
+            PausableOperation::NslpRemoveLiquidity => &self.nslp_remove_liquidity,
+            PausableOperation::FtTransfer => &self.ft_transfer,
+            PausableOperation::DistributeStaking => &self.distribute_staking,
+            PausableOperation::DistributeUnstaking => &self.distribute_unstaking,
+            PausableOperation::DistributeRewards => &self.distribute_rewards,
+            PausableOperation::RetrieveFunds => &self.retrieve_funds,
+        }
+    }
+
+    fn switch_mut(&mut self, operation: PausableOperation) -> &mut bool {
+        match operation {
+            PausableOperation::DepositAndStake => &mut self.deposit_and_stake,
+            PausableOperation::LiquidUnstake => &mut self.liquid_unstake,
+            PausableOperation::BuyStNear => &mut self.buy_stnear,
+            PausableOperation::DelayedUnstake => &mut self.delayed_unstake,
+            PausableOperation::NslpAddLiquidity => &mut self.nslp_add_liquidity,
+            PausableOperation::NslpRemoveLiquidity => &mut self.nslp_remove_liquidity,
+            PausableOperation::FtTransfer => &mut self.ft_transfer,
+            PausableOperation::DistributeStaking => &mut self.distribute_staking,
+            PausableOperation::DistributeUnstaking => &mut self.distribute_unstaking,
+            PausableOperation::DistributeRewards => &mut self.distribute_rewards,
+            PausableOperation::RetrieveFunds => &mut self.retrieve_funds,
+        }
+    }
+}
+
+/// Struct returned from get_pause_state
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PauseStateJSON {
+    /// global brake: no staking, unstaking or rebalancing with the pools
+    pub staking_paused: bool,
+    #[serde(flatten)]
+    pub operations: PauseState,
+}
+
+impl MetaPool {
+    pub(crate) fn assert_operation_not_paused(&self, operation: PausableOperation) {
+        assert!(
+            !self.pause_state.is_paused(operation),
+            "{} is paused",
+            operation.as_str()
+        );
+    }
+
+    /// heartbeat fns moving funds to/from the pools must respect pause_staking
+    pub(crate) fn assert_staking_not_paused(&self) {
+        assert!(!self.staking_paused, "staking is paused");
+    }
+
+    pub(crate) fn emit_pause_event(&self, operation: &str, paused: bool) {
+        event!(
+            r#"{{"event":"PAUSE","operation":"{}","paused":{},"by":"{}"}}"#,
+            operation,
+            paused,
+            env::predecessor_account_id()
+        );
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// Pauser's method.
+    /// Pauses or un-pauses a single operation
+    pub fn set_operation_paused(&mut self, operation: PausableOperation, paused: bool) {
+        self.assert_role(Role::Pauser);
+        let switch = self.pause_state.switch_mut(operation);
+        assert!(
+            *switch != paused,
+            "{} paused is already {}",
+            operation.as_str(),
+            paused
+        );
+        *switch = paused;
+        self.emit_pause_event(operation.as_str(), paused);
+    }
+
+    /// Returns the global staking brake and all per-operation pause switches
+    pub fn get_pause_state(&self) -> PauseStateJSON {
+        PauseStateJSON {
+            staking_paused: self.staking_paused,
+            operations: self.pause_state.clone(),
+        }
+    }
+}
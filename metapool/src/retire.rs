@@ -0,0 +1,188 @@
+use crate::*;
+use near_sdk::near_bindgen;
+
+//------------------------------------
+// Staking pool retirement
+//------------------------------------
+// retire_staking_pool marks a pool as draining: its weight is spread across the other pools and no new stake goes there.
+// The heartbeat (Retire stage) then unstakes all its stake, the SyncAndRetrieve stage retrieves it after the
+// unstaking delay, and once the pool is empty the entry is removed.
+// The staking pool rounds shares, so a few yoctos (<= UNSTAKED_YOCTOS_TO_IGNORE) can stay staked after the sync.
+// They can't be unstaked ("shares should be positive"), so the pool counts as drained and they are written off on removal.
+// The retrieved funds are accounted as unstaked-for-rebalance, so they're restaked into the other pools.
+
+impl MetaPool {
+    /// removes the pool at sp_inx, the caller checked it is empty (or drained)
+    pub(crate) fn internal_remove_staking_pool(&mut self, sp_inx: usize) {
+        let sp = self.staking_pools.remove(sp_inx);
+        if sp.staked > 0 {
+            // rounding yoctos left in a drained pool, written off
+            self.total_actually_staked = self.total_actually_staked.saturating_sub(sp.staked);
+            self.total_for_staking = self.total_for_staking.saturating_sub(sp.staked);
+        }
+        if self.heartbeat_synced_sp == Some(sp.id) {
+            self.heartbeat_synced_sp = None;
+        }
+        self.keeper_bounty_paid_pool_steps.remove(&sp.id);
+        event!(r#"{{"event":"REM.SP","sp":"{}","id":{}}}"#, sp.account_id, sp.id);
+    }
+
+    /// a draining pool that can be unstaked now
+    /// (unstaking again while a previous unstake is waiting would extend the waiting period)
+    pub(crate) fn sp_ready_to_drain(&self, sp: &StakingPoolInfo) -> bool {
+        sp.draining
+            && !sp.is_busy()
+            && sp.staked > UNSTAKED_YOCTOS_TO_IGNORE
+            && (sp.unstaked == 0 || sp.unstk_req_epoch_height == env::epoch_height())
+    }
+
+    /// first draining pool with nothing left in it
+    pub(crate) fn get_retired_pool_to_remove(&self) -> Option<u16> {
+        self.staking_pools
+            .iter()
+            .find(|sp| sp.draining && sp.is_drained())
+            .map(|sp| sp.id)
+    }
+
+    /// first draining pool ready to be unstaked
+    pub(crate) fn get_retiring_pool_to_unstake(&self) -> Option<u16> {
+        self.staking_pools
+            .iter()
+            .find(|sp| self.sp_ready_to_drain(sp))
+            .map(|sp| sp.id)
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// Weight manager's method.
+    /// Starts the retirement of a pool: its weight is spread across the other pools, staking into it stops,
+    /// and the heartbeat unstakes, retrieves and finally removes it
+    pub fn retire_staking_pool(&mut self, account_id: AccountId) {
+        self.assert_role(Role::WeightManager);
+        let sp_inx = self.sp_inx(&StakingPoolRef::AccountId(account_id));
+        let sp = &mut self.staking_pools[sp_inx];
+        assert!(!sp.draining, "sp {} is already draining", sp.account_id);
+        sp.draining = true;
+        sp.stake_paused = true;
+        let weight = sp.weight_basis_points;
+        self.internal_redistribute_weight(sp_inx);
+        let sp = &self.staking_pools[sp_inx];
+        event!(
+            r#"{{"event":"RETIRE.SP","sp":"{}","id":{},"weight_bp":{},"staked":"{}","unstaked":"{}"}}"#,
+            sp.account_id,
+            sp.id,
+            weight,
+            sp.staked,
+            sp.unstaked
+        );
+    }
+
+    /// Operator method, but open to anyone
+    /// unstakes all the stake of a draining pool (see retire_staking_pool)
+    /// the amount is considered unstaked for rebalance: once retrieved, it's restaked in the other pools
+    pub fn unstake_retiring_pool(&mut self, sp: StakingPoolRef) {
+        self.assert_not_busy();
+        self.assert_staking_not_paused();
+        self.assert_operation_not_paused(PausableOperation::DistributeUnstaking);
+        let sp_inx = self.sp_inx(&sp);
+        let sp = &self.staking_pools[sp_inx];
+        assert!(sp.draining, "sp {} is not draining", sp.account_id);
+        assert!(
+            self.sp_ready_to_drain(sp),
+            "sp {} can not be unstaked now. busy:{} staked:{} unstaked:{} unstk_req_epoch_height:{}",
+            sp.account_id,
+            sp.is_busy(),
+            sp.staked,
+            sp.unstaked,
+            sp.unstk_req_epoch_height
+        );
+        let amount = sp.staked;
+        let sp_id = sp.id;
+        self.internal_launch_unstake(sp_inx, 0, amount)
+            .then(ext_self_owner::on_staking_pool_unstake(
+                sp_id,
+                0.into(),
+                amount.into(),
+                //extra async call args
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                gas::owner_callbacks::ON_STAKING_POOL_UNSTAKE,
+            ));
+    }
+
+    /// Operator method, but open to anyone
+    /// removes a draining pool once all its funds were retrieved
+    pub fn remove_retired_pool(&mut self, sp: StakingPoolRef) {
+        let sp_inx = self.sp_inx(&sp);
+        let sp = &self.staking_pools[sp_inx];
+        assert!(sp.draining, "sp {} is not draining", sp.account_id);
+        assert!(sp.is_drained(), "sp {} is not empty", sp.account_id);
+        self.internal_remove_staking_pool(sp_inx);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::PromiseResult;
+
+    const EPOCH: EpochHeight = 10;
+
+    fn new_contract_with_retiring_pool(staked: u128) -> (MetaPool, u16) {
+        let mut contract = new_contract();
+        let sp_id = add_staked_pool(&mut contract, "retiring.testnet", 0, staked);
+        add_pool(&mut contract, "other.testnet", 10000);
+        let sp = &mut contract.staking_pools[0];
+        sp.draining = true;
+        sp.stake_paused = true;
+        contract.heartbeat_epoch = EPOCH;
+        contract.heartbeat_stage = HeartbeatStage::Retire;
+        (contract, sp_id)
+    }
+
+    #[test]
+    fn test_rounding_yoctos_count_as_drained() {
+        let (mut contract, sp_id) = new_contract_with_retiring_pool(7);
+        set_context("keeper.testnet", EPOCH, 0);
+        assert_eq!(
+            contract.get_next_heartbeat_action().action,
+            HeartbeatAction::RemoveRetiredPool { sp_id }
+        );
+        contract.heartbeat();
+        assert_eq!(contract.staking_pools.len(), 1);
+        assert_eq!(contract.total_actually_staked, 0);
+        assert_eq!(contract.total_for_staking, 0);
+    }
+
+    #[test]
+    fn test_failed_unstake_does_not_loop() {
+        let (mut contract, sp_id) = new_contract_with_retiring_pool(100 * NEAR);
+        set_context("keeper.testnet", EPOCH, 0);
+        assert_eq!(contract.heartbeat(), HeartbeatAction::UnstakeRetiringPool { sp_id });
+        assert_eq!(contract.heartbeat_stage, HeartbeatStage::Staking);
+
+        set_callback_context(EPOCH, PromiseResult::Failed);
+        contract.on_staking_pool_unstake(sp_id, 0.into(), (100 * NEAR).into());
+        assert_eq!(contract.staking_pools[0].staked, 100 * NEAR);
+        set_context("keeper.testnet", EPOCH, 0);
+        assert_eq!(contract.get_next_heartbeat_action().action, HeartbeatAction::Done);
+        // retried in the next epoch
+        set_context("keeper.testnet", EPOCH + 1, 0);
+        contract.staking_pools[0].last_asked_rewards_epoch_height = EPOCH + 1;
+        contract.staking_pools[1].last_asked_rewards_epoch_height = EPOCH + 1;
+        assert_eq!(
+            contract.get_next_heartbeat_action().action,
+            HeartbeatAction::UnstakeRetiringPool { sp_id }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "is not empty")]
+    fn test_pool_with_stake_is_not_removed() {
+        let (mut contract, sp_id) = new_contract_with_retiring_pool(UNSTAKED_YOCTOS_TO_IGNORE + 1);
+        set_context("anyone.testnet", EPOCH, 0);
+        contract.remove_retired_pool(StakingPoolRef::Id(sp_id));
+    }
+}
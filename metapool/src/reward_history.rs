@@ -0,0 +1,258 @@
+use crate::*;
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+
+//------------------------------------
+// Per-pool reward history
+//------------------------------------
+// Each time distribute_rewards settles, the pool's figures are kept in a bounded ring buffer,
+// so pool performance can be read from the contract instead of re-derived from logs.
+// A record is also added when there were no rewards (or there was a loss): missing rewards are part of the performance.
+
+/// records kept per pool, ~32 days with 12h epochs
+pub const REWARD_HISTORY_LEN: usize = 64;
+/// ~12h epochs
+pub const EPOCHS_PER_YEAR: u128 = 730;
+/// fixed point scale of the per-epoch reward rate used to compute APY
+const RATE_SCALE: u128 = 1_000_000_000_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RewardRecord {
+    /// epoch when the rewards were computed
+    pub epoch: U64String,
+    /// previous epoch when rewards were computed, the rewards cover (from_epoch..epoch]
+    pub from_epoch: U64String,
+    /// pool staked balance before the rewards
+    pub staked: U128String,
+    pub rewards: U128String,
+    /// operator + developers fee taken from the rewards
+    pub fee: U128String,
+}
+
+impl RewardRecord {
+    /// epochs covered by this record, at least 1
+    pub fn epochs(&self) -> u64 {
+        if self.from_epoch.0 == 0 || self.from_epoch.0 >= self.epoch.0 {
+            1
+        } else {
+            self.epoch.0 - self.from_epoch.0
+        }
+    }
+}
+
+/// ring buffer of the last REWARD_HISTORY_LEN records
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct RewardHistory {
+    pub records: Vec<RewardRecord>,
+    /// when full, position of the oldest record (the next to overwrite)
+    pub head: u16,
+}
+
+impl RewardHistory {
+    pub fn push(&mut self, record: RewardRecord) {
+        if self.records.len() < REWARD_HISTORY_LEN {
+            self.records.push(record);
+        } else {
+            self.records[self.head as usize] = record;
+            self.head = ((self.head as usize + 1) % REWARD_HISTORY_LEN) as u16;
+        }
+    }
+
+    /// records oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &RewardRecord> {
+        let (newest, oldest) = self.records.split_at(self.head as usize);
+        oldest.iter().chain(newest.iter())
+    }
+}
+
+/// Struct returned from get_sp_apy
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SpApyJSON {
+    pub account_id: AccountId,
+    /// annualized reward rate of the pool, before our fee, in basis points
+    pub apy_bp: u32,
+    /// epochs covered by the records used
+    pub epochs: U64String,
+    /// records used
+    pub records: u16,
+    /// records without rewards
+    pub records_without_rewards: u16,
+    /// epochs covered by records with rewards
+    pub epochs_with_rewards: U64String,
+    pub total_rewards: U128String,
+}
+
+impl MetaPool {
+    pub(crate) fn internal_sp_apy(&self, account_id: AccountId, window: u16) -> SpApyJSON {
+        let history = self.sp_reward_history.get(&account_id).unwrap_or_default();
+        let from_epoch = env::epoch_height().saturating_sub(window as u64);
+
+        let mut rate_sum: u128 = 0;
+        let mut epochs: u64 = 0;
+        let mut epochs_with_rewards: u64 = 0;
+        let mut records: u16 = 0;
+        let mut records_without_rewards: u16 = 0;
+        let mut total_rewards: u128 = 0;
+        for record in history.iter().filter(|r| r.epoch.0 > from_epoch) {
+            records += 1;
+            epochs += record.epochs();
+            total_rewards += record.rewards.0;
+            if record.rewards.0 == 0 {
+                records_without_rewards += 1;
+            } else {
+                epochs_with_rewards += record.epochs();
+            }
+            if record.staked.0 > 0 {
+                rate_sum += proportional(RATE_SCALE, record.rewards.0, record.staked.0);
+            }
+        }
+        let apy_bp = if epochs == 0 {
+            0
+        } else {
+            rate_sum * EPOCHS_PER_YEAR * 10_000 / RATE_SCALE / epochs as u128
+        };
+        SpApyJSON {
+            account_id,
+            apy_bp: std::cmp::min(apy_bp, u32::MAX as u128) as u32,
+            epochs: epochs.into(),
+            records,
+            records_without_rewards,
+            epochs_with_rewards: epochs_with_rewards.into(),
+            total_rewards: total_rewards.into(),
+        }
+    }
+
+    /// this fn is called from callbacks, it SHOULD NOT PANIC
+    pub(crate) fn internal_record_rewards(
+        &mut self,
+        sp_inx: usize,
+        from_epoch: EpochHeight,
+        staked: u128,
+        rewards: u128,
+        fee: u128,
+    ) {
+        let account_id = self.staking_pools[sp_inx].account_id.clone();
+        let mut history = self.sp_reward_history.get(&account_id).unwrap_or_default();
+        history.push(RewardRecord {
+            epoch: env::epoch_height().into(),
+            from_epoch: from_epoch.into(),
+            staked: staked.into(),
+            rewards: rewards.into(),
+            fee: fee.into(),
+        });
+        self.sp_reward_history.insert(&account_id, &history);
+    }
+
+    /// account id of a pool. An account id is accepted even if the pool was removed
+    pub(crate) fn sp_account_id(&self, sp: &StakingPoolRef) -> AccountId {
+        match sp {
+            StakingPoolRef::AccountId(account_id) => account_id.clone(),
+            StakingPoolRef::Id(_) => self.staking_pools[self.sp_inx(sp)].account_id.clone(),
+        }
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// reward records of a pool with epoch >= from_epoch, oldest first
+    pub fn get_sp_reward_history(&self, pool: StakingPoolRef, from_epoch: U64String, limit: u16) -> Vec<RewardRecord> {
+        let account_id = self.sp_account_id(&pool);
+        self.sp_reward_history
+            .get(&account_id)
+            .unwrap_or_default()
+            .iter()
+            .filter(|r| r.epoch.0 >= from_epoch.0)
+            .take(limit as usize)
+            .cloned()
+            .collect()
+    }
+
+    /// annualized reward rate of a pool, from the records of the last `window` epochs
+    /// (simple, not compounded: average rate per epoch * EPOCHS_PER_YEAR)
+    pub fn get_sp_apy(&self, pool: StakingPoolRef, window: u16) -> SpApyJSON {
+        self.internal_sp_apy(self.sp_account_id(&pool), window)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn record(epoch: u64, staked: u128, rewards: u128) -> RewardRecord {
+        RewardRecord {
+            epoch: epoch.into(),
+            from_epoch: (epoch - 1).into(),
+            staked: staked.into(),
+            rewards: rewards.into(),
+            fee: 0.into(),
+        }
+    }
+
+    fn epochs(history: &RewardHistory) -> Vec<u64> {
+        history.iter().map(|r| r.epoch.0).collect()
+    }
+
+    #[test]
+    fn test_ring_buffer_keeps_the_last_records_in_order() {
+        let mut history = RewardHistory::default();
+        for epoch in 1..=REWARD_HISTORY_LEN as u64 {
+            history.push(record(epoch, 1, 0));
+        }
+        assert_eq!(history.head, 0);
+        assert_eq!(epochs(&history), (1..=REWARD_HISTORY_LEN as u64).collect::<Vec<u64>>());
+
+        // full, each push overwrites the oldest
+        for epoch in REWARD_HISTORY_LEN as u64 + 1..=REWARD_HISTORY_LEN as u64 + 10 {
+            history.push(record(epoch, 1, 0));
+        }
+        assert_eq!(history.records.len(), REWARD_HISTORY_LEN);
+        assert_eq!(history.head, 10);
+        assert_eq!(epochs(&history), (11..=REWARD_HISTORY_LEN as u64 + 10).collect::<Vec<u64>>());
+
+        // head wraps around
+        for epoch in REWARD_HISTORY_LEN as u64 + 11..=2 * REWARD_HISTORY_LEN as u64 {
+            history.push(record(epoch, 1, 0));
+        }
+        assert_eq!(history.head, 0);
+        assert_eq!(
+            epochs(&history),
+            (REWARD_HISTORY_LEN as u64 + 1..=2 * REWARD_HISTORY_LEN as u64).collect::<Vec<u64>>()
+        );
+    }
+
+    #[test]
+    fn test_record_epochs() {
+        assert_eq!(record(10, 1, 0).epochs(), 1);
+        let mut gap = record(10, 1, 0);
+        gap.from_epoch = 7.into();
+        assert_eq!(gap.epochs(), 3);
+        // first record of a pool
+        gap.from_epoch = 0.into();
+        assert_eq!(gap.epochs(), 1);
+    }
+
+    #[test]
+    fn test_history_view_and_apy() {
+        let mut contract = new_contract();
+        add_pool(&mut contract, "pool.testnet", 10000);
+        for epoch in 1..=20 {
+            set_context(OWNER_ID, epoch, 0);
+            // 0.01% per epoch, one epoch without rewards
+            let rewards = if epoch == 20 { 0 } else { 100_000 * NEAR / 10_000 };
+            contract.internal_record_rewards(0, epoch - 1, 100_000 * NEAR, rewards, 0);
+        }
+        let history = contract.get_sp_reward_history(StakingPoolRef::Id(0), 15.into(), 3);
+        assert_eq!(history.iter().map(|r| r.epoch.0).collect::<Vec<u64>>(), vec![15, 16, 17]);
+
+        let apy = contract.get_sp_apy(StakingPoolRef::AccountId("pool.testnet".into()), 10);
+        assert_eq!(apy.records, 10);
+        assert_eq!(apy.records_without_rewards, 1);
+        assert_eq!(apy.epochs.0, 10);
+        assert_eq!(apy.epochs_with_rewards.0, 9);
+        // 9 epochs of 1bp in 10 epochs, * 730 epochs per year
+        assert_eq!(apy.apy_bp, 657);
+    }
+}
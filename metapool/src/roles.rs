@@ -0,0 +1,144 @@
+use crate::*;
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+
+//------------------------------------
+// Role-based access control
+//------------------------------------
+// The owner (DAO) implicitly holds every role.
+// The operator bot gets only the roles it needs to run the heartbeat.
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// pause/un-pause staking and single operations, set/clear busy locks
+    Pauser,
+    /// add/remove staking pools, set pool weights
+    WeightManager,
+    /// set fees & contract params
+    FeeManager,
+    /// operational heartbeat fns that are not open to anyone: manual stake, rebalances, stake from nslp
+    Keeper,
+    /// remote code upgrade
+    Upgrader,
+}
+
+pub const ALL_ROLES: [Role; 5] = [
+    Role::Pauser,
+    Role::WeightManager,
+    Role::FeeManager,
+    Role::Keeper,
+    Role::Upgrader,
+];
+
+/// roles granted to the operator account, moved to the new operator when it changes
+pub const OPERATOR_ROLES: [Role; 2] = [Role::Keeper, Role::Pauser];
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Pauser => "pauser",
+            Role::WeightManager => "weight_manager",
+            Role::FeeManager => "fee_manager",
+            Role::Keeper => "keeper",
+            Role::Upgrader => "upgrader",
+        }
+    }
+}
+
+impl MetaPool {
+    /// owner has all roles
+    pub(crate) fn internal_has_role(&self, account_id: &AccountId, role: Role) -> bool {
+        account_id == &self.owner_account_id
+            || self
+                .roles
+                .get(account_id)
+                .map_or(false, |roles| roles.contains(&role))
+    }
+
+    /// Asserts that the method was called by the owner or by an account holding `role`
+    pub fn assert_role(&self, role: Role) {
+        assert!(
+            self.internal_has_role(&env::predecessor_account_id(), role),
+            "Can only be called by the owner or an account with the {} role",
+            role.as_str()
+        );
+    }
+
+    pub(crate) fn internal_grant_role(&mut self, account_id: &AccountId, role: Role) {
+        let mut roles = self.roles.get(account_id).unwrap_or_default();
+        if !roles.contains(&role) {
+            roles.push(role);
+            self.roles.insert(account_id, &roles);
+            event!(
+                r#"{{"event":"ROLE.G","account_id":"{}","role":"{}"}}"#,
+                account_id,
+                role.as_str()
+            );
+        }
+    }
+
+    pub(crate) fn internal_revoke_role(&mut self, account_id: &AccountId, role: Role) {
+        let mut roles = self.roles.get(account_id).unwrap_or_default();
+        assert!(
+            roles.contains(&role),
+            "@{} does not have the {} role",
+            account_id,
+            role.as_str()
+        );
+        roles.retain(|r| *r != role);
+        if roles.is_empty() {
+            self.roles.remove(account_id);
+        } else {
+            self.roles.insert(account_id, &roles);
+        }
+        event!(
+            r#"{{"event":"ROLE.R","account_id":"{}","role":"{}"}}"#,
+            account_id,
+            role.as_str()
+        );
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// Owner's method. Grants `role` to `account_id`
+    pub fn grant_role(&mut self, account_id: AccountId, role: Role) {
+        assert!(env::is_valid_account_id(account_id.as_bytes()));
+        self.assert_owner_calling();
+        self.internal_grant_role(&account_id, role);
+    }
+
+    /// Owner's method. Revokes `role` from `account_id`
+    pub fn revoke_role(&mut self, account_id: AccountId, role: Role) {
+        self.assert_owner_calling();
+        self.internal_revoke_role(&account_id, role);
+    }
+
+    /// the caller gives up one of its roles
+    pub fn renounce_role(&mut self, role: Role) {
+        self.internal_revoke_role(&env::predecessor_account_id(), role);
+    }
+
+    /// roles explicitly granted to `account_id`. (The owner implicitly has all roles)
+    pub fn get_roles(&self, account_id: AccountId) -> Vec<Role> {
+        if account_id == self.owner_account_id {
+            return ALL_ROLES.to_vec();
+        }
+        self.roles.get(&account_id).unwrap_or_default()
+    }
+
+    pub fn has_role(&self, account_id: AccountId, role: Role) -> bool {
+        self.internal_has_role(&account_id, role)
+    }
+
+    /// list all accounts with granted roles
+    pub fn get_role_holders(&self, from_index: u64, limit: u64) -> Vec<(AccountId, Vec<Role>)> {
+        let keys = self.roles.keys_as_vector();
+        let values = self.roles.values_as_vector();
+        (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| (keys.get(index).unwrap(), values.get(index).unwrap()))
+            .collect()
+    }
+}
@@ -0,0 +1,218 @@
+use crate::*;
+use near_sdk::{log, near_bindgen};
+use near_sdk::serde::{Deserialize, Serialize};
+
+//------------------------------------
+// Loss accounting
+//------------------------------------
+// When a pool reports a total balance lower than our records (slashing, or any other loss)
+// the loss is taken out of total_for_staking, lowering the stNEAR price for everyone.
+// The loss is taken from the pool's staked, then from its unstaked.
+// Unstaked funds were reserved for unstake claims, so a loss there is replaced by unstaking the same amount again.
+// If slash_threshold_bp > 0 and a single loss reaches that fraction of the pool's balance,
+// the pool's weight is spread across the other pools and staking into it is paused.
+
+/// entries kept per pool in the loss history
+pub const LOSS_HISTORY_LEN: usize = 16;
+/// smaller differences are staking-pool rounding, accounted but not recorded
+pub const MIN_LOSS_TO_RECORD: u128 = ONE_MILLI_NEAR;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LossRecord {
+    pub epoch: U64String,
+    pub amount: U128String,
+    /// pool total balance (staked+unstaked) before the loss
+    pub balance_before: U128String,
+}
+
+impl MetaPool {
+    /// the pool reported new_total_balance < sp.total_balance()
+    /// this fn is called from callbacks, it SHOULD NOT PANIC
+    pub(crate) fn internal_register_loss(&mut self, sp_inx: usize, new_total_balance: u128) {
+        let sp = &mut self.staking_pools[sp_inx];
+        let balance_before = sp.total_balance();
+        let loss = balance_before - new_total_balance;
+
+        // take from staked first, then from unstaked
+        let staked_loss = std::cmp::min(loss, sp.staked);
+        let unstaked_loss = loss - staked_loss;
+        sp.staked -= staked_loss;
+        sp.unstaked -= unstaked_loss;
+        sp.total_loss += loss;
+        let account_id = sp.account_id.clone();
+
+        // everybody shares the loss
+        self.total_for_staking = self.total_for_staking.saturating_sub(loss);
+        self.total_actually_staked = self.total_actually_staked.saturating_sub(staked_loss);
+        if unstaked_loss > 0 {
+            // the lost unstaked funds were waiting to fulfill unstake claims, unstake the same amount again
+            self.total_unstaked_and_waiting = self.total_unstaked_and_waiting.saturating_sub(unstaked_loss);
+            self.epoch_unstake_orders += unstaked_loss;
+        }
+
+        if loss < MIN_LOSS_TO_RECORD {
+            log!("@{} rounding loss {}", account_id, loss);
+            return;
+        }
+
+        let mut history = self.sp_loss_history.get(&account_id).unwrap_or_default();
+        if history.len() >= LOSS_HISTORY_LEN {
+            history.remove(0);
+        }
+        history.push(LossRecord {
+            epoch: env::epoch_height().into(),
+            amount: loss.into(),
+            balance_before: balance_before.into(),
+        });
+        self.sp_loss_history.insert(&account_id, &history);
+
+        event!(
+            r#"{{"event":"slash","sp":"{}","loss":"{}","balance_before":"{}","staked_loss":"{}","unstaked_loss":"{}"}}"#,
+            account_id,
+            loss,
+            balance_before,
+            staked_loss,
+            unstaked_loss
+        );
+
+        // threshold: stop staking into this pool
+        let loss_bp = proportional(10_000, loss, balance_before);
+        if self.slash_threshold_bp > 0 && loss_bp >= self.slash_threshold_bp as u128 {
+            let sp = &mut self.staking_pools[sp_inx];
+            sp.stake_paused = true;
+            let weight = sp.weight_basis_points;
+            self.internal_redistribute_weight(sp_inx);
+            event!(
+                r#"{{"event":"slash.pause","sp":"{}","loss_bp":{},"weight_bp":{}}}"#,
+                account_id,
+                loss_bp,
+                weight
+            );
+        }
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// last losses reported by a pool, oldest first
+    pub fn get_sp_loss_history(&self, account_id: AccountId) -> Vec<LossRecord> {
+        self.sp_loss_history.get(&account_id).unwrap_or_default()
+    }
+
+    /// Weight manager's method. Queues a change of slash_threshold_bp, 0 disables auto-pausing
+    pub fn set_slash_threshold(&mut self, basis_points: u16) -> u64 {
+        self.assert_role(Role::WeightManager);
+        self.internal_propose_change(GovernanceAction::SetSlashThreshold { basis_points })
+    }
+
+    pub fn get_slash_threshold(&self) -> u16 {
+        self.slash_threshold_bp
+    }
+
+    /// Pauser's method. Pauses or resumes staking into a pool
+    /// Note: resuming does not restore the pool's weight, use set_staking_pools
+    pub fn set_sp_stake_paused(&mut self, inx: StakingPoolRef, paused: bool) {
+        self.assert_role(Role::Pauser);
+        let sp_inx = self.sp_inx(&inx);
+        let sp = &mut self.staking_pools[sp_inx];
+        assert!(sp.stake_paused != paused, "sp {}.stake_paused is already {}", sp.account_id, paused);
+        assert!(!sp.draining, "sp {} is draining", sp.account_id);
+        sp.stake_paused = paused;
+        let account_id = sp.account_id.clone();
+        self.emit_pause_event(&format!("stake:{}", account_id), paused);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn history(contract: &MetaPool, sp_inx: usize) -> Vec<(u128, u128)> {
+        contract
+            .get_sp_loss_history(contract.staking_pools[sp_inx].account_id.clone())
+            .iter()
+            .map(|record| (record.amount.0, record.balance_before.0))
+            .collect()
+    }
+
+    fn weights(contract: &MetaPool) -> Vec<u16> {
+        contract.staking_pools.iter().map(|sp| sp.weight_basis_points).collect()
+    }
+
+    #[test]
+    fn test_loss_taken_from_staked() {
+        let mut contract = new_contract_with_pools(&[1000, 1000, 1000, 1000]);
+        contract.internal_register_loss(0, 990 * NEAR);
+        let sp = &contract.staking_pools[0];
+        assert_eq!((sp.staked, sp.unstaked, sp.total_loss), (990 * NEAR, 0, 10 * NEAR));
+        assert_eq!(contract.total_for_staking, 3990 * NEAR);
+        assert_eq!(contract.total_actually_staked, 3990 * NEAR);
+        assert_eq!(contract.epoch_unstake_orders, 0);
+        assert_eq!(history(&contract, 0), vec![(10 * NEAR, 1000 * NEAR)]);
+        assert!(!sp.stake_paused);
+    }
+
+    #[test]
+    fn test_loss_spilling_into_unstaked_is_unstaked_again() {
+        let mut contract = new_contract_with_pools(&[20, 1000]);
+        contract.staking_pools[0].unstaked = 100 * NEAR;
+        contract.total_unstaked_and_waiting = 100 * NEAR;
+        contract.internal_register_loss(0, 90 * NEAR);
+        let sp = &contract.staking_pools[0];
+        assert_eq!((sp.staked, sp.unstaked), (0, 90 * NEAR));
+        assert_eq!(contract.total_for_staking, 990 * NEAR);
+        assert_eq!(contract.total_actually_staked, 1000 * NEAR);
+        assert_eq!(contract.total_unstaked_and_waiting, 90 * NEAR);
+        // the 10 NEAR lost from unstaked are unstaked again to fulfill the claims
+        assert_eq!(contract.epoch_unstake_orders, 10 * NEAR);
+        assert_eq!(history(&contract, 0), vec![(30 * NEAR, 120 * NEAR)]);
+    }
+
+    #[test]
+    fn test_rounding_loss_is_accounted_but_not_recorded() {
+        let mut contract = new_contract_with_pools(&[1000, 1000]);
+        contract.slash_threshold_bp = 1;
+        let loss = MIN_LOSS_TO_RECORD - 1;
+        contract.internal_register_loss(0, 1000 * NEAR - loss);
+        let sp = &contract.staking_pools[0];
+        assert_eq!(sp.staked, 1000 * NEAR - loss);
+        assert_eq!(sp.total_loss, loss);
+        assert_eq!(contract.total_for_staking, 2000 * NEAR - loss);
+        assert!(history(&contract, 0).is_empty());
+        assert!(!sp.stake_paused);
+    }
+
+    #[test]
+    fn test_loss_over_the_threshold_pauses_the_pool() {
+        let mut contract = new_contract_with_pools(&[1000, 1000, 1000, 1000]);
+        contract.slash_threshold_bp = 500;
+        // 4.9%, under the threshold
+        contract.internal_register_loss(1, 951 * NEAR);
+        assert!(!contract.staking_pools[1].stake_paused);
+        assert_eq!(weights(&contract), vec![2500, 2500, 2500, 2500]);
+
+        // 5%
+        contract.internal_register_loss(0, 950 * NEAR);
+        assert!(contract.staking_pools[0].stake_paused);
+        let weights = weights(&contract);
+        assert_eq!(weights[0], 0);
+        assert_eq!(weights.iter().sum::<u16>(), 10000);
+        assert!(weights[1..].iter().all(|w| *w >= 3333));
+    }
+
+    #[test]
+    fn test_rewards_callback_registers_a_loss() {
+        let mut contract = new_contract_with_pools(&[1000]);
+        contract.staking_pools[0].last_asked_rewards_epoch_height = 9;
+        set_context("keeper.testnet", 10, 0);
+        contract.internal_launch_get_total_balance(0);
+        contract.internal_settle_total_balance(0, 900 * NEAR);
+        let sp = &contract.staking_pools[0];
+        assert!(!sp.busy_lock);
+        assert_eq!(sp.staked, 900 * NEAR);
+        assert_eq!(contract.accumulated_staked_rewards, 0);
+        assert_eq!(history(&contract, 0), vec![(100 * NEAR, 1000 * NEAR)]);
+    }
+}
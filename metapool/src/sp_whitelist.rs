@@ -0,0 +1,278 @@
+use crate::*;
+use near_sdk::{near_bindgen, PromiseResult};
+use near_sdk::serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+//------------------------------------
+// Staking pool registration
+//------------------------------------
+// add_staking_pool is async. Before a pool is added we ask:
+// - the whitelist contract (same contract the lockups use): is_whitelisted(pool)
+// - the pool: get_reward_fee_fraction() and get_owner_id()
+// The pool is added only if all calls succeed, it is whitelisted and its fee is <= max_sp_reward_fee_bp.
+// What we got is recorded on the pool entry (StakingPoolInfo.validation)
+
+/// default max reward fee of a new pool, 10%
+pub const DEFAULT_MAX_SP_REWARD_FEE_BP: u16 = 1000;
+
+impl RewardFeeFraction {
+    pub fn basis_points(&self) -> u128 {
+        if self.denominator == 0 {
+            return u128::MAX;
+        }
+        self.numerator as u128 * 10_000 / self.denominator as u128
+    }
+}
+
+/// result of the checks made when the pool was added
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakingPoolValidation {
+    pub whitelist_account_id: AccountId,
+    pub reward_fee_fraction: RewardFeeFraction,
+    pub owner_id: AccountId,
+    pub epoch: U64String,
+}
+
+/// config returned by get_staking_pool_requirements
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakingPoolRequirements {
+    pub whitelist_account_id: AccountId,
+    pub max_reward_fee_bp: u16,
+}
+
+#[ext_contract(ext_whitelist)]
+pub trait ExtWhitelist {
+    fn is_whitelisted(&self, staking_pool_account_id: AccountId) -> bool;
+}
+
+fn parse_json_result<T: DeserializeOwned>(result: PromiseResult) -> Option<T> {
+    match result {
+        PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<T>(&value).ok(),
+        _ => None,
+    }
+}
+
+impl MetaPool {
+    fn emit_add_sp_rejected(&self, account_id: &AccountId, reason: &str) {
+        event!(
+            r#"{{"event":"ADD.SP.R","sp":"{}","reason":"{}"}}"#,
+            account_id,
+            reason
+        );
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// Weight manager's method.
+    /// Starts the registration of a new staking pool, see on_add_staking_pool_checks
+    /// added with weight_basis_points = 0, to preserve sum(weights)=100%
+    pub fn add_staking_pool(&mut self, account_id: AccountId) -> Promise {
+        self.assert_role(Role::WeightManager);
+        assert!(
+            env::is_valid_account_id(account_id.as_bytes()),
+            "invalid staking-pool contract account {}",
+            account_id
+        );
+        // assert that is not already in the list
+        assert!(
+            self.staking_pools.iter().find(|x| x.account_id == account_id).is_none(),
+            "already in the list"
+        );
+        ext_whitelist::is_whitelisted(
+            account_id.clone(),
+            &self.staking_pool_whitelist_account_id,
+            NO_DEPOSIT,
+            gas::whitelist::IS_WHITELISTED,
+        )
+        .and(ext_staking_pool::get_reward_fee_fraction(
+            &account_id,
+            NO_DEPOSIT,
+            gas::staking_pool::GET_REWARD_FEE_FRACTION,
+        ))
+        .and(ext_staking_pool::get_owner_id(
+            &account_id,
+            NO_DEPOSIT,
+            gas::staking_pool::GET_OWNER_ID,
+        ))
+        .then(ext_self_owner::on_add_staking_pool_checks(
+            account_id,
+            self.staking_pool_whitelist_account_id.clone(),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            gas::owner_callbacks::ON_ADD_STAKING_POOL_CHECKS,
+        ))
+    }
+
+    /// callback for add_staking_pool
+    /// results: 0 => is_whitelisted, 1 => get_reward_fee_fraction, 2 => get_owner_id
+    /// returns true if the pool was added
+    #[private]
+    pub fn on_add_staking_pool_checks(
+        &mut self,
+        account_id: AccountId,
+        whitelist_account_id: AccountId,
+    ) -> bool {
+        assert_eq!(env::promise_results_count(), 3, "expected 3 promise results");
+
+        if parse_json_result::<bool>(env::promise_result(0)) != Some(true) {
+            self.emit_add_sp_rejected(&account_id, "not whitelisted");
+            return false;
+        }
+        let reward_fee_fraction = match parse_json_result::<RewardFeeFraction>(env::promise_result(1)) {
+            Some(fraction) => fraction,
+            None => {
+                self.emit_add_sp_rejected(&account_id, "get_reward_fee_fraction failed");
+                return false;
+            }
+        };
+        if reward_fee_fraction.basis_points() > self.max_sp_reward_fee_bp as u128 {
+            self.emit_add_sp_rejected(&account_id, "reward fee too high");
+            return false;
+        }
+        let owner_id = match parse_json_result::<AccountId>(env::promise_result(2)) {
+            Some(owner_id) => owner_id,
+            None => {
+                self.emit_add_sp_rejected(&account_id, "get_owner_id failed");
+                return false;
+            }
+        };
+        // could have been added by another call while we were waiting
+        if self.staking_pools.iter().any(|x| x.account_id == account_id) {
+            self.emit_add_sp_rejected(&account_id, "already in the list");
+            return false;
+        }
+
+        let sp_id = self.internal_next_sp_id();
+        let mut sp = StakingPoolInfo::new(sp_id, account_id.clone(), 0);
+        sp.validation = Some(StakingPoolValidation {
+            whitelist_account_id,
+            reward_fee_fraction,
+            owner_id,
+            epoch: env::epoch_height().into(),
+        });
+        self.staking_pools.push(sp);
+        event!(
+            r#"{{"event":"ADD.SP","sp":"{}","id":{}}}"#,
+            account_id,
+            sp_id
+        );
+        true
+    }
+
+    /// Owner's method. Queues a change of the whitelist contract and the max reward fee for new pools
+    pub fn set_staking_pool_requirements(&mut self, requirements: StakingPoolRequirements) -> u64 {
+        self.assert_owner_calling();
+        self.internal_propose_change(GovernanceAction::SetStakingPoolRequirements { requirements })
+    }
+
+    pub fn get_staking_pool_requirements(&self) -> StakingPoolRequirements {
+        StakingPoolRequirements {
+            whitelist_account_id: self.staking_pool_whitelist_account_id.clone(),
+            max_reward_fee_bp: self.max_sp_reward_fee_bp,
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::test_utils::get_logs;
+
+    const POOL: &str = "pool.testnet";
+    const WHITELIST: &str = "whitelist.testnet";
+
+    fn json_result<T: Serialize>(value: &T) -> PromiseResult {
+        PromiseResult::Successful(near_sdk::serde_json::to_vec(value).unwrap())
+    }
+
+    fn fee(numerator: u32, denominator: u32) -> PromiseResult {
+        json_result(&RewardFeeFraction { numerator, denominator })
+    }
+
+    /// runs the callback with the is_whitelisted, get_reward_fee_fraction & get_owner_id results
+    fn checks(
+        contract: &mut MetaPool,
+        whitelisted: PromiseResult,
+        fee: PromiseResult,
+        owner: PromiseResult,
+    ) -> bool {
+        set_callback_context_with_results(10, vec![whitelisted, fee, owner]);
+        contract.on_add_staking_pool_checks(POOL.into(), WHITELIST.into())
+    }
+
+    fn assert_rejected(contract: &MetaPool, reason: &str) {
+        assert!(contract.staking_pools.is_empty());
+        let expected = format!(r#""event":"ADD.SP.R","sp":"{}","reason":"{}""#, POOL, reason);
+        assert!(get_logs().iter().any(|line| line.contains(&expected)), "{:?}", get_logs());
+    }
+
+    #[test]
+    fn test_pool_passing_the_checks_is_added() {
+        let mut contract = new_contract();
+        assert!(checks(&mut contract, json_result(&true), fee(10, 100), json_result(&"owner.testnet")));
+        let sp = &contract.staking_pools[0];
+        assert_eq!(sp.account_id, POOL);
+        assert_eq!(sp.weight_basis_points, 0);
+        let validation = sp.validation.as_ref().unwrap();
+        assert_eq!(validation.whitelist_account_id, WHITELIST);
+        assert_eq!(validation.reward_fee_fraction.basis_points(), 1000);
+        assert_eq!(validation.owner_id, "owner.testnet");
+        assert_eq!(validation.epoch.0, 10);
+    }
+
+    #[test]
+    fn test_not_whitelisted() {
+        let mut contract = new_contract();
+        assert!(!checks(&mut contract, json_result(&false), fee(10, 100), json_result(&"owner.testnet")));
+        assert_rejected(&contract, "not whitelisted");
+        // a failed whitelist call is not a whitelisted pool either
+        assert!(!checks(&mut contract, PromiseResult::Failed, fee(10, 100), json_result(&"owner.testnet")));
+        assert_rejected(&contract, "not whitelisted");
+    }
+
+    #[test]
+    fn test_failed_reward_fee_fraction() {
+        let mut contract = new_contract();
+        let owner = json_result(&"owner.testnet");
+        assert!(!checks(&mut contract, json_result(&true), PromiseResult::Failed, owner));
+        assert_rejected(&contract, "get_reward_fee_fraction failed");
+    }
+
+    #[test]
+    fn test_reward_fee_too_high() {
+        let mut contract = new_contract();
+        assert!(!checks(&mut contract, json_result(&true), fee(11, 100), json_result(&"owner.testnet")));
+        assert_rejected(&contract, "reward fee too high");
+        // a 0 denominator is refused too
+        assert!(!checks(&mut contract, json_result(&true), fee(0, 0), json_result(&"owner.testnet")));
+        assert_rejected(&contract, "reward fee too high");
+    }
+
+    #[test]
+    fn test_failed_owner_id() {
+        let mut contract = new_contract();
+        assert!(!checks(&mut contract, json_result(&true), fee(10, 100), PromiseResult::Failed));
+        assert_rejected(&contract, "get_owner_id failed");
+    }
+
+    #[test]
+    fn test_added_while_waiting() {
+        let mut contract = new_contract();
+        add_pool(&mut contract, POOL, 0);
+        assert!(!checks(&mut contract, json_result(&true), fee(10, 100), json_result(&"owner.testnet")));
+        assert_eq!(contract.staking_pools.len(), 1);
+        assert!(contract.staking_pools[0].validation.is_none());
+        assert!(get_logs().iter().any(|line| line.contains("already in the list")));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected 3 promise results")]
+    fn test_three_results_are_required() {
+        let mut contract = new_contract();
+        set_callback_context(10, json_result(&true));
+        contract.on_add_staking_pool_checks(POOL.into(), WHITELIST.into());
+    }
+}
@@ -1,4 +1,5 @@
 use crate::*;
+use near_sdk::serde::{Deserialize, Serialize};
 
 pub use crate::types::*;
 pub use crate::utils::*;
@@ -30,23 +31,70 @@ pub struct StakingPoolInfo {
     pub unstaked: u128,
 
     //set when the unstake command is passed to the pool
-    //waiting period is until env::EpochHeight == unstaked_requested_epoch_height+NUM_EPOCHS_TO_UNLOCK
+    //waiting period is until env::EpochHeight == unstaked_requested_epoch_height+num_epochs_to_unlock
     //We might have to block users from unstaking if all the pools are in a waiting period
-    pub unstk_req_epoch_height: EpochHeight, // = env::epoch_height() + NUM_EPOCHS_TO_UNLOCK
+    pub unstk_req_epoch_height: EpochHeight, // = env::epoch_height() + num_epochs_to_unlock
 
     //EpochHeight where we asked the sp what were our staking rewards
     pub last_asked_rewards_epoch_height: EpochHeight,
+
+    //when busy_lock was set (only meaningful while busy_lock==true)
+    pub busy_lock_since_block: u64,
+    pub busy_lock_since_epoch: EpochHeight,
+
+    //stake/unstake/retrieve calls launched and not yet settled by their callback.
+    //Unlike busy_lock it can't be cleared by hand, so the pool can't be removed with funds in flight
+    pub in_flight: u16,
+    //sum of all losses (slashing) reported by this pool, see slashing.rs
+    pub total_loss: u128,
+    //no new stake goes to this pool (set when a loss crosses slash_threshold_bp)
+    pub stake_paused: bool,
+
+    //checks made when the pool was added, None for pools added before the whitelist validation
+    pub validation: Option<StakingPoolValidation>,
+
+    //stable id, never reused. Callbacks & public methods use it instead of the index in the Vec,
+    //so removing a pool does not change how the other pools are addressed
+    pub id: u16,
+
+    //retire_staking_pool was called, the heartbeat unstakes & retrieves all, then removes the pool
+    pub draining: bool,
+
+    //governance score used by the weight strategy, 0..10000
+    pub governance_score_bp: u16,
+}
+
+/// governance score of new pools, see weight_strategy.rs
+pub const DEFAULT_GOVERNANCE_SCORE_BP: u16 = 10000;
+
+/// how public methods receive a staking pool: by id (a number) or by account id (a string)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(untagged)]
+pub enum StakingPoolRef {
+    Id(u16),
+    AccountId(AccountId),
 }
 
 impl StakingPoolInfo {
     pub fn is_empty(&self) -> bool {
         return self.busy_lock == false
+            && self.in_flight == 0
             && self.weight_basis_points == 0
             && self.staked == 0
             && self.unstaked == 0;
     }
-    pub fn new(account_id: AccountId, weight_basis_points: u16) -> Self {
+    /// empty except for the rounding yoctos that can't be unstaked, see retire.rs
+    pub fn is_drained(&self) -> bool {
+        return self.busy_lock == false
+            && self.in_flight == 0
+            && self.weight_basis_points == 0
+            && self.staked <= UNSTAKED_YOCTOS_TO_IGNORE
+            && self.unstaked == 0;
+    }
+    pub fn new(id: u16, account_id: AccountId, weight_basis_points: u16) -> Self {
         return Self {
+            id,
             account_id,
             weight_basis_points,
             busy_lock: false,
@@ -54,8 +102,61 @@ impl StakingPoolInfo {
             unstaked: 0,
             unstk_req_epoch_height: 0,
             last_asked_rewards_epoch_height: 0,
+            busy_lock_since_block: 0,
+            busy_lock_since_epoch: 0,
+            in_flight: 0,
+            total_loss: 0,
+            stake_paused: false,
+            validation: None,
+            draining: false,
+            governance_score_bp: DEFAULT_GOVERNANCE_SCORE_BP,
         };
     }
+
+    pub fn json_info(&self, inx: u16) -> StakingPoolJSONInfo {
+        StakingPoolJSONInfo {
+            inx,
+            id: self.id,
+            account_id: self.account_id.clone(),
+            weight_basis_points: self.weight_basis_points,
+            staked: self.staked.into(),
+            unstaked: self.unstaked.into(),
+            unstaked_requested_epoch_height: self.unstk_req_epoch_height.into(),
+            last_asked_rewards_epoch_height: self.last_asked_rewards_epoch_height.into(),
+            busy_lock: self.busy_lock,
+            busy_lock_since_block: self.busy_lock_since_block.into(),
+            busy_lock_since_epoch: self.busy_lock_since_epoch.into(),
+            busy_lock_age_blocks: self.busy_lock_age_blocks().into(),
+            total_loss: self.total_loss.into(),
+            stake_paused: self.stake_paused,
+            validation: self.validation.clone(),
+            draining: self.draining,
+            governance_score_bp: self.governance_score_bp,
+        }
+    }
+
+    /// set busy_lock before a cross-contract call, remembering when
+    pub fn lock(&mut self) {
+        self.busy_lock = true;
+        self.busy_lock_since_block = env::block_index();
+        self.busy_lock_since_epoch = env::epoch_height();
+    }
+    pub fn unlock(&mut self) {
+        self.busy_lock = false;
+    }
+    /// locked, or with stake/unstake/retrieve calls not yet settled
+    /// a cleared busy_lock does not free the pool while its callbacks are pending
+    pub fn is_busy(&self) -> bool {
+        self.busy_lock || self.in_flight > 0
+    }
+    /// how many blocks ago the lock was taken, 0 if not busy
+    pub fn busy_lock_age_blocks(&self) -> u64 {
+        if self.busy_lock {
+            env::block_index().saturating_sub(self.busy_lock_since_block)
+        } else {
+            0
+        }
+    }
     pub fn total_balance(&self) -> u128 {
         self.staked + self.unstaked
     }
@@ -63,14 +164,38 @@ impl StakingPoolInfo {
     // sometimes the core-contracts/stake-pool does not unstakes all, it leaves a few yoctos as "unstaked" 
     // so we trust the bot to retrieve all unstaked at the start of the epoch, and in orde to know
     // if a sp can be unstaked again, we just check that the last unstake waiting period is over
-    pub fn wait_period_ended(&self) -> bool {
+    pub fn wait_period_ended(&self, num_epochs_to_unlock: EpochHeight) -> bool {
         let epoch_height = env::epoch_height();
         if self.unstk_req_epoch_height > epoch_height {
             //bad data at unstk_req_epoch_height or there was a hard-fork
             return true;
         }
-        //true if we reached epoch_requested+NUM_EPOCHS_TO_UNLOCK
-        return epoch_height >= self.unstk_req_epoch_height + NUM_EPOCHS_TO_UNLOCK;
+        //true if we reached epoch_requested+num_epochs_to_unlock
+        return epoch_height >= self.unstk_req_epoch_height + num_epochs_to_unlock;
+    }
+}
+
+impl MetaPool {
+    /// current index in self.staking_pools of the pool with this id
+    pub(crate) fn sp_inx_by_id(&self, sp_id: u16) -> Option<usize> {
+        self.staking_pools.iter().position(|sp| sp.id == sp_id)
+    }
+
+    /// current index in self.staking_pools, panics if not found
+    pub(crate) fn sp_inx(&self, sp: &StakingPoolRef) -> usize {
+        match sp {
+            StakingPoolRef::Id(sp_id) => self.sp_inx_by_id(*sp_id),
+            StakingPoolRef::AccountId(account_id) => {
+                self.staking_pools.iter().position(|sp| &sp.account_id == account_id)
+            }
+        }
+        .unwrap_or_else(|| panic!("staking pool {:?} not found", sp))
+    }
+
+    pub(crate) fn internal_next_sp_id(&mut self) -> u16 {
+        let sp_id = self.next_sp_id;
+        self.next_sp_id = sp_id.checked_add(1).expect("no more pool ids");
+        sp_id
     }
 }
 
@@ -97,4 +222,8 @@ pub trait ExtStakingPool {
     fn unstake(&mut self, amount: U128String);
 
     fn unstake_all(&mut self);
+
+    fn get_reward_fee_fraction(&self) -> RewardFeeFraction;
+
+    fn get_owner_id(&self) -> AccountId;
 }
@@ -0,0 +1,120 @@
+//! shared setup for the unit tests
+use crate::*;
+use near_sdk::test_utils::{testing_env_with_promise_results, VMContextBuilder};
+use near_sdk::{env, testing_env, MockedBlockchain, PromiseResult, RuntimeFeesConfig, VMConfig};
+use std::convert::TryInto;
+
+pub const CONTRACT_ID: &str = "meta-pool.testnet";
+pub const OWNER_ID: &str = "owner.testnet";
+pub const TREASURY_ID: &str = "treasury.testnet";
+pub const OPERATOR_ID: &str = "operator.testnet";
+pub const META_TOKEN_ID: &str = "meta-token.testnet";
+
+pub fn context(predecessor: &str, epoch: EpochHeight, attached_deposit: u128) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(CONTRACT_ID.try_into().unwrap())
+        .signer_account_id(predecessor.try_into().unwrap())
+        .predecessor_account_id(predecessor.try_into().unwrap())
+        .epoch_height(epoch)
+        .attached_deposit(attached_deposit);
+    builder
+}
+
+/// next calls come from `predecessor` at `epoch`, with `attached_deposit`
+pub fn set_context(predecessor: &str, epoch: EpochHeight, attached_deposit: u128) {
+    testing_env!(context(predecessor, epoch, attached_deposit).build());
+}
+
+/// next call is a callback receiving `result`
+pub fn set_callback_context(epoch: EpochHeight, result: PromiseResult) {
+    testing_env_with_promise_results(context(CONTRACT_ID, epoch, 0).build(), result);
+}
+
+/// next call is a callback receiving one result per joined promise, e.g. on_batch_settle
+pub fn set_callback_context_with_results(epoch: EpochHeight, results: Vec<PromiseResult>) {
+    let storage = env::take_blockchain_interface()
+        .unwrap()
+        .as_mut_mocked_blockchain()
+        .unwrap()
+        .take_storage();
+    env::set_blockchain_interface(Box::new(MockedBlockchain::new(
+        context(CONTRACT_ID, epoch, 0).build(),
+        VMConfig::default(),
+        RuntimeFeesConfig::default(),
+        results,
+        storage,
+        Default::default(),
+        None,
+    )));
+}
+
+pub fn new_contract() -> MetaPool {
+    set_context(OWNER_ID, 10, 0);
+    MetaPool::new(
+        OWNER_ID.into(),
+        TREASURY_ID.into(),
+        OPERATOR_ID.into(),
+        META_TOKEN_ID.into(),
+        Some("testnet".into()),
+    )
+}
+
+/// adds a pool without the whitelist checks, returns its id
+pub fn add_pool(contract: &mut MetaPool, account_id: &str, weight_basis_points: u16) -> u16 {
+    let sp_id = contract.internal_next_sp_id();
+    contract
+        .staking_pools
+        .push(StakingPoolInfo::new(sp_id, account_id.into(), weight_basis_points));
+    sp_id
+}
+
+/// adds a pool with `staked` yoctos, that already asked for rewards in this epoch, returns its id
+pub fn add_staked_pool(contract: &mut MetaPool, account_id: &str, weight_basis_points: u16, staked: u128) -> u16 {
+    let sp_id = add_pool(contract, account_id, weight_basis_points);
+    let sp = contract.staking_pools.last_mut().unwrap();
+    sp.staked = staked;
+    sp.last_asked_rewards_epoch_height = env::epoch_height();
+    contract.total_actually_staked += staked;
+    contract.total_for_staking += staked;
+    sp_id
+}
+
+/// a pool "pool-{n}.testnet" for each amount of NEAR staked, with equal weights
+pub fn new_contract_with_pools(staked: &[u128]) -> MetaPool {
+    let mut contract = new_contract();
+    let weight_basis_points = (10000 / staked.len()) as u16;
+    for (n, amount) in staked.iter().enumerate() {
+        add_staked_pool(&mut contract, &format!("pool-{}.testnet", n), weight_basis_points, amount * NEAR);
+    }
+    contract
+}
+
+/// gives `account_id` stNEAR worth `amount` yoctos, at a share price of 1
+pub fn add_account_with_stake(contract: &mut MetaPool, account_id: &str, amount: u128) {
+    let mut account = contract.accounts.get(&account_id.into()).unwrap_or_default();
+    account.stake_shares += amount;
+    contract.internal_update_account(&account_id.into(), &account);
+    contract.total_stake_shares += amount;
+    contract.total_for_staking += amount;
+}
+
+/// gives `account_id` an unstake ticket of `amount` yoctos unlocking at `unlock_epoch`,
+/// with the NEAR already retrieved from the pools
+pub fn add_account_with_ticket(contract: &mut MetaPool, account_id: &str, amount: u128, unlock_epoch: EpochHeight) {
+    let mut account = contract.accounts.get(&account_id.into()).unwrap_or_default();
+    contract.internal_add_unstake_ticket(&account_id.into(), &mut account, amount, unlock_epoch);
+    contract.internal_update_account(&account_id.into(), &account);
+    contract.total_unstake_claims += amount;
+    contract.retrieved_for_unstake_claims += amount;
+    contract.contract_account_balance += amount;
+}
+
+/// adds `amount` yoctos of NEAR liquidity to the NSLP
+pub fn add_nslp_liquidity(contract: &mut MetaPool, amount: u128) {
+    let mut nslp_account = contract.internal_get_nslp_account();
+    nslp_account.available += amount;
+    contract.internal_save_nslp_account(&nslp_account);
+    contract.total_available += amount;
+    contract.contract_account_balance += amount;
+}
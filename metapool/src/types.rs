@@ -1,6 +1,8 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::AccountId;
+use crate::sp_whitelist::StakingPoolValidation;
 use uint::construct_uint;
 
 //----------------------------------------
@@ -12,6 +14,8 @@ pub const STNEAR: &str = "stNEAR";
 
 // internal pseudo-account (must be an invalid near-account-id)
 pub const NSLP_INTERNAL_ACCOUNT: &str = "..NSLP..";
+// holds the stNEAR that funds the keeper bounty, see keeper.rs
+pub const KEEPER_FUND_INTERNAL_ACCOUNT: &str = "..KEEPER..";
 
 /// useful constants
 pub const NO_DEPOSIT: u128 = 0;
@@ -36,17 +40,24 @@ pub const TGAS: u64 = 1_000_000_000_000;
 /// when the unstaking promise can arrive at the next epoch, while the inner state is already
 /// updated in the previous epoch. It will not unlock the funds for 4 epochs.
 /// If all staking-pools are unstaking, the user might have to wait 2*NUM_EPOCHS_TO_UNLOCK
-pub const NUM_EPOCHS_TO_UNLOCK: EpochHeight = 4; //0 for testing in guild-net, 4 for mainnet & testnet;
+/// Default of the built-in network profiles, the value in use is NetworkProfile.num_epochs_to_unlock
+pub const NUM_EPOCHS_TO_UNLOCK: EpochHeight = 4;
 
 /// The contract keeps at least 35 NEAR in the account to avoid being transferred out to cover
 /// contract code storage and some internal state.
 pub const MIN_BALANCE_FOR_STORAGE: u128 = 35_000_000_000_000_000_000_000_000;
+/// busy locks older than this can be cleared by anyone (~ 15 minutes, callbacks land in a few blocks)
+pub const DEFAULT_BUSY_LOCK_EXPIRY_BLOCKS: u64 = 1_000;
+pub const MIN_BUSY_LOCK_EXPIRY_BLOCKS: u64 = 100;
 /// if the remainder falls below this amount, rebalance is not performed
 pub const MIN_STAKE_UNSTAKE_AMOUNT_MOVEMENT: u128 = TEN_NEAR;
 
 //cut on swap fees
 pub const DEFAULT_TREASURY_SWAP_CUT_BASIS_POINTS: u16 = 2500; // 25% swap fees go to Treasury
 pub const DEFAULT_OPERATOR_SWAP_CUT_BASIS_POINTS: u16 = 300; // 3% swap fees go to operator
+//premium on NEAR->stNEAR swaps (buy_stnear), kept by the NSLP
+pub const DEFAULT_NSLP_BUY_PREMIUM_BP: u16 = 10; // 0.1%
+pub const MAX_NSLP_BUY_PREMIUM_BP: u16 = 500; // 5%
                                                              //Fee on staking rewards
 pub const DEFAULT_OPERATOR_REWARDS_FEE_BASIS_POINTS: u16 = 50; // 0.5% -- CANT BE HIGHER THAN 1000 / 10%
 
@@ -89,7 +100,7 @@ pub struct NEP129Response {
 }
 
 /// Rewards fee fraction structure for the staking pool contract.
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct RewardFeeFraction {
     pub numerator: u32,
@@ -226,12 +237,20 @@ pub struct GetContractStateResult {
     pub max_meta_rewards_lu: U128String,      //liquid-unstakers
 
     pub unstaked_for_rebalance: U128String,
+
+    pub contract_busy: bool,
+    pub contract_busy_since_block: U64String,
+    pub contract_busy_since_epoch: U64String,
+    pub contract_busy_age_blocks: U64String,
+    pub busy_lock_expiry_blocks: U64String,
+    /// how many staking pools have an in-flight operation
+    pub busy_pools: u16,
 }
 
 /// Struct returned from get_contract_params
 /// div-pool parameters info
 /// Represents contact parameters as JSON compatible struct
-#[derive(Serialize, Deserialize)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct ContractParamsJSON {
     ///NEAR/stNEAR Liquidity pool 1% fee target. If Liquidity=target, fee is 1%
@@ -261,6 +280,19 @@ pub struct ContractParamsJSON {
     pub unstake_for_rebalance_cap_bp: u16,
 }
 
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AddLiquidityResult {
+    pub shares_minted: U128String,
+    /// % of the pool the account owns, in basis points
+    pub ownership_bp: u16,
+    pub pool_value_before: U128String,
+    pub pool_value_after: U128String,
+    /// value of one NSLP share (1e24 yocto-shares)
+    pub share_price_before: U128String,
+    pub share_price_after: U128String,
+}
+
 #[derive(Serialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct RemoveLiquidityResult {
@@ -268,6 +300,69 @@ pub struct RemoveLiquidityResult {
     pub st_near: U128String,
 }
 
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BuyStNearResult {
+    pub st_near: U128String,
+    /// NEAR kept by the NSLP
+    pub premium: U128String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SmartUnstakeResult {
+    /// stNEAR sold in the NSLP
+    pub liquid_st_near: U128String,
+    /// NEAR transferred
+    pub near: U128String,
+    /// liquid unstake fee, in stNEAR
+    pub fee: U128String,
+    /// stNEAR delayed-unstaked
+    pub delayed_st_near: U128String,
+    /// NEAR unstaked, withdrawable from unlock_epoch
+    pub delayed_near: U128String,
+    pub unlock_epoch: Option<U64String>,
+}
+
+/// amounts of a liquid unstake, see MetaPool::internal_quote_liquid_unstake
+pub struct LiquidUnstakeQuote {
+    /// value of the stNEAR sold
+    pub nears_out: u128,
+    pub fee_bp: u16,
+    /// fee in NEAR
+    pub fee: u128,
+    pub near_to_receive: u128,
+    pub fee_in_st_near: u128,
+    pub treasury_st_near_cut: u128,
+    pub operator_st_near_cut: u128,
+    pub developers_st_near_cut: u128,
+    /// stNEAR sold minus the cuts, goes to the NSLP
+    pub st_near_to_liq_pool: u128,
+}
+
+// quote_liquid_unstake returns LiquidUnstakeQuoteJSON
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LiquidUnstakeQuoteJSON {
+    pub st_near_to_sell: U128String,
+    pub near_out: U128String,
+    pub fee_bp: u16,
+    pub fee_near: U128String,
+    pub fee_st_near: U128String,
+    pub treasury_st_near_cut: U128String,
+    pub operator_st_near_cut: U128String,
+    pub developers_st_near_cut: U128String,
+    /// part of the fee kept by the NSLP, in stNEAR
+    pub nslp_st_near_cut: U128String,
+    /// false if liquid_unstake would fail with "Not enough liquidity in the liquidity pool"
+    pub enough_liquidity: bool,
+    /// NEAR in the NSLP after the trade
+    pub nslp_liquidity_after: U128String,
+    /// largest stNEAR amount sellable now with a fee <= max_fee_bp
+    pub max_fee_bp: u16,
+    pub max_st_near_under_fee: U128String,
+}
+
 #[derive(Serialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct LiquidUnstakeResult {
@@ -280,7 +375,10 @@ pub struct LiquidUnstakeResult {
 #[derive(Serialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct StakingPoolJSONInfo {
+    /// current position in the list, changes when a pool is removed
     pub inx: u16,
+    /// stable id, use it (or account_id) to address the pool
+    pub id: u16,
     pub account_id: String,
     pub weight_basis_points: u16,
     pub staked: U128String,
@@ -289,10 +387,19 @@ pub struct StakingPoolJSONInfo {
     //EpochHeight where we asked the sp what were our staking rewards
     pub last_asked_rewards_epoch_height: U64String,
     pub busy_lock: bool,
+    //block height & epoch when busy_lock was set, and how many blocks ago (0 if not busy)
+    pub busy_lock_since_block: U64String,
+    pub busy_lock_since_epoch: U64String,
+    pub busy_lock_age_blocks: U64String,
+    pub total_loss: U128String,
+    pub stake_paused: bool,
+    pub validation: Option<StakingPoolValidation>,
+    pub draining: bool,
+    pub governance_score_bp: u16,
 }
 
 /// struct used as parameter for set_staking_pools
-#[derive(Serialize, Deserialize)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct StakingPoolArgItem {
     pub account_id: AccountId, 
@@ -0,0 +1,567 @@
+use crate::*;
+use near_sdk::json_types::ValidAccountId;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{ext_contract, log, near_bindgen, Gas, PromiseOrValue, PromiseResult};
+use std::collections::HashMap;
+
+//------------------------------------
+// Transferable unstake claims, NEP-171
+//------------------------------------
+// A pending delayed-unstake ticket (or part of it) can be turned into a NEP-171 token with mint_unstake_nft.
+// The amount leaves the account's unstaked balance and stays counted in total_unstake_claims, so the heartbeat
+// keeps retrieving it from the pools. The token can be transferred (nft_transfer, nft_transfer_call)
+// and whoever holds it calls redeem_unstake_nft once it unlocks, to get the NEAR (withdraw_unstaked semantics).
+// No approvals (NEP-178): only the owner can transfer.
+
+pub type TokenId = String;
+
+/// smallest claim that can be minted
+pub const MIN_UNSTAKE_NFT_AMOUNT: u128 = ONE_NEAR;
+
+const NFT_METADATA_SPEC: &str = "nft-1.0.0";
+const GAS_FOR_NFT_TRANSFER_CALL: Gas = 30_000_000_000_000;
+const GAS_FOR_NFT_RESOLVE_TRANSFER: Gas = 11_000_000_000_000;
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct UnstakeClaim {
+    pub owner_id: AccountId,
+    pub amount: u128,
+    pub unlock_epoch: EpochHeight,
+    pub minted_epoch: EpochHeight,
+}
+
+/// NEP-177 contract metadata
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NFTContractMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub base_uri: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<String>,
+}
+
+/// NEP-177 token metadata
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub media: Option<String>,
+    pub media_hash: Option<String>,
+    pub copies: Option<u64>,
+    pub issued_at: Option<String>,
+    pub expires_at: Option<String>,
+    pub starts_at: Option<String>,
+    pub updated_at: Option<String>,
+    /// JSON: {"amount","unlock_epoch"}
+    pub extra: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<String>,
+}
+
+/// NEP-171 token
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Token {
+    pub token_id: TokenId,
+    pub owner_id: AccountId,
+    pub metadata: Option<TokenMetadata>,
+    pub approved_account_ids: Option<HashMap<AccountId, u64>>,
+}
+
+/// Struct returned from get_unstake_nft
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UnstakeClaimJSON {
+    pub token_id: TokenId,
+    pub owner_id: AccountId,
+    pub amount: U128String,
+    pub unlock_epoch: U64String,
+    pub can_redeem: bool,
+}
+
+#[ext_contract(ext_nft_receiver)]
+pub trait NonFungibleTokenReceiver {
+    fn nft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_id: AccountId,
+        token_id: TokenId,
+        msg: String,
+    ) -> PromiseOrValue<bool>;
+}
+
+#[ext_contract(ext_self_nft)]
+pub trait NonFungibleTokenResolver {
+    fn nft_resolve_transfer(
+        &mut self,
+        previous_owner_id: AccountId,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approved_account_ids: Option<HashMap<AccountId, u64>>,
+    ) -> bool;
+}
+
+/// NEP-297 event
+fn emit_nft_event(event: &str, data: String) {
+    env::log(
+        format!(
+            r#"EVENT_JSON:{{"standard":"nep171","version":"1.0.0","event":"{}","data":[{}]}}"#,
+            event, data
+        )
+        .as_bytes(),
+    );
+}
+
+impl MetaPool {
+    fn internal_get_unstake_claim(&self, token_id: &TokenId) -> UnstakeClaim {
+        self.unstake_nfts.get(token_id).expect("token not found")
+    }
+
+    fn internal_add_owner_token(&mut self, owner_id: &AccountId, token_id: &TokenId) {
+        let mut tokens = self.unstake_nfts_per_owner.get(owner_id).unwrap_or_default();
+        tokens.push(token_id.clone());
+        self.unstake_nfts_per_owner.insert(owner_id, &tokens);
+    }
+
+    fn internal_remove_owner_token(&mut self, owner_id: &AccountId, token_id: &TokenId) {
+        let mut tokens = self.unstake_nfts_per_owner.get(owner_id).unwrap_or_default();
+        tokens.retain(|t| t != token_id);
+        if tokens.is_empty() {
+            self.unstake_nfts_per_owner.remove(owner_id);
+        } else {
+            self.unstake_nfts_per_owner.insert(owner_id, &tokens);
+        }
+    }
+
+    pub(crate) fn internal_nft_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        token_id: &TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+    ) -> AccountId {
+        assert!(approval_id.is_none(), "approvals are not supported");
+        let mut claim = self.internal_get_unstake_claim(token_id);
+        assert_eq!(&claim.owner_id, sender_id, "only the token owner can transfer it");
+        assert_ne!(sender_id, receiver_id, "the token owner and the receiver should be different");
+        let previous_owner_id = claim.owner_id.clone();
+        claim.owner_id = receiver_id.clone();
+        self.unstake_nfts.insert(token_id, &claim);
+        self.internal_remove_owner_token(&previous_owner_id, token_id);
+        self.internal_add_owner_token(receiver_id, token_id);
+        emit_nft_event(
+            "nft_transfer",
+            format!(
+                r#"{{"old_owner_id":"{}","new_owner_id":"{}","token_ids":["{}"]{}}}"#,
+                previous_owner_id,
+                receiver_id,
+                token_id,
+                memo.map_or(String::new(), |memo| format!(r#","memo":{:?}"#, memo))
+            ),
+        );
+        previous_owner_id
+    }
+
+    fn claim_to_token(&self, token_id: TokenId, claim: UnstakeClaim) -> Token {
+        Token {
+            token_id,
+            owner_id: claim.owner_id,
+            metadata: Some(TokenMetadata {
+                title: Some(format!(
+                    "Unstake claim: {} NEAR at epoch {}",
+                    claim.amount / ONE_NEAR,
+                    claim.unlock_epoch
+                )),
+                description: Some("Meta Pool delayed-unstake claim, redeem with redeem_unstake_nft".into()),
+                media: None,
+                media_hash: None,
+                copies: Some(1),
+                issued_at: None,
+                expires_at: None,
+                starts_at: None,
+                updated_at: None,
+                extra: Some(format!(
+                    r#"{{"amount":"{}","unlock_epoch":"{}","minted_epoch":"{}"}}"#,
+                    claim.amount, claim.unlock_epoch, claim.minted_epoch
+                )),
+                reference: None,
+                reference_hash: None,
+            }),
+            approved_account_ids: Some(HashMap::new()),
+        }
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// user method
+    /// turns `amount` (all if omitted) of the unstake ticket unlocking at `unlock_epoch` into a transferable token
+    /// returns the token id
+    #[payable]
+    pub fn mint_unstake_nft(&mut self, unlock_epoch: U64String, amount: Option<U128String>) -> TokenId {
+        assert_one_yocto();
+        self.assert_not_busy();
+        self.assert_not_lockup_account_calling();
+        self.assert_operation_not_paused(PausableOperation::DelayedUnstake);
+        let account_id = env::predecessor_account_id();
+        let mut acc = self.internal_get_account(&account_id);
+        let amount = self.internal_take_from_unstake_ticket(
+            &account_id,
+            &mut acc,
+            unlock_epoch.0,
+            amount.map(|a| a.0),
+        );
+        assert!(
+            amount >= MIN_UNSTAKE_NFT_AMOUNT,
+            "min amount is {} NEAR",
+            MIN_UNSTAKE_NFT_AMOUNT / ONE_NEAR
+        );
+        self.internal_update_account(&account_id, &acc);
+
+        let token_id: TokenId = self.next_unstake_nft_id.to_string();
+        self.next_unstake_nft_id += 1;
+        self.unstake_nfts.insert(
+            &token_id,
+            &UnstakeClaim {
+                owner_id: account_id.clone(),
+                amount,
+                unlock_epoch: unlock_epoch.0,
+                minted_epoch: env::epoch_height(),
+            },
+        );
+        self.internal_add_owner_token(&account_id, &token_id);
+        emit_nft_event(
+            "nft_mint",
+            format!(r#"{{"owner_id":"{}","token_ids":["{}"]}}"#, account_id, token_id),
+        );
+        token_id
+    }
+
+    /// token holder method
+    /// once unlocked, burns the token and transfers its NEAR to the holder
+    pub fn redeem_unstake_nft(&mut self, token_id: TokenId) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let claim = self.internal_get_unstake_claim(&token_id);
+        assert_eq!(claim.owner_id, account_id, "only the token owner can redeem it");
+        let epoch = env::epoch_height();
+        assert!(
+            epoch >= claim.unlock_epoch,
+            "The claim is not yet available due to unstaking delay. You need to wait {} epochs",
+            claim.unlock_epoch - epoch
+        );
+        // same checks as Account.in_memory_try_finish_unstaking
+        assert!(
+            self.retrieved_for_unstake_claims >= claim.amount,
+            "Funds are not yet available due to unstaking delay. Epoch:{}",
+            epoch
+        );
+        self.retrieved_for_unstake_claims -= claim.amount;
+        assert!(self.total_unstake_claims >= claim.amount, "ITUC");
+        self.total_unstake_claims -= claim.amount;
+
+        self.unstake_nfts.remove(&token_id);
+        self.internal_remove_owner_token(&account_id, &token_id);
+        emit_nft_event(
+            "nft_burn",
+            format!(r#"{{"owner_id":"{}","token_ids":["{}"]}}"#, account_id, token_id),
+        );
+        event!(
+            r#"{{"event":"NFT-WITHD","account_id":"{}","token_id":"{}","amount":"{}"}}"#,
+            account_id,
+            token_id,
+            claim.amount
+        );
+        self.native_transfer(&account_id, claim.amount)
+    }
+
+    pub fn get_unstake_nft(&self, token_id: TokenId) -> Option<UnstakeClaimJSON> {
+        self.unstake_nfts.get(&token_id).map(|claim| UnstakeClaimJSON {
+            token_id,
+            owner_id: claim.owner_id,
+            amount: claim.amount.into(),
+            unlock_epoch: claim.unlock_epoch.into(),
+            can_redeem: env::epoch_height() >= claim.unlock_epoch,
+        })
+    }
+
+    //-----------------------
+    // NEP-171 core
+    //-----------------------
+    #[payable]
+    pub fn nft_transfer(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_id: TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+    ) {
+        assert_one_yocto();
+        self.internal_nft_transfer(
+            &env::predecessor_account_id(),
+            &receiver_id.into(),
+            &token_id,
+            approval_id,
+            memo,
+        );
+    }
+
+    /// returns true if the token was transferred
+    #[payable]
+    pub fn nft_transfer_call(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_id: TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<bool> {
+        assert_one_yocto();
+        assert!(
+            env::prepaid_gas() > GAS_FOR_NFT_TRANSFER_CALL + GAS_FOR_NFT_RESOLVE_TRANSFER,
+            "gas required {}",
+            GAS_FOR_NFT_TRANSFER_CALL + GAS_FOR_NFT_RESOLVE_TRANSFER
+        );
+        let sender_id = env::predecessor_account_id();
+        let receiver_id: AccountId = receiver_id.into();
+        let previous_owner_id =
+            self.internal_nft_transfer(&sender_id, &receiver_id, &token_id, approval_id, memo);
+        ext_nft_receiver::nft_on_transfer(
+            sender_id,
+            previous_owner_id.clone(),
+            token_id.clone(),
+            msg,
+            //promise params:
+            &receiver_id,
+            NO_DEPOSIT,
+            env::prepaid_gas() - GAS_FOR_NFT_TRANSFER_CALL,
+        )
+        .then(ext_self_nft::nft_resolve_transfer(
+            previous_owner_id,
+            receiver_id,
+            token_id,
+            None,
+            //promise params:
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_NFT_RESOLVE_TRANSFER,
+        ))
+        .into()
+    }
+
+    /// returns true if the token stays with receiver_id.
+    /// The token goes back to previous_owner_id if nft_on_transfer failed or returned true
+    #[private]
+    pub fn nft_resolve_transfer(
+        &mut self,
+        previous_owner_id: AccountId,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approved_account_ids: Option<HashMap<AccountId, u64>>,
+    ) -> bool {
+        // approvals are not supported, there is nothing to restore
+        let _ = approved_account_ids;
+        let must_revert = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                near_sdk::serde_json::from_slice::<bool>(&value).unwrap_or(true)
+            }
+            _ => true,
+        };
+        if !must_revert {
+            return true;
+        }
+        // the receiver could have transferred or redeemed it already
+        match self.unstake_nfts.get(&token_id) {
+            Some(claim) if claim.owner_id == receiver_id => {
+                self.internal_nft_transfer(&receiver_id, &previous_owner_id, &token_id, None, None);
+                false
+            }
+            _ => {
+                log!("token {} is no longer owned by {}", token_id, receiver_id);
+                true
+            }
+        }
+    }
+
+    pub fn nft_token(&self, token_id: TokenId) -> Option<Token> {
+        self.unstake_nfts
+            .get(&token_id)
+            .map(|claim| self.claim_to_token(token_id, claim))
+    }
+
+    //-----------------------
+    // NEP-177 metadata
+    //-----------------------
+    pub fn nft_metadata(&self) -> NFTContractMetadata {
+        NFTContractMetadata {
+            spec: NFT_METADATA_SPEC.into(),
+            name: "Meta Pool unstake claims".into(),
+            symbol: "STNEAR-CLAIM".into(),
+            icon: None,
+            base_uri: None,
+            reference: Some("https://metapool.app".into()),
+            reference_hash: None,
+        }
+    }
+
+    //-----------------------
+    // NEP-181 enumeration
+    //-----------------------
+    pub fn nft_total_supply(&self) -> U128String {
+        (self.unstake_nfts.len() as u128).into()
+    }
+
+    pub fn nft_tokens(&self, from_index: Option<U128String>, limit: Option<u64>) -> Vec<Token> {
+        let keys = self.unstake_nfts.keys_as_vector();
+        let from_index = from_index.map_or(0, |i| i.0 as u64);
+        let limit = limit.unwrap_or(keys.len());
+        (from_index..std::cmp::min(from_index.saturating_add(limit), keys.len()))
+            .filter_map(|index| self.nft_token(keys.get(index).unwrap()))
+            .collect()
+    }
+
+    pub fn nft_supply_for_owner(&self, account_id: AccountId) -> U128String {
+        (self.unstake_nfts_per_owner.get(&account_id).map_or(0, |tokens| tokens.len()) as u128).into()
+    }
+
+    pub fn nft_tokens_for_owner(
+        &self,
+        account_id: AccountId,
+        from_index: Option<U128String>,
+        limit: Option<u64>,
+    ) -> Vec<Token> {
+        let tokens = self.unstake_nfts_per_owner.get(&account_id).unwrap_or_default();
+        let from_index = from_index.map_or(0, |i| i.0 as usize);
+        tokens
+            .into_iter()
+            .skip(from_index)
+            .take(limit.map_or(usize::MAX, |l| l as usize))
+            .filter_map(|token_id| self.nft_token(token_id))
+            .collect()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use std::convert::TryInto;
+
+    const ALICE: &str = "alice.testnet";
+    const BOB: &str = "bob.testnet";
+
+    /// alice has a 10 NEAR ticket unlocking at epoch 14, already retrieved from the pools
+    fn new_contract_with_ticket() -> MetaPool {
+        let mut contract = new_contract();
+        add_account_with_ticket(&mut contract, ALICE, 10 * NEAR, 14);
+        contract
+    }
+
+    fn mint(contract: &mut MetaPool, amount: u128) -> TokenId {
+        set_context(ALICE, 10, 1);
+        contract.mint_unstake_nft(14.into(), Some(amount.into()))
+    }
+
+    fn transfer(contract: &mut MetaPool, from: &str, to: &str, token_id: &TokenId) {
+        set_context(from, 10, 1);
+        contract.nft_transfer(to.try_into().unwrap(), token_id.clone(), None, None);
+    }
+
+    fn owner_of(contract: &MetaPool, token_id: &TokenId) -> AccountId {
+        contract.nft_token(token_id.clone()).unwrap().owner_id
+    }
+
+    #[test]
+    fn test_mint_takes_from_the_ticket() {
+        let mut contract = new_contract_with_ticket();
+        let token_id = mint(&mut contract, 4 * NEAR);
+        assert_eq!(owner_of(&contract, &token_id), ALICE);
+        assert_eq!(contract.get_unstake_nft(token_id.clone()).unwrap().amount.0, 4 * NEAR);
+        assert_eq!(contract.get_account_unstaked_balance(ALICE.into()).0, 6 * NEAR);
+        // still a claim on the pools
+        assert_eq!(contract.total_unstake_claims, 10 * NEAR);
+        assert_eq!(contract.nft_supply_for_owner(ALICE.into()).0, 1);
+        assert_ne!(mint(&mut contract, 6 * NEAR), token_id);
+        assert_eq!(contract.nft_total_supply().0, 2);
+        assert!(contract.get_unstake_tickets(ALICE.into()).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "min amount is 1 NEAR")]
+    fn test_mint_min_amount() {
+        let mut contract = new_contract_with_ticket();
+        mint(&mut contract, NEAR / 2);
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut contract = new_contract_with_ticket();
+        let token_id = mint(&mut contract, 4 * NEAR);
+        transfer(&mut contract, ALICE, BOB, &token_id);
+        assert_eq!(owner_of(&contract, &token_id), BOB);
+        assert_eq!(contract.nft_supply_for_owner(ALICE.into()).0, 0);
+        assert_eq!(contract.nft_tokens_for_owner(BOB.into(), None, None).len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "only the token owner can transfer it")]
+    fn test_only_the_owner_transfers() {
+        let mut contract = new_contract_with_ticket();
+        let token_id = mint(&mut contract, 4 * NEAR);
+        transfer(&mut contract, ALICE, BOB, &token_id);
+        transfer(&mut contract, ALICE, BOB, &token_id);
+    }
+
+    #[test]
+    fn test_redeem_burns_the_token() {
+        let mut contract = new_contract_with_ticket();
+        let token_id = mint(&mut contract, 4 * NEAR);
+        transfer(&mut contract, ALICE, BOB, &token_id);
+        set_context(BOB, 14, 0);
+        contract.redeem_unstake_nft(token_id.clone());
+        assert!(contract.nft_token(token_id).is_none());
+        assert_eq!(contract.nft_supply_for_owner(BOB.into()).0, 0);
+        assert_eq!(contract.total_unstake_claims, 6 * NEAR);
+        assert_eq!(contract.retrieved_for_unstake_claims, 6 * NEAR);
+        assert_eq!(contract.contract_account_balance, 6 * NEAR);
+    }
+
+    #[test]
+    #[should_panic(expected = "You need to wait 1 epochs")]
+    fn test_redeem_before_unlock() {
+        let mut contract = new_contract_with_ticket();
+        let token_id = mint(&mut contract, 4 * NEAR);
+        set_context(ALICE, 13, 0);
+        contract.redeem_unstake_nft(token_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "only the token owner can redeem it")]
+    fn test_only_the_owner_redeems() {
+        let mut contract = new_contract_with_ticket();
+        let token_id = mint(&mut contract, 4 * NEAR);
+        transfer(&mut contract, ALICE, BOB, &token_id);
+        set_context(ALICE, 14, 0);
+        contract.redeem_unstake_nft(token_id);
+    }
+
+    #[test]
+    fn test_resolve_transfer() {
+        let mut contract = new_contract_with_ticket();
+        let token_id = mint(&mut contract, 4 * NEAR);
+        // the receiver keeps it
+        transfer(&mut contract, ALICE, BOB, &token_id);
+        set_callback_context(10, PromiseResult::Successful(b"false".to_vec()));
+        assert!(contract.nft_resolve_transfer(ALICE.into(), BOB.into(), token_id.clone(), None));
+        assert_eq!(owner_of(&contract, &token_id), BOB);
+        // the receiver failed, back to the previous owner
+        transfer(&mut contract, BOB, ALICE, &token_id);
+        set_callback_context(10, PromiseResult::Failed);
+        assert!(!contract.nft_resolve_transfer(BOB.into(), ALICE.into(), token_id.clone(), None));
+        assert_eq!(owner_of(&contract, &token_id), BOB);
+    }
+}
@@ -0,0 +1,312 @@
+use crate::*;
+use near_sdk::{log, near_bindgen};
+
+//------------------------------------
+// Unstake planner
+//------------------------------------
+// A pool can receive an unstake only if it's not waiting (or started waiting in this same epoch),
+// because a new unstake restarts the pool's num_epochs_to_unlock wait.
+// - internal_plan_unstake splits epoch_unstake_orders across the unblocked pools, using as few pools as possible
+//   and, among those, proportional to their extra stake. If the extra is not enough, the rest is taken proportional to
+//   the pools' remaining stake.
+// - internal_unstake_unlock_epoch computes when an amount ordered now can be withdrawn, from the epoch
+//   each pool can receive an unstake again (unstk_req_epoch_height + num_epochs_to_unlock)
+
+/// smallest part of a split, smaller parts are given to the other pools
+pub const MIN_UNSTAKE_SPLIT_AMOUNT: u128 = MIN_STAKE_UNSTAKE_AMOUNT_MOVEMENT;
+
+/// result of internal_plan_unstake, one per pool
+pub struct UnstakePlan {
+    pub sp_inx: usize,
+    /// extra stake in the pool, 0 if the pool is at or under its weight
+    pub sp_extra: u128,
+    pub from_orders: u128,
+    pub from_rebalance: u128,
+}
+
+/// a pool that can receive an unstake now
+struct UnstakeCandidate {
+    sp_inx: usize,
+    staked: u128,
+    extra: u128,
+    /// already unstaked in this epoch, adding more does not extend its wait
+    waiting_this_epoch: bool,
+}
+
+impl MetaPool {
+    /// true if the pool can receive an unstake now without extending a wait
+    fn sp_unstake_unblocked(sp: &StakingPoolInfo) -> bool {
+        sp.unstaked <= UNSTAKED_YOCTOS_TO_IGNORE || sp.unstk_req_epoch_height == env::epoch_height()
+    }
+
+    /// computes which pools & how much to unstake to fulfill epoch_unstake_orders, at most max_pools pools.
+    /// The largest part goes first. Returns an empty vec if no pool can receive an unstake now.
+    /// Seizes the opportunity to also unstake for rebalance if a pool has more extra than ordered from it
+    pub(crate) fn internal_plan_unstake(&self, max_pools: usize) -> Vec<UnstakePlan> {
+        let orders = self.epoch_unstake_orders;
+        if orders == 0 || max_pools == 0 {
+            return Vec::new();
+        }
+        let mut candidates: Vec<UnstakeCandidate> = self
+            .staking_pools
+            .iter()
+            .enumerate()
+            .filter(|(_, sp)| !sp.is_busy() && sp.staked > 0 && Self::sp_unstake_unblocked(sp))
+            .map(|(sp_inx, sp)| UnstakeCandidate {
+                sp_inx,
+                staked: sp.staked,
+                extra: sp
+                    .staked
+                    .saturating_sub(apply_pct(sp.weight_basis_points, self.total_for_staking)),
+                waiting_this_epoch: sp.unstaked > UNSTAKED_YOCTOS_TO_IGNORE,
+            })
+            .collect();
+        // pools already waiting from this epoch first (no new pool gets blocked), then by extra
+        candidates.sort_by(|a, b| {
+            b.waiting_this_epoch
+                .cmp(&a.waiting_this_epoch)
+                .then(b.extra.cmp(&a.extra))
+                .then(b.staked.cmp(&a.staked))
+        });
+        // use as few pools as possible: stop when their extra covers the orders
+        let mut extra_sum: u128 = 0;
+        let mut used: usize = 0;
+        for candidate in candidates.iter() {
+            if used == max_pools || (used > 0 && extra_sum >= orders) {
+                break;
+            }
+            extra_sum += candidate.extra;
+            used += 1;
+        }
+        candidates.truncate(used);
+
+        // split, dropping parts too small to justify an unstake
+        let mut parts = Self::split_unstake_orders(orders, &candidates);
+        while candidates.len() > 1 {
+            let (smallest, amount) = parts
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, amount)| **amount)
+                .map(|(inx, amount)| (inx, *amount))
+                .unwrap();
+            if amount >= MIN_UNSTAKE_SPLIT_AMOUNT {
+                break;
+            }
+            candidates.remove(smallest);
+            parts = Self::split_unstake_orders(orders, &candidates);
+        }
+
+        let mut rebalance_cap = self
+            .max_unstake_for_rebalance()
+            .saturating_sub(self.unstaked_for_rebalance);
+        let mut result: Vec<UnstakePlan> = Vec::with_capacity(candidates.len());
+        for (inx, candidate) in candidates.iter().enumerate() {
+            let from_orders = parts[inx];
+            if from_orders == 0 {
+                continue;
+            }
+            let mut from_rebalance = 0;
+            if candidate.extra > from_orders && rebalance_cap > 1 * NEAR {
+                from_rebalance = std::cmp::min(rebalance_cap, candidate.extra - from_orders);
+                rebalance_cap -= from_rebalance;
+            }
+            result.push(UnstakePlan {
+                sp_inx: candidate.sp_inx,
+                sp_extra: candidate.extra,
+                from_orders,
+                from_rebalance,
+            });
+        }
+        result.sort_by(|a, b| (b.from_orders + b.from_rebalance).cmp(&(a.from_orders + a.from_rebalance)));
+        log!(
+            r#"{{"event":"unstk.plan","pools":{},"amount":"{}"}}"#,
+            result.len(),
+            orders
+        );
+        result
+    }
+
+    /// splits orders proportional to each candidate's extra.
+    /// If their extra is not enough, each gives all its extra and the rest proportional to its remaining stake
+    fn split_unstake_orders(orders: u128, candidates: &[UnstakeCandidate]) -> Vec<u128> {
+        let extra_sum: u128 = candidates.iter().map(|c| c.extra).sum();
+        let mut parts: Vec<u128> = if extra_sum >= orders {
+            candidates
+                .iter()
+                .map(|c| proportional(orders, c.extra, extra_sum))
+                .collect()
+        } else {
+            let rest = orders - extra_sum;
+            let remaining_sum: u128 = candidates.iter().map(|c| c.staked - c.extra).sum();
+            candidates
+                .iter()
+                .map(|c| {
+                    let from_remaining = if remaining_sum == 0 {
+                        0
+                    } else {
+                        proportional(rest, c.staked - c.extra, remaining_sum)
+                    };
+                    std::cmp::min(c.extra + from_remaining, c.staked)
+                })
+                .collect()
+        };
+        // rounding remainder, to the first pools with room
+        let mut remainder = orders.saturating_sub(parts.iter().sum());
+        for (inx, candidate) in candidates.iter().enumerate() {
+            if remainder == 0 {
+                break;
+            }
+            let add = std::cmp::min(remainder, candidate.staked - parts[inx]);
+            parts[inx] += add;
+            remainder -= add;
+        }
+        parts
+    }
+
+    /// epoch when an amount ordered to unstake now can be withdrawn.
+    /// Pending orders are served first; each pool can receive an unstake from the epoch its current wait ends
+    pub(crate) fn internal_unstake_unlock_epoch(&self, amount: u128) -> EpochHeight {
+        let epoch_height = env::epoch_height();
+        let num_epochs_to_unlock = self.num_epochs_to_unlock();
+        // orders are first cleared against stake orders, that part does not need an unstake
+        let needed = (self.epoch_unstake_orders + amount).saturating_sub(self.epoch_stake_orders);
+        if needed == 0 {
+            return epoch_height + num_epochs_to_unlock;
+        }
+        // (epoch the pool can receive an unstake, staked)
+        let mut slots: Vec<(EpochHeight, u128)> = self
+            .staking_pools
+            .iter()
+            .filter(|sp| sp.staked > 0)
+            .map(|sp| {
+                let available_epoch = if Self::sp_unstake_unblocked(sp) || sp.wait_period_ended(num_epochs_to_unlock) {
+                    epoch_height
+                } else {
+                    sp.unstk_req_epoch_height + num_epochs_to_unlock
+                };
+                if sp.is_busy() {
+                    // in the middle of an operation, assume it is available next epoch at the earliest
+                    (std::cmp::max(available_epoch, epoch_height + 1), sp.staked)
+                } else {
+                    (available_epoch, sp.staked)
+                }
+            })
+            .collect();
+        if slots.is_empty() {
+            //initial stake, nothing staked, someone delay-unstaking in contract epoch 0
+            return epoch_height + num_epochs_to_unlock;
+        }
+        slots.sort_by_key(|(available_epoch, _)| *available_epoch);
+        let mut covered: u128 = 0;
+        for (available_epoch, staked) in slots.iter() {
+            covered += staked;
+            if covered >= needed {
+                return available_epoch + num_epochs_to_unlock;
+            }
+        }
+        // more ordered than staked, only with rounding differences. Use the worst case
+        epoch_height + 2 * num_epochs_to_unlock
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// epoch when `amount` unstaked now (delayed unstake) could be withdrawn
+    pub fn get_unstake_unlock_epoch(&self, amount: U128String) -> U64String {
+        self.internal_unstake_unlock_epoch(amount.0).into()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    /// pools with equal weights and these stakes, and `orders` NEAR to unstake, in NEAR
+    fn new_contract_with_orders(staked: &[u128], orders: u128) -> MetaPool {
+        let mut contract = new_contract_with_pools(staked);
+        contract.epoch_unstake_orders = orders * NEAR;
+        contract.unstake_for_rebalance_cap_bp = 0;
+        set_context("anyone.testnet", 10, 0);
+        contract
+    }
+
+    /// (sp_inx, from_orders, from_rebalance)
+    fn plan(contract: &MetaPool, max_pools: usize) -> Vec<(usize, u128, u128)> {
+        contract
+            .internal_plan_unstake(max_pools)
+            .iter()
+            .map(|p| (p.sp_inx, p.from_orders, p.from_rebalance))
+            .collect()
+    }
+
+    #[test]
+    fn test_one_pool_when_its_extra_covers_the_orders() {
+        let mut contract = new_contract_with_orders(&[300, 200, 250, 250], 30);
+        assert_eq!(plan(&contract, 4), vec![(0, 30 * NEAR, 0)]);
+        // with a rebalance cap, the rest of its extra is unstaked too, up to the cap
+        contract.unstake_for_rebalance_cap_bp = 100;
+        assert_eq!(plan(&contract, 4), vec![(0, 30 * NEAR, 10 * NEAR)]);
+    }
+
+    #[test]
+    fn test_split_proportional_to_extra() {
+        let contract = new_contract_with_orders(&[400, 300, 150, 150], 180);
+        assert_eq!(plan(&contract, 4), vec![(0, 135 * NEAR, 0), (1, 45 * NEAR, 0)]);
+    }
+
+    #[test]
+    fn test_rest_proportional_to_remaining_stake() {
+        let contract = new_contract_with_orders(&[300, 300, 200, 200], 300);
+        let plan = plan(&contract, 4);
+        assert_eq!(plan.len(), 4);
+        assert_eq!(plan.iter().map(|p| p.1).sum::<u128>(), 300 * NEAR);
+        // all its extra (50) plus its share of the rest
+        assert!(plan[0].1 > 105 * NEAR && plan[1].1 > 105 * NEAR);
+        assert!(plan[2].1 > 44 * NEAR && plan[2].1 < 45 * NEAR);
+        assert!(plan[3].1 > 44 * NEAR && plan[3].1 < 45 * NEAR);
+    }
+
+    #[test]
+    fn test_small_parts_are_given_to_the_other_pools() {
+        // pool 1 would get < MIN_UNSTAKE_SPLIT_AMOUNT
+        let contract = new_contract_with_orders(&[350, 255, 200, 195], 104);
+        assert_eq!(plan(&contract, 4), vec![(0, 104 * NEAR, 0)]);
+    }
+
+    #[test]
+    fn test_busy_and_blocked_pools_are_skipped() {
+        let mut contract = new_contract_with_orders(&[400, 300, 150, 150], 20);
+        contract.staking_pools[0].busy_lock = true;
+        // waiting since a previous epoch
+        contract.staking_pools[1].unstaked = 20 * NEAR;
+        contract.staking_pools[1].unstk_req_epoch_height = 8;
+        // waiting since this epoch, preferred over pool 3
+        contract.staking_pools[2].unstaked = 20 * NEAR;
+        contract.staking_pools[2].unstk_req_epoch_height = 10;
+        assert_eq!(plan(&contract, 1), vec![(2, 20 * NEAR, 0)]);
+        assert_eq!(plan(&contract, 4), vec![(2, 10 * NEAR, 0), (3, 10 * NEAR, 0)]);
+    }
+
+    #[test]
+    fn test_no_plan_when_all_pools_are_blocked() {
+        let mut contract = new_contract_with_orders(&[500, 500], 20);
+        contract.staking_pools[0].busy_lock = true;
+        contract.staking_pools[1].unstaked = 20 * NEAR;
+        contract.staking_pools[1].unstk_req_epoch_height = 8;
+        assert!(plan(&contract, 4).is_empty());
+    }
+
+    #[test]
+    fn test_unlock_epoch_waits_for_the_blocked_pools() {
+        let mut contract = new_contract_with_orders(&[100, 100], 0);
+        contract.staking_pools[0].unstaked = 10 * NEAR;
+        contract.staking_pools[0].unstk_req_epoch_height = 9;
+        let num_epochs = contract.num_epochs_to_unlock();
+        assert_eq!(contract.get_unstake_unlock_epoch((50 * NEAR).into()).0, 10 + num_epochs);
+        assert_eq!(contract.get_unstake_unlock_epoch((150 * NEAR).into()).0, 9 + 2 * num_epochs);
+        // cleared against stake orders, no unstake needed
+        contract.epoch_stake_orders = 200 * NEAR;
+        assert_eq!(contract.get_unstake_unlock_epoch((150 * NEAR).into()).0, 10 + num_epochs);
+    }
+}
@@ -0,0 +1,302 @@
+use crate::*;
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+
+//------------------------------------
+// Delayed-unstake tickets
+//------------------------------------
+// Each delayed unstake creates a ticket with its own amount and unlock epoch, so a new unstake
+// does not delay what was unstaked before. Withdrawals take from matured tickets, oldest first.
+// Account.unstaked keeps the sum of the tickets and Account.unstaked_requested_unlock_epoch the latest unlock epoch,
+// so the staking-pool & lockup views keep their meaning (can_withdraw: all tickets matured).
+// Accounts that had unstaked before tickets existed: the difference between Account.unstaked and the tickets
+// is read as a ticket unlocking at Account.unstaked_requested_unlock_epoch
+
+/// max pending tickets per account. When full, a new unstake is merged into the latest ticket
+pub const MAX_UNSTAKE_TICKETS: usize = 16;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UnstakeTicket {
+    pub amount: U128String,
+    pub unlock_epoch: U64String,
+}
+
+impl MetaPool {
+    /// pending tickets of the account, oldest first, including the pre-tickets unstaked amount
+    pub(crate) fn internal_get_unstake_tickets(
+        &self,
+        account_id: &AccountId,
+        acc: &Account,
+    ) -> Vec<UnstakeTicket> {
+        let mut tickets = self.unstake_tickets.get(account_id).unwrap_or_default();
+        let in_tickets: u128 = tickets.iter().map(|t| t.amount.0).sum();
+        if acc.unstaked > in_tickets {
+            tickets.insert(
+                0,
+                UnstakeTicket {
+                    amount: (acc.unstaked - in_tickets).into(),
+                    unlock_epoch: acc.unstaked_requested_unlock_epoch.into(),
+                },
+            );
+        }
+        tickets
+    }
+
+    fn internal_save_unstake_tickets(
+        &mut self,
+        account_id: &AccountId,
+        acc: &mut Account,
+        tickets: &Vec<UnstakeTicket>,
+    ) {
+        if tickets.is_empty() {
+            self.unstake_tickets.remove(account_id);
+        } else {
+            acc.unstaked_requested_unlock_epoch =
+                tickets.iter().map(|t| t.unlock_epoch.0).max().unwrap();
+            self.unstake_tickets.insert(account_id, tickets);
+        }
+    }
+
+    /// adds amount to the account's unstaked, in a ticket unlocking at unlock_epoch.
+    /// Returns the unlock epoch of the ticket where the amount was added
+    pub(crate) fn internal_add_unstake_ticket(
+        &mut self,
+        account_id: &AccountId,
+        acc: &mut Account,
+        amount: u128,
+        unlock_epoch: EpochHeight,
+    ) -> EpochHeight {
+        let mut tickets = self.internal_get_unstake_tickets(account_id, acc);
+        let same_epoch = tickets.iter().position(|t| t.unlock_epoch.0 == unlock_epoch);
+        let ticket_epoch = if let Some(inx) = same_epoch {
+            tickets[inx].amount.0 += amount;
+            unlock_epoch
+        } else if tickets.len() >= MAX_UNSTAKE_TICKETS {
+            // merge into the latest ticket, the amount is not unlocked before unlock_epoch
+            let latest = tickets.iter_mut().max_by_key(|t| t.unlock_epoch.0).unwrap();
+            latest.amount.0 += amount;
+            latest.unlock_epoch.0 = std::cmp::max(latest.unlock_epoch.0, unlock_epoch);
+            latest.unlock_epoch.0
+        } else {
+            tickets.push(UnstakeTicket {
+                amount: amount.into(),
+                unlock_epoch: unlock_epoch.into(),
+            });
+            unlock_epoch
+        };
+        acc.unstaked += amount;
+        self.internal_save_unstake_tickets(account_id, acc, &tickets);
+        ticket_epoch
+    }
+
+    /// removes amount (all if None) from the ticket unlocking at unlock_epoch and from acc.unstaked.
+    /// total_unstake_claims is not changed, the amount is still to be retrieved from the pools. Returns the amount
+    pub(crate) fn internal_take_from_unstake_ticket(
+        &mut self,
+        account_id: &AccountId,
+        acc: &mut Account,
+        unlock_epoch: EpochHeight,
+        amount: Option<u128>,
+    ) -> u128 {
+        let mut tickets = self.internal_get_unstake_tickets(account_id, acc);
+        let inx = tickets
+            .iter()
+            .position(|t| t.unlock_epoch.0 == unlock_epoch)
+            .expect("no unstake ticket unlocking at that epoch");
+        let amount = amount.unwrap_or(tickets[inx].amount.0);
+        assert!(amount > 0, "amount must be > 0");
+        assert!(
+            amount <= tickets[inx].amount.0,
+            "the ticket has only {}",
+            tickets[inx].amount.0
+        );
+        tickets[inx].amount.0 -= amount;
+        tickets.retain(|t| t.amount.0 > 0);
+        acc.unstaked -= amount;
+        self.internal_save_unstake_tickets(account_id, acc, &tickets);
+        amount
+    }
+
+    /// unstaked amount in matured tickets
+    pub(crate) fn internal_matured_unstaked(&self, account_id: &AccountId, acc: &Account) -> u128 {
+        let epoch = env::epoch_height();
+        self.internal_get_unstake_tickets(account_id, acc)
+            .iter()
+            .filter(|t| epoch >= t.unlock_epoch.0)
+            .map(|t| t.amount.0)
+            .sum()
+    }
+
+    /// removes amount from matured tickets (oldest first) and completes the unstake:
+    /// moves it from acc.unstaked to acc.available, see Account.in_memory_try_finish_unstaking
+    pub(crate) fn internal_finish_unstaking(
+        &mut self,
+        account_id: &AccountId,
+        acc: &mut Account,
+        amount: u128,
+    ) {
+        assert!(
+            amount <= acc.unstaked,
+            "Not enough unstaked balance {}",
+            acc.unstaked
+        );
+        let epoch = env::epoch_height();
+        let mut tickets = self.internal_get_unstake_tickets(account_id, acc);
+        let matured: u128 = tickets
+            .iter()
+            .filter(|t| epoch >= t.unlock_epoch.0)
+            .map(|t| t.amount.0)
+            .sum();
+        if amount > matured {
+            let next_unlock = tickets
+                .iter()
+                .filter(|t| epoch < t.unlock_epoch.0)
+                .map(|t| t.unlock_epoch.0)
+                .min()
+                .unwrap_or(epoch);
+            panic!(
+                "The unstaked balance is not yet available due to unstaking delay. {} available now, next unlock in {} epochs",
+                matured,
+                next_unlock - epoch
+            );
+        }
+        let mut to_take = amount;
+        for ticket in tickets.iter_mut().filter(|t| epoch >= t.unlock_epoch.0) {
+            let take = std::cmp::min(to_take, ticket.amount.0);
+            ticket.amount.0 -= take;
+            to_take -= take;
+            if to_take == 0 {
+                break;
+            }
+        }
+        tickets.retain(|t| t.amount.0 > 0);
+        acc.in_memory_try_finish_unstaking(account_id, amount, self);
+        self.internal_save_unstake_tickets(account_id, acc, &tickets);
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// pending delayed-unstake tickets of an account, oldest first
+    pub fn get_unstake_tickets(&self, account_id: AccountId) -> Vec<UnstakeTicket> {
+        match self.accounts.get(&account_id) {
+            Some(acc) => self.internal_get_unstake_tickets(&account_id, &acc),
+            None => Vec::new(),
+        }
+    }
+
+    /// unstaked amount of an account that can be withdrawn now
+    pub fn get_withdrawable_unstaked(&self, account_id: AccountId) -> U128String {
+        match self.accounts.get(&account_id) {
+            Some(acc) => self.internal_matured_unstaked(&account_id, &acc).into(),
+            None => 0.into(),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    const ALICE: &str = "alice.testnet";
+
+    /// (amount in NEAR, unlock_epoch)
+    fn tickets(contract: &MetaPool, acc: &Account) -> Vec<(u128, EpochHeight)> {
+        contract
+            .internal_get_unstake_tickets(&ALICE.into(), acc)
+            .iter()
+            .map(|t| (t.amount.0 / NEAR, t.unlock_epoch.0))
+            .collect()
+    }
+
+    #[test]
+    fn test_one_ticket_per_unlock_epoch() {
+        let mut contract = new_contract();
+        let mut acc = Account::default();
+        let alice: AccountId = ALICE.into();
+        assert_eq!(contract.internal_add_unstake_ticket(&alice, &mut acc, 10 * NEAR, 14), 14);
+        assert_eq!(contract.internal_add_unstake_ticket(&alice, &mut acc, 5 * NEAR, 16), 16);
+        assert_eq!(contract.internal_add_unstake_ticket(&alice, &mut acc, 2 * NEAR, 14), 14);
+        assert_eq!(tickets(&contract, &acc), vec![(12, 14), (5, 16)]);
+        assert_eq!(acc.unstaked, 17 * NEAR);
+        assert_eq!(acc.unstaked_requested_unlock_epoch, 16);
+    }
+
+    #[test]
+    fn test_merged_into_the_latest_ticket_when_full() {
+        let mut contract = new_contract();
+        let mut acc = Account::default();
+        let alice: AccountId = ALICE.into();
+        for n in 0..MAX_UNSTAKE_TICKETS as u64 {
+            contract.internal_add_unstake_ticket(&alice, &mut acc, NEAR, 14 + n);
+        }
+        let latest = 14 + MAX_UNSTAKE_TICKETS as u64 - 1;
+        // an earlier unlock epoch is not honored, the amount waits for the latest ticket
+        assert_eq!(contract.internal_add_unstake_ticket(&alice, &mut acc, NEAR, 12), latest);
+        assert_eq!(contract.internal_add_unstake_ticket(&alice, &mut acc, NEAR, latest + 5), latest + 5);
+        let tickets = tickets(&contract, &acc);
+        assert_eq!(tickets.len(), MAX_UNSTAKE_TICKETS);
+        assert_eq!(tickets[MAX_UNSTAKE_TICKETS - 1], (3, latest + 5));
+        assert_eq!(acc.unstaked, (MAX_UNSTAKE_TICKETS as u128 + 2) * NEAR);
+    }
+
+    #[test]
+    fn test_unstaked_before_tickets_is_the_oldest_ticket() {
+        let mut contract = new_contract();
+        let mut acc = Account::default();
+        acc.unstaked = 7 * NEAR;
+        acc.unstaked_requested_unlock_epoch = 12;
+        contract.internal_add_unstake_ticket(&ALICE.into(), &mut acc, 3 * NEAR, 14);
+        assert_eq!(tickets(&contract, &acc), vec![(7, 12), (3, 14)]);
+        set_context(ALICE, 12, 0);
+        assert_eq!(contract.internal_matured_unstaked(&ALICE.into(), &acc), 7 * NEAR);
+    }
+
+    #[test]
+    fn test_finish_unstaking_takes_matured_tickets_oldest_first() {
+        let mut contract = new_contract();
+        add_account_with_ticket(&mut contract, ALICE, 10 * NEAR, 12);
+        add_account_with_ticket(&mut contract, ALICE, 10 * NEAR, 13);
+        add_account_with_ticket(&mut contract, ALICE, 10 * NEAR, 20);
+        let alice: AccountId = ALICE.into();
+        let mut acc = contract.internal_get_account(&alice);
+        set_context(ALICE, 14, 0);
+        assert_eq!(contract.internal_matured_unstaked(&alice, &acc), 20 * NEAR);
+        contract.internal_finish_unstaking(&alice, &mut acc, 15 * NEAR);
+        assert_eq!(tickets(&contract, &acc), vec![(5, 13), (10, 20)]);
+        assert_eq!(acc.unstaked, 15 * NEAR);
+        assert_eq!(acc.available, 15 * NEAR);
+        assert_eq!(contract.total_unstake_claims, 15 * NEAR);
+        assert_eq!(contract.total_available, 15 * NEAR);
+    }
+
+    #[test]
+    #[should_panic(expected = "5000000000000000000000000 available now, next unlock in 6 epochs")]
+    fn test_finish_unstaking_more_than_matured() {
+        let mut contract = new_contract();
+        add_account_with_ticket(&mut contract, ALICE, 5 * NEAR, 12);
+        add_account_with_ticket(&mut contract, ALICE, 10 * NEAR, 20);
+        let alice: AccountId = ALICE.into();
+        let mut acc = contract.internal_get_account(&alice);
+        set_context(ALICE, 14, 0);
+        contract.internal_finish_unstaking(&alice, &mut acc, 6 * NEAR);
+    }
+
+    #[test]
+    fn test_take_from_a_ticket() {
+        let mut contract = new_contract();
+        let mut acc = Account::default();
+        let alice: AccountId = ALICE.into();
+        contract.internal_add_unstake_ticket(&alice, &mut acc, 10 * NEAR, 14);
+        contract.internal_add_unstake_ticket(&alice, &mut acc, 5 * NEAR, 16);
+        assert_eq!(contract.internal_take_from_unstake_ticket(&alice, &mut acc, 14, Some(4 * NEAR)), 4 * NEAR);
+        assert_eq!(tickets(&contract, &acc), vec![(6, 14), (5, 16)]);
+        // the whole ticket
+        assert_eq!(contract.internal_take_from_unstake_ticket(&alice, &mut acc, 16, None), 5 * NEAR);
+        assert_eq!(tickets(&contract, &acc), vec![(6, 14)]);
+        assert_eq!(acc.unstaked, 6 * NEAR);
+        assert_eq!(acc.unstaked_requested_unlock_epoch, 14);
+    }
+}
@@ -37,26 +37,6 @@ pub fn assert_one_yocto() {
     );
 }
 
-pub fn assert_lockup_contract_calling() {
-    assert!(
-        env::predecessor_account_id() == "lockup-meta-pool.near"
-        || env::predecessor_account_id() == "lockup.meta-v2.pool.testnet",
-        "the function can only be operated by lockup-meta-pool.near"
-    );
-}
-
-/// verify if it a lockup account
-pub fn is_lockup_account(account_id: &String) -> bool{
-    account_id.ends_with(".lockup.near") 
-    || account_id.ends_with(".lockupy.testnet") 
-}
-
-/// assert it is not a lockup account
-pub fn assert_not_lockup_account_calling() {
-    assert!(!is_lockup_account(&env::predecessor_account_id()),"a .lockup.near account can not be used here");
-}
-
-
 pub fn is_promise_success() -> bool {
     assert_eq!(
         env::promise_results_count(),
@@ -0,0 +1,415 @@
+use crate::*;
+use near_sdk::near_bindgen;
+use near_sdk::serde::{Deserialize, Serialize};
+
+//------------------------------------
+// Performance-driven weight strategy
+//------------------------------------
+// Computes pool weights from each pool's measured performance (see reward_history.rs):
+// - reward rate: APY over the window, relative to the best pool
+// - uptime: fraction of the window's epochs where rewards arrived
+// - fee: the pool's reward fee, recorded when it was added (see sp_whitelist.rs)
+// - governance score: set per pool by a weight manager
+// score = weighted sum of the 4 metrics. target weights are proportional to the score, capped by max_weight_bp.
+// With too few pools to hold 10000 under the cap (e.g. the default 1000 with less than 10 pools),
+// the cap is raised to 10000 / pools with score, rounded up.
+// The proposed weights move from the current ones to the target, at most max_change_bp_per_epoch per pool.
+// get_weight_strategy_proposal() shows the result, apply_weight_strategy() (keeper role, once per epoch) applies it.
+
+/// score of the rate & uptime metrics of pools without records in the window (new pools)
+const NEUTRAL_SCORE_BP: u128 = 5000;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WeightStrategyConfig {
+    /// apply_weight_strategy is allowed
+    pub enabled: bool,
+    /// epochs of reward history considered
+    pub window_epochs: u16,
+    /// concentration cap, no pool gets more than this weight
+    pub max_weight_bp: u16,
+    /// max change of a pool's weight per epoch (up or down)
+    pub max_change_bp_per_epoch: u16,
+    /// how much each metric counts in the score, must add 10000
+    pub rate_factor_bp: u16,
+    pub uptime_factor_bp: u16,
+    pub fee_factor_bp: u16,
+    pub governance_factor_bp: u16,
+}
+
+impl Default for WeightStrategyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_epochs: 28,
+            max_weight_bp: 1000,
+            max_change_bp_per_epoch: 50,
+            rate_factor_bp: 4000,
+            uptime_factor_bp: 3000,
+            fee_factor_bp: 1000,
+            governance_factor_bp: 2000,
+        }
+    }
+}
+
+/// Struct returned from get_weight_strategy_proposal, one per pool
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WeightProposalJSON {
+    pub id: u16,
+    pub account_id: AccountId,
+    pub current_bp: u16,
+    /// weight if there were no per-epoch change limit
+    pub target_bp: u16,
+    /// weight apply_weight_strategy would set now
+    pub proposed_bp: u16,
+    pub score_bp: u16,
+    pub apy_bp: u32,
+    pub uptime_bp: u16,
+    /// pool's reward fee, None if unknown (pool added before whitelist validation)
+    pub fee_bp: Option<u16>,
+    pub governance_score_bp: u16,
+}
+
+impl MetaPool {
+    pub(crate) fn assert_weight_strategy_config_is_valid(config: &WeightStrategyConfig) {
+        assert!(config.window_epochs > 0, "window_epochs must be > 0");
+        // same limit as set_staking_pools
+        assert!(
+            config.max_weight_bp > 0 && config.max_weight_bp < 5000,
+            "max_weight_bp must be 1..4999"
+        );
+        assert!(config.max_change_bp_per_epoch > 0, "max_change_bp_per_epoch must be > 0");
+        assert_eq!(
+            config.rate_factor_bp as u32
+                + config.uptime_factor_bp as u32
+                + config.fee_factor_bp as u32
+                + config.governance_factor_bp as u32,
+            10000,
+            "factors must add 10000"
+        );
+    }
+
+    /// pools that can receive weight
+    fn sp_eligible_for_weight(sp: &StakingPoolInfo) -> bool {
+        !sp.stake_paused && !sp.draining
+    }
+
+    /// computes the proposal for every pool, in staking_pools order
+    pub(crate) fn internal_weight_strategy_proposal(&self) -> Vec<WeightProposalJSON> {
+        let config = &self.weight_strategy_config;
+        let apys: Vec<SpApyJSON> = self
+            .staking_pools
+            .iter()
+            .map(|sp| self.internal_sp_apy(sp.account_id.clone(), config.window_epochs))
+            .collect();
+        let max_apy = apys.iter().map(|apy| apy.apy_bp).max().unwrap_or(0) as u128;
+
+        // score
+        let mut result: Vec<WeightProposalJSON> = Vec::with_capacity(self.staking_pools.len());
+        for (inx, sp) in self.staking_pools.iter().enumerate() {
+            let apy = &apys[inx];
+            let (rate_score, uptime_bp) = if apy.records == 0 {
+                (NEUTRAL_SCORE_BP, NEUTRAL_SCORE_BP)
+            } else {
+                (
+                    if max_apy == 0 { 0 } else { apy.apy_bp as u128 * 10000 / max_apy },
+                    apy.epochs_with_rewards.0 as u128 * 10000 / apy.epochs.0 as u128,
+                )
+            };
+            let fee_bp = sp
+                .validation
+                .as_ref()
+                .map(|v| std::cmp::min(v.reward_fee_fraction.basis_points(), 10000) as u16);
+            // unknown fee counts as the max accepted for new pools
+            let fee_score = 10000 - fee_bp.unwrap_or(self.max_sp_reward_fee_bp) as u128;
+            let score = if Self::sp_eligible_for_weight(sp) {
+                (rate_score * config.rate_factor_bp as u128
+                    + uptime_bp * config.uptime_factor_bp as u128
+                    + fee_score * config.fee_factor_bp as u128
+                    + sp.governance_score_bp as u128 * config.governance_factor_bp as u128)
+                    / 10000
+            } else {
+                0
+            };
+            result.push(WeightProposalJSON {
+                id: sp.id,
+                account_id: sp.account_id.clone(),
+                current_bp: sp.weight_basis_points,
+                target_bp: 0,
+                proposed_bp: 0,
+                score_bp: score as u16,
+                apy_bp: apy.apy_bp,
+                uptime_bp: uptime_bp as u16,
+                fee_bp,
+                governance_score_bp: sp.governance_score_bp,
+            });
+        }
+
+        // target: proportional to the score, capped. What exceeds the cap goes to the uncapped pools
+        let max_weight_bp = Self::effective_max_weight_bp(&result, config.max_weight_bp);
+        let mut capped = vec![false; result.len()];
+        let mut to_assign: u128 = 10000;
+        loop {
+            let score_sum: u128 = result
+                .iter()
+                .enumerate()
+                .filter(|(inx, _)| !capped[*inx])
+                .map(|(_, p)| p.score_bp as u128)
+                .sum();
+            if score_sum == 0 {
+                break;
+            }
+            let mut new_capped = false;
+            for (inx, p) in result.iter_mut().enumerate() {
+                if capped[inx] {
+                    continue;
+                }
+                let target = to_assign * p.score_bp as u128 / score_sum;
+                if target >= max_weight_bp as u128 {
+                    p.target_bp = max_weight_bp;
+                    capped[inx] = true;
+                    new_capped = true;
+                } else {
+                    p.target_bp = target as u16;
+                }
+            }
+            if !new_capped {
+                break;
+            }
+            // assign again what's left to the uncapped pools
+            let capped_total: u128 = result
+                .iter()
+                .enumerate()
+                .filter(|(inx, _)| capped[*inx])
+                .map(|(_, p)| p.target_bp as u128)
+                .sum();
+            to_assign = 10000u128.saturating_sub(capped_total);
+        }
+        Self::assign_remainder(&mut result, |p| &mut p.target_bp, max_weight_bp);
+
+        // proposed: move towards the target, limited per epoch
+        let max_change = config.max_change_bp_per_epoch as i32;
+        let deltas: Vec<i32> = result
+            .iter()
+            .map(|p| (p.target_bp as i32 - p.current_bp as i32).max(-max_change).min(max_change))
+            .collect();
+        let up: i32 = deltas.iter().filter(|d| **d > 0).sum();
+        let down: i32 = -deltas.iter().filter(|d| **d < 0).sum::<i32>();
+        // only move as much up as down, so the sum is preserved
+        let moved = up.min(down);
+        for (inx, p) in result.iter_mut().enumerate() {
+            let delta = deltas[inx];
+            let scaled = if delta > 0 {
+                delta as i64 * moved as i64 / up as i64
+            } else if delta < 0 {
+                delta as i64 * moved as i64 / down as i64
+            } else {
+                0
+            };
+            p.proposed_bp = (p.current_bp as i64 + scaled) as u16;
+        }
+        // the current sum can differ from 10000 only if all weights are 0; then use the target
+        if result.iter().map(|p| p.current_bp as u32).sum::<u32>() != 10000 {
+            for p in result.iter_mut() {
+                p.proposed_bp = p.target_bp;
+            }
+        }
+        Self::assign_remainder(&mut result, |p| &mut p.proposed_bp, max_weight_bp);
+        result
+    }
+
+    /// max_weight_bp, raised if the pools with score can't hold 10000 under it
+    fn effective_max_weight_bp(result: &[WeightProposalJSON], max_weight_bp: u16) -> u16 {
+        let with_score = result.iter().filter(|p| p.score_bp > 0).count() as u32;
+        if with_score == 0 {
+            return max_weight_bp;
+        }
+        std::cmp::max(max_weight_bp as u32, (10000 + with_score - 1) / with_score) as u16
+    }
+
+    /// makes the weights add 10000, adding/removing the rounding remainder from the pools with higher weight
+    fn assign_remainder(
+        result: &mut [WeightProposalJSON],
+        weight: fn(&mut WeightProposalJSON) -> &mut u16,
+        max_weight_bp: u16,
+    ) {
+        let total: i32 = result.iter_mut().map(|p| *weight(p) as i32).sum();
+        if total == 0 {
+            return;
+        }
+        let mut remainder = 10000 - total;
+        let mut order: Vec<usize> = (0..result.len()).collect();
+        order.sort_by_key(|inx| std::cmp::Reverse(*weight(&mut result[*inx])));
+        for inx in order {
+            if remainder == 0 {
+                break;
+            }
+            let w = weight(&mut result[inx]);
+            if *w == 0 {
+                continue;
+            }
+            if remainder > 0 {
+                let add = remainder.min(max_weight_bp as i32 - *w as i32).max(0);
+                *w += add as u16;
+                remainder -= add;
+            } else {
+                let sub = (-remainder).min(*w as i32);
+                *w -= sub as u16;
+                remainder += sub;
+            }
+        }
+    }
+}
+
+#[near_bindgen]
+impl MetaPool {
+    /// the weights the strategy would set now, for review
+    pub fn get_weight_strategy_proposal(&self) -> Vec<WeightProposalJSON> {
+        self.internal_weight_strategy_proposal()
+    }
+
+    /// Keeper's method. Applies the proposed weights, once per epoch
+    pub fn apply_weight_strategy(&mut self) {
+        self.assert_role(Role::Keeper);
+        assert!(self.weight_strategy_config.enabled, "weight strategy is not enabled");
+        let epoch = env::epoch_height();
+        assert!(
+            self.weight_strategy_applied_epoch < epoch,
+            "weight strategy already applied in epoch {}",
+            epoch
+        );
+        let proposal = self.internal_weight_strategy_proposal();
+        let total: u32 = proposal.iter().map(|p| p.proposed_bp as u32).sum();
+        assert_eq!(total, 10000, "proposed weights add {}", total);
+        for (inx, p) in proposal.iter().enumerate() {
+            let sp = &mut self.staking_pools[inx];
+            if sp.weight_basis_points != p.proposed_bp {
+                // same check as set_staking_pools
+                assert!(!sp.is_busy(), "sp {} is busy", sp.account_id);
+                sp.weight_basis_points = p.proposed_bp;
+            }
+        }
+        self.weight_strategy_applied_epoch = epoch;
+        event!(r#"{{"event":"WEIGHTS","epoch":"{}","pools":{}}}"#, epoch, proposal.len());
+    }
+
+    /// Weight manager's method. Queues a change of the weight strategy config
+    pub fn set_weight_strategy_config(&mut self, config: WeightStrategyConfig) -> u64 {
+        self.assert_role(Role::WeightManager);
+        self.internal_propose_change(GovernanceAction::SetWeightStrategyConfig { config })
+    }
+
+    pub fn get_weight_strategy_config(&self) -> WeightStrategyConfig {
+        self.weight_strategy_config.clone()
+    }
+
+    /// Weight manager's method. Sets the governance score (0..10000) of a pool used by the weight strategy
+    pub fn set_sp_governance_score(&mut self, pool: StakingPoolRef, score_bp: u16) {
+        self.assert_role(Role::WeightManager);
+        assert!(score_bp <= 10000, "score_bp must be <= 10000");
+        let sp_inx = self.sp_inx(&pool);
+        self.staking_pools[sp_inx].governance_score_bp = score_bp;
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    /// pools scored only by their governance score
+    fn new_contract_with_scores(governance_scores: &[u16], max_weight_bp: u16) -> MetaPool {
+        let mut contract = new_contract_with_pools(&vec![0; governance_scores.len()]);
+        for (sp, score) in contract.staking_pools.iter_mut().zip(governance_scores) {
+            sp.governance_score_bp = *score;
+        }
+        contract.weight_strategy_config = WeightStrategyConfig {
+            enabled: true,
+            max_weight_bp,
+            max_change_bp_per_epoch: 10000,
+            rate_factor_bp: 0,
+            uptime_factor_bp: 0,
+            fee_factor_bp: 0,
+            governance_factor_bp: 10000,
+            ..WeightStrategyConfig::default()
+        };
+        set_context(OPERATOR_ID, 10, 0);
+        contract
+    }
+
+    fn targets(contract: &MetaPool) -> Vec<u16> {
+        contract.internal_weight_strategy_proposal().iter().map(|p| p.target_bp).collect()
+    }
+
+    #[test]
+    fn test_what_exceeds_the_cap_goes_to_the_uncapped_pools() {
+        let mut scores = vec![10000, 10000];
+        scores.extend(vec![1000; 10]);
+        let contract = new_contract_with_scores(&scores, 1000);
+        let mut expected = vec![1000, 1000];
+        expected.extend(vec![800; 10]);
+        assert_eq!(targets(&contract), expected);
+    }
+
+    #[test]
+    fn test_capping_cascades() {
+        // after capping the first pool, the second one reaches the cap too
+        let contract = new_contract_with_scores(&[9000, 3000, 1000, 1000, 1000, 1000, 1000, 1000], 2000);
+        let targets = targets(&contract);
+        assert_eq!(targets[..2], [2000, 2000]);
+        assert_eq!(targets[2..], [1000; 6]);
+    }
+
+    #[test]
+    fn test_remainder_goes_to_the_larger_weights() {
+        let contract = new_contract_with_scores(&[5000, 5000, 5000], 4000);
+        assert_eq!(targets(&contract), vec![3334, 3333, 3333]);
+    }
+
+    #[test]
+    fn test_remainder_does_not_go_over_the_cap() {
+        let contract = new_contract_with_scores(&[9000, 5000, 5000, 5000], 3000);
+        assert_eq!(targets(&contract), vec![3000, 2334, 2333, 2333]);
+    }
+
+    #[test]
+    fn test_cap_is_raised_with_few_pools() {
+        // the default cap of 1000 can't hold 10000 with 3 pools
+        let contract = new_contract_with_scores(&[10000, 5000, 5000], 1000);
+        assert_eq!(targets(&contract), vec![3334, 3333, 3333]);
+    }
+
+    #[test]
+    fn test_pools_without_score_do_not_count_for_the_cap() {
+        let contract = new_contract_with_scores(&[10000, 10000, 0], 1000);
+        assert_eq!(targets(&contract), vec![5000, 5000, 0]);
+    }
+
+    #[test]
+    fn test_paused_and_draining_pools_get_no_weight() {
+        let mut contract = new_contract_with_scores(&[5000, 5000, 5000, 5000], 5000);
+        contract.staking_pools[1].stake_paused = true;
+        contract.staking_pools[2].draining = true;
+        assert_eq!(targets(&contract), vec![5000, 0, 0, 5000]);
+    }
+
+    #[test]
+    fn test_apply_limits_the_change_per_epoch() {
+        let mut contract = new_contract_with_scores(&[10000, 10000], 5000);
+        contract.staking_pools[0].weight_basis_points = 8000;
+        contract.staking_pools[1].weight_basis_points = 2000;
+        contract.weight_strategy_config.max_change_bp_per_epoch = 500;
+        contract.apply_weight_strategy();
+        assert_eq!(contract.staking_pools[0].weight_basis_points, 7500);
+        assert_eq!(contract.staking_pools[1].weight_basis_points, 2500);
+    }
+
+    #[test]
+    #[should_panic(expected = "already applied")]
+    fn test_apply_once_per_epoch() {
+        let mut contract = new_contract_with_scores(&[10000, 10000], 5000);
+        contract.apply_weight_strategy();
+        contract.apply_weight_strategy();
+    }
+}
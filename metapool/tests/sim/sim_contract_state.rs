@@ -144,7 +144,7 @@ pub fn set_staking_pools(pools: Vec<StakingPoolArgItem>, sim:&Simulation){
         125 * TGAS
     );
     print_exec_result(&res);
-    check_exec_result(&res);
+    execute_pending_change(&sim.operator, &metapool_contract.user_account, &sim.get_epoch_acc, &res);
 }
 
 
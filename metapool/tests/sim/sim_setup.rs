@@ -118,7 +118,7 @@ fn deploy_simulated_staking_pool(
     return sp;
 }
 
-const METAPOOL_CONTRACT_ID: &str = "metapool";
+const METAPOOL_CONTRACT_ID: &str = "metapool.test.near";
 //-----------------------------
 //-----------------------------
 //-----------------------------
@@ -145,6 +145,9 @@ impl Simulation {
         let owner = testnet.create_user("contract-owner".into(), ntoy(1_000_000));
         let treasury = testnet.create_user("treasury".into(), ntoy(1_000_000));
         let operator = testnet.create_user("operator".into(), ntoy(1_000_000));
+        // the contract is deployed to a sandbox account (*.test.near), so it can use the "sandbox" network profile
+        let near = master_account.create_user("near".into(), ntoy(10_000_000));
+        let test_near = near.create_user("test.near".into(), ntoy(1_000_000));
 
         // NO MACROS -------------
         //create acc, deploy & init the main contract
@@ -172,16 +175,41 @@ impl Simulation {
         contract_id: &METAPOOL_CONTRACT_ID,
         bytes: &WASM_BYTES_META_POOL,
         // User deploying the contract
-        signer_account: &owner,
+        signer_account: &test_near,
         // MetaPool.new(
           //   owner_account_id: AccountId,
           //   treasury_account_id: AccountId,
           //   operator_account_id: AccountId,
+          //   meta_token_account_id: AccountId,
+          //   network_profile: Option<String>,
         deposit:500*NEAR,
         gas:25*TGAS,
-        init_method:new(owner.account_id(), treasury.account_id(), operator.account_id(), "meta_token_contract_account".into())
+        init_method:new(owner.account_id(), treasury.account_id(), operator.account_id(), "meta_token_contract_account".into(), Some("sandbox".into()))
         );
 
+        //deploy a contract to get the current epoch (also used to advance epochs)
+        let get_epoch_acc = master_account.deploy(
+            &WASM_BYTES_GET_EPOCH,
+            String::from("get_epoch_acc"),
+            SP_INITIAL_BALANCE,
+        );
+        master_account
+            .create_transaction(get_epoch_acc.account_id())
+            .function_call("new".into(), "{}".into(), 50 * TGAS, 0)
+            .submit();
+
+        // add_staking_pool asks the whitelist contract of the sandbox profile (whitelist.test.near),
+        // the get_epoch contract also works as a mock whitelist
+        let whitelist = test_near.deploy(
+            &WASM_BYTES_GET_EPOCH,
+            String::from("whitelist.test.near"),
+            SP_INITIAL_BALANCE,
+        );
+        test_near
+            .create_transaction(whitelist.account_id())
+            .function_call("new".into(), "{}".into(), 50 * TGAS, 0)
+            .submit();
+
         // deploy all the staking pools and register with meta_pool
         let mut sp = Vec::with_capacity(4);
         let weights_vec: Vec<u8> = vec![15, 40, 25, 20];
@@ -194,11 +222,11 @@ impl Simulation {
                 deploy_simulated_staking_pool(&testnet, &acc_id[..], &owner.account_id());
             //call(&owner,&sp_contract,"pause_staking","{}",0,10*TGAS);
             sp.push(sp_contract);
-            //-- register the staking pool in metapool
+            //-- register the staking pool in metapool (async, checked against the whitelist)
             let res = call!(
                 owner,
                 metapool.add_staking_pool(acc_id.clone()),
-                gas = 25 * TGAS
+                gas = 100 * TGAS
             );
             check_exec_result(&res);
             // prepare weight
@@ -216,7 +244,8 @@ impl Simulation {
             125 * TGAS
         );
         print_exec_result(&res);
-        check_exec_result(&res);
+        // the weights are queued, see governance.rs
+        execute_pending_change(&owner, &metapool.user_account, &get_epoch_acc, &res);
 
         // test contract checks
         // should fail because sum(bp)!=10000
@@ -252,17 +281,6 @@ impl Simulation {
             assert!(!res.is_ok(),"expected sum(bp)!=10000 check to be triggered");
         }
 
-        //deploy a contract to get the current epoch
-        let get_epoch_acc = master_account.deploy(
-            &WASM_BYTES_GET_EPOCH,
-            String::from("get_epoch_acc"),
-            SP_INITIAL_BALANCE,
-        );
-        master_account
-            .create_transaction(get_epoch_acc.account_id())
-            .function_call("new".into(), "{}".into(), 50 * TGAS, 0)
-            .submit();
-
         return Self {
             metapool,
 
@@ -298,13 +298,13 @@ pub fn bot_ping_rewards(sim: &Simulation, start: &State) -> Result<StateAndDiff,
             check_exec_result(&ping);
             //await near.call(pool.account_id, "ping", {}, OPERATOR_ACCOUNT, credentials.private_key, 200);
             //calculates rewards now in the meta for that pool
-            //pub fn distribute_rewards(&mut self, sp_inx: u16) -> void
+            //pub fn distribute_rewards(&mut self, sp_inx: StakingPoolRef) -> void
             println!("meta.DISTR");
             let result = step_call(
                 sim,
                 &sim.operator,
                 "distribute_rewards",
-                json!({ "sp_inx": inx }),
+                json!({ "sp_inx": pool["id"] }),
                 200 * TGAS,
                 NO_DEPOSIT,
                 &state,
@@ -343,7 +343,7 @@ pub fn bot_retrieve(sim: &Simulation, start: &State) -> Result<StateAndDiff, Str
                     sim,
                     &sim.operator,
                     "retrieve_funds_from_a_pool",
-                    json!({ "inx": inx }),
+                    json!({ "inx": pool["id"] }),
                     200 * TGAS,
                     NO_DEPOSIT,
                     &state,
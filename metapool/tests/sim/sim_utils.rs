@@ -185,6 +185,38 @@ pub fn call(
     return exec_res;
 }
 
+//----------------------
+/// advances the simulated chain `epochs` epochs, by making dummy txns to the get_epoch contract
+pub fn wait_epochs(who: &UserAccount, get_epoch_acc: &UserAccount, epochs: u64) {
+    let target = view(get_epoch_acc, "get_epoch_height", "{}").as_u64().unwrap() + epochs;
+    while view(get_epoch_acc, "get_epoch_height", "{}").as_u64().unwrap() < target {
+        call(who, get_epoch_acc, "set_i32", r#"{"num":0}"#, 0, 10 * TGAS);
+    }
+}
+
+/// governance methods (e.g. set_staking_pools) only queue the change and return its id.
+/// Waits the governance delay and executes the change queued by `res`
+pub fn execute_pending_change(
+    who: &UserAccount,
+    metapool: &UserAccount,
+    get_epoch_acc: &UserAccount,
+    res: &ExecutionResult,
+) {
+    check_exec_result(res);
+    let id = res.unwrap_json_value().as_u64().unwrap();
+    let delay = as_u128(&view(metapool, "get_governance_delay", "{}")) as u64;
+    wait_epochs(who, get_epoch_acc, delay);
+    let res = call(
+        who,
+        metapool,
+        "execute_pending_change",
+        &format!(r#"{{"id":{}}}"#, id),
+        0,
+        100 * TGAS,
+    );
+    check_exec_result(&res);
+}
+
 #[allow(dead_code)]
 pub fn show_balance(ua: &UserAccount) {
     println!("@{} balance: {}", ua.account_id(), balance(ua));
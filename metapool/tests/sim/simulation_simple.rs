@@ -306,7 +306,7 @@ fn simtest_simple() {
       println!("------- pool #{} sync unstaked", inx);
       let retrieve_result_sync = call!(
         sim.operator,
-        metapool.sync_unstaked_balance(inx as u16),
+        metapool.sync_unstaked_balance(StakingPoolRef::Id(inx as u16)),
         gas = 200 * TGAS
       );
       check_exec_result(&retrieve_result_sync);
@@ -314,7 +314,7 @@ fn simtest_simple() {
       println!("------- pool #{} retrieve unstaked", inx);
       let retrieve_result_2 = call!(
         sim.operator,
-        metapool.retrieve_funds_from_a_pool(inx as u16),
+        metapool.retrieve_funds_from_a_pool(StakingPoolRef::Id(inx as u16)),
         gas = 200 * TGAS
       );
       check_exec_result(&retrieve_result_2);
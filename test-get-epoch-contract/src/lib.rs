@@ -117,6 +117,12 @@ impl TestContract {
         return env::block_index();
     }
 
+    ///Mock of the lockup whitelist contract, every staking pool is whitelisted
+    pub fn is_whitelisted(&self, staking_pool_account_id: String) -> bool {
+        log!("is_whitelisted {}", staking_pool_account_id);
+        return true;
+    }
+
     // ------------------------------
     //Test u128 as argument type in a callback
     // ------------------------------